@@ -29,28 +29,444 @@ pub enum PartialRegister {
 #[repr(u8)]
 // Intel style partial registers
 pub enum SetccConditions {
-    Overflow = 0,     // O
-    NotOverflow = 1,  // NO
-    Below = 2,        // B, C, NAE
-    AboveOrEqual = 3, // AE, NB, NC
-    Equal = 4,        // E, Z
+    Overflow = 0,         // O
+    NotOverflow = 1,      // NO
+    Below = 2,            // B, C, NAE
+    AboveOrEqual = 3,     // AE, NB, NC
+    Equal = 4,            // E, Z
+    NotEqual = 5,         // NE, NZ
+    BelowOrEqual = 6,     // BE, NA
+    Above = 7,            // A, NBE
+    Parity = 0xa,         // P, PE: an unordered `ucomisd`
+    NotParity = 0xb,      // NP, PO
+    Less = 0xc,           // L, NGE
+    GreaterOrEqual = 0xd, // GE, NL
+    LessOrEqual = 0xe,    // LE, NG
+    Greater = 0xf,        // G, NLE
 }
 
+/// The SSE registers; the ones past XMM7 would need a REX prefix.
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum XmmRegister {
+    Xmm0 = 0,
+    Xmm1,
+    Xmm2,
+    Xmm3,
+    Xmm4,
+    Xmm5,
+    Xmm6,
+    Xmm7,
+}
+
+/// A position in the code stream that jumps can target before it is known.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
 const REX_W_PREFIX: u8 = 0x48;
 pub struct Assembler {
     code: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    // (offset of a rel32 field, label it points at)
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Assembler {
     pub fn new() -> Self {
-        Assembler { code: Vec::new() }
+        Assembler {
+            code: Vec::new(),
+            labels: Vec::new(),
+            fixups: Vec::new(),
+        }
     }
 
     /// Consumes the assembler and returns the raw machine code bytes. IT SHOULD< FOR NOW USE JUST A REF
-    pub fn finalize(self) -> Vec<u8> {
+    pub fn finalize(mut self) -> Vec<u8> {
+        for (at, label) in std::mem::take(&mut self.fixups) {
+            let target = self.labels[label.0].expect("Jump to a label that was never bound");
+            let rel = target as i64 - (at as i64 + 4);
+            self.code[at..at + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
         self.code
     }
 
+    /// Creates a new, unbound label.
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Binds `label` to the current position.
+    pub fn bind(&mut self, label: Label) -> &mut Self {
+        assert!(self.labels[label.0].is_none(), "Label bound twice");
+        self.labels[label.0] = Some(self.code.len());
+        self
+    }
+
+    /// Emits raw bytes, e.g. constant data placed after the code.
+    pub fn emit_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.code.extend_from_slice(bytes);
+        self
+    }
+
+    fn emit_rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.code.extend_from_slice(&[0; 4]);
+    }
+
+    /// Emits the ModR/M (and SIB/displacement) bytes for a `[base + disp]` operand.
+    fn emit_mem_operand(&mut self, reg: u8, base: Register, disp: i32) {
+        let base = base as u8;
+        let mode = if disp == 0 && base != Register::Rbp as u8 {
+            0b00
+        } else if i8::try_from(disp).is_ok() {
+            0b01
+        } else {
+            0b10
+        };
+        self.code.push((mode << 6) | (reg << 3) | base);
+        if base == Register::Rsp as u8 {
+            self.code.push(0x24); // SIB: no index, base = rsp
+        }
+        match mode {
+            0b01 => self.code.push(disp as i8 as u8),
+            0b10 => self.code.extend_from_slice(&disp.to_le_bytes()),
+            _ => {}
+        }
+    }
+
+    /// Emits a 64-bit register to register operation, `op dst, src`, for the
+    /// `op r/m64, r64` family of opcodes.
+    fn emit_reg_reg(&mut self, opcode: u8, dst: Register, src: Register) {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(opcode);
+        self.code.push(0xc0 | ((src as u8) << 3) | dst as u8);
+    }
+
+    /// Emits a full 64-bit immediate move (`movabs`).
+    /// Example: `mov rax, 0x1122334455667788`
+    pub fn mov_reg_imm64(&mut self, dst: Register, src: i64) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0xb8 + dst as u8);
+        self.code.extend_from_slice(&src.to_le_bytes());
+        self
+    }
+
+    /// `mov dst, imm` with the shortest encoding that produces `imm`: a 32-bit
+    /// move (which zero-extends), a sign-extended imm32, or `movabs`.
+    pub fn mov_reg_imm(&mut self, dst: Register, imm: i64) -> &mut Self {
+        if let Ok(imm) = u32::try_from(imm) {
+            self.code.push(0xb8 + dst as u8);
+            self.code.extend_from_slice(&imm.to_le_bytes());
+        } else if let Ok(imm) = i32::try_from(imm) {
+            self.mov_reg_imm32(dst, imm);
+        } else {
+            self.mov_reg_imm64(dst, imm);
+        }
+        self
+    }
+
+    /// Emits `op dst, imm` for the group-1 arithmetic opcodes, where `ext` is
+    /// the opcode extension in the ModR/M reg field. Uses the sign-extended
+    /// imm8 form when `imm` fits.
+    fn emit_arith_imm(&mut self, ext: u8, dst: Register, imm: i32) {
+        self.code.push(REX_W_PREFIX);
+        match i8::try_from(imm) {
+            Ok(imm) => {
+                self.code.push(0x83);
+                self.code.push(0xc0 | (ext << 3) | dst as u8);
+                self.code.push(imm as u8);
+            }
+            Err(_) => {
+                self.code.push(0x81);
+                self.code.push(0xc0 | (ext << 3) | dst as u8);
+                self.code.extend_from_slice(&imm.to_le_bytes());
+            }
+        }
+    }
+
+    /// `add dst, imm`, using an imm8 when it fits.
+    pub fn add_reg_imm(&mut self, dst: Register, imm: i32) -> &mut Self {
+        self.emit_arith_imm(0, dst, imm);
+        self
+    }
+
+    /// `sub dst, imm`, using an imm8 when it fits.
+    pub fn sub_reg_imm(&mut self, dst: Register, imm: i32) -> &mut Self {
+        self.emit_arith_imm(5, dst, imm);
+        self
+    }
+
+    /// `cmp dst, imm`, using an imm8 when it fits.
+    pub fn cmp_reg_imm(&mut self, dst: Register, imm: i32) -> &mut Self {
+        self.emit_arith_imm(7, dst, imm);
+        self
+    }
+
+    /// `and dst, imm`, using an imm8 when it fits. The immediate is
+    /// sign-extended, so `0xff` needs the imm32 form.
+    pub fn and_reg_imm(&mut self, dst: Register, imm: i32) -> &mut Self {
+        self.emit_arith_imm(4, dst, imm);
+        self
+    }
+
+    /// `or dst, imm`, using an imm8 when it fits.
+    pub fn or_reg_imm(&mut self, dst: Register, imm: i32) -> &mut Self {
+        self.emit_arith_imm(1, dst, imm);
+        self
+    }
+
+    /// `sub dst, src`
+    pub fn sub_reg_reg(&mut self, dst: Register, src: Register) -> &mut Self {
+        self.emit_reg_reg(0x29, dst, src);
+        self
+    }
+
+    /// `mov dst, src`
+    pub fn mov_reg_reg(&mut self, dst: Register, src: Register) -> &mut Self {
+        self.emit_reg_reg(0x89, dst, src);
+        self
+    }
+
+    /// `mov dst, [base + disp]`
+    pub fn mov_reg_mem(&mut self, dst: Register, base: Register, disp: i32) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0x8b);
+        self.emit_mem_operand(dst as u8, base, disp);
+        self
+    }
+
+    /// `mov [base + disp], src`
+    pub fn mov_mem_reg(&mut self, base: Register, disp: i32, src: Register) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0x89);
+        self.emit_mem_operand(src as u8, base, disp);
+        self
+    }
+
+    /// `add dst, [base + disp]`
+    pub fn add_reg_mem(&mut self, dst: Register, base: Register, disp: i32) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0x03);
+        self.emit_mem_operand(dst as u8, base, disp);
+        self
+    }
+
+    /// `sub dst, [base + disp]`
+    pub fn sub_reg_mem(&mut self, dst: Register, base: Register, disp: i32) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0x2b);
+        self.emit_mem_operand(dst as u8, base, disp);
+        self
+    }
+
+    /// `cmp dst, [base + disp]`
+    pub fn cmp_reg_mem(&mut self, dst: Register, base: Register, disp: i32) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0x3b);
+        self.emit_mem_operand(dst as u8, base, disp);
+        self
+    }
+
+    /// `mov qword [base + disp], imm`, sign-extending the immediate.
+    pub fn mov_mem_imm32(&mut self, base: Register, disp: i32, imm: i32) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0xc7);
+        self.emit_mem_operand(0, base, disp);
+        self.code.extend_from_slice(&imm.to_le_bytes());
+        self
+    }
+
+    /// `mov byte [base + disp], imm8`
+    pub fn mov_mem8_imm8(&mut self, base: Register, disp: i32, imm: u8) -> &mut Self {
+        self.code.push(0xc6);
+        self.emit_mem_operand(0, base, disp);
+        self.code.push(imm);
+        self
+    }
+
+    /// `lea dst, [base + disp]`
+    pub fn lea_reg_mem(&mut self, dst: Register, base: Register, disp: i32) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0x8d);
+        self.emit_mem_operand(dst as u8, base, disp);
+        self
+    }
+
+    /// `lea dst, [rip + label]`, used to reference data emitted next to the code.
+    pub fn lea_reg_label(&mut self, dst: Register, label: Label) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0x8d);
+        self.code.push(((dst as u8) << 3) | 0b101); // mod=00, r/m=101 is rip-relative
+        self.emit_rel32(label);
+        self
+    }
+
+    /// `add dst, src`
+    pub fn add_reg_reg(&mut self, dst: Register, src: Register) -> &mut Self {
+        self.emit_reg_reg(0x01, dst, src);
+        self
+    }
+
+    /// `cmp dst, src`
+    pub fn cmp_reg_reg(&mut self, dst: Register, src: Register) -> &mut Self {
+        self.emit_reg_reg(0x39, dst, src);
+        self
+    }
+
+    /// `or dst, src`
+    pub fn or_reg_reg(&mut self, dst: Register, src: Register) -> &mut Self {
+        self.emit_reg_reg(0x09, dst, src);
+        self
+    }
+
+    /// `imul dst, src`, a signed multiply setting the overflow flag when the
+    /// product does not fit 64 bits.
+    pub fn imul_reg_reg(&mut self, dst: Register, src: Register) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.extend_from_slice(&[0x0f, 0xaf]);
+        // The destination goes in the reg field, unlike `emit_reg_reg`.
+        self.code.push(0xc0 | ((dst as u8) << 3) | src as u8);
+        self
+    }
+
+    /// `cqo`: sign-extends RAX into RDX, ahead of `idiv`.
+    pub fn cqo(&mut self) -> &mut Self {
+        self.code.extend_from_slice(&[REX_W_PREFIX, 0x99]);
+        self
+    }
+
+    /// `idiv src`: divides RDX:RAX by `src`, leaving the quotient in RAX and
+    /// the remainder in RDX.
+    pub fn idiv_reg(&mut self, src: Register) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0xf7);
+        self.code.push(0xf8 + src as u8); // ModR/M: mod=11, reg=111 (/7 = IDIV)
+        self
+    }
+
+    /// `cmp byte [base + disp], imm8`
+    pub fn cmp_mem8_imm8(&mut self, base: Register, disp: i32, imm: u8) -> &mut Self {
+        self.code.push(0x80);
+        self.emit_mem_operand(7, base, disp);
+        self.code.push(imm);
+        self
+    }
+
+    /// Emits a scalar double operation, `op dst, src`, for the `F2 0F`
+    /// family of opcodes.
+    fn emit_sse_reg_reg(&mut self, opcode: u8, dst: XmmRegister, src: XmmRegister) {
+        self.code.extend_from_slice(&[0xf2, 0x0f, opcode]);
+        self.code.push(0xc0 | ((dst as u8) << 3) | src as u8);
+    }
+
+    /// `movsd dst, [base + disp]`
+    pub fn movsd_xmm_mem(&mut self, dst: XmmRegister, base: Register, disp: i32) -> &mut Self {
+        self.code.extend_from_slice(&[0xf2, 0x0f, 0x10]);
+        self.emit_mem_operand(dst as u8, base, disp);
+        self
+    }
+
+    /// `movsd [base + disp], src`
+    pub fn movsd_mem_xmm(&mut self, base: Register, disp: i32, src: XmmRegister) -> &mut Self {
+        self.code.extend_from_slice(&[0xf2, 0x0f, 0x11]);
+        self.emit_mem_operand(src as u8, base, disp);
+        self
+    }
+
+    /// `addsd dst, src`
+    pub fn addsd(&mut self, dst: XmmRegister, src: XmmRegister) -> &mut Self {
+        self.emit_sse_reg_reg(0x58, dst, src);
+        self
+    }
+
+    /// `subsd dst, src`
+    pub fn subsd(&mut self, dst: XmmRegister, src: XmmRegister) -> &mut Self {
+        self.emit_sse_reg_reg(0x5c, dst, src);
+        self
+    }
+
+    /// `mulsd dst, src`
+    pub fn mulsd(&mut self, dst: XmmRegister, src: XmmRegister) -> &mut Self {
+        self.emit_sse_reg_reg(0x59, dst, src);
+        self
+    }
+
+    /// `divsd dst, src`
+    pub fn divsd(&mut self, dst: XmmRegister, src: XmmRegister) -> &mut Self {
+        self.emit_sse_reg_reg(0x5e, dst, src);
+        self
+    }
+
+    /// `ucomisd a, b`: sets ZF, PF and CF like an unsigned `cmp`, and all
+    /// three when either is NaN.
+    pub fn ucomisd(&mut self, a: XmmRegister, b: XmmRegister) -> &mut Self {
+        self.code.extend_from_slice(&[0x66, 0x0f, 0x2e]);
+        self.code.push(0xc0 | ((a as u8) << 3) | b as u8);
+        self
+    }
+
+    /// `movq dst, src`, moving the bits of a general register.
+    pub fn movq_xmm_reg(&mut self, dst: XmmRegister, src: Register) -> &mut Self {
+        self.code
+            .extend_from_slice(&[0x66, REX_W_PREFIX, 0x0f, 0x6e]);
+        self.code.push(0xc0 | ((dst as u8) << 3) | src as u8);
+        self
+    }
+
+    /// `movq dst, src`, moving the bits of a double.
+    pub fn movq_reg_xmm(&mut self, dst: Register, src: XmmRegister) -> &mut Self {
+        self.code
+            .extend_from_slice(&[0x66, REX_W_PREFIX, 0x0f, 0x7e]);
+        self.code.push(0xc0 | ((src as u8) << 3) | dst as u8);
+        self
+    }
+
+    /// `cvtsi2sd dst, src`, converting a signed 64-bit integer.
+    pub fn cvtsi2sd(&mut self, dst: XmmRegister, src: Register) -> &mut Self {
+        self.code
+            .extend_from_slice(&[0xf2, REX_W_PREFIX, 0x0f, 0x2a]);
+        self.code.push(0xc0 | ((dst as u8) << 3) | src as u8);
+        self
+    }
+
+    pub fn push_reg(&mut self, src: Register) -> &mut Self {
+        self.code.push(0x50 + src as u8);
+        self
+    }
+
+    pub fn pop_reg(&mut self, dst: Register) -> &mut Self {
+        self.code.push(0x58 + dst as u8);
+        self
+    }
+
+    /// `call reg`, an absolute indirect call.
+    pub fn call_reg(&mut self, target: Register) -> &mut Self {
+        self.code.push(0xff);
+        self.code.push(0xd0 + target as u8); // ModR/M: mod=11, reg=010 (/2 = CALL)
+        self
+    }
+
+    pub fn jmp(&mut self, label: Label) -> &mut Self {
+        self.code.push(0xe9);
+        self.emit_rel32(label);
+        self
+    }
+
+    /// Conditional jump, e.g. `jne label`. Reuses the setcc condition codes.
+    pub fn jcc(&mut self, cond: SetccConditions, label: Label) -> &mut Self {
+        self.code.push(0x0f);
+        self.code.push(0x80 + cond as u8);
+        self.emit_rel32(label);
+        self
+    }
+
     /// Emits a 64-bit "move register, immediate" instruction.
     /// Example: `mov rax, 42`
     pub fn mov_reg_imm32(&mut self, dst: Register, src: i32) -> &mut Self {
@@ -83,6 +499,32 @@ impl Assembler {
         self
     }
 
+    /// `sar dst, imm8`, an arithmetic shift right.
+    pub fn sar_reg_imm8(&mut self, dst: Register, imm8: u8) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0xc1);
+        self.code.push(0xf8 + dst as u8); // ModR/M: mod=11, reg=111 (/7 = SAR), r/m=dst
+        self.code.push(imm8);
+        self
+    }
+
+    /// `shr dst, imm8`, a logical shift right.
+    pub fn shr_reg_imm8(&mut self, dst: Register, imm8: u8) -> &mut Self {
+        self.code.push(REX_W_PREFIX);
+        self.code.push(0xc1);
+        self.code.push(0xe8 + dst as u8); // ModR/M: mod=11, reg=101 (/5 = SHR), r/m=dst
+        self.code.push(imm8);
+        self
+    }
+
+    /// `nop dword [rax + disp32]`: does nothing, but carries 32 bits of data
+    /// in the instruction stream, readable at a known address.
+    pub fn nop_disp32(&mut self, data: u32) -> &mut Self {
+        self.code.extend_from_slice(&[0x0f, 0x1f, 0x80]);
+        self.code.extend_from_slice(&data.to_le_bytes());
+        self
+    }
+
     pub fn or_reg_imm8(&mut self, dst: Register, imm8: u8) -> &mut Self {
         self.code.push(REX_W_PREFIX); // 0x48 → use 64-bit operands
         self.code.push(0x83); // Opcode for arithmetic with imm8 (sign-extended)
@@ -117,3 +559,196 @@ impl Assembler {
         self // Return `&mut Self` to allow chaining
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(emit: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+        let mut asm = Assembler::new();
+        emit(&mut asm);
+        asm.finalize()
+    }
+
+    #[test]
+    fn test_mov_reg_imm_picks_shortest_encoding() {
+        // mov ecx, 42
+        assert_eq!(
+            encode(|asm| {
+                asm.mov_reg_imm(Register::Rcx, 42);
+            }),
+            vec![0xb9, 42, 0, 0, 0]
+        );
+        // mov eax, 0xffffffff: zero-extension covers the whole u32 range.
+        assert_eq!(
+            encode(|asm| {
+                asm.mov_reg_imm(Register::Rax, 0xffff_ffff);
+            }),
+            vec![0xb8, 0xff, 0xff, 0xff, 0xff]
+        );
+        // mov rax, -1
+        assert_eq!(
+            encode(|asm| {
+                asm.mov_reg_imm(Register::Rax, -1);
+            }),
+            vec![0x48, 0xc7, 0xc0, 0xff, 0xff, 0xff, 0xff]
+        );
+        // movabs rdx, 0x100000000
+        assert_eq!(
+            encode(|asm| {
+                asm.mov_reg_imm(Register::Rdx, 1 << 32);
+            }),
+            vec![0x48, 0xba, 0, 0, 0, 0, 1, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_arith_imm_picks_shortest_encoding() {
+        // add rax, 4
+        assert_eq!(
+            encode(|asm| {
+                asm.add_reg_imm(Register::Rax, 4);
+            }),
+            vec![0x48, 0x83, 0xc0, 4]
+        );
+        // sub rsp, -128
+        assert_eq!(
+            encode(|asm| {
+                asm.sub_reg_imm(Register::Rsp, -128);
+            }),
+            vec![0x48, 0x83, 0xec, 0x80]
+        );
+        // cmp rdi, 128
+        assert_eq!(
+            encode(|asm| {
+                asm.cmp_reg_imm(Register::Rdi, 128);
+            }),
+            vec![0x48, 0x81, 0xff, 128, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_header_instructions() {
+        // shr rdi, 14
+        assert_eq!(
+            encode(|asm| {
+                asm.shr_reg_imm8(Register::Rdi, 14);
+            }),
+            vec![0x48, 0xc1, 0xef, 14]
+        );
+        // or rsi, 0x24f
+        assert_eq!(
+            encode(|asm| {
+                asm.or_reg_imm(Register::Rsi, 0x24f);
+            }),
+            vec![0x48, 0x81, 0xce, 0x4f, 0x02, 0, 0]
+        );
+        // nop dword [rax + 3]
+        assert_eq!(
+            encode(|asm| {
+                asm.nop_disp32(3);
+            }),
+            vec![0x0f, 0x1f, 0x80, 3, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_instructions() {
+        // imul rax, rcx
+        assert_eq!(
+            encode(|asm| {
+                asm.imul_reg_reg(Register::Rax, Register::Rcx);
+            }),
+            vec![0x48, 0x0f, 0xaf, 0xc1]
+        );
+        // or rdi, rcx
+        assert_eq!(
+            encode(|asm| {
+                asm.or_reg_reg(Register::Rdi, Register::Rcx);
+            }),
+            vec![0x48, 0x09, 0xcf]
+        );
+        // sar rcx, 2; cqo; idiv rcx
+        assert_eq!(
+            encode(|asm| {
+                asm.sar_reg_imm8(Register::Rcx, 2)
+                    .cqo()
+                    .idiv_reg(Register::Rcx);
+            }),
+            vec![0x48, 0xc1, 0xf9, 2, 0x48, 0x99, 0x48, 0xf7, 0xf9]
+        );
+    }
+
+    #[test]
+    fn test_sse2_instructions() {
+        use XmmRegister::{Xmm0, Xmm1, Xmm7};
+        // movsd xmm0, [rax + 5]; movsd [rcx + 8], xmm1
+        assert_eq!(
+            encode(|asm| {
+                asm.movsd_xmm_mem(Xmm0, Register::Rax, 5)
+                    .movsd_mem_xmm(Register::Rcx, 8, Xmm1);
+            }),
+            vec![0xf2, 0x0f, 0x10, 0x40, 5, 0xf2, 0x0f, 0x11, 0x49, 8]
+        );
+        // addsd xmm0, xmm1; subsd xmm0, xmm1; mulsd xmm7, xmm0; divsd xmm0, xmm7
+        assert_eq!(
+            encode(|asm| {
+                asm.addsd(Xmm0, Xmm1)
+                    .subsd(Xmm0, Xmm1)
+                    .mulsd(Xmm7, Xmm0)
+                    .divsd(Xmm0, Xmm7);
+            }),
+            vec![
+                0xf2, 0x0f, 0x58, 0xc1, 0xf2, 0x0f, 0x5c, 0xc1, 0xf2, 0x0f, 0x59, 0xf8, 0xf2, 0x0f,
+                0x5e, 0xc7
+            ]
+        );
+        // ucomisd xmm1, xmm0; cvtsi2sd xmm1, rdi
+        assert_eq!(
+            encode(|asm| {
+                asm.ucomisd(Xmm1, Xmm0).cvtsi2sd(Xmm1, Register::Rdi);
+            }),
+            vec![0x66, 0x0f, 0x2e, 0xc8, 0xf2, 0x48, 0x0f, 0x2a, 0xcf]
+        );
+        // movq xmm1, rsi; movq rax, xmm7
+        assert_eq!(
+            encode(|asm| {
+                asm.movq_xmm_reg(Xmm1, Register::Rsi)
+                    .movq_reg_xmm(Register::Rax, Xmm7);
+            }),
+            vec![0x66, 0x48, 0x0f, 0x6e, 0xce, 0x66, 0x48, 0x0f, 0x7e, 0xf8]
+        );
+    }
+
+    #[test]
+    fn test_memory_operand_instructions() {
+        // sub rdi, [rbx + 0x28]
+        assert_eq!(
+            encode(|asm| {
+                asm.sub_reg_mem(Register::Rdi, Register::Rbx, 0x28);
+            }),
+            vec![0x48, 0x2b, 0x7b, 0x28]
+        );
+        // mov byte [rdi], 1
+        assert_eq!(
+            encode(|asm| {
+                asm.mov_mem8_imm8(Register::Rdi, 0, 1);
+            }),
+            vec![0xc6, 0x07, 1]
+        );
+        // mov qword [rbx + 8], 1
+        assert_eq!(
+            encode(|asm| {
+                asm.mov_mem_imm32(Register::Rbx, 8, 1);
+            }),
+            vec![0x48, 0xc7, 0x43, 8, 1, 0, 0, 0]
+        );
+        // cmp byte [rax - 2], 9
+        assert_eq!(
+            encode(|asm| {
+                asm.cmp_mem8_imm8(Register::Rax, -2, 9);
+            }),
+            vec![0x80, 0x78, 0xfe, 9]
+        );
+    }
+}
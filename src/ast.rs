@@ -1,8 +1,14 @@
+use crate::bignum::BigInt;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum AstNode {
     Integer(i64),
+    /// An integer literal too large for an `i64`.
+    BigInteger(BigInt),
+    Float(f64),
     Bool(bool),
     Char(char),
+    Str(String),
     Nil,
     Pair {
         car: Box<AstNode>,
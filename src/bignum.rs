@@ -0,0 +1,542 @@
+// Arbitrary-precision integers, for arithmetic whose results leave the
+// fixnum range.
+//
+// `BigInt` is the Rust-side working representation: a sign and a magnitude
+// in 64-bit limbs, least significant first. The runtime converts to and from
+// the `Bignum` heap objects, which hold the same limbs, and to fixnums when a
+// result fits one. The algorithms are the schoolbook ones: numbers in Lisp
+// programs are rarely large enough for anything cleverer to pay off.
+
+use crate::encodings::{Bignum, LispValue};
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+
+/// An integer of any size. The magnitude has no most significant zero limbs,
+/// and zero is not negative, so equal integers compare equal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BigInt {
+    negative: bool,
+    magnitude: Vec<u64>,
+}
+
+impl BigInt {
+    pub fn zero() -> Self {
+        BigInt {
+            negative: false,
+            magnitude: Vec::new(),
+        }
+    }
+
+    /// The integer with the sign `negative` and the magnitude `limbs`, least
+    /// significant first.
+    pub fn from_limbs(negative: bool, limbs: &[u64]) -> Self {
+        BigInt {
+            negative,
+            magnitude: limbs.to_vec(),
+        }
+        .normalized()
+    }
+
+    /// The integer a bignum on the heap holds.
+    ///
+    /// # Safety
+    /// `bignum` must be a bignum header followed by its limbs.
+    pub unsafe fn from_bignum(bignum: &Bignum) -> Self {
+        Self::from_limbs(bignum.is_negative(), unsafe { bignum.limbs() })
+    }
+
+    fn normalized(mut self) -> Self {
+        while self.magnitude.last() == Some(&0) {
+            self.magnitude.pop();
+        }
+        if self.magnitude.is_empty() {
+            self.negative = false;
+        }
+        self
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// The limbs of the magnitude, least significant first.
+    pub fn limbs(&self) -> &[u64] {
+        &self.magnitude
+    }
+
+    /// The value, if it fits an `i64`.
+    pub fn to_i64(&self) -> Option<i64> {
+        match self.magnitude.as_slice() {
+            [] => Some(0),
+            [limb] if self.negative => 0_i64.checked_sub_unsigned(*limb),
+            [limb] => i64::try_from(*limb).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn abs(&self) -> Self {
+        BigInt {
+            negative: false,
+            magnitude: self.magnitude.clone(),
+        }
+    }
+
+    /// The quotient truncated towards zero, and the remainder, which has the
+    /// sign of `self`. `None` if `divisor` is zero.
+    pub fn div_rem(&self, divisor: &BigInt) -> Option<(BigInt, BigInt)> {
+        if divisor.is_zero() {
+            return None;
+        }
+        let (quotient, remainder) = div_rem_magnitude(&self.magnitude, &divisor.magnitude);
+        Some((
+            BigInt {
+                negative: self.negative != divisor.negative,
+                magnitude: quotient,
+            }
+            .normalized(),
+            BigInt {
+                negative: self.negative,
+                magnitude: remainder,
+            }
+            .normalized(),
+        ))
+    }
+
+    /// The greatest common divisor, which is never negative.
+    pub fn gcd(&self, other: &BigInt) -> BigInt {
+        let (mut a, mut b) = (self.abs(), other.abs());
+        while !b.is_zero() {
+            let (_, remainder) = a.div_rem(&b).unwrap();
+            a = b;
+            b = remainder;
+        }
+        a
+    }
+
+    /// `self` to the power `exponent`, by repeated squaring.
+    pub fn pow(&self, mut exponent: u32) -> BigInt {
+        let mut result = BigInt::from(1);
+        let mut base = self.clone();
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            if exponent > 0 {
+                base = &base * &base;
+            }
+        }
+        result
+    }
+
+    /// The largest integer whose square does not exceed `self`, which must
+    /// not be negative.
+    pub fn sqrt(&self) -> BigInt {
+        assert!(!self.negative, "square root of a negative integer");
+        if self.is_zero() {
+            return BigInt::zero();
+        }
+        // Newton's iteration decreases to the root from any start above it.
+        let two = BigInt::from(2);
+        let mut root = two.pow(self.bits().div_ceil(2) as u32);
+        loop {
+            let (quotient, _) = self.div_rem(&root).unwrap();
+            let (next, _) = (&root + &quotient).div_rem(&two).unwrap();
+            if next >= root {
+                return root;
+            }
+            root = next;
+        }
+    }
+
+    /// The nearest double, or an infinity past their range. Each limb is
+    /// rounded in turn, so the last bit may be off.
+    pub fn to_f64(&self) -> f64 {
+        let magnitude = self
+            .magnitude
+            .iter()
+            .rev()
+            .fold(0.0, |value, &limb| value * 2f64.powi(64) + limb as f64);
+        if self.negative { -magnitude } else { magnitude }
+    }
+
+    /// The integer `value` is, if it is finite and whole.
+    pub fn from_f64(value: f64) -> Option<BigInt> {
+        if !value.is_finite() || value.fract() != 0.0 {
+            return None;
+        }
+        if value.abs() < 2f64.powi(63) {
+            return Some(BigInt::from(value as i64));
+        }
+        // A double this large is its 53-bit mantissa shifted left.
+        let bits = value.to_bits();
+        let exponent = ((bits >> 52) & 0x7ff) as u32 - 1075;
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let magnitude = &BigInt::from(mantissa as i64) * &BigInt::from(2).pow(exponent);
+        Some(if value < 0.0 { -&magnitude } else { magnitude })
+    }
+
+    /// Number of bits in the magnitude.
+    pub fn bits(&self) -> u64 {
+        match self.magnitude.last() {
+            Some(top) => self.magnitude.len() as u64 * 64 - top.leading_zeros() as u64,
+            None => 0,
+        }
+    }
+
+    /// Parses an optionally signed integer in `radix`, from 2 to 36.
+    pub fn parse(text: &str, radix: u32) -> Option<BigInt> {
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        if digits.is_empty() {
+            return None;
+        }
+        let mut magnitude = Vec::new();
+        for c in digits.chars() {
+            let digit = c.to_digit(radix)?;
+            mul_add_small(&mut magnitude, radix as u64, digit as u64);
+        }
+        Some(
+            BigInt {
+                negative,
+                magnitude,
+            }
+            .normalized(),
+        )
+    }
+
+    /// Writes the integer in `radix`, from 2 to 36, with lower-case digits.
+    pub fn to_string_radix(&self, radix: u32) -> String {
+        if self.is_zero() {
+            return "0".to_string();
+        }
+        // Divide by the largest power of the radix that fits a limb, for
+        // several digits per division.
+        let mut chunk_digits = 1;
+        let mut chunk = radix as u64;
+        while let Some(next) = chunk.checked_mul(radix as u64) {
+            chunk = next;
+            chunk_digits += 1;
+        }
+        let mut magnitude = self.magnitude.clone();
+        let mut digits = Vec::new();
+        while !magnitude.is_empty() {
+            let (quotient, mut remainder) = div_rem_small(&magnitude, chunk);
+            magnitude = quotient;
+            for _ in 0..chunk_digits {
+                if magnitude.is_empty() && remainder == 0 {
+                    break;
+                }
+                let digit = (remainder % radix as u64) as u32;
+                digits.push(std::char::from_digit(digit, radix).unwrap());
+                remainder /= radix as u64;
+            }
+        }
+        if self.negative {
+            digits.push('-');
+        }
+        digits.iter().rev().collect()
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(value: i64) -> Self {
+        BigInt {
+            negative: value < 0,
+            magnitude: vec![value.unsigned_abs()],
+        }
+        .normalized()
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_radix(10))
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_magnitude(&self.magnitude, &other.magnitude),
+            (true, true) => cmp_magnitude(&other.magnitude, &self.magnitude),
+        }
+    }
+}
+
+impl Neg for &BigInt {
+    type Output = BigInt;
+
+    fn neg(self) -> BigInt {
+        BigInt {
+            negative: !self.negative,
+            magnitude: self.magnitude.clone(),
+        }
+        .normalized()
+    }
+}
+
+impl Add for &BigInt {
+    type Output = BigInt;
+
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt {
+                negative: self.negative,
+                magnitude: add_magnitude(&self.magnitude, &other.magnitude),
+            };
+        }
+        // Opposite signs: subtract the smaller magnitude from the larger,
+        // which gives its sign.
+        match cmp_magnitude(&self.magnitude, &other.magnitude) {
+            Ordering::Less => BigInt {
+                negative: other.negative,
+                magnitude: sub_magnitude(&other.magnitude, &self.magnitude),
+            },
+            _ => BigInt {
+                negative: self.negative,
+                magnitude: sub_magnitude(&self.magnitude, &other.magnitude),
+            },
+        }
+        .normalized()
+    }
+}
+
+impl Sub for &BigInt {
+    type Output = BigInt;
+
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl Mul for &BigInt {
+    type Output = BigInt;
+
+    fn mul(self, other: &BigInt) -> BigInt {
+        let mut product = vec![0_u64; self.magnitude.len() + other.magnitude.len()];
+        for (i, &a) in self.magnitude.iter().enumerate() {
+            let mut carry = 0_u128;
+            for (j, &b) in other.magnitude.iter().enumerate() {
+                let sum = product[i + j] as u128 + a as u128 * b as u128 + carry;
+                product[i + j] = sum as u64;
+                carry = sum >> 64;
+            }
+            product[i + other.magnitude.len()] = carry as u64;
+        }
+        BigInt {
+            negative: self.negative != other.negative,
+            magnitude: product,
+        }
+        .normalized()
+    }
+}
+
+/// The integer `value` holds, if it is a fixnum or a bignum.
+pub fn integer_value(value: LispValue) -> Option<BigInt> {
+    if let Some(value) = value.as_integer() {
+        Some(BigInt::from(value))
+    } else {
+        let bignum = value.as_bignum_pointer()?;
+        Some(unsafe { BigInt::from_bignum(&*bignum) })
+    }
+}
+
+/// Compares magnitudes without most significant zero limbs.
+fn cmp_magnitude(a: &[u64], b: &[u64]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn add_magnitude(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut sum = Vec::with_capacity(long.len() + 1);
+    let mut carry = false;
+    for (i, &limb) in long.iter().enumerate() {
+        let (partial, overflow1) = limb.overflowing_add(short.get(i).copied().unwrap_or(0));
+        let (partial, overflow2) = partial.overflowing_add(carry as u64);
+        sum.push(partial);
+        carry = overflow1 || overflow2;
+    }
+    if carry {
+        sum.push(1);
+    }
+    sum
+}
+
+/// `a - b`, where `a` is at least `b`.
+fn sub_magnitude(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut difference = a.to_vec();
+    sub_assign_magnitude(&mut difference, b);
+    difference
+}
+
+fn sub_assign_magnitude(a: &mut Vec<u64>, b: &[u64]) {
+    let mut borrow = false;
+    for (i, limb) in a.iter_mut().enumerate() {
+        let (partial, overflow1) = limb.overflowing_sub(b.get(i).copied().unwrap_or(0));
+        let (partial, overflow2) = partial.overflowing_sub(borrow as u64);
+        *limb = partial;
+        borrow = overflow1 || overflow2;
+    }
+    debug_assert!(!borrow, "subtracting a larger magnitude");
+    while a.last() == Some(&0) {
+        a.pop();
+    }
+}
+
+/// `magnitude * factor + addend`, in place.
+fn mul_add_small(magnitude: &mut Vec<u64>, factor: u64, addend: u64) {
+    let mut carry = addend as u128;
+    for limb in magnitude.iter_mut() {
+        let product = *limb as u128 * factor as u128 + carry;
+        *limb = product as u64;
+        carry = product >> 64;
+    }
+    if carry != 0 {
+        magnitude.push(carry as u64);
+    }
+}
+
+/// Divides by a single limb, returning the quotient without most
+/// significant zero limbs and the remainder.
+fn div_rem_small(magnitude: &[u64], divisor: u64) -> (Vec<u64>, u64) {
+    let mut quotient = vec![0; magnitude.len()];
+    let mut remainder = 0_u128;
+    for (i, &limb) in magnitude.iter().enumerate().rev() {
+        let dividend = (remainder << 64) | limb as u128;
+        quotient[i] = (dividend / divisor as u128) as u64;
+        remainder = dividend % divisor as u128;
+    }
+    while quotient.last() == Some(&0) {
+        quotient.pop();
+    }
+    (quotient, remainder as u64)
+}
+
+/// Divides magnitudes, a bit at a time unless the divisor is a single limb.
+fn div_rem_magnitude(dividend: &[u64], divisor: &[u64]) -> (Vec<u64>, Vec<u64>) {
+    if let [divisor] = divisor {
+        let (quotient, remainder) = div_rem_small(dividend, *divisor);
+        let remainder = if remainder == 0 {
+            vec![]
+        } else {
+            vec![remainder]
+        };
+        return (quotient, remainder);
+    }
+    let mut quotient = vec![0_u64; dividend.len()];
+    let mut remainder: Vec<u64> = Vec::new();
+    for bit in (0..dividend.len() * 64).rev() {
+        // remainder = remainder * 2 + the next bit of the dividend.
+        let mut carry = (dividend[bit / 64] >> (bit % 64)) & 1;
+        for limb in remainder.iter_mut() {
+            let next = *limb >> 63;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+        if carry != 0 {
+            remainder.push(carry);
+        }
+        if cmp_magnitude(&remainder, divisor) != Ordering::Less {
+            sub_assign_magnitude(&mut remainder, divisor);
+            quotient[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+    while quotient.last() == Some(&0) {
+        quotient.pop();
+    }
+    (quotient, remainder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn big(text: &str) -> BigInt {
+        BigInt::parse(text, 10).unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+        assert_eq!((&a + &b).to_string(), "-864197532086419753208641975320");
+        assert_eq!((&a - &b).to_string(), "1111111110111111111011111111100");
+        assert_eq!(
+            (&a * &b).to_string(),
+            "-121932631137021795226185032733622923332237463801111263526900"
+        );
+        assert_eq!(&a - &a, BigInt::zero());
+        assert!(!(&a - &a).is_negative());
+
+        let (quotient, remainder) = b.div_rem(&a).unwrap();
+        assert_eq!(quotient.to_string(), "-8");
+        assert_eq!(remainder.to_string(), "-9000000000900000000090");
+        assert_eq!(&(&quotient * &a) + &remainder, b);
+        assert!(a.div_rem(&BigInt::zero()).is_none());
+        let (quotient, remainder) = a.div_rem(&BigInt::from(-7)).unwrap();
+        assert_eq!(&(&quotient * &BigInt::from(-7)) + &remainder, a);
+
+        assert_eq!(
+            BigInt::from(2).pow(100).to_string(),
+            "1267650600228229401496703205376"
+        );
+        assert_eq!(BigInt::from(-3).pow(3), BigInt::from(-27));
+        assert_eq!(big("1267650600228229401496703205376").bits(), 101);
+        assert_eq!(
+            BigInt::from(2).pow(80).gcd(&BigInt::from(-6).pow(40)),
+            BigInt::from(2).pow(40)
+        );
+        assert!(b < a && BigInt::from(-1) > b);
+    }
+
+    #[test]
+    fn test_conversions() {
+        for value in [0, 1, -1, i64::MAX, i64::MIN] {
+            let big = BigInt::from(value);
+            assert_eq!(big.to_i64(), Some(value));
+            assert_eq!(big.to_string(), value.to_string());
+            assert_eq!(BigInt::parse(&value.to_string(), 10), Some(big));
+        }
+        assert_eq!((&BigInt::from(i64::MAX) + &BigInt::from(1)).to_i64(), None);
+        assert_eq!((&BigInt::from(i64::MIN) - &BigInt::from(1)).to_i64(), None);
+        assert_eq!(BigInt::parse("-ff", 16), Some(BigInt::from(-255)));
+        assert_eq!(
+            BigInt::from(2).pow(64).to_string_radix(16),
+            "10000000000000000"
+        );
+        assert_eq!(BigInt::parse("", 10), None);
+        assert_eq!(BigInt::parse("-", 10), None);
+        assert_eq!(BigInt::parse("12a", 10), None);
+
+        assert_eq!(BigInt::from(2).pow(100).to_f64(), 2f64.powi(100));
+        assert_eq!(BigInt::from(-3).to_f64(), -3.0);
+        assert_eq!(
+            BigInt::from_f64(-2f64.powi(100)),
+            Some(-&BigInt::from(2).pow(100))
+        );
+        assert_eq!(BigInt::from_f64(-7.0), Some(BigInt::from(-7)));
+        assert_eq!(BigInt::from_f64(0.5), None);
+        assert_eq!(BigInt::from_f64(f64::INFINITY), None);
+        assert_eq!(BigInt::from(2).pow(100).sqrt(), BigInt::from(2).pow(50));
+        assert_eq!(big("99999999999999999999").sqrt(), big("9999999999"));
+        assert_eq!(BigInt::from(1).sqrt(), BigInt::from(1));
+    }
+}
@@ -0,0 +1,315 @@
+// Heap introspection: counting the live objects by type, and dumping the
+// heap to a file that can be summarised later, away from the runtime.
+//
+// A dump is a text file. Its first line is `DUMP_MAGIC`, then one line per
+// root and one per object, with addresses in hex:
+//
+//     root 7f3a10000010
+//     object 7f3a10000010 pair 16 7f3a10000020
+//
+// An object line gives the address, type and size of the object followed by
+// the addresses of the objects its fields refer to.
+
+use crate::encodings::{K_STRING_BYTES_KIND, LispValue, TagsDict};
+use crate::gc;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// The first line of every heap dump.
+pub const DUMP_MAGIC: &str = "lisp-comp heap dump v1";
+
+/// How many objects of one type there are, and how many bytes they take.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TypeCensus {
+    pub objects: usize,
+    pub bytes: usize,
+}
+
+/// Objects counted by type, named as in the tagging scheme.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeapCensus {
+    by_type: BTreeMap<String, TypeCensus>,
+    roots: usize,
+}
+
+impl HeapCensus {
+    /// Counts the objects in `start..end`, laid end to end.
+    ///
+    /// # Safety
+    /// `start` must be the start of a heap object.
+    pub unsafe fn of_objects(start: usize, end: usize) -> Self {
+        let tags = TagsDict::new();
+        let mut census = HeapCensus::default();
+        unsafe {
+            gc::for_each_object(start, end, |object| {
+                let size = gc::object_size(object);
+                census.add(type_name(&tags, object), size);
+            })
+        };
+        census
+    }
+
+    /// Summarises the dump read from `reader`.
+    pub fn from_dump(reader: impl BufRead) -> io::Result<Self> {
+        let invalid = |line: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid heap dump line: {}", line),
+            )
+        };
+        let mut lines = reader.lines();
+        match lines.next().transpose()? {
+            Some(first) if first == DUMP_MAGIC => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a heap dump",
+                ));
+            }
+        }
+        let mut census = HeapCensus::default();
+        for line in lines {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("root") => census.roots += 1,
+                Some("object") => {
+                    let type_name = words.nth(1).ok_or_else(|| invalid(&line))?;
+                    let size = words
+                        .next()
+                        .and_then(|size| size.parse().ok())
+                        .ok_or_else(|| invalid(&line))?;
+                    census.add(type_name, size);
+                }
+                None => {}
+                Some(_) => return Err(invalid(&line)),
+            }
+        }
+        Ok(census)
+    }
+
+    fn add(&mut self, type_name: &str, size: usize) {
+        let entry = self.by_type.entry(type_name.to_string()).or_default();
+        entry.objects += 1;
+        entry.bytes += size;
+    }
+
+    pub(crate) fn set_roots(&mut self, roots: usize) {
+        self.roots = roots;
+    }
+
+    /// The count for `type_name`, which is zero for types with no objects.
+    pub fn get(&self, type_name: &str) -> TypeCensus {
+        self.by_type.get(type_name).copied().unwrap_or_default()
+    }
+
+    /// The counts of the types that have objects, by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, TypeCensus)> {
+        self.by_type
+            .iter()
+            .map(|(name, census)| (name.as_str(), *census))
+    }
+
+    pub fn objects(&self) -> usize {
+        self.by_type.values().map(|census| census.objects).sum()
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.by_type.values().map(|census| census.bytes).sum()
+    }
+
+    /// Number of roots, when known: only dumps record them.
+    pub fn roots(&self) -> usize {
+        self.roots
+    }
+}
+
+impl fmt::Display for HeapCensus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<12} {:>10} {:>12}", "type", "objects", "bytes")?;
+        for (name, census) in self.iter() {
+            writeln!(
+                f,
+                "{:<12} {:>10} {:>12}",
+                name, census.objects, census.bytes
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<12} {:>10} {:>12}",
+            "total",
+            self.objects(),
+            self.bytes()
+        )?;
+        write!(f, "{} roots", self.roots)
+    }
+}
+
+/// The name of the type of the heap object at `address`.
+///
+/// # Safety
+/// `address` must be the start of a heap object.
+unsafe fn type_name(tags: &TagsDict, address: usize) -> &'static str {
+    match unsafe { gc::object_kind(address) } {
+        K_STRING_BYTES_KIND => "string-bytes",
+        kind => tags.heap_type(kind).map_or("unknown", |tag| tag.name),
+    }
+}
+
+/// Writes a dump of the objects in `start..end`, referred to by `roots`.
+///
+/// # Safety
+/// `start` must be the start of a heap object, and the objects must be laid
+/// end to end up to `end`.
+pub unsafe fn write_dump(
+    out: &mut impl Write,
+    roots: &[LispValue],
+    start: usize,
+    end: usize,
+) -> io::Result<()> {
+    let tags = TagsDict::new();
+    writeln!(out, "{}", DUMP_MAGIC)?;
+    for address in roots.iter().filter_map(LispValue::heap_address) {
+        writeln!(out, "root {:x}", address)?;
+    }
+    let mut result = Ok(());
+    unsafe {
+        gc::for_each_object(start, end, |object| {
+            if result.is_err() {
+                return;
+            }
+            let mut line = format!(
+                "object {:x} {} {}",
+                object,
+                type_name(&tags, object),
+                gc::object_size(object)
+            );
+            gc::for_each_field(object, |field| {
+                if let Some(address) = field.heap_address() {
+                    line.push_str(&format!(" {:x}", address));
+                }
+            });
+            result = writeln!(out, "{}", line);
+        })
+    };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+    use crate::testing::eval_str;
+
+    #[test]
+    fn test_census_counts_live_objects() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define l '(1 2 3))").unwrap();
+        eval_str(&mut runtime, "(define v (make-vector 4 0))").unwrap();
+        eval_str(&mut runtime, "(cons (vector 1 2) (cons 3 4))").unwrap();
+        let census = runtime.heap_census().unwrap();
+        assert_eq!(census.get("pair").objects, 3);
+        assert_eq!(census.get("pair").bytes, 48);
+        assert_eq!(census.get("vector").objects, 1);
+        assert_eq!(census.get("vector").bytes, 40);
+        // Every interned symbol has a name.
+        assert_eq!(census.get("symbol").objects, census.get("string").objects);
+        assert_eq!(census.bytes(), runtime.heap().old().used());
+    }
+
+    #[test]
+    fn test_gc_stats() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        assert_eq!(runtime.gc_stats(), Default::default());
+        eval_str(&mut runtime, "(cons 1 2)").unwrap();
+        assert_eq!(runtime.gc_stats().bytes_allocated, 16);
+        for _ in 0..10 {
+            eval_str(&mut runtime, "(make-vector 100 0)").unwrap();
+        }
+        runtime.collect();
+        let stats = runtime.gc_stats();
+        assert_eq!(stats.bytes_allocated, 16 + 10 * 808);
+        assert!(stats.minor_collections > 0);
+        assert_eq!(stats.major_collections, 1);
+        assert!(stats.max_pause <= stats.total_pause);
+    }
+
+    #[test]
+    fn test_gc_primitives() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define l '(1 2 3))").unwrap();
+        assert_eq!(eval_str(&mut runtime, "(gc)").unwrap(), "()");
+        assert_eq!(runtime.heap().major_collections(), 1);
+        assert_eq!(
+            eval_str(&mut runtime, "(heap-object-count 'pair)").unwrap(),
+            "3"
+        );
+        assert_eq!(
+            eval_str(&mut runtime, "(cons l (heap-object-count 'vector))").unwrap(),
+            "((1 2 3) . 0)"
+        );
+        let total = runtime.heap_census().unwrap().objects();
+        assert_eq!(
+            eval_str(&mut runtime, "(heap-object-count)").unwrap(),
+            total.to_string()
+        );
+        assert_eq!(
+            eval_str(&mut runtime, "(heap-object-count 'frob)"),
+            Err("heap-object-count: unknown type frob".to_string())
+        );
+        let stats = eval_str(&mut runtime, "(gc-stats)").unwrap();
+        assert!(stats.starts_with("((bytes-allocated "), "{}", stats);
+        assert!(stats.contains("(live (pair 3 48) "), "{}", stats);
+    }
+
+    #[test]
+    fn test_failed_gc_stats_leaves_no_roots() {
+        let mut runtime = Runtime::with_heap(256, 1 << 16);
+        // The lists outgrow the nursery. Interns the names first, so only
+        // the lists are left to allocate.
+        eval_str(&mut runtime, "(define l '(a))").unwrap();
+        eval_str(&mut runtime, "(gc-stats)").unwrap();
+        let roots = runtime.heap_census().unwrap().roots();
+        let mut pairs = 0;
+        while let Some(pair) = runtime.alloc_pair(LispValue::nil(), LispValue::nil()) {
+            runtime.push_root(pair);
+            pairs += 1;
+        }
+        // Freeing a pair at a time, some attempts fail part way through.
+        let mut failures = 0;
+        for _ in 0..64 {
+            runtime.pop_root();
+            pairs -= 1;
+            failures += eval_str(&mut runtime, "(gc-stats)").is_err() as usize;
+            assert_eq!(runtime.heap_census().unwrap().roots(), roots + pairs);
+        }
+        assert!(failures > 1, "{}", failures);
+    }
+
+    #[test]
+    fn test_dump_round_trip() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define v (vector 'a (cons 1 2)))").unwrap();
+        let path = std::env::temp_dir().join(format!("heap-dump-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        runtime.dump_heap(path).unwrap();
+        let dump = std::fs::read_to_string(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        // The vector refers to the symbol and the pair.
+        let vector = runtime.global("v").unwrap().heap_address().unwrap();
+        let line = dump
+            .lines()
+            .find(|line| line.starts_with(&format!("object {:x} ", vector)))
+            .unwrap();
+        assert_eq!(line.split(' ').count(), 6, "{}", line);
+
+        let census = HeapCensus::from_dump(dump.as_bytes()).unwrap();
+        assert!(census.roots() > 0);
+        let mut expected = runtime.heap_census().unwrap();
+        expected.set_roots(census.roots());
+        assert_eq!(census, expected);
+        assert!(HeapCensus::from_dump("not a dump".as_bytes()).is_err());
+    }
+}
@@ -1,223 +1,1258 @@
-use crate::assembler::{Assembler, PartialRegister, Register, SetccConditions};
+use crate::assembler::{Assembler, Label, PartialRegister, Register, SetccConditions, XmmRegister};
 use crate::ast::AstNode;
+use crate::bignum::BigInt;
+#[cfg(not(feature = "nan-boxing"))]
+use crate::encodings::K_FLONUM_KIND;
 use crate::encodings::{
-    K_BOOL_MASK, K_BOOL_SHIFT, K_BOOL_TAG, K_CHAR_SHIFT, K_CHAR_TAG, K_INTEGER_MASK,
-    K_INTEGER_SHIFT, K_INTEGER_TAG, LispValue, Pair, Symbol,
+    Closure, Flonum, Header, K_BOOL_SHIFT, K_BOOL_TAG, K_CLOSURE_TAG, K_HEAP_TAG_MASK,
+    K_INTEGER_MAX, K_INTEGER_MIN, K_PAIR_TAG, K_RECORD_KIND, K_STRING_TAG, K_UNBOUND_VALUE,
+    K_VALUE_BITS, K_VECTOR_TAG, LispValue, Pair, Record, TypeTag, Vector, Word,
 };
-use std::collections::HashMap;
+#[cfg(feature = "nan-boxing")]
+use crate::encodings::{K_CANONICAL_NAN, K_DOUBLE_OFFSET};
+use crate::executable_buffer::ExecBuffer;
+use crate::heap::{CARD_DIRTY, CARD_SHIFT};
+use crate::numbers::rt_bignum_literal;
+use crate::primitives::{ARG_REGISTERS, ArgType, Primitive};
+use crate::runtime::{
+    RT_CARD_TABLE, RT_ENTRY_FRAME, RT_ERROR_PENDING, RT_GLOBALS_DIRTY, RT_HEAP_LIMIT, RT_HEAP_PTR,
+    RT_LAST_FP, RT_LAST_SP, RT_OLD_SIZE, RT_OLD_START, Runtime, rt_alloc_slow, rt_raise,
+};
+use crate::strings::rt_string_literal;
+#[cfg(not(feature = "nan-boxing"))]
+use std::mem::offset_of;
+
+// Register conventions for generated code:
+//   RAX  result of the expression being compiled
+//   RBX  pointer to the `Runtime`, preserved across the whole run
+//   RBP  frame pointer
+//   RCX, RDX, RSI, RDI  scratch
+//
+// Every value the code keeps on the stack is pushed below the frame pointer
+// and counted in `stack_slots`, and every call is followed by a stack map
+// recording that count, so the collector can find and update them all.
 
 #[derive(Debug)]
 pub enum CompilerError {
-    ValueTooLarge,
-    IntegerTooLarge(i64),
-    ValueRequires64BitMove,
     AssemblerError(String),
     NotAFunction(String),
     NotASymbol,
     InvalidArguments(String),
 }
 
-pub struct Compiler {
+/// Whether compiled primitives check the types of their operands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Safety {
+    /// Operand tags are checked, raising a Lisp error on a mismatch.
+    Checked,
+    /// Tag checks are left out. Passing a value of the wrong type is undefined behaviour.
+    Unchecked,
+}
+
+/// What the primitives `add1` and `sub1` do when a fixnum result leaves the
+/// fixnum range. Generic arithmetic such as `+` always moves on to bignums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Move on to a bignum, as generic arithmetic does. Primitives with no
+    /// bignum version raise instead.
+    Promote,
+    /// Raise a Lisp error.
+    Raise,
+    /// Keep the wrapped-around 64-bit result.
+    Wrap,
+}
+
+/// The out-of-line part of an inline allocation, taken when the current heap
+/// chunk is full.
+struct SlowPath {
+    label: Label,
+    // Where the fast path continues.
+    resume: Label,
+    // Raises "heap exhausted" when the runtime cannot grow the heap.
+    exhausted: Label,
+    // Values pushed at the allocation site, for the stack map.
+    slots: usize,
+}
+
+/// An out-of-line jump target that raises a runtime error.
+struct ErrorStub {
+    label: Label,
+    // Register holding the offending value when the stub is reached.
+    value: Register,
+    message: String,
+}
+
+pub struct Compiler<'rt> {
     asm: Assembler,
-    symbol_table: HashMap<String, *mut Symbol>,
+    // The runtime the code is compiled for; constants such as symbols live in it.
+    runtime: &'rt mut Runtime,
+    error_stubs: Vec<ErrorStub>,
+    slow_paths: Vec<SlowPath>,
+    // Jumps here return to the caller of the compiled code once an error is recorded.
+    unwind: Option<Label>,
+    // Values pushed on the stack since the prologue.
+    stack_slots: usize,
+    // 1 if RSP is 8 bytes off a 16-byte boundary right after the prologue, 0 otherwise.
+    frame_parity: usize,
+    // False while compiling the body of a procedure.
+    toplevel: bool,
+    // Parameters of the procedure being compiled, in order.
+    params: Vec<String>,
+    safety: Safety,
+    overflow: Overflow,
 }
 
-impl Compiler {
-    pub fn new() -> Self {
+impl<'rt> Compiler<'rt> {
+    pub fn new(runtime: &'rt mut Runtime) -> Self {
         Compiler {
             asm: Assembler::new(),
-            symbol_table: HashMap::new(),
+            runtime,
+            error_stubs: Vec::new(),
+            slow_paths: Vec::new(),
+            unwind: None,
+            stack_slots: 0,
+            frame_parity: 1,
+            toplevel: true,
+            params: Vec::new(),
+            safety: Safety::Checked,
+            overflow: Overflow::Promote,
         }
     }
 
-    /// Allocates a Pair on the heap and returns a raw pointer.
-    fn heap_alloc_pair(&mut self, car: LispValue, cdr: LispValue) -> *mut Pair {
-        let pair = Box::new(Pair { car, cdr });
-        Box::leak(pair)
-    }
-
-    /// Allocates a Symbol on the heap and returns a raw pointer.
-    /// TODO: Change this for bump alloc
-    fn heap_alloc_symbol(&mut self, name: String) -> *mut Symbol {
-        let symbol = Box::new(Symbol { name });
-        Box::leak(symbol)
+    /// Selects whether the code checks operand types; `Safety::Checked` by default.
+    /// Procedures defined by the code inherit the setting.
+    pub fn with_safety(mut self, safety: Safety) -> Self {
+        self.safety = safety;
+        self
     }
 
-    /// Interns a symbol: ensures only one copy of each symbol string exists.
-    fn intern_symbol(&mut self, name: &str) -> LispValue {
-        if let Some(ptr) = self.symbol_table.get(name) {
-            LispValue::from_symbol_pointer(*ptr)
-        } else {
-            let ptr = self.heap_alloc_symbol(name.to_string());
-            self.symbol_table.insert(name.to_string(), ptr);
-            LispValue::from_symbol_pointer(ptr)
-        }
+    /// Loads the symbol `name` into `dst`. Symbols may move, so the code reads
+    /// them from their cell in the symbol table rather than embedding them.
+    fn emit_load_symbol(&mut self, dst: Register, name: &str) {
+        self.runtime.intern(name);
+        let cell = self.runtime.symbols_mut().cell(name).unwrap();
+        self.asm
+            .mov_reg_imm64(dst, cell as i64)
+            .mov_reg_mem(dst, dst, 0);
     }
 
     /// Consumes the compiler and returns the compiled machine code.
+    /// The code must be called as a `JitFunction`, with a pointer to the runtime.
     pub fn compile_function(
         mut self, // Takes ownership of self TODO Add this
         ast_node: &AstNode,
     ) -> Result<Vec<u8>, CompilerError> {
+        self.asm
+            .push_reg(Register::Rbx)
+            .mov_reg_reg(Register::Rbx, Register::Rdi)
+            .push_reg(Register::Rbp)
+            .mov_reg_reg(Register::Rbp, Register::Rsp)
+            .mov_mem_reg(Register::Rbx, RT_ENTRY_FRAME, Register::Rbp);
         self.compile_expr(ast_node)?;
-        self.asm.ret();
+        self.asm.pop_reg(Register::Rbp).pop_reg(Register::Rbx).ret();
+        self.emit_error_stubs();
         Ok(self.asm.finalize())
     }
-    fn compile_call(&mut self, car: &AstNode, cdr: &AstNode) -> Result<(), CompilerError> {
-        // A Pair in evaluation position means a function call.
-        // We must check that the 'car' is a symbol.
-        if let AstNode::Symbol(name) = car {
-            match name.as_str() {
-                // TODO: This is temporary, we should use a more complex symbol table
-                "add1" => {
-                    // This is a primitive unary function.
-                    // We expect one argument in the `cdr` list.
-                    if let AstNode::Pair {
-                        car: arg1,
-                        cdr: arg_rest,
-                    } = cdr
-                    {
-                        // Check it's (add1 arg1), not (add1 arg1 arg2 ...)
-                        if let AstNode::Nil = &**arg_rest {
-                            // 1. Compile the argument. Result is in RAX.
-                            self.compile_expr(arg1)?;
-
-                            // 2. Emit the 'add1' operation. Adding 1 << 2 due to pointer tagging
-                            let encoded_one = LispValue::from_integer(1).as_raw_word();
-                            self.asm.add_reg_imm32(Register::Rax, encoded_one as i32);
-                            Ok(())
-                        } else {
-                            return Err(CompilerError::InvalidArguments(
-                                "add1 expects 1 argument".to_string(),
-                            ));
-                        }
-                    } else {
-                        return Err(CompilerError::InvalidArguments(
-                            "add1 expects 1 argument. Needs a pair".to_string(),
-                        ));
-                    }
-                }
-                "sub1" => {
-                    if let AstNode::Pair { car: arg, cdr: _ } = cdr {
-                        self.compile_expr(arg)?;
-
-                        let encoded_one = LispValue::from_integer(1).as_raw_word();
-                        self.asm.sub_reg_imm32(Register::Rax, encoded_one as i32);
-                        Ok(())
-                    } else {
-                        Err(CompilerError::InvalidArguments(
-                            "sub1 expects 1 argument. Needs a pair".to_string(),
-                        ))
-                    }
-                }
-                "integer->char" => {
-                    if let AstNode::Pair { car: arg1, cdr: _ } = cdr {
-                        self.compile_expr(arg1)?;
-                        self.asm
-                            .shl_reg_imm8(Register::Rax, (K_CHAR_SHIFT - K_INTEGER_SHIFT) as u8)
-                            .or_reg_imm8(Register::Rax, K_CHAR_TAG as u8);
-                        Ok(())
-                    } else {
-                        return Err(CompilerError::InvalidArguments(
-                            "integer->char expects 1 argument. Needs a pair".to_string(),
-                        ));
-                    }
+
+    /// Compiles a procedure named `name` taking `self.params`, whose body
+    /// `body` emits. Callers push the arguments in order, pass the closure in
+    /// RDX and the argument count (encoded) in RCX, and keep RSP 16-byte
+    /// aligned at the call.
+    fn compile_procedure_body(
+        mut self,
+        name: &str,
+        body: impl FnOnce(&mut Compiler<'_>) -> Result<(), CompilerError>,
+    ) -> Result<Vec<u8>, CompilerError> {
+        self.asm
+            .push_reg(Register::Rbp)
+            .mov_reg_reg(Register::Rbp, Register::Rsp);
+        let arity = self.params.len();
+        let wrong_arity = self.error_stub(
+            Register::Rcx,
+            format!("{}: expected {} argument(s), got {{}}", name, arity),
+        );
+        self.asm
+            .cmp_reg_imm32(
+                Register::Rcx,
+                LispValue::from_integer(arity as Word).as_raw_word() as u32,
+            )
+            .jcc(SetccConditions::NotEqual, wrong_arity);
+        body(&mut self)?;
+        self.asm.pop_reg(Register::Rbp).ret();
+        self.emit_error_stubs();
+        Ok(self.asm.finalize())
+    }
+
+    /// Selects what integer overflow does; `Overflow::Promote` by default.
+    /// Procedures defined by the code inherit the setting.
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Emits the error stubs collected while compiling, followed by the shared
+    /// error exit and the messages they reference.
+    fn emit_error_stubs(&mut self) {
+        if !self.slow_paths.is_empty() {
+            self.emit_slow_paths();
+        }
+        if self.error_stubs.is_empty() && self.unwind.is_none() {
+            return;
+        }
+        let error_exit = self.asm.new_label();
+        let unwind = self.unwind_label();
+        let mut messages: Vec<(Label, String)> = Vec::new();
+        for stub in std::mem::take(&mut self.error_stubs) {
+            let message_label = match messages.iter().find(|(_, m)| *m == stub.message) {
+                Some((label, _)) => *label,
+                None => {
+                    let label = self.asm.new_label();
+                    messages.push((label, stub.message));
+                    label
                 }
-                "nil?" => {
-                    if let AstNode::Pair { car: arg1, cdr: _ } = cdr {
-                        self.compile_expr(arg1)?;
-                        self.compile_compare_imm32(LispValue::nil());
-                        Ok(())
-                    } else {
-                        return Err(CompilerError::InvalidArguments(
-                            "nil? expects 1 argument. Needs a pair".to_string(),
-                        ));
-                    }
+            };
+            self.asm
+                .bind(stub.label)
+                .mov_reg_reg(Register::Rdx, stub.value)
+                .lea_reg_label(Register::Rsi, message_label)
+                .jmp(error_exit);
+        }
+
+        // Unwind to the outermost frame, keeping the stack 16-byte aligned for the call.
+        self.asm
+            .bind(error_exit)
+            .mov_reg_mem(Register::Rsp, Register::Rbx, RT_ENTRY_FRAME)
+            .sub_reg_imm32(Register::Rsp, 8)
+            .mov_reg_reg(Register::Rdi, Register::Rbx)
+            .mov_reg_imm64(Register::Rax, rt_raise as *const () as i64)
+            .call_reg(Register::Rax)
+            .bind(unwind)
+            .mov_reg_mem(Register::Rsp, Register::Rbx, RT_ENTRY_FRAME)
+            .pop_reg(Register::Rbp)
+            .pop_reg(Register::Rbx)
+            .ret();
+
+        for (label, message) in messages {
+            self.asm
+                .bind(label)
+                .emit_bytes(&(message.len() as u32).to_le_bytes())
+                .emit_bytes(message.as_bytes());
+        }
+    }
+
+    fn unwind_label(&mut self) -> Label {
+        match self.unwind {
+            Some(label) => label,
+            None => {
+                let label = self.asm.new_label();
+                self.unwind = Some(label);
+                label
+            }
+        }
+    }
+
+    /// The assembler, for primitives emitting their own code.
+    pub fn asm(&mut self) -> &mut Assembler {
+        &mut self.asm
+    }
+
+    /// Pushes `reg`, keeping track of the stack depth. Code that pushes
+    /// should use this rather than the assembler.
+    pub fn push(&mut self, reg: Register) {
+        self.asm.push_reg(reg);
+        self.stack_slots += 1;
+    }
+
+    pub fn pop(&mut self, reg: Register) {
+        self.asm.pop_reg(reg);
+        self.stack_slots -= 1;
+    }
+
+    /// Calls a Rust runtime function, passing the runtime in RDI. Further
+    /// arguments must already be in RSI and RDX, and the result is left in RAX.
+    /// Unwinds if the function recorded an error.
+    pub fn emit_runtime_call(&mut self, function: *const ()) {
+        let pad = !(self.stack_slots + self.frame_parity).is_multiple_of(2);
+        if pad {
+            self.asm.sub_reg_imm32(Register::Rsp, 8);
+        }
+        self.asm
+            .mov_reg_reg(Register::Rdi, Register::Rbx)
+            .mov_reg_imm64(Register::Rcx, function as i64);
+        self.emit_rust_call(Register::Rcx, self.stack_slots);
+        if pad {
+            self.asm.add_reg_imm32(Register::Rsp, 8);
+        }
+        let unwind = self.unwind_label();
+        self.asm
+            .mov_reg_mem(Register::Rcx, Register::Rbx, RT_ERROR_PENDING)
+            .cmp_reg_imm32(Register::Rcx, 0)
+            .jcc(SetccConditions::NotEqual, unwind);
+    }
+
+    /// Calls a Rust runtime function with the `argc` values on top of the
+    /// stack, the first pushed deepest: passes their address in RSI and their
+    /// count in RDX, then drops them. The values stay on the stack during the
+    /// call, where collections keep them up to date; see `runtime::StackArgs`.
+    pub fn emit_runtime_call_with_stack_args(&mut self, function: *const (), argc: usize) {
+        self.asm
+            .mov_reg_reg(Register::Rsi, Register::Rsp)
+            .mov_reg_imm(Register::Rdx, argc as i64);
+        self.emit_runtime_call(function);
+        if argc > 0 {
+            self.asm
+                .add_reg_imm32(Register::Rsp, (argc * size_of::<Word>()) as i32);
+            self.stack_slots -= argc;
+        }
+    }
+
+    /// Calls the Rust function in `target`, which may collect: records the frame
+    /// for the collector to walk and follows the call with its stack map,
+    /// `slots` values pushed below the frame pointer.
+    fn emit_rust_call(&mut self, target: Register, slots: usize) {
+        self.asm
+            .mov_mem_reg(Register::Rbx, RT_LAST_FP, Register::Rbp)
+            .mov_mem_reg(Register::Rbx, RT_LAST_SP, Register::Rsp)
+            .call_reg(target);
+        self.emit_stack_map(slots);
+    }
+
+    /// Emits the stack map of the call just emitted; see `gc::read_stack_map`.
+    fn emit_stack_map(&mut self, slots: usize) {
+        self.asm.nop_disp32(slots as u32);
+    }
+
+    /// Returns a label that raises `message` with the value held in `value`.
+    /// A `{}` in the message is replaced by the printed value.
+    pub fn error_stub(&mut self, value: Register, message: String) -> Label {
+        let label = self.asm.new_label();
+        self.error_stubs.push(ErrorStub {
+            label,
+            value,
+            message,
+        });
+        label
+    }
+
+    /// Raises a type error unless `reg & mask == tag`. Clobbers RDI.
+    /// Emits nothing when compiling unchecked code.
+    pub fn emit_tag_check(
+        &mut self,
+        reg: Register,
+        mask: Word,
+        tag: Word,
+        primitive: &str,
+        expected: &str,
+    ) {
+        if self.safety == Safety::Unchecked {
+            return;
+        }
+        let error = self.error_stub(
+            reg,
+            format!("{}: expected {}, got {{}}", primitive, expected),
+        );
+        self.emit_type_test(reg, mask, tag, Register::Rdi);
+        self.asm.jcc(SetccConditions::NotEqual, error);
+    }
+
+    /// Jumps to `other` unless `reg & mask == tag`, for primitives that handle
+    /// the other types out of line. Clobbers RDI. Emits nothing when compiling
+    /// unchecked code.
+    pub fn emit_tag_dispatch(&mut self, reg: Register, mask: Word, tag: Word, other: Label) {
+        if self.safety == Safety::Unchecked {
+            return;
+        }
+        self.emit_type_test(reg, mask, tag, Register::Rdi);
+        self.asm.jcc(SetccConditions::NotEqual, other);
+    }
+
+    /// Raises a type error unless the object `reg` points to, which has the
+    /// string tag, has the header kind `kind`. Emits nothing when compiling
+    /// unchecked code.
+    pub fn emit_kind_check(&mut self, reg: Register, kind: Word, primitive: &str, expected: &str) {
+        if self.safety == Safety::Unchecked {
+            return;
+        }
+        let error = self.error_stub(
+            reg,
+            format!("{}: expected {}, got {{}}", primitive, expected),
+        );
+        self.emit_kind_test(reg, kind);
+        self.asm.jcc(SetccConditions::NotEqual, error);
+    }
+
+    /// Sets the flags so that `Equal` holds when the header of the object
+    /// `reg` points to, which has the string tag, has the kind `kind`.
+    fn emit_kind_test(&mut self, reg: Register, kind: Word) {
+        // The kind is the second byte of the header.
+        let offset = Self::field_offset(K_STRING_TAG, 0) + 1;
+        self.asm.cmp_mem8_imm8(reg, offset, kind as u8);
+    }
+
+    /// Sets the flags so that `Equal` holds when the value in `reg` has the
+    /// type `type_tag`, reading the header for boxed types. Clobbers RDI.
+    pub fn emit_value_type_test(&mut self, reg: Register, type_tag: TypeTag) {
+        if type_tag.double {
+            self.emit_double_test(reg);
+            return;
+        }
+        self.emit_type_test(reg, type_tag.mask, type_tag.tag, Register::Rdi);
+        if let Some(kind) = type_tag.kind {
+            let done = self.asm.new_label();
+            self.asm.jcc(SetccConditions::NotEqual, done);
+            self.emit_kind_test(reg, kind);
+            self.asm.bind(done);
+        }
+    }
+
+    /// Sets the flags so that `Equal` holds when `reg & mask == tag`, using
+    /// `scratch` to keep `reg` intact. `scratch` may be `reg` itself, except
+    /// under NaN-boxing, where doubles must not match whatever their bits.
+    pub fn emit_type_test(&mut self, reg: Register, mask: Word, tag: Word, scratch: Register) {
+        if mask == !0 {
+            self.asm.cmp_reg_imm(reg, tag as i32);
+            return;
+        }
+        let done = self.asm.new_label();
+        if K_VALUE_BITS < 64 {
+            assert_ne!(scratch as u8, reg as u8, "no scratch register");
+            self.emit_value_bits_test(reg, scratch);
+            self.asm.jcc(SetccConditions::NotEqual, done);
+        }
+        if scratch as u8 != reg as u8 {
+            self.asm.mov_reg_reg(scratch, reg);
+        }
+        self.asm
+            .and_reg_imm(scratch, mask as i32)
+            .cmp_reg_imm(scratch, tag as i32)
+            .bind(done);
+    }
+
+    /// Sets the flags so that `Equal` holds when the word in `reg` fits in
+    /// `K_VALUE_BITS`, which under NaN-boxing means it is not a double.
+    /// Clobbers `scratch`.
+    fn emit_value_bits_test(&mut self, reg: Register, scratch: Register) {
+        // The bits from the top one of a value up are all equal, so shifted
+        // down they make 0 or -1. Plus one, 1 or 0: only those shift to zero.
+        self.asm
+            .mov_reg_reg(scratch, reg)
+            .sar_reg_imm8(scratch, (K_VALUE_BITS - 1) as u8)
+            .add_reg_imm(scratch, 1)
+            .shr_reg_imm8(scratch, 1);
+    }
+
+    /// Sets the flags so that `Equal` holds when `reg` is an unboxed double.
+    /// Clobbers RDI.
+    fn emit_double_test(&mut self, reg: Register) {
+        let double = self.asm.new_label();
+        let done = self.asm.new_label();
+        self.emit_value_bits_test(reg, Register::Rdi);
+        self.asm
+            .jcc(SetccConditions::NotEqual, double)
+            // RDI is zero.
+            .cmp_reg_imm(Register::Rdi, 1)
+            .jmp(done)
+            .bind(double)
+            .cmp_reg_reg(Register::Rdi, Register::Rdi)
+            .bind(done);
+    }
+
+    /// Like `emit_overflow_check`, but in `Promote` mode jumps to `promote`
+    /// instead, for the primitive to redo the operation on bignums.
+    pub fn emit_promoting_overflow_check(&mut self, primitive: &str, promote: Label) {
+        if self.overflow == Overflow::Promote {
+            self.emit_fixnum_overflow_jump(Register::Rax, promote);
+        } else {
+            self.emit_overflow_check(primitive);
+        }
+    }
+
+    /// Checks that tagged arithmetic on RAX left a fixnum, or in `Wrap` mode
+    /// wraps it around to one. Clobbers RDI.
+    pub fn emit_overflow_check(&mut self, primitive: &str) {
+        if self.overflow == Overflow::Wrap {
+            if K_VALUE_BITS < 64 {
+                let unused = (64 - K_VALUE_BITS) as u8;
+                self.asm
+                    .shl_reg_imm8(Register::Rax, unused)
+                    .sar_reg_imm8(Register::Rax, unused);
+            }
+            return;
+        }
+        let overflow = self.error_stub(Register::Rax, format!("{}: integer overflow", primitive));
+        self.emit_fixnum_overflow_jump(Register::Rax, overflow);
+    }
+
+    /// Jumps to `overflow` unless tagged arithmetic left a fixnum in `reg`.
+    /// When fixnums fill the word, the overflow flag says it all; under
+    /// NaN-boxing, the result must also fit in `K_VALUE_BITS`. Clobbers RDI.
+    pub fn emit_fixnum_overflow_jump(&mut self, reg: Register, overflow: Label) {
+        self.asm.jcc(SetccConditions::Overflow, overflow);
+        if K_VALUE_BITS < 64 {
+            self.emit_value_bits_test(reg, Register::Rdi);
+            self.asm.jcc(SetccConditions::NotEqual, overflow);
+        }
+    }
+
+    /// Bump-allocates `size` bytes from the runtime heap, leaving the untagged
+    /// address in RCX and the new heap pointer in RDX. Clobbers RSI and RDI.
+    /// RAX must hold a value: if the allocation collects, it is kept alive and
+    /// updated, like the values on the stack. Other registers are not.
+    pub fn emit_alloc(&mut self, size: i32, primitive: &str) {
+        self.asm
+            .mov_reg_mem(Register::Rcx, Register::Rbx, RT_HEAP_PTR)
+            .lea_reg_mem(Register::Rdx, Register::Rcx, size);
+        self.emit_alloc_commit(primitive);
+    }
+
+    /// Like `emit_alloc`, but with the size in bytes taken from RDX.
+    pub fn emit_alloc_dynamic(&mut self, primitive: &str) {
+        let slow_path = self.alloc_slow_path(primitive);
+        self.asm
+            .mov_reg_mem(Register::Rcx, Register::Rbx, RT_HEAP_PTR)
+            .add_reg_reg(Register::Rdx, Register::Rcx)
+            // Carry: the end wrapped around, which the slow path reports.
+            .jcc(SetccConditions::Below, slow_path.label);
+        self.emit_alloc_commit_to(slow_path);
+    }
+
+    /// Checks the new heap end in RDX against the limit and commits it,
+    /// taking the slow path when the heap is full.
+    fn emit_alloc_commit(&mut self, primitive: &str) {
+        let slow_path = self.alloc_slow_path(primitive);
+        self.emit_alloc_commit_to(slow_path);
+    }
+
+    fn emit_alloc_commit_to(&mut self, slow_path: SlowPath) {
+        self.asm
+            .mov_reg_mem(Register::Rsi, Register::Rbx, RT_HEAP_LIMIT)
+            .cmp_reg_reg(Register::Rdx, Register::Rsi)
+            .jcc(SetccConditions::Above, slow_path.label)
+            .mov_mem_reg(Register::Rbx, RT_HEAP_PTR, Register::Rdx)
+            .bind(slow_path.resume);
+        self.slow_paths.push(slow_path);
+    }
+
+    fn alloc_slow_path(&mut self, primitive: &str) -> SlowPath {
+        let exhausted = self.error_stub(Register::Rax, format!("{}: heap exhausted", primitive));
+        SlowPath {
+            label: self.asm.new_label(),
+            resume: self.asm.new_label(),
+            exhausted,
+            slots: self.stack_slots,
+        }
+    }
+
+    /// Emits the allocation slow paths: each asks `rt_alloc_slow` for the
+    /// RDX - RCX bytes the fast path could not bump, with RAX pushed as one
+    /// more root, and resumes with the same registers the fast path leaves.
+    /// The object may come from the old generation, so RDX is recomputed
+    /// from the size, which is pushed too: a multiple of 8, it reads as an
+    /// integer to the collector.
+    fn emit_slow_paths(&mut self) {
+        for slow_path in std::mem::take(&mut self.slow_paths) {
+            let slots = slow_path.slots + 2;
+            let pad = !(slots + self.frame_parity).is_multiple_of(2);
+            self.asm
+                .bind(slow_path.label)
+                .push_reg(Register::Rax)
+                .mov_reg_reg(Register::Rsi, Register::Rdx)
+                .sub_reg_reg(Register::Rsi, Register::Rcx)
+                .push_reg(Register::Rsi);
+            if pad {
+                self.asm.sub_reg_imm(Register::Rsp, 8);
+            }
+            self.asm
+                .mov_reg_reg(Register::Rdi, Register::Rbx)
+                .mov_reg_imm64(Register::Rax, rt_alloc_slow as *const () as i64);
+            self.emit_rust_call(Register::Rax, slots);
+            if pad {
+                self.asm.add_reg_imm(Register::Rsp, 8);
+            }
+            self.asm
+                .mov_reg_reg(Register::Rcx, Register::Rax)
+                .pop_reg(Register::Rdx)
+                .pop_reg(Register::Rax)
+                .cmp_reg_imm(Register::Rcx, 0)
+                .jcc(SetccConditions::Equal, slow_path.exhausted)
+                .add_reg_reg(Register::Rdx, Register::Rcx)
+                .jmp(slow_path.resume);
+        }
+    }
+
+    /// Records a store into the heap field whose address is in `field`, which
+    /// is clobbered: if the field is in the old generation, its card is marked
+    /// so the next minor collection looks at it for pointers into the nursery.
+    pub fn emit_write_barrier(&mut self, field: Register) {
+        let done = self.asm.new_label();
+        // One unsigned compare checks both bounds.
+        self.asm
+            .sub_reg_mem(field, Register::Rbx, RT_OLD_START)
+            .cmp_reg_mem(field, Register::Rbx, RT_OLD_SIZE)
+            .jcc(SetccConditions::AboveOrEqual, done)
+            .shr_reg_imm8(field, CARD_SHIFT as u8)
+            .add_reg_mem(field, Register::Rbx, RT_CARD_TABLE)
+            .mov_mem8_imm8(field, 0, CARD_DIRTY)
+            .bind(done);
+    }
+
+    /// Splits a call's argument list, checking that it holds exactly `count` arguments.
+    fn call_args<'a>(
+        name: &str,
+        args: &'a AstNode,
+        count: usize,
+    ) -> Result<Vec<&'a AstNode>, CompilerError> {
+        Self::call_args_range(name, args, count, count)
+    }
+
+    /// Splits a call's argument list, checking that it holds between `min` and
+    /// `max` arguments.
+    fn call_args_range<'a>(
+        name: &str,
+        mut args: &'a AstNode,
+        min: usize,
+        max: usize,
+    ) -> Result<Vec<&'a AstNode>, CompilerError> {
+        let mut result = Vec::new();
+        while let AstNode::Pair { car, cdr } = args {
+            result.push(&**car);
+            args = cdr;
+        }
+        if result.len() < min || result.len() > max || *args != AstNode::Nil {
+            let expected = if min == max {
+                min.to_string()
+            } else if max == usize::MAX {
+                format!("at least {}", min)
+            } else {
+                format!("{} to {}", min, max)
+            };
+            return Err(CompilerError::InvalidArguments(format!(
+                "{} expects {} argument(s)",
+                name, expected
+            )));
+        }
+        Ok(result)
+    }
+
+    /// Allocates a pair from the car on top of the stack and the cdr in RAX.
+    pub fn emit_cons(&mut self) {
+        self.emit_alloc(size_of::<Pair>() as i32, "cons");
+        self.pop(Register::Rdx);
+        self.asm
+            .mov_mem_reg(Register::Rcx, 0, Register::Rdx)
+            .mov_mem_reg(Register::Rcx, size_of::<Word>() as i32, Register::Rax)
+            .lea_reg_mem(Register::Rax, Register::Rcx, K_PAIR_TAG as i32);
+    }
+
+    /// Compiles `(define name expr)` or `(define (name params...) body...)`,
+    /// storing into the global's cell. Evaluates to the name.
+    fn compile_define(&mut self, args: &AstNode) -> Result<(), CompilerError> {
+        if !self.toplevel {
+            return Err(CompilerError::InvalidArguments(
+                "define is only allowed at top level".to_string(),
+            ));
+        }
+        let args = Self::call_args_range("define", args, 2, usize::MAX)?;
+        let name = match args[0] {
+            AstNode::Symbol(name) => {
+                if args.len() != 2 {
+                    return Err(CompilerError::InvalidArguments(
+                        "define expects 2 argument(s)".to_string(),
+                    ));
                 }
-                "zero?" => {
-                    if let AstNode::Pair { car: arg1, cdr: _ } = cdr {
-                        self.compile_expr(arg1)?;
-                        self.compile_compare_imm32(LispValue::from_integer(0));
-                        Ok(())
-                    } else {
-                        return Err(CompilerError::InvalidArguments(
-                            "zero? expects 1 argument. Needs a pair".to_string(),
-                        ));
-                    }
+                self.compile_expr(args[1])?;
+                name
+            }
+            AstNode::Pair { car, cdr } => {
+                let AstNode::Symbol(name) = &**car else {
+                    return Err(CompilerError::NotASymbol);
+                };
+                let mut params = Vec::new();
+                for param in Self::call_args_range(name, cdr, 0, usize::MAX)? {
+                    let AstNode::Symbol(param) = param else {
+                        return Err(CompilerError::NotASymbol);
+                    };
+                    params.push(param.clone());
                 }
+                self.compile_closure(name, params, &args[1..])?;
+                name
+            }
+            _ => return Err(CompilerError::NotASymbol),
+        };
+        self.emit_define_global(name);
+        Ok(())
+    }
+
+    /// Stores RAX into the global `name`, leaving the name in RAX.
+    fn emit_define_global(&mut self, name: &str) {
+        let cell = self.runtime.global_cell(name);
+        // Globals are not in the heap, so their write barrier is a single flag.
+        self.asm
+            .mov_reg_imm64(Register::Rcx, cell as i64)
+            .mov_mem_reg(Register::Rcx, 0, Register::Rax)
+            .mov_mem_imm32(Register::Rbx, RT_GLOBALS_DIRTY, 1);
+        self.emit_load_symbol(Register::Rax, name);
+    }
 
-                "integer?" => {
-                    if let AstNode::Pair { car: arg1, cdr: _ } = cdr {
-                        self.compile_expr(arg1)?;
-                        self.asm.and_reg_imm8(Register::Rax, K_INTEGER_MASK as u8);
-                        self.compile_compare_imm32(LispValue::from_raw_word(K_INTEGER_TAG));
-                        Ok(())
-                    } else {
-                        return Err(CompilerError::InvalidArguments(
-                            "integer? expects 1 argument. Needs a pair".to_string(),
-                        ));
-                    }
+    /// Compiles a procedure into its own code buffer, owned by the runtime, and
+    /// emits code that allocates a closure for it into RAX.
+    fn compile_closure(
+        &mut self,
+        name: &str,
+        params: Vec<String>,
+        body: &[&AstNode],
+    ) -> Result<(), CompilerError> {
+        self.compile_closure_with(name, params, |compiler| {
+            body.iter().try_for_each(|expr| compiler.compile_expr(expr))
+        })
+    }
+
+    /// Like `compile_closure`, but with the code of the body emitted by `body`.
+    fn compile_closure_with(
+        &mut self,
+        name: &str,
+        params: Vec<String>,
+        body: impl FnOnce(&mut Compiler<'_>) -> Result<(), CompilerError>,
+    ) -> Result<(), CompilerError> {
+        let arity = LispValue::from_integer(params.len() as Word);
+        let mut compiler = Compiler::new(self.runtime)
+            .with_safety(self.safety)
+            .with_overflow(self.overflow);
+        compiler.frame_parity = 0;
+        compiler.toplevel = false;
+        compiler.params = params;
+        let code = compiler.compile_procedure_body(name, body)?;
+        let code =
+            ExecBuffer::new(&code).map_err(|err| CompilerError::AssemblerError(err.to_string()))?;
+        let code = self.runtime.keep_procedure(code);
+
+        let word = size_of::<Word>() as i32;
+        let header = Header::new(K_CLOSURE_TAG, 0);
+        // RAX is live across the allocation, so it must hold a value.
+        self.asm
+            .mov_reg_imm(Register::Rax, LispValue::nil().as_raw_word());
+        self.emit_alloc(size_of::<Closure>() as i32, name);
+        self.asm
+            .mov_reg_imm(Register::Rax, header.as_raw_word())
+            .mov_mem_reg(Register::Rcx, 0, Register::Rax)
+            .mov_reg_imm64(Register::Rax, code as i64)
+            .mov_mem_reg(Register::Rcx, word, Register::Rax)
+            .mov_reg_imm32(Register::Rax, arity.as_raw_word() as i32)
+            .mov_mem_reg(Register::Rcx, 2 * word, Register::Rax);
+        self.emit_load_symbol(Register::Rax, name);
+        self.asm
+            .mov_mem_reg(Register::Rcx, 3 * word, Register::Rax)
+            .lea_reg_mem(Register::Rax, Register::Rcx, K_CLOSURE_TAG as i32);
+        Ok(())
+    }
+
+    /// Compiles `(define-record-type name (constructor field...) predicate
+    /// (field accessor [modifier])...)`. Each evaluation creates a new type:
+    /// `name` is defined as its descriptor, and the procedures as globals
+    /// that test for it. A type named `<name>` prints as `name`. Fields left
+    /// out of the constructor start as false. Evaluates to the name.
+    fn compile_define_record_type(&mut self, args: &AstNode) -> Result<(), CompilerError> {
+        const FORM: &str = "define-record-type";
+        if !self.toplevel {
+            return Err(CompilerError::InvalidArguments(format!(
+                "{} is only allowed at top level",
+                FORM
+            )));
+        }
+        let args = Self::call_args_range(FORM, args, 3, usize::MAX)?;
+        let type_name = Self::symbol_name(args[0])?;
+        let constructor = Self::symbol_names(FORM, args[1], 1, usize::MAX)?;
+        let predicate = Self::symbol_name(args[2])?;
+        let specs = args[3..]
+            .iter()
+            .map(|spec| Self::symbol_names(FORM, spec, 2, 3))
+            .collect::<Result<Vec<_>, _>>()?;
+        let fields: Vec<&str> = specs.iter().map(|spec| spec[0]).collect();
+        for names in [&fields[..], &constructor[1..]] {
+            for (index, name) in names.iter().enumerate() {
+                if names[..index].contains(name) {
+                    return Err(CompilerError::InvalidArguments(format!(
+                        "{}: duplicate field {}",
+                        FORM, name
+                    )));
                 }
-                "bool?" => {
-                    // TODO: Add test to this func
-                    if let AstNode::Pair { car: arg1, cdr: _ } = cdr {
-                        self.compile_expr(arg1)?;
-                        self.asm.and_reg_imm8(Register::Rax, K_BOOL_MASK as u8);
-                        self.compile_compare_imm32(LispValue::from_raw_word(K_BOOL_TAG));
-                        Ok(())
-                    } else {
-                        return Err(CompilerError::InvalidArguments(
-                            "integer? expects 1 argument. Needs a pair".to_string(),
-                        ));
-                    }
+            }
+        }
+        if let Some(param) = constructor[1..]
+            .iter()
+            .find(|param| !fields.contains(param))
+        {
+            return Err(CompilerError::InvalidArguments(format!(
+                "{}: {} is not a field",
+                FORM, param
+            )));
+        }
+        let display_name = type_name
+            .strip_prefix('<')
+            .and_then(|name| name.strip_suffix('>'))
+            .unwrap_or(type_name);
+
+        // The descriptor: a vector of the type name and the field names.
+        let names: Vec<&str> = std::iter::once(display_name)
+            .chain(fields.iter().copied())
+            .collect();
+        let word = size_of::<Word>() as i32;
+        self.asm
+            .mov_reg_imm(Register::Rax, LispValue::nil().as_raw_word());
+        self.emit_alloc(
+            (size_of::<Vector>() + names.len() * size_of::<Word>()) as i32,
+            FORM,
+        );
+        self.asm
+            .mov_reg_imm(
+                Register::Rax,
+                Header::new(K_VECTOR_TAG, names.len()).as_raw_word(),
+            )
+            .mov_mem_reg(Register::Rcx, 0, Register::Rax);
+        for (index, name) in names.iter().enumerate() {
+            self.emit_load_symbol(Register::Rax, name);
+            self.asm
+                .mov_mem_reg(Register::Rcx, (index as i32 + 1) * word, Register::Rax);
+        }
+        let cell = self.runtime.record_type_cell();
+        self.asm
+            .lea_reg_mem(Register::Rax, Register::Rcx, K_VECTOR_TAG as i32)
+            .mov_reg_imm64(Register::Rcx, cell as i64)
+            .mov_mem_reg(Register::Rcx, 0, Register::Rax);
+        self.emit_define_global(type_name);
+
+        let params = constructor[1..]
+            .iter()
+            .map(|param| param.to_string())
+            .collect();
+        self.compile_closure_with(constructor[0], params, |c| {
+            c.emit_record_alloc(cell, &fields, constructor[0]);
+            Ok(())
+        })?;
+        self.emit_define_global(constructor[0]);
+        self.compile_closure_with(predicate, vec!["object".to_string()], |c| {
+            c.compile_variable("object");
+            c.emit_record_type_test(cell);
+            c.emit_condition_to_bool(SetccConditions::Equal);
+            Ok(())
+        })?;
+        self.emit_define_global(predicate);
+        for (index, spec) in specs.iter().enumerate() {
+            let offset = Self::field_offset(K_STRING_TAG, 2 + index);
+            let accessor = spec[1];
+            self.compile_closure_with(accessor, vec!["record".to_string()], |c| {
+                c.compile_variable("record");
+                c.emit_record_type_check(cell, accessor, display_name);
+                c.asm.mov_reg_mem(Register::Rax, Register::Rax, offset);
+                Ok(())
+            })?;
+            self.emit_define_global(accessor);
+            let Some(&modifier) = spec.get(2) else {
+                continue;
+            };
+            let params = vec!["record".to_string(), "value".to_string()];
+            self.compile_closure_with(modifier, params, |c| {
+                c.compile_variable("record");
+                c.emit_record_type_check(cell, modifier, display_name);
+                c.asm.mov_reg_reg(Register::Rcx, Register::Rax);
+                c.compile_variable("value");
+                c.asm
+                    .mov_mem_reg(Register::Rcx, offset, Register::Rax)
+                    .lea_reg_mem(Register::Rdi, Register::Rcx, offset);
+                c.emit_write_barrier(Register::Rdi);
+                c.asm
+                    .mov_reg_imm(Register::Rax, LispValue::nil().as_raw_word());
+                Ok(())
+            })?;
+            self.emit_define_global(modifier);
+        }
+        self.emit_load_symbol(Register::Rax, type_name);
+        Ok(())
+    }
+
+    /// Allocates a record of the type whose descriptor is in `cell` into RAX,
+    /// from the parameters named after its `fields`.
+    fn emit_record_alloc(&mut self, cell: *mut LispValue, fields: &[&str], name: &str) {
+        let word = size_of::<Word>() as i32;
+        // RAX is live across the allocation, so it must hold a value.
+        self.asm
+            .mov_reg_imm(Register::Rax, LispValue::nil().as_raw_word());
+        self.emit_alloc(Record::allocation_size(fields.len()) as i32, name);
+        self.asm
+            .mov_reg_imm(
+                Register::Rax,
+                Header::new(K_RECORD_KIND, fields.len()).as_raw_word(),
+            )
+            .mov_mem_reg(Register::Rcx, 0, Register::Rax)
+            .mov_reg_imm64(Register::Rax, cell as i64)
+            .mov_reg_mem(Register::Rax, Register::Rax, 0)
+            .mov_mem_reg(Register::Rcx, word, Register::Rax);
+        for (index, field) in fields.iter().enumerate() {
+            if self.params.iter().any(|param| param == field) {
+                self.compile_variable(field);
+            } else {
+                self.asm
+                    .mov_reg_imm(Register::Rax, LispValue::false_val().as_raw_word());
+            }
+            self.asm
+                .mov_mem_reg(Register::Rcx, (index as i32 + 2) * word, Register::Rax);
+        }
+        self.asm
+            .lea_reg_mem(Register::Rax, Register::Rcx, K_STRING_TAG as i32);
+    }
+
+    /// Sets the flags so that `Equal` holds when RAX is a record of the type
+    /// whose descriptor is in `cell`. Clobbers RSI and RDI.
+    fn emit_record_type_test(&mut self, cell: *mut LispValue) {
+        let done = self.asm.new_label();
+        self.emit_value_type_test(Register::Rax, TypeTag::RECORD);
+        self.asm
+            .jcc(SetccConditions::NotEqual, done)
+            .mov_reg_mem(
+                Register::Rdi,
+                Register::Rax,
+                Self::field_offset(K_STRING_TAG, 1),
+            )
+            .mov_reg_imm64(Register::Rsi, cell as i64)
+            .cmp_reg_mem(Register::Rdi, Register::Rsi, 0)
+            .bind(done);
+    }
+
+    /// Raises a type error unless RAX is a record of the type whose descriptor
+    /// is in `cell`. Clobbers RSI and RDI. Emits nothing when compiling
+    /// unchecked code.
+    fn emit_record_type_check(&mut self, cell: *mut LispValue, procedure: &str, type_name: &str) {
+        if self.safety == Safety::Unchecked {
+            return;
+        }
+        let error = self.error_stub(
+            Register::Rax,
+            format!("{}: expected {}, got {{}}", procedure, type_name),
+        );
+        self.emit_record_type_test(cell);
+        self.asm.jcc(SetccConditions::NotEqual, error);
+    }
+
+    /// The name of the symbol `node`.
+    fn symbol_name(node: &AstNode) -> Result<&str, CompilerError> {
+        match node {
+            AstNode::Symbol(name) => Ok(name),
+            _ => Err(CompilerError::NotASymbol),
+        }
+    }
+
+    /// Splits a list of symbols in the syntax of `form`, checking that it
+    /// holds between `min` and `max` of them.
+    fn symbol_names<'a>(
+        form: &str,
+        list: &'a AstNode,
+        min: usize,
+        max: usize,
+    ) -> Result<Vec<&'a str>, CompilerError> {
+        Self::call_args_range(form, list, min, max)?
+            .into_iter()
+            .map(Self::symbol_name)
+            .collect()
+    }
+
+    /// Calls the procedure `operator` evaluates to, following the convention
+    /// described on `compile_procedure_body`.
+    fn compile_procedure_call(
+        &mut self,
+        operator: &AstNode,
+        args: &AstNode,
+    ) -> Result<(), CompilerError> {
+        let args = Self::call_args_range("call", args, 0, usize::MAX)?;
+        let pad = !(self.stack_slots + self.frame_parity + args.len()).is_multiple_of(2);
+        if pad {
+            // Padding is a valid value, so every stack slot holds one.
+            self.asm.mov_reg_imm32(Register::Rax, 0);
+            self.push(Register::Rax);
+        }
+        for arg in &args {
+            self.compile_expr(arg)?;
+            self.push(Register::Rax);
+        }
+        self.compile_expr(operator)?;
+        self.emit_tag_check(
+            Register::Rax,
+            K_HEAP_TAG_MASK,
+            K_CLOSURE_TAG,
+            "call",
+            "procedure",
+        );
+        let argc = LispValue::from_integer(args.len() as Word);
+        let slots = args.len() + pad as usize;
+        self.asm
+            .mov_reg_reg(Register::Rdx, Register::Rax)
+            .mov_reg_imm32(Register::Rcx, argc.as_raw_word() as i32)
+            .mov_reg_mem(
+                Register::Rax,
+                Register::Rdx,
+                Self::field_offset(K_CLOSURE_TAG, 1),
+            )
+            .call_reg(Register::Rax);
+        self.emit_stack_map(self.stack_slots);
+        self.asm
+            .add_reg_imm32(Register::Rsp, (slots * size_of::<Word>()) as i32);
+        self.stack_slots -= slots;
+        Ok(())
+    }
+
+    /// Loads the variable `name` into RAX: a parameter of the current
+    /// procedure, or else a global.
+    fn compile_variable(&mut self, name: &str) {
+        if let Some(index) = self.params.iter().rposition(|param| param == name) {
+            // Arguments sit above the saved RBP and the return address, last one first.
+            let slot = 2 + self.params.len() - 1 - index;
+            self.asm.mov_reg_mem(
+                Register::Rax,
+                Register::Rbp,
+                (slot * size_of::<Word>()) as i32,
+            );
+            return;
+        }
+        let cell = self.runtime.global_cell(name);
+        let unbound = self.error_stub(Register::Rax, format!("{}: unbound variable", name));
+        self.asm
+            .mov_reg_imm64(Register::Rax, cell as i64)
+            .mov_reg_mem(Register::Rax, Register::Rax, 0)
+            .cmp_reg_imm32(Register::Rax, K_UNBOUND_VALUE as u32)
+            .jcc(SetccConditions::Equal, unbound);
+    }
+
+    /// Offset to add to a tagged pointer to reach the given word of the object.
+    pub fn field_offset(tag: Word, index: usize) -> i32 {
+        (index * size_of::<Word>()) as i32 - tag as i32
+    }
+
+    /// Compiles a quoted datum. Lists are rebuilt on the heap each time the code runs.
+    fn compile_quote(&mut self, datum: &AstNode) -> Result<(), CompilerError> {
+        match datum {
+            AstNode::Pair { car, cdr } => {
+                self.compile_quote(car)?;
+                self.push(Register::Rax);
+                self.compile_quote(cdr)?;
+                self.emit_cons();
+                Ok(())
+            }
+            AstNode::Symbol(name) => {
+                self.emit_load_symbol(Register::Rax, name);
+                Ok(())
+            }
+            _ => self.compile_expr(datum),
+        }
+    }
+
+    fn compile_call(&mut self, car: &AstNode, cdr: &AstNode) -> Result<(), CompilerError> {
+        // A Pair in evaluation position means a function call.
+        // Symbols naming a special form or primitive are compiled inline, unless
+        // shadowed by a parameter; anything else is a call to a procedure.
+        if let AstNode::Symbol(name) = car
+            && !self.params.contains(name)
+        {
+            match name.as_str() {
+                "define" => return self.compile_define(cdr),
+                "define-record-type" => return self.compile_define_record_type(cdr),
+                "quote" => {
+                    let args = Self::call_args(name, cdr, 1)?;
+                    return self.compile_quote(args[0]);
                 }
-                // "sub1" => { ... }
                 // "if" => { ... this will be a "special form" ... }
-                _ => return Err(CompilerError::NotAFunction(name.clone())),
+                _ => {}
+            }
+            if let Some(primitive) = self.runtime.primitives().get(name) {
+                return self.compile_primitive(&primitive, cdr);
+            }
+        }
+        self.compile_procedure_call(car, cdr)
+    }
+
+    /// Evaluates and checks the arguments of `primitive` as described on
+    /// `Primitive`, then emits its body.
+    fn compile_primitive(
+        &mut self,
+        primitive: &Primitive,
+        args: &AstNode,
+    ) -> Result<(), CompilerError> {
+        let (min, max) = primitive.arity_bounds();
+        let args = Self::call_args_range(primitive.name(), args, min, max)?;
+        let in_registers = primitive.passes_args_in_registers();
+        for (index, arg) in args.iter().enumerate() {
+            self.compile_expr(arg)?;
+            let arg_type = primitive.arg_type(index);
+            if arg_type != ArgType::ANY {
+                self.emit_tag_check(
+                    Register::Rax,
+                    arg_type.mask,
+                    arg_type.tag,
+                    primitive.name(),
+                    arg_type.name,
+                );
+            }
+            if let Some(kind) = arg_type.kind {
+                self.emit_kind_check(Register::Rax, kind, primitive.name(), arg_type.name);
+            }
+            if !in_registers || index + 1 < args.len() {
+                self.push(Register::Rax);
             }
-        } else {
-            // The 'car' was not a symbol, e.g., ((+ 1 2) 3)
-            // This is a higher-order function, which we don't support yet.
-            return Err(CompilerError::NotASymbol);
         }
+        if in_registers && args.len() > 1 {
+            let last = args.len() - 1;
+            self.asm.mov_reg_reg(ARG_REGISTERS[last], Register::Rax);
+            for index in (0..last).rev() {
+                self.pop(ARG_REGISTERS[index]);
+            }
+        }
+        primitive.emit(self, args.len());
+        Ok(())
     }
-    fn compile_compare_imm32(&mut self, value: LispValue) {
+
+    /// Compares RAX with `value`, leaving a boolean in RAX.
+    pub fn compile_compare_imm32(&mut self, value: LispValue) {
+        self.asm
+            .cmp_reg_imm(Register::Rax, value.as_raw_word() as i32);
+        self.emit_condition_to_bool(SetccConditions::Equal);
+    }
+
+    /// Materialises the flags of the last comparison as a boolean in RAX.
+    pub fn emit_condition_to_bool(&mut self, cond: SetccConditions) {
         self.asm
-            .cmp_reg_imm32(Register::Rax, value.as_raw_word() as u32)
             .mov_reg_imm32(Register::Rax, 0)
-            .setcc_imm8(SetccConditions::Equal, PartialRegister::Al)
+            .setcc_imm8(cond, PartialRegister::Al)
             .shl_reg_imm8(Register::Rax, K_BOOL_SHIFT as u8)
             .or_reg_imm8(Register::Rax, K_BOOL_TAG as u8);
     }
 
+    /// Loads an integer outside the fixnum range, which is allocated each
+    /// time the code runs.
+    fn compile_bignum_literal(&mut self, value: &BigInt) {
+        let literal = self.runtime.keep_bignum_literal(value);
+        self.asm.mov_reg_imm64(Register::Rsi, literal as i64);
+        self.emit_runtime_call(rt_bignum_literal as *const ());
+    }
+
+    /// Loads a float literal, which is boxed each time the code runs.
+    #[cfg(not(feature = "nan-boxing"))]
+    fn compile_flonum_literal(&mut self, value: f64) {
+        // RAX is a root while allocating.
+        self.asm
+            .mov_reg_imm(Register::Rax, LispValue::nil().as_raw_word());
+        self.emit_alloc(size_of::<Flonum>() as i32, "float literal");
+        self.asm
+            .mov_reg_imm64(Register::Rsi, value.to_bits() as i64)
+            .mov_mem_reg(
+                Register::Rcx,
+                offset_of!(Flonum, value) as i32,
+                Register::Rsi,
+            );
+        self.emit_flonum_header();
+    }
+
+    /// Writes a flonum header to the object `emit_alloc` left in RCX, whose
+    /// value is stored already, and tags it into RAX. Clobbers RSI.
+    #[cfg(not(feature = "nan-boxing"))]
+    fn emit_flonum_header(&mut self) {
+        let header = Header::new(K_FLONUM_KIND, 0).as_raw_word();
+        self.asm
+            .mov_reg_imm(Register::Rsi, header)
+            .mov_mem_reg(Register::Rcx, 0, Register::Rsi)
+            .lea_reg_mem(Register::Rax, Register::Rcx, K_STRING_TAG as i32);
+    }
+
+    /// Loads a float literal, which NaN-boxing encodes like any constant.
+    #[cfg(feature = "nan-boxing")]
+    fn compile_flonum_literal(&mut self, value: f64) {
+        self.asm
+            .mov_reg_imm(Register::Rax, LispValue::from_f64(value).as_raw_word());
+    }
+
+    /// Makes room for a flonum ahead of computing its double, since boxing
+    /// allocates and a collection does not keep the XMM registers. Leaves
+    /// the box in RCX for `emit_box_double`; NaN-boxing needs none. RAX must
+    /// hold a value, as for `emit_alloc`.
+    pub fn emit_flonum_alloc(&mut self, primitive: &str) {
+        if !cfg!(feature = "nan-boxing") {
+            self.emit_alloc(size_of::<Flonum>() as i32, primitive);
+        }
+    }
+
+    /// Makes a flonum of the double in XMM0, in the box `emit_flonum_alloc`
+    /// left in RCX, and tags it into RAX. Clobbers RSI.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn emit_box_double(&mut self) {
+        self.asm.movsd_mem_xmm(
+            Register::Rcx,
+            offset_of!(Flonum, value) as i32,
+            XmmRegister::Xmm0,
+        );
+        self.emit_flonum_header();
+    }
+
+    /// Encodes the double in XMM0 as a flonum in RAX. Clobbers RSI.
+    #[cfg(feature = "nan-boxing")]
+    pub fn emit_box_double(&mut self) {
+        // Any other NaN could land on a value.
+        let ordered = self.asm.new_label();
+        self.asm
+            .movq_reg_xmm(Register::Rax, XmmRegister::Xmm0)
+            .ucomisd(XmmRegister::Xmm0, XmmRegister::Xmm0)
+            .jcc(SetccConditions::NotParity, ordered)
+            .mov_reg_imm(Register::Rax, K_CANONICAL_NAN as i64)
+            .bind(ordered)
+            .mov_reg_imm(Register::Rsi, K_DOUBLE_OFFSET)
+            .add_reg_reg(Register::Rax, Register::Rsi);
+    }
+
+    /// Loads the double of the flonum in `reg` into `xmm`. Clobbers RDI.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn emit_unbox_double(&mut self, reg: Register, xmm: XmmRegister) {
+        self.asm
+            .movsd_xmm_mem(xmm, reg, Self::field_offset(K_STRING_TAG, 1));
+    }
+
+    /// Loads the double of the flonum in `reg` into `xmm`. Clobbers RDI.
+    #[cfg(feature = "nan-boxing")]
+    pub fn emit_unbox_double(&mut self, reg: Register, xmm: XmmRegister) {
+        self.asm
+            .mov_reg_imm(Register::Rdi, -K_DOUBLE_OFFSET)
+            .add_reg_reg(Register::Rdi, reg)
+            .movq_xmm_reg(xmm, Register::Rdi);
+    }
+
     fn compile_expr(&mut self, node: &AstNode) -> Result<(), CompilerError> {
         match node {
             AstNode::Integer(value) => {
+                if !(K_INTEGER_MIN..=K_INTEGER_MAX).contains(value) {
+                    self.compile_bignum_literal(&BigInt::from(*value));
+                    return Ok(());
+                }
                 let lisp_val = LispValue::from_integer(*value);
-                self.asm
-                    .mov_reg_imm32(Register::Rax, lisp_val.as_raw_word() as i32);
+                self.asm.mov_reg_imm(Register::Rax, lisp_val.as_raw_word());
             }
+            AstNode::BigInteger(value) => self.compile_bignum_literal(value),
+            AstNode::Float(value) => self.compile_flonum_literal(*value),
             AstNode::Bool(value) => {
                 let lisp_val = LispValue::from_bool(*value);
-                self.asm
-                    .mov_reg_imm32(Register::Rax, lisp_val.as_raw_word() as i32);
+                self.asm.mov_reg_imm(Register::Rax, lisp_val.as_raw_word());
             }
             AstNode::Char(value) => {
                 let lisp_val = LispValue::from_char(*value);
-                self.asm
-                    .mov_reg_imm32(Register::Rax, lisp_val.as_raw_word() as i32);
+                self.asm.mov_reg_imm(Register::Rax, lisp_val.as_raw_word());
             }
             AstNode::Nil => {
                 let lisp_val = LispValue::nil();
-                self.asm
-                    .mov_reg_imm32(Register::Rax, lisp_val.as_raw_word() as i32);
+                self.asm.mov_reg_imm(Register::Rax, lisp_val.as_raw_word());
             }
-            AstNode::Symbol(name) => {
-                let lisp_val = self.intern_symbol(name);
-                // For now symbols are not fully implemented
-                // self.asm
-                //     .mov_reg_imm64(Register::Rax, lisp_val.as_raw_word());
+            AstNode::Str(value) => {
+                // Strings are mutable, so each evaluation makes a fresh copy.
+                let (bytes, len) = self.runtime.keep_string_literal(value);
+                self.asm
+                    .mov_reg_imm64(Register::Rsi, bytes as i64)
+                    .mov_reg_imm(Register::Rdx, len as i64);
+                self.emit_runtime_call(rt_string_literal as *const ());
             }
+            AstNode::Symbol(name) => self.compile_variable(name),
 
             AstNode::Pair { car, cdr } => self.compile_call(car, cdr)?,
         }
@@ -225,26 +1260,33 @@ impl Compiler {
     }
 }
 
+#[cfg(test)]
 mod tests {
 
     use super::*;
     use crate::ExecBuffer;
     use crate::ast::AstNode; // Import AstNode
-    use crate::encodings::LispValue; // Import LispValue
+    use crate::encodings::{LispValue, Vector}; // Import LispValue
+    use crate::reader::Parser;
+    use crate::runtime::Runtime;
+    use crate::testing::{
+        assert_errors, assert_errors_with, assert_evals, eval, eval_configured, eval_with,
+    };
     fn compile_ast(ast_node: AstNode) -> LispValue {
-        let mut compiler = Compiler::new();
+        let mut runtime = Runtime::new();
+        let compiler = Compiler::new(&mut runtime);
         let result = compiler.compile_function(&ast_node);
         assert!(result.is_ok());
         let code = result.unwrap();
         let exec = ExecBuffer::new(&code).unwrap();
 
-        let func = unsafe { exec.as_function::<unsafe extern "C" fn() -> i64>() };
-        let encoded_result = unsafe { func() };
-        LispValue::from_raw_word(encoded_result)
+        runtime.run(&exec).unwrap()
     }
+
     #[test]
     fn test_compiler() {
-        let mut compiler = Compiler::new();
+        let mut runtime = Runtime::new();
+        let compiler = Compiler::new(&mut runtime);
         let expr = 42;
         let ast_node = AstNode::Integer(expr);
         let result = compiler.compile_function(&ast_node);
@@ -252,14 +1294,13 @@ mod tests {
         let code = result.unwrap();
         let exec = ExecBuffer::new(&code).unwrap();
 
-        let func = unsafe { exec.as_function::<unsafe extern "C" fn() -> i64>() };
-        let encoded_result = unsafe { func() };
-        let lisp_val = LispValue::from_raw_word(encoded_result);
+        let lisp_val = runtime.run(&exec).unwrap();
         assert_eq!(lisp_val.as_integer(), Some(expr));
     }
     #[test]
     fn test_bool() {
-        let mut compiler = Compiler::new();
+        let mut runtime = Runtime::new();
+        let compiler = Compiler::new(&mut runtime);
         let expr = true;
         let ast_node = AstNode::Bool(expr);
         let result = compiler.compile_function(&ast_node);
@@ -267,15 +1308,14 @@ mod tests {
         let code = result.unwrap();
         let exec = ExecBuffer::new(&code).unwrap();
 
-        let func = unsafe { exec.as_function::<unsafe extern "C" fn() -> i64>() };
-        let encoded = unsafe { func() };
-        let lisp_val = LispValue::from_raw_word(encoded);
+        let lisp_val = runtime.run(&exec).unwrap();
         assert_eq!(lisp_val.as_bool(), Some(expr));
     }
 
     #[test]
     fn test_add1() {
-        let mut compiler = Compiler::new();
+        let mut runtime = Runtime::new();
+        let compiler = Compiler::new(&mut runtime);
 
         // This is the "Lisp way" AST for `(add1 10)`
         let ast_node = AstNode::Pair {
@@ -292,9 +1332,7 @@ mod tests {
         let code = result.unwrap();
         let exec = ExecBuffer::new(&code).unwrap();
 
-        let func = unsafe { exec.as_function::<unsafe extern "C" fn() -> i64>() };
-        let encoded_result = unsafe { func() };
-        let lisp_val = LispValue::from_raw_word(encoded_result);
+        let lisp_val = runtime.run(&exec).unwrap();
 
         // The result should be the encoded value for 11
         assert!(lisp_val.is_integer());
@@ -303,7 +1341,8 @@ mod tests {
 
     #[test]
     fn test_sub1() {
-        let mut compiler = Compiler::new();
+        let mut runtime = Runtime::new();
+        let compiler = Compiler::new(&mut runtime);
 
         // This is the "Lisp way" AST for `(add1 10)`
         let ast_node = AstNode::Pair {
@@ -320,9 +1359,7 @@ mod tests {
         let code = result.unwrap();
         let exec = ExecBuffer::new(&code).unwrap();
 
-        let func = unsafe { exec.as_function::<unsafe extern "C" fn() -> i64>() };
-        let encoded_result = unsafe { func() };
-        let lisp_val = LispValue::from_raw_word(encoded_result);
+        let lisp_val = runtime.run(&exec).unwrap();
 
         // The result should be the encoded value for 11
         assert!(lisp_val.is_integer());
@@ -330,7 +1367,8 @@ mod tests {
     }
     #[test]
     fn test_nested_adds() {
-        let mut compiler = Compiler::new();
+        let mut runtime = Runtime::new();
+        let compiler = Compiler::new(&mut runtime);
         let val = 10;
         let expected = val + 2;
         // Test (add1 (add1 5))
@@ -354,9 +1392,7 @@ mod tests {
         let code = result.unwrap();
         let exec = ExecBuffer::new(&code).unwrap();
 
-        let func = unsafe { exec.as_function::<unsafe extern "C" fn() -> i64>() };
-        let encoded_result = unsafe { func() };
-        let lisp_val = LispValue::from_raw_word(encoded_result);
+        let lisp_val = runtime.run(&exec).unwrap();
 
         // The result should be the encoded value for 11
         assert!(lisp_val.is_integer());
@@ -388,7 +1424,7 @@ mod tests {
         let lisp_val = compile_ast(ast_node);
         let bool = lisp_val.as_bool();
         assert!(bool.is_some());
-        assert_eq!(bool.unwrap(), true);
+        assert!(bool.unwrap());
     }
 
     #[test]
@@ -403,7 +1439,7 @@ mod tests {
         let lisp_val = compile_ast(ast_node);
         let bool = lisp_val.as_bool();
         assert!(bool.is_some());
-        assert_eq!(bool.unwrap(), true);
+        assert!(bool.unwrap());
     }
 
     #[test]
@@ -418,7 +1454,7 @@ mod tests {
         let lisp_val = compile_ast(ast_node);
         let bool = lisp_val.as_bool();
         assert!(bool.is_some());
-        assert_eq!(bool.unwrap(), true);
+        assert!(bool.unwrap());
     }
     #[test]
     fn test_not_integer() {
@@ -435,6 +1471,684 @@ mod tests {
         }
         let bool = lisp_val.as_bool();
         assert!(bool.is_some());
-        assert_eq!(bool.unwrap(), false);
+        assert!(!bool.unwrap());
+    }
+
+    #[test]
+    fn test_cons_car_cdr() {
+        let mut runtime = Runtime::new();
+        let pair = eval(&mut runtime, "(cons 1 (cons #\\a nil))").unwrap();
+        assert!(pair.is_pair());
+        let pair = unsafe { *pair.as_pair_pointer().unwrap() };
+        assert_eq!(pair.car.as_integer(), Some(1));
+        assert!(pair.cdr.is_pair());
+
+        let car = eval(&mut runtime, "(car (cons 1 2))").unwrap();
+        assert_eq!(car.as_integer(), Some(1));
+        let cadr = eval(&mut runtime, "(car (cdr (cons 1 (cons 2 nil))))").unwrap();
+        assert_eq!(cadr.as_integer(), Some(2));
+        assert_eq!(runtime.heap_used(), 5 * size_of::<Pair>());
+    }
+
+    #[test]
+    fn test_pair_predicates() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("(pair? (cons 1 2))", true),
+            ("(pair? 1)", false),
+            ("(pair? nil)", false),
+            ("(null? nil)", true),
+            ("(null? (cons 1 nil))", false),
+            ("(null? (cdr (cons 1 nil)))", true),
+        ];
+        for (input, expected) in cases {
+            let value = eval(&mut runtime, input).unwrap();
+            assert_eq!(value.as_bool(), Some(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_pair_mutators() {
+        let mut runtime = Runtime::new();
+        let first = eval(&mut runtime, "(cons 0 0)").unwrap();
+        let value = eval(&mut runtime, "(set-car! (cons 1 2) 3)").unwrap();
+        assert!(value.is_nil());
+        let value = eval(&mut runtime, "(set-cdr! (cons 1 2) 4)").unwrap();
+        assert!(value.is_nil());
+
+        // Pairs are bump allocated, so the mutated pairs follow the first one.
+        let first = first.as_pair_pointer().unwrap();
+        let (second, third) = unsafe { (*first.add(1), *first.add(2)) };
+        assert_eq!(second.car.as_integer(), Some(3));
+        assert_eq!(second.cdr.as_integer(), Some(2));
+        assert_eq!(third.car.as_integer(), Some(1));
+        assert_eq!(third.cdr.as_integer(), Some(4));
+    }
+
+    #[test]
+    fn test_pair_type_errors() {
+        let mut runtime = Runtime::new();
+        assert_eq!(
+            eval(&mut runtime, "(car 5)"),
+            Err("car: expected pair, got 5".to_string())
+        );
+        assert_eq!(
+            eval(&mut runtime, "(cdr #\\a)"),
+            Err("cdr: expected pair, got #\\a".to_string())
+        );
+        assert_eq!(
+            eval(&mut runtime, "(set-car! nil 1)"),
+            Err("set-car!: expected pair, got ()".to_string())
+        );
+        // The runtime is still usable after an error.
+        assert_eq!(
+            eval(&mut runtime, "(add1 1)").unwrap().as_integer(),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_pair_arity() {
+        let ast = Parser::new("(cons 1)").read_form().unwrap();
+        assert!(matches!(
+            Compiler::new(&mut Runtime::new()).compile_function(&ast),
+            Err(CompilerError::InvalidArguments(_))
+        ));
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mut runtime = Runtime::new();
+        let vector = eval(&mut runtime, "(vector 1 #\\a (vector))").unwrap();
+        let elements = unsafe { (*vector.as_vector_pointer().unwrap()).elements() };
+        assert_eq!(elements.len(), 3);
+        assert_eq!(elements[0].as_integer(), Some(1));
+        assert_eq!(elements[1].as_char(), Some('a'));
+        assert!(elements[2].is_vector());
+
+        let vector = eval(&mut runtime, "(make-vector 4 #\\z)").unwrap();
+        let elements = unsafe { (*vector.as_vector_pointer().unwrap()).elements() };
+        assert!(elements.iter().all(|e| e.as_char() == Some('z')));
+
+        let cases = [
+            ("(vector-length (make-vector 5))", 5),
+            ("(vector-length (vector))", 0),
+            ("(vector-ref (vector 1 2 3) 2)", 3),
+            ("(vector-ref (make-vector 3 7) 0)", 7),
+        ];
+        for (input, expected) in cases {
+            let value = eval(&mut runtime, input).unwrap();
+            assert_eq!(value.as_integer(), Some(expected), "{}", input);
+        }
+        assert_eq!(
+            eval(&mut runtime, "(vector? (vector))").unwrap().as_bool(),
+            Some(true)
+        );
+        assert_eq!(
+            eval(&mut runtime, "(vector? (cons 1 2))")
+                .unwrap()
+                .as_bool(),
+            Some(false)
+        );
+    }
+
+    #[test]
+    fn test_vector_set() {
+        let mut runtime = Runtime::new();
+        let vector = eval(&mut runtime, "(make-vector 2 0)").unwrap();
+        let value = eval(&mut runtime, "(vector-set! (vector 1 2 3) 1 #\\x)").unwrap();
+        assert!(value.is_nil());
+        // The second vector was bump allocated right after the first one.
+        let second = unsafe {
+            vector
+                .as_vector_pointer()
+                .unwrap()
+                .cast::<LispValue>()
+                .add(3)
+        };
+        let elements = unsafe { (*second.cast::<Vector>()).elements() };
+        assert_eq!(elements[1].as_char(), Some('x'));
+        assert_eq!(elements[2].as_integer(), Some(3));
+    }
+
+    #[test]
+    fn test_vector_errors() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            (
+                "(vector-ref (vector 1 2 3) 3)",
+                "vector-ref: index 3 out of range",
+            ),
+            (
+                "(vector-ref (vector 1 2 3) (sub1 0))",
+                "vector-ref: index -1 out of range",
+            ),
+            (
+                "(vector-set! (make-vector 2) 10 0)",
+                "vector-set!: index 10 out of range",
+            ),
+            (
+                "(vector-ref (cons 1 2) 0)",
+                "vector-ref: expected vector, got (1 . 2)",
+            ),
+            (
+                "(vector-ref (vector 1) #\\a)",
+                "vector-ref: expected integer, got #\\a",
+            ),
+            ("(vector-length 1)", "vector-length: expected vector, got 1"),
+            ("(make-vector (sub1 0))", "make-vector: negative length -1"),
+            ("(make-vector 100000000000)", "make-vector: heap exhausted"),
+        ];
+        for (input, expected) in cases {
+            let message = eval(&mut runtime, input).unwrap_err();
+            assert!(message.starts_with(expected), "{}: {}", input, message);
+        }
+        // Its size in bytes would wrap around to nothing. Not a fixnum under
+        // NaN-boxing.
+        #[cfg(not(feature = "nan-boxing"))]
+        assert_eq!(
+            eval(&mut runtime, "(make-vector 2305843009213693951 0)").unwrap_err(),
+            "make-vector: length 2305843009213693951 too large"
+        );
+        assert_eq!(runtime.verify_heap(), Ok(()));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut runtime = Runtime::new();
+        let symbol = eval(&mut runtime, "'hello").unwrap();
+        assert!(symbol.is_symbol());
+        assert_eq!(symbol.to_string(), "hello");
+
+        let cases = [
+            ("(eq? 'a 'a)", true),
+            ("(eq? 'a 'b)", false),
+            ("(eq? (quote abc) 'abc)", true),
+            ("(eq? 1 1)", true),
+            ("(eq? (cons 1 2) (cons 1 2))", false),
+            ("(symbol? 'a)", true),
+            ("(symbol? 1)", false),
+            ("(symbol? (cons 'a nil))", false),
+            ("(symbol? (car '(a b)))", true),
+            ("(eq? (string->symbol (symbol->string 'xyz)) 'xyz)", true),
+        ];
+        for (input, expected) in cases {
+            let value = eval(&mut runtime, input).unwrap();
+            assert_eq!(value.as_bool(), Some(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_symbol_to_string() {
+        let mut runtime = Runtime::new();
+        let string = eval(&mut runtime, "(symbol->string 'lambda)").unwrap();
+        assert!(string.is_string());
+        assert_eq!(
+            unsafe { (*string.as_string_pointer().unwrap()).as_str() },
+            "lambda"
+        );
+        assert_eq!(string.to_string(), "\"lambda\"");
+
+        // Runtime calls keep working with values pushed on the stack.
+        let list = eval(&mut runtime, "(cons 1 (cons (symbol->string 'ab) nil))").unwrap();
+        let second = unsafe {
+            (*(*list.as_pair_pointer().unwrap())
+                .cdr
+                .as_pair_pointer()
+                .unwrap())
+            .car
+        };
+        assert_eq!(second.to_string(), "\"ab\"");
+
+        assert_eq!(
+            eval(&mut runtime, "(symbol->string 1)"),
+            Err("symbol->string: expected symbol, got 1".to_string())
+        );
+        assert_eq!(
+            eval(&mut runtime, "(string->symbol 'a)"),
+            Err("string->symbol: expected string, got a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_quoted_list() {
+        let mut runtime = Runtime::new();
+        let list = eval(&mut runtime, "'(1 #\\a b)").unwrap();
+        let pair = unsafe { *list.as_pair_pointer().unwrap() };
+        assert_eq!(pair.car.as_integer(), Some(1));
+        let cadr = eval(&mut runtime, "(car (cdr '(1 #\\a b)))").unwrap();
+        assert_eq!(cadr.as_char(), Some('a'));
+    }
+
+    #[test]
+    fn test_symbols_shared_across_compilations() {
+        let mut runtime = Runtime::new();
+        let first = eval(&mut runtime, "'shared").unwrap();
+        let second = eval(&mut runtime, "(string->symbol (symbol->string 'shared))").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, runtime.intern("shared"));
+
+        let cases = [
+            ("(symbol? (gensym))", true),
+            ("(eq? (gensym) (gensym))", false),
+            ("(symbol? (string->symbol (symbol->string (gensym))))", true),
+        ];
+        for (input, expected) in cases {
+            let value = eval(&mut runtime, input).unwrap();
+            assert_eq!(value.as_bool(), Some(expected), "{}", input);
+        }
+        // Four gensyms so far, only one of which had its name interned.
+        assert_eq!(runtime.symbols().len(), 2 + 4);
+        assert_eq!(runtime.symbols_mut().sweep_weak(|_| false), 4);
+    }
+
+    #[test]
+    fn test_define_globals() {
+        let mut runtime = Runtime::new();
+        let name = eval(&mut runtime, "(define x 10)").unwrap();
+        assert_eq!(name.to_string(), "x");
+        assert_eq!(runtime.global("x").unwrap().as_integer(), Some(10));
+        let value = eval(&mut runtime, "(add1 x)").unwrap();
+        assert_eq!(value.as_integer(), Some(11));
+
+        eval(&mut runtime, "(define x (cons x 'y))").unwrap();
+        let value = eval(&mut runtime, "(car x)").unwrap();
+        assert_eq!(value.as_integer(), Some(10));
+
+        assert_eq!(
+            eval(&mut runtime, "(add1 undefined)"),
+            Err("undefined: unbound variable".to_string())
+        );
+        assert!(runtime.global("undefined").is_none());
+    }
+
+    #[test]
+    fn test_define_procedures() {
+        let mut runtime = Runtime::new();
+        eval(&mut runtime, "(define (second a b) b)").unwrap();
+        eval(&mut runtime, "(define (swap p) (cons (cdr p) (car p)))").unwrap();
+        eval(&mut runtime, "(define (three) (add1 (second 1 2)))").unwrap();
+
+        let value = eval(&mut runtime, "(second 1 (second 2 3))").unwrap();
+        assert_eq!(value.as_integer(), Some(3));
+        let value = eval(&mut runtime, "(car (swap (cons 1 2)))").unwrap();
+        assert_eq!(value.as_integer(), Some(2));
+        let value = eval(&mut runtime, "(three)").unwrap();
+        assert_eq!(value.as_integer(), Some(3));
+
+        let procedure = runtime.global("swap").unwrap();
+        assert!(procedure.is_closure());
+        assert_eq!(procedure.to_string(), "#<procedure swap>");
+    }
+
+    #[test]
+    fn test_redefinition_updates_callers() {
+        let mut runtime = Runtime::new();
+        // `caller` refers to `callee` before it exists.
+        eval(&mut runtime, "(define (caller n) (callee n))").unwrap();
+        assert_eq!(
+            eval(&mut runtime, "(caller 1)"),
+            Err("callee: unbound variable".to_string())
+        );
+        eval(&mut runtime, "(define (callee n) (add1 n))").unwrap();
+        assert_eq!(
+            eval(&mut runtime, "(caller 1)").unwrap().as_integer(),
+            Some(2)
+        );
+        eval(&mut runtime, "(define (callee n) (sub1 n))").unwrap();
+        assert_eq!(
+            eval(&mut runtime, "(caller 1)").unwrap().as_integer(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_procedure_stack_alignment() {
+        // Runtime calls need an aligned stack at any depth and with any number
+        // of arguments or temporaries pushed.
+        let mut runtime = Runtime::new();
+        eval(&mut runtime, "(define (name0) (symbol->string 'a))").unwrap();
+        eval(
+            &mut runtime,
+            "(define (name1 x) (cons x (symbol->string 'b)))",
+        )
+        .unwrap();
+        eval(&mut runtime, "(define (name2 x y) (name1 (name0)))").unwrap();
+        let value = eval(&mut runtime, "(cons 1 (name2 1 (name0)))").unwrap();
+        let pair = unsafe { *value.as_pair_pointer().unwrap() };
+        let inner = unsafe { *pair.cdr.as_pair_pointer().unwrap() };
+        assert_eq!(inner.car.to_string(), "\"a\"");
+        assert_eq!(inner.cdr.to_string(), "\"b\"");
+    }
+
+    #[test]
+    fn test_procedure_errors() {
+        let mut runtime = Runtime::new();
+        eval(&mut runtime, "(define (f a b) a)").unwrap();
+        eval(&mut runtime, "(define (g) (car (f 1 2)))").unwrap();
+        let cases = [
+            ("(f 1)", "f: expected 2 argument(s), got 1"),
+            ("(cons 1 (f 1 2 3))", "f: expected 2 argument(s), got 3"),
+            ("(g)", "car: expected pair, got 1"),
+            ("(1 2)", "call: expected procedure, got 1"),
+        ];
+        assert_errors(&mut runtime, &cases);
+        let ast = Parser::new("(define (h) (define y 1))")
+            .read_form()
+            .unwrap();
+        assert!(matches!(
+            Compiler::new(&mut runtime).compile_function(&ast),
+            Err(CompilerError::InvalidArguments(_))
+        ));
+    }
+
+    #[test]
+    fn test_define_record_type() {
+        let mut runtime = Runtime::new();
+        let name = eval(
+            &mut runtime,
+            "(define-record-type <point> (make-point x y) point? \
+             (x point-x set-point-x!) (y point-y) (label point-label set-point-label!))",
+        )
+        .unwrap();
+        assert_eq!(name.to_string(), "<point>");
+        eval(&mut runtime, "(define p (make-point 1 (cons 2 3)))").unwrap();
+        let cases = [
+            ("p", "#<record point x: 1 y: (2 . 3) label: #f>"),
+            ("(point-x p)", "1"),
+            ("(cdr (point-y p))", "3"),
+            ("(point-label p)", "#f"),
+            ("(set-point-x! p 10)", "()"),
+            ("(set-point-label! p \"origin\")", "()"),
+            ("p", "#<record point x: 10 y: (2 . 3) label: \"origin\">"),
+            ("(point? p)", "#t"),
+            ("(point? (vector 1 2))", "#f"),
+            ("(point? 1)", "#f"),
+            ("(record? p)", "#t"),
+            ("<point>", "#(point x y label)"),
+        ];
+        assert_evals(&mut runtime, &cases);
+
+        // A type of the same shape, or the same type defined again, is a
+        // different type.
+        eval(
+            &mut runtime,
+            "(define-record-type pair2 (make-pair2 x y) pair2? (x pair2-x) (y pair2-y))",
+        )
+        .unwrap();
+        let value = eval(&mut runtime, "(pair2? p)").unwrap();
+        assert_eq!(value.as_bool(), Some(false));
+        eval(
+            &mut runtime,
+            "(define-record-type <point> (make-point x y) point? (x point-x) (y point-y))",
+        )
+        .unwrap();
+        let value = eval(&mut runtime, "(point? p)").unwrap();
+        assert_eq!(value.as_bool(), Some(false));
+        let value = eval(&mut runtime, "(point-y (make-point 1 2))").unwrap();
+        assert_eq!(value.as_integer(), Some(2));
+    }
+
+    #[test]
+    fn test_record_errors() {
+        let mut runtime = Runtime::new();
+        eval(
+            &mut runtime,
+            "(define-record-type node (make-node value) node? (value node-value set-node-value!))",
+        )
+        .unwrap();
+        eval(
+            &mut runtime,
+            "(define-record-type other (make-other value) other? (value other-value))",
+        )
+        .unwrap();
+        let cases = [
+            ("(node-value 1)", "node-value: expected node, got 1"),
+            (
+                "(node-value (make-other 1))",
+                "node-value: expected node, got #<record other value: 1>",
+            ),
+            (
+                "(set-node-value! (cons 1 2) 3)",
+                "set-node-value!: expected node, got (1 . 2)",
+            ),
+            ("(make-node)", "make-node: expected 1 argument(s), got 0"),
+        ];
+        assert_errors(&mut runtime, &cases);
+        for input in [
+            "(define-record-type t (make-t))",
+            "(define-record-type t (make-t y) t? (x t-x))",
+            "(define-record-type t (make-t x x) t? (x t-x))",
+            "(define-record-type t (make-t) t? (x t-x) (x t-x2))",
+            "(define-record-type t (make-t) t? (x))",
+            "(define-record-type t make-t t? (x t-x))",
+            "(define (f) (define-record-type t (make-t) t? (x t-x)))",
+        ] {
+            let ast = Parser::new(input).read_form().unwrap();
+            assert!(
+                Compiler::new(&mut runtime).compile_function(&ast).is_err(),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_records_survive_collections() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        runtime.set_heap_verification(true);
+        runtime.set_gc_stress(true);
+        for input in [
+            "(define-record-type node (make-node value next) node? \
+             (value node-value) (next node-next set-node-next!))",
+            "(define (push value list) (make-node value list))",
+            "(define l (push 1 (push (symbol->string 'two) (push 3 nil))))",
+            "(define old (make-node 0 nil))",
+            "(set-node-next! old (make-node (cons 4 5) nil))",
+        ] {
+            eval(&mut runtime, input).unwrap();
+        }
+        assert!(runtime.heap().collections() > 0);
+        assert_eq!(
+            eval(&mut runtime, "(node-value (node-next l))")
+                .unwrap()
+                .write(),
+            "\"two\""
+        );
+        assert_eq!(
+            eval(&mut runtime, "old").unwrap().write(),
+            "#<record node value: 0 next: #<record node value: (4 . 5) next: ()>>"
+        );
+        let value = eval(&mut runtime, "(node? (node-next (node-next l)))").unwrap();
+        assert_eq!(value.as_bool(), Some(true));
+        assert_eq!(runtime.verify_heap(), Ok(()));
+    }
+
+    #[test]
+    fn test_parameters_shadow_primitives() {
+        let mut runtime = Runtime::new();
+        eval(&mut runtime, "(define (apply1 car x) (car x))").unwrap();
+        eval(&mut runtime, "(define (inc n) (add1 n))").unwrap();
+        let value = eval(&mut runtime, "(apply1 inc 41)").unwrap();
+        assert_eq!(value.as_integer(), Some(42));
+    }
+
+    #[test]
+    fn test_checked_primitives() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("(add1 #\\a)", "add1: expected integer, got #\\a"),
+            ("(sub1 'x)", "sub1: expected integer, got x"),
+            (
+                "(integer->char nil)",
+                "integer->char: expected integer, got ()",
+            ),
+            (
+                "(cons 1 (add1 (cons 2 3)))",
+                "add1: expected integer, got (2 . 3)",
+            ),
+        ];
+        for (input, expected) in cases {
+            let message = eval(&mut runtime, input).unwrap_err();
+            assert!(message.starts_with(expected), "{}: {}", input, message);
+        }
+        // Errors unwind through procedure frames too.
+        eval(&mut runtime, "(define (inc n) (add1 n))").unwrap();
+        eval(&mut runtime, "(define (g) (cons 1 (inc true)))").unwrap();
+        assert_eq!(
+            eval(&mut runtime, "(g)"),
+            Err("add1: expected integer, got #t".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unchecked_primitives() {
+        let mut runtime = Runtime::new();
+        // Without the check, add1 just adds to the raw word of the character.
+        let value = eval_with(&mut runtime, "(add1 #\\a)", Safety::Unchecked).unwrap();
+        assert!(!value.is_integer());
+        let value = eval_with(&mut runtime, "(add1 41)", Safety::Unchecked).unwrap();
+        assert_eq!(value.as_integer(), Some(42));
+
+        // Procedures inherit the setting of the compilation defining them.
+        eval_with(&mut runtime, "(define (inc n) (add1 n))", Safety::Unchecked).unwrap();
+        assert!(eval(&mut runtime, "(inc #\\a)").is_ok());
+        eval(&mut runtime, "(define (inc n) (add1 n))").unwrap();
+        assert!(eval(&mut runtime, "(inc #\\a)").is_err());
+
+        // Runtime calls still check what they are passed, raising the usual
+        // error rather than crashing.
+        let cases = [
+            (
+                "(symbol->string 1)",
+                "symbol->string: expected symbol, got 1",
+            ),
+            (
+                "(string->symbol 'a)",
+                "string->symbol: expected string, got a",
+            ),
+            ("(char-upcase 1)", "char-upcase: expected char, got 1"),
+            ("(char-downcase 'a)", "char-downcase: expected char, got a"),
+            (
+                "(char-alphabetic? nil)",
+                "char-alphabetic?: expected char, got ()",
+            ),
+            ("(char-numeric? 1)", "char-numeric?: expected char, got 1"),
+            (
+                "(char-whitespace? 1)",
+                "char-whitespace?: expected char, got 1",
+            ),
+        ];
+        assert_errors_with(&mut runtime, Safety::Unchecked, &cases);
+    }
+
+    #[test]
+    fn test_integer_overflow() {
+        let mut runtime = Runtime::new();
+        unsafe {
+            *runtime.global_cell("max") = LispValue::from_integer(K_INTEGER_MAX);
+            *runtime.global_cell("min") = LispValue::from_integer(K_INTEGER_MIN);
+        }
+        let value = eval(&mut runtime, "(sub1 max)").unwrap();
+        assert_eq!(value.as_integer(), Some(K_INTEGER_MAX - 1));
+        let value = eval(&mut runtime, "(add1 min)").unwrap();
+        assert_eq!(value.as_integer(), Some(K_INTEGER_MIN + 1));
+        // By default, results past the fixnums are bignums...
+        let (max, min) = (K_INTEGER_MAX as i128, K_INTEGER_MIN as i128);
+        let value = eval(&mut runtime, "(add1 max)").unwrap();
+        assert!(value.is_bignum());
+        assert_eq!(value.write(), (max + 1).to_string());
+        let value = eval(&mut runtime, "(sub1 min)").unwrap();
+        assert_eq!(value.write(), (min - 1).to_string());
+        let value = eval(&mut runtime, "(sub1 (add1 max))").unwrap();
+        assert_eq!(value.as_integer(), Some(K_INTEGER_MAX));
+
+        // ... or errors.
+        assert_eq!(
+            eval_configured(&mut runtime, "(add1 max)", Safety::Checked, Overflow::Raise),
+            Err("add1: integer overflow".to_string())
+        );
+        assert_eq!(
+            eval_configured(&mut runtime, "(sub1 min)", Safety::Checked, Overflow::Raise),
+            Err("sub1: integer overflow".to_string())
+        );
+
+        let wrapped =
+            eval_configured(&mut runtime, "(add1 max)", Safety::Checked, Overflow::Wrap).unwrap();
+        assert_eq!(wrapped.as_integer(), Some(K_INTEGER_MIN));
+    }
+
+    #[test]
+    fn test_full_range_integer_literals() {
+        let mut runtime = Runtime::new();
+        let value = eval(&mut runtime, "(add1 1000000000)").unwrap();
+        assert_eq!(value.as_integer(), Some(1000000001));
+        let max = K_INTEGER_MAX.to_string();
+        let value = eval(&mut runtime, &max).unwrap();
+        assert_eq!(value.as_integer(), Some(K_INTEGER_MAX));
+        let value = eval(&mut runtime, "(sub1 0)").unwrap();
+        assert_eq!(value.as_integer(), Some(-1));
+
+        // Beyond the fixnum range, literals are bignums.
+        let past = (K_INTEGER_MAX as i128 + 1).to_string();
+        let value = eval(&mut runtime, &past).unwrap();
+        assert!(value.is_bignum());
+        assert_eq!(value.write(), past);
+        let value = eval(&mut runtime, &format!("(add1 {})", past)).unwrap();
+        assert_eq!(value.write(), (K_INTEGER_MAX as i128 + 2).to_string());
+        let value = eval(&mut runtime, &K_INTEGER_MIN.to_string()).unwrap();
+        assert_eq!(value.as_integer(), Some(K_INTEGER_MIN));
+        let value = eval(&mut runtime, "-123456789012345678901234567890").unwrap();
+        assert_eq!(value.write(), "-123456789012345678901234567890");
+    }
+
+    #[test]
+    fn test_allocation_slow_path() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        // 600 pairs do not fit in one chunk, so some conses take the slow path
+        // with live values in RAX and on the stack.
+        let items: Vec<String> = (0..600).map(|i| i.to_string()).collect();
+        let list = format!("({})", items.join(" "));
+        let value = eval(&mut runtime, &format!("'{}", list)).unwrap();
+        assert_eq!(value.write(), list);
+        assert!(runtime.heap().mapped() > 4096);
+        assert_eq!(runtime.heap_used(), 600 * size_of::<Pair>());
+
+        // Larger than a chunk.
+        let value = eval(&mut runtime, "(make-vector 1000 7)").unwrap();
+        let vector = unsafe { &*value.as_vector_pointer().unwrap() };
+        let elements = unsafe { vector.elements() };
+        assert_eq!(elements.len(), 1000);
+        assert!(elements.iter().all(|e| e.as_integer() == Some(7)));
+
+        // Inside a procedure, at both stack parities.
+        eval(&mut runtime, &format!("(define (f x) (cons x '{}))", list)).unwrap();
+        eval(
+            &mut runtime,
+            &format!("(define (g x y) (cons x '{}))", list),
+        )
+        .unwrap();
+        let value = eval(&mut runtime, "(cons (f 1) (g 2 3))").unwrap();
+        assert_eq!(
+            value.write(),
+            format!("((1 {0}) 2 {0})", &list[1..list.len() - 1])
+        );
+    }
+
+    #[test]
+    fn test_heap_exhausted() {
+        let mut runtime = Runtime::with_heap(4096, 3 * 4096);
+        assert_eq!(
+            eval(&mut runtime, "(make-vector 2000)"),
+            Err("make-vector: heap exhausted".to_string())
+        );
+        let items = vec!["1"; 800].join(" ");
+        assert_eq!(
+            eval(&mut runtime, &format!("'({})", items)),
+            Err("cons: heap exhausted".to_string())
+        );
+        // The runtime is still usable, short of allocating.
+        let value = eval(&mut runtime, "(add1 1)").unwrap();
+        assert_eq!(value.as_integer(), Some(2));
     }
 }
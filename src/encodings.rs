@@ -1,55 +1,365 @@
+use crate::printer::{self, Style};
+use std::fmt;
+
 pub type Word = i64;
-pub type UWord = u64;
 
 // POINTER TAGGING SCHEMA
 // High                                                         Low
 // XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX00  Integer
-// 0000000000000000000000000000000000000000000000000XXXXXXX00001111  Character
+// 0000000000000000000000000000000000XXXXXXXXXXXXXXXXXXXXX00001111  Character
 // 00000000000000000000000000000000000000000000000000000000X0011111  Boolean
 // 0000000000000000000000000000000000000000000000000000000000101111  Nil
+// 0000000000000000000000000000000000000000000000000000000000111111  Unbound (never a user value)
 // XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX001  Pair
-// XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX010  Vector
-// XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX011  String
+// XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX010  Vector (length-prefixed)
+// XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX011  String, or other boxed object
 // XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX101  Symbol
 // XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXX110  Closure
-
-// struct TagsDict {
-//     tags: Vec<(Word, Word)>,
-// }
-// impl TagsDict {
-//     fn new() -> Self {
-//         TagsDict { tags: Vec::new() }
-//     }
-//     fn add_tag(&mut self, tag: Word, mask: Word) {
-//         self.tags.push((tag, mask));
-//     }
-//     pub fn check_for_overlapping(&self) {}
-// }
+//
+// HEAP OBJECTS
+// A pair is two words, car then cdr. Every other object starts with a header
+// word, which no value can be mistaken for, so the heap can be walked:
+// XXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXXKKKKKKKK01001111  Header
+// K is the kind of the object, X its length: the number of elements of a
+// vector, bytes of a string's storage, limbs of a bignum or fields of a
+// record, and zero for other objects. The kind is the pointer tag values refer to the object
+// with, except for the kinds below. Every pointer tag is taken, so further
+// types of object share the string tag and are told apart by the kind in
+// their header: values of those types are checked by reading it.
+//
+// NAN-BOXING
+// With the `nan-boxing` feature, flonums are not boxed. A double is stored as
+// its bits plus K_DOUBLE_OFFSET, and every other value keeps the encoding
+// above but lies in [-2^50, 2^50), which leaves fixnums 49 bits. Offset, the
+// doubles fill the rest of the words exactly, as long as NaNs are
+// canonicalised: the values take the place of the negative quiet NaNs.
+// A word is a double when its top 14 bits are not all equal, which every
+// tag test checks first.
 
 pub const K_CHAR_TAG: Word = 0x0f;
-const K_CHAR_MASK: Word = 0xff;
+/// A character is a Unicode scalar value, which takes 21 bits.
+pub const K_CHAR_MASK: Word = 0x1f_ffff;
 pub const K_CHAR_SHIFT: u32 = 8;
 
 pub const K_BOOL_TAG: Word = 0x1f;
+// The bit holding the value of a boolean. Not a type mask: see `TypeTag::BOOLEAN`.
 pub const K_BOOL_MASK: Word = 0x80;
 pub const K_BOOL_SHIFT: u32 = 7;
 
 const K_NIL_VALUE: Word = 0x2f;
 
-const K_INTEGER_MAX: Word = (1_i64 << (62 - 1)) - 1;
-const K_INTEGER_MIN: Word = -(1_i64 << (62 - 1));
+// Marks a global that has not been defined yet.
+pub const K_UNBOUND_VALUE: Word = 0x3f;
+
+/// Bits of a fixnum: whatever is left of the word once shifted.
+pub const K_INTEGER_BITS: u32 = K_VALUE_BITS - K_INTEGER_SHIFT;
+pub const K_INTEGER_MAX: Word = (1_i64 << (K_INTEGER_BITS - 1)) - 1;
+pub const K_INTEGER_MIN: Word = -(1_i64 << (K_INTEGER_BITS - 1));
 pub const K_INTEGER_SHIFT: u32 = 2;
 pub const K_INTEGER_MASK: Word = 0x03;
 pub const K_INTEGER_TAG: Word = 0x00;
 
 // Pairs
-const K_PAIR_TAG: Word = 0x1;
-const K_HEAP_TAG_MASK: Word = 0x7; // 0b111
+pub const K_PAIR_TAG: Word = 0x1;
+pub const K_HEAP_TAG_MASK: Word = 0x7; // 0b111
 const K_HEAP_PTR_MASK: Word = !K_HEAP_TAG_MASK;
+// Vectors
+pub const K_VECTOR_TAG: Word = 0x2; // 0b010
+// Strings
+pub const K_STRING_TAG: Word = 0x3; // 0b011
 // Symbols
-const K_SYMBOL_TAG: Word = 0x5; // 0b101
+pub const K_SYMBOL_TAG: Word = 0x5; // 0b101
+// Closures (procedures)
+pub const K_CLOSURE_TAG: Word = 0x6; // 0b110
+
+/// Significant bits of a value: words are these bits, sign-extended. Under
+/// NaN-boxing, the other words are doubles.
+#[cfg(not(feature = "nan-boxing"))]
+pub const K_VALUE_BITS: u32 = 64;
+#[cfg(feature = "nan-boxing")]
+pub const K_VALUE_BITS: u32 = 51;
+/// Added to the bits of a double to make a value under NaN-boxing.
+#[cfg(feature = "nan-boxing")]
+pub const K_DOUBLE_OFFSET: Word = 1 << (K_VALUE_BITS - 1);
+/// The only NaN a value holds under NaN-boxing: a positive quiet NaN.
+#[cfg(feature = "nan-boxing")]
+pub const K_CANONICAL_NAN: u64 = 0x7ff8_0000_0000_0000;
+
+/// Whether `word` is an unboxed double, which it never is without NaN-boxing.
+pub const fn is_double_word(word: Word) -> bool {
+    K_VALUE_BITS < 64 && !matches!(word >> (K_VALUE_BITS - 1), 0 | -1)
+}
+
+pub const K_HEADER_TAG: Word = 0x4f;
+pub const K_HEADER_KIND_SHIFT: u32 = 8;
+/// The UTF-8 bytes of a string, referred to with the string tag.
+pub const K_STRING_BYTES_KIND: Word = 0x08;
+/// An integer outside the fixnum range.
+pub const K_BIGNUM_KIND: Word = 0x09;
+/// An inexact real, boxed as a double.
+pub const K_FLONUM_KIND: Word = 0x0a;
+pub const K_HASH_TABLE_KIND: Word = 0x0b;
+/// An instance of a type defined with `define-record-type`.
+pub const K_RECORD_KIND: Word = 0x0c;
+/// Persistent collections; see `persistent.rs`.
+pub const K_PERSISTENT_VECTOR_KIND: Word = 0x0d;
+pub const K_PERSISTENT_MAP_KIND: Word = 0x0e;
+pub const K_HEADER_LENGTH_SHIFT: u32 = 16;
+/// The longest length a header holds.
+pub const K_HEADER_MAX_LENGTH: usize = (1 << (64 - K_HEADER_LENGTH_SHIFT)) - 1;
+
+/// One type of the tagging scheme: a word has the type when `word & mask == tag`
+/// and, for the types sharing the string tag, the header of the object it
+/// points to has the kind `kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeTag {
+    /// Used in error messages: "car: expected pair, got 1".
+    pub name: &'static str,
+    /// The Lisp predicate testing for the type, if it has one.
+    pub predicate: Option<&'static str>,
+    pub mask: Word,
+    pub tag: Word,
+    pub kind: Option<Word>,
+    /// Unboxed doubles, which are told apart by their high bits rather than
+    /// a tag, and which no other type matches.
+    pub double: bool,
+}
+
+impl TypeTag {
+    /// Fixnums; `integer?` also holds for bignums.
+    pub const INTEGER: TypeTag =
+        TypeTag::new("integer", Some("fixnum?"), K_INTEGER_MASK, K_INTEGER_TAG);
+    pub const CHAR: TypeTag =
+        TypeTag::new("char", Some("char?"), (1 << K_CHAR_SHIFT) - 1, K_CHAR_TAG);
+    // Every bit of the low byte but the value.
+    pub const BOOLEAN: TypeTag =
+        TypeTag::new("boolean", Some("boolean?"), 0xff & !K_BOOL_MASK, K_BOOL_TAG);
+    pub const NIL: TypeTag = TypeTag::new("null", Some("null?"), !0, K_NIL_VALUE);
+    pub const UNBOUND: TypeTag = TypeTag::new("unbound", None, !0, K_UNBOUND_VALUE);
+    pub const PAIR: TypeTag = TypeTag::new("pair", Some("pair?"), K_HEAP_TAG_MASK, K_PAIR_TAG);
+    pub const VECTOR: TypeTag =
+        TypeTag::new("vector", Some("vector?"), K_HEAP_TAG_MASK, K_VECTOR_TAG);
+    pub const STRING: TypeTag = TypeTag::boxed("string", Some("string?"), K_STRING_TAG);
+    pub const BIGNUM: TypeTag = TypeTag::boxed("bignum", Some("bignum?"), K_BIGNUM_KIND);
+    #[cfg(not(feature = "nan-boxing"))]
+    pub const FLONUM: TypeTag = TypeTag::boxed("flonum", Some("flonum?"), K_FLONUM_KIND);
+    #[cfg(feature = "nan-boxing")]
+    pub const FLONUM: TypeTag = TypeTag::double("flonum", Some("flonum?"));
+    pub const HASH_TABLE: TypeTag =
+        TypeTag::boxed("hash-table", Some("hash-table?"), K_HASH_TABLE_KIND);
+    pub const RECORD: TypeTag = TypeTag::boxed("record", Some("record?"), K_RECORD_KIND);
+    pub const PERSISTENT_VECTOR: TypeTag = TypeTag::boxed(
+        "persistent-vector",
+        Some("persistent-vector?"),
+        K_PERSISTENT_VECTOR_KIND,
+    );
+    pub const PERSISTENT_MAP: TypeTag = TypeTag::boxed(
+        "persistent-map",
+        Some("persistent-map?"),
+        K_PERSISTENT_MAP_KIND,
+    );
+    pub const SYMBOL: TypeTag =
+        TypeTag::new("symbol", Some("symbol?"), K_HEAP_TAG_MASK, K_SYMBOL_TAG);
+    pub const CLOSURE: TypeTag = TypeTag::new(
+        "procedure",
+        Some("procedure?"),
+        K_HEAP_TAG_MASK,
+        K_CLOSURE_TAG,
+    );
+    /// Header words of heap objects. Not a value, but must not look like one.
+    pub const HEADER: TypeTag = TypeTag::new("header", None, 0xff, K_HEADER_TAG);
+
+    pub const fn new(
+        name: &'static str,
+        predicate: Option<&'static str>,
+        mask: Word,
+        tag: Word,
+    ) -> Self {
+        TypeTag {
+            name,
+            predicate,
+            mask,
+            tag,
+            kind: None,
+            double: false,
+        }
+    }
+
+    /// The unboxed doubles of NaN-boxing.
+    pub const fn double(name: &'static str, predicate: Option<&'static str>) -> Self {
+        TypeTag {
+            double: true,
+            ..TypeTag::new(name, predicate, 0, 0)
+        }
+    }
+
+    /// A type of object referred to with the string tag, with header kind `kind`.
+    pub const fn boxed(name: &'static str, predicate: Option<&'static str>, kind: Word) -> Self {
+        TypeTag {
+            kind: Some(kind),
+            ..TypeTag::new(name, predicate, K_HEAP_TAG_MASK, K_STRING_TAG)
+        }
+    }
+
+    /// Whether `word` has the type. For a boxed type the header `word` points
+    /// to is read, so `word` must be a valid value.
+    pub fn matches(&self, word: Word) -> bool {
+        self.matches_tag(word)
+            && self.kind.is_none_or(|kind| {
+                let header = unsafe { *((word & K_HEAP_PTR_MASK) as *const Word) };
+                Header::from_raw_word(header).kind() == kind
+            })
+    }
+
+    /// Whether `word` carries the tag of the type, which is all there is to
+    /// check for the types that are not boxed.
+    pub fn matches_tag(&self, word: Word) -> bool {
+        if self.double {
+            return is_double_word(word);
+        }
+        !is_double_word(word) && word & self.mask == self.tag
+    }
+
+    /// Whether some word has both types: the tags agree on the bits both
+    /// masks test, and boxed types have the same kind.
+    pub fn overlaps(&self, other: &TypeTag) -> bool {
+        let kinds_differ = matches!((self.kind, other.kind), (Some(a), Some(b)) if a != b);
+        (self.tag ^ other.tag) & self.mask & other.mask == 0
+            && !kinds_differ
+            && self.double == other.double
+    }
+}
+
+/// The registry of the types in the tagging scheme. Every value has at most
+/// one of them, which `check_for_overlapping` verifies.
+pub struct TagsDict {
+    tags: Vec<TypeTag>,
+}
+
+impl Default for TagsDict {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TagsDict {
+    /// The types of the scheme drawn at the top of this file.
+    pub fn new() -> Self {
+        TagsDict {
+            tags: vec![
+                TypeTag::INTEGER,
+                TypeTag::CHAR,
+                TypeTag::BOOLEAN,
+                TypeTag::NIL,
+                TypeTag::UNBOUND,
+                TypeTag::PAIR,
+                TypeTag::VECTOR,
+                TypeTag::STRING,
+                TypeTag::SYMBOL,
+                TypeTag::CLOSURE,
+                TypeTag::BIGNUM,
+                TypeTag::FLONUM,
+                TypeTag::HASH_TABLE,
+                TypeTag::RECORD,
+                TypeTag::PERSISTENT_VECTOR,
+                TypeTag::PERSISTENT_MAP,
+                TypeTag::HEADER,
+            ],
+        }
+    }
+
+    pub fn add_tag(&mut self, tag: TypeTag) {
+        self.tags.push(tag);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &TypeTag> {
+        self.tags.iter()
+    }
+
+    /// The type of `value`, if it has one.
+    pub fn type_of(&self, value: LispValue) -> Option<&TypeTag> {
+        self.tags.iter().find(|tag| tag.matches(value.0))
+    }
+
+    /// The type of the heap objects of kind `kind`.
+    pub fn heap_type(&self, kind: Word) -> Option<&TypeTag> {
+        self.tags
+            .iter()
+            .find(|tag| tag.mask == K_HEAP_TAG_MASK && tag.kind.unwrap_or(tag.tag) == kind)
+    }
+
+    /// Fails with the names of the first two types some word could have at once,
+    /// or whose tag has bits outside their mask and so matches nothing.
+    pub fn check_for_overlapping(&self) -> Result<(), String> {
+        for (index, tag) in self.tags.iter().enumerate() {
+            if tag.tag & !tag.mask != 0 {
+                return Err(format!(
+                    "{}: tag {:#x} outside mask {:#x}",
+                    tag.name, tag.tag, tag.mask
+                ));
+            }
+            if let Some(other) = self.tags[index + 1..]
+                .iter()
+                .find(|other| tag.overlaps(other))
+            {
+                return Err(format!("{} and {} overlap", tag.name, other.name));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The first word of every heap object but pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Header(Word);
+
+impl Header {
+    /// A header for an object with the pointer tag `kind`.
+    pub fn new(kind: Word, length: usize) -> Self {
+        Header(
+            ((length as Word) << K_HEADER_LENGTH_SHIFT)
+                | (kind << K_HEADER_KIND_SHIFT)
+                | K_HEADER_TAG,
+        )
+    }
+
+    pub fn from_raw_word(word: Word) -> Self {
+        Header(word)
+    }
+
+    pub fn as_raw_word(&self) -> Word {
+        self.0
+    }
+
+    pub fn is_header(word: Word) -> bool {
+        TypeTag::HEADER.matches(word)
+    }
+
+    /// The kind of the object; see the top of this file.
+    pub fn kind(&self) -> Word {
+        (self.0 >> K_HEADER_KIND_SHIFT) & 0xff
+    }
+
+    /// The pointer tag values referring to the object carry.
+    pub fn pointer_tag(&self) -> Word {
+        match self.kind() {
+            K_STRING_BYTES_KIND
+            | K_BIGNUM_KIND
+            | K_FLONUM_KIND
+            | K_HASH_TABLE_KIND
+            | K_RECORD_KIND
+            | K_PERSISTENT_VECTOR_KIND
+            | K_PERSISTENT_MAP_KIND => K_STRING_TAG,
+            kind => kind,
+        }
+    }
+
+    pub fn length(&self) -> usize {
+        (self.0 as u64 >> K_HEADER_LENGTH_SHIFT) as usize
+    }
+}
 
-/// TODO: Alloc this in our custom heap, using a bump allocator
 /// This is the memory layout for a 'cons' cell on the heap.
 #[derive(Debug, Clone, Copy)]
 // We align it to 8 bytes, which is standard for 64-bit.
@@ -60,13 +370,243 @@ pub struct Pair {
     pub cdr: LispValue,
 }
 
-// Should I own it?
-#[derive(Debug, Clone)]
+/// A vector on the heap: a header holding the length, immediately followed
+/// by `length` elements.
+#[derive(Debug)]
+#[repr(C, align(8))]
+pub struct Vector {
+    pub header: Header,
+    pub elements: [LispValue; 0],
+}
+
+impl Vector {
+    pub fn length(&self) -> usize {
+        self.header.length()
+    }
+
+    /// # Safety
+    /// `self` must be a vector header followed by its elements, as laid out by
+    /// the compiled `make-vector` and `vector` primitives.
+    pub unsafe fn elements(&self) -> &[LispValue] {
+        unsafe { std::slice::from_raw_parts(self.elements.as_ptr(), self.length()) }
+    }
+
+    /// # Safety
+    /// As for `elements`.
+    pub unsafe fn elements_mut(&mut self) -> &mut [LispValue] {
+        unsafe { std::slice::from_raw_parts_mut(self.elements.as_mut_ptr(), self.length()) }
+    }
+}
+
+/// A string on the heap. Its characters are stored in UTF-8 in a separate
+/// `StringBytes`, which `string-set!` replaces when a character it stores has
+/// a different width than the one it overwrites.
+#[derive(Debug)]
+#[repr(C, align(8))]
+pub struct LispString {
+    pub header: Header,
+    /// Number of characters, as an encoded integer.
+    pub length: LispValue,
+    /// The `StringBytes` holding the characters, tagged as a string.
+    pub bytes: LispValue,
+}
+
+impl LispString {
+    /// The storage of the string.
+    pub fn storage(&self) -> *mut StringBytes {
+        self.bytes.heap_address().expect("string without storage") as *mut StringBytes
+    }
+
+    /// Number of characters.
+    pub fn char_count(&self) -> usize {
+        self.length.as_integer().unwrap() as usize
+    }
+
+    /// # Safety
+    /// `self` must be a string on the heap, with its storage.
+    pub unsafe fn as_str(&self) -> &str {
+        unsafe { (*self.storage()).as_str() }
+    }
+}
+
+/// The characters of a `LispString`: a header holding the length in bytes,
+/// followed by the UTF-8 bytes padded to a whole number of words.
+#[derive(Debug)]
+#[repr(C, align(8))]
+pub struct StringBytes {
+    pub header: Header,
+    pub bytes: [u8; 0],
+}
+
+impl StringBytes {
+    /// Bytes needed to store `length` bytes, header included.
+    pub fn allocation_size(length: usize) -> usize {
+        size_of::<StringBytes>() + length.next_multiple_of(size_of::<Word>())
+    }
+
+    /// # Safety
+    /// `self` must be a header followed by its bytes, which are valid UTF-8.
+    pub unsafe fn as_str(&self) -> &str {
+        unsafe { std::str::from_utf8_unchecked(self.as_bytes()) }
+    }
+
+    /// # Safety
+    /// `self` must be a header followed by its bytes.
+    pub unsafe fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.bytes.as_ptr(), self.header.length()) }
+    }
+
+    /// # Safety
+    /// As for `as_bytes`; the bytes must be left valid UTF-8.
+    pub unsafe fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let length = self.header.length();
+        unsafe { std::slice::from_raw_parts_mut(self.bytes.as_mut_ptr(), length) }
+    }
+}
+
+/// An integer outside the fixnum range, in sign and magnitude: a header
+/// holding the number of limbs, followed by the limbs, least significant
+/// first. The most significant limb is not zero, and the value never fits a
+/// fixnum, so every integer has a single representation.
+#[derive(Debug)]
+#[repr(C, align(8))]
+pub struct Bignum {
+    pub header: Header,
+    /// 1 if the integer is negative, 0 otherwise. Not a `LispValue`.
+    pub negative: Word,
+    pub limbs: [u64; 0],
+}
+
+impl Bignum {
+    /// Bytes needed for a bignum of `limbs` limbs, header included.
+    pub fn allocation_size(limbs: usize) -> usize {
+        size_of::<Bignum>() + limbs * size_of::<u64>()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative != 0
+    }
+
+    /// # Safety
+    /// `self` must be a header followed by its limbs.
+    pub unsafe fn limbs(&self) -> &[u64] {
+        unsafe { std::slice::from_raw_parts(self.limbs.as_ptr(), self.header.length()) }
+    }
+}
+
+/// An inexact real: a header, followed by the double.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(8))]
+pub struct Flonum {
+    pub header: Header,
+    /// Not a `LispValue`.
+    pub value: f64,
+}
+
+/// A hash table; see `hashtables.rs`. Every field is a value.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(8))]
+pub struct HashTable {
+    pub header: Header,
+    /// How keys are compared, as an encoded `Equivalence`.
+    pub equivalence: LispValue,
+    /// Number of entries, as an encoded integer.
+    pub count: LispValue,
+    /// The number of collections when the keys were hashed, as an encoded
+    /// integer, if some key hashed by address; false otherwise.
+    pub epoch: LispValue,
+    /// A vector of buckets, each a list of `(key . value)` entries.
+    pub buckets: LispValue,
+}
+
+/// An instance of a record type: a header holding the number of fields,
+/// the type descriptor, then the fields in the order the type declares them.
+/// The descriptor is a vector of the type name followed by the field names,
+/// all symbols; instances of the same type share it.
+#[derive(Debug)]
+#[repr(C, align(8))]
+pub struct Record {
+    pub header: Header,
+    pub descriptor: LispValue,
+    pub fields: [LispValue; 0],
+}
+
+impl Record {
+    /// Bytes needed for a record of `fields` fields, header included.
+    pub fn allocation_size(fields: usize) -> usize {
+        size_of::<Record>() + fields * size_of::<LispValue>()
+    }
+
+    /// # Safety
+    /// `self` must be a record header followed by its fields.
+    pub unsafe fn fields(&self) -> &[LispValue] {
+        unsafe { std::slice::from_raw_parts(self.fields.as_ptr(), self.header.length()) }
+    }
+
+    /// # Safety
+    /// As for `fields`.
+    pub unsafe fn fields_mut(&mut self) -> &mut [LispValue] {
+        let length = self.header.length();
+        unsafe { std::slice::from_raw_parts_mut(self.fields.as_mut_ptr(), length) }
+    }
+}
+
+/// A persistent vector; see `persistent.rs`. Every field is a value.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(8))]
+pub struct PersistentVector {
+    pub header: Header,
+    /// Number of elements, as an encoded integer.
+    pub count: LispValue,
+    /// How far an index is shifted right to pick a child of the root, as an
+    /// encoded integer: zero when the root holds the elements themselves.
+    pub shift: LispValue,
+    /// The root node of the trie.
+    pub root: LispValue,
+}
+
+/// A persistent map; see `persistent.rs`. Every field is a value.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(8))]
+pub struct PersistentMap {
+    pub header: Header,
+    /// Number of entries, as an encoded integer.
+    pub count: LispValue,
+    /// The root node of the trie, or nil if the map is empty.
+    pub root: LispValue,
+}
+
+/// A procedure on the heap.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(8))]
+pub struct Closure {
+    pub header: Header,
+    /// Address of the compiled code. Not a `LispValue`.
+    pub code: usize,
+    /// Number of parameters, as an encoded integer.
+    pub arity: LispValue,
+    /// The symbol the procedure was defined as, used when printing.
+    pub name: LispValue,
+}
+
+/// A symbol on the heap. Interned symbols are unique per name, so they can
+/// be compared by pointer.
+#[derive(Debug, Clone, Copy)]
 // We align it to 8 bytes, which is standard for 64-bit.
 // This guarantees the pointer to it will end in 0b000.
 #[repr(C, align(8))]
 pub struct Symbol {
-    pub name: String,
+    pub header: Header,
+    /// The name, a string on the heap.
+    pub name: LispValue,
+}
+
+impl Symbol {
+    /// # Safety
+    /// `self.name` must point to a valid string.
+    pub unsafe fn as_str(&self) -> &str {
+        unsafe { (*self.name.as_string_pointer().unwrap()).as_str() }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -84,7 +624,210 @@ impl LispValue {
         LispValue(addr | K_PAIR_TAG)
     }
     pub fn is_pair(&self) -> bool {
-        (self.0 & K_HEAP_TAG_MASK) == K_PAIR_TAG
+        TypeTag::PAIR.matches(self.0)
+    }
+    pub fn from_vector_pointer(ptr: *mut Vector) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_VECTOR_TAG)
+    }
+    pub fn is_vector(&self) -> bool {
+        TypeTag::VECTOR.matches(self.0)
+    }
+    pub fn as_vector_pointer(&self) -> Option<*mut Vector> {
+        if self.is_vector() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut Vector)
+        } else {
+            None
+        }
+    }
+    pub fn from_string_pointer(ptr: *mut LispString) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_STRING_TAG)
+    }
+    /// Refers to the storage of a string, from its `bytes` field.
+    pub fn from_string_bytes_pointer(ptr: *mut StringBytes) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_STRING_TAG)
+    }
+    /// Checks the header for boxed values, so the value must be valid.
+    pub fn is_string(&self) -> bool {
+        TypeTag::STRING.matches(self.0)
+    }
+    pub fn as_string_pointer(&self) -> Option<*mut LispString> {
+        if self.is_string() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut LispString)
+        } else {
+            None
+        }
+    }
+    pub fn from_bignum_pointer(ptr: *mut Bignum) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_STRING_TAG)
+    }
+    pub fn is_bignum(&self) -> bool {
+        TypeTag::BIGNUM.matches(self.0)
+    }
+    pub fn as_bignum_pointer(&self) -> Option<*mut Bignum> {
+        if self.is_bignum() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut Bignum)
+        } else {
+            None
+        }
+    }
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn from_flonum_pointer(ptr: *mut Flonum) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_STRING_TAG)
+    }
+    pub fn is_flonum(&self) -> bool {
+        TypeTag::FLONUM.matches(self.0)
+    }
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn as_flonum_pointer(&self) -> Option<*mut Flonum> {
+        if self.is_flonum() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut Flonum)
+        } else {
+            None
+        }
+    }
+    /// The double of a flonum.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn as_flonum(&self) -> Option<f64> {
+        self.as_flonum_pointer().map(|ptr| unsafe { (*ptr).value })
+    }
+    #[cfg(feature = "nan-boxing")]
+    pub fn as_flonum(&self) -> Option<f64> {
+        self.is_flonum()
+            .then(|| f64::from_bits(self.0.wrapping_sub(K_DOUBLE_OFFSET) as u64))
+    }
+    /// An unboxed flonum, with NaNs canonicalised.
+    #[cfg(feature = "nan-boxing")]
+    pub fn from_f64(value: f64) -> Self {
+        let bits = if value.is_nan() {
+            K_CANONICAL_NAN
+        } else {
+            value.to_bits()
+        };
+        LispValue((bits as Word).wrapping_add(K_DOUBLE_OFFSET))
+    }
+    pub fn from_hash_table_pointer(ptr: *mut HashTable) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_STRING_TAG)
+    }
+    pub fn is_hash_table(&self) -> bool {
+        TypeTag::HASH_TABLE.matches(self.0)
+    }
+    pub fn as_hash_table_pointer(&self) -> Option<*mut HashTable> {
+        if self.is_hash_table() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut HashTable)
+        } else {
+            None
+        }
+    }
+    pub fn from_record_pointer(ptr: *mut Record) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_STRING_TAG)
+    }
+    pub fn is_record(&self) -> bool {
+        TypeTag::RECORD.matches(self.0)
+    }
+    pub fn as_record_pointer(&self) -> Option<*mut Record> {
+        if self.is_record() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut Record)
+        } else {
+            None
+        }
+    }
+    pub fn from_persistent_vector_pointer(ptr: *mut PersistentVector) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_STRING_TAG)
+    }
+    pub fn is_persistent_vector(&self) -> bool {
+        TypeTag::PERSISTENT_VECTOR.matches(self.0)
+    }
+    pub fn as_persistent_vector_pointer(&self) -> Option<*mut PersistentVector> {
+        if self.is_persistent_vector() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut PersistentVector)
+        } else {
+            None
+        }
+    }
+    pub fn from_persistent_map_pointer(ptr: *mut PersistentMap) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_STRING_TAG)
+    }
+    pub fn is_persistent_map(&self) -> bool {
+        TypeTag::PERSISTENT_MAP.matches(self.0)
+    }
+    pub fn as_persistent_map_pointer(&self) -> Option<*mut PersistentMap> {
+        if self.is_persistent_map() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut PersistentMap)
+        } else {
+            None
+        }
+    }
+    pub fn from_closure_pointer(ptr: *mut Closure) -> Self {
+        let addr = ptr as Word;
+        assert!(
+            (addr & K_HEAP_TAG_MASK) == 0,
+            "Pointer is not 8-byte aligned!"
+        );
+        LispValue(addr | K_CLOSURE_TAG)
+    }
+    pub fn is_closure(&self) -> bool {
+        TypeTag::CLOSURE.matches(self.0)
+    }
+    pub fn as_closure_pointer(&self) -> Option<*mut Closure> {
+        if self.is_closure() {
+            let addr = self.0 & K_HEAP_PTR_MASK;
+            Some(addr as *mut Closure)
+        } else {
+            None
+        }
     }
     pub fn from_symbol_pointer(ptr: *mut Symbol) -> Self {
         let addr = ptr as Word;
@@ -97,7 +840,7 @@ impl LispValue {
 
     /// Checks if this LispValue is a tagged pointer to a Symbol.
     pub fn is_symbol(&self) -> bool {
-        (self.0 & K_HEAP_TAG_MASK) == K_SYMBOL_TAG
+        TypeTag::SYMBOL.matches(self.0)
     }
 
     /// If this value is a Symbol, returns the raw, untagged pointer to it.
@@ -113,10 +856,44 @@ impl LispValue {
         LispValue(word)
     }
 
+    /// Whether the value points to an object on the heap. Only the tag is
+    /// looked at, so this holds for any boxed object.
+    pub fn is_heap_object(&self) -> bool {
+        [
+            TypeTag::PAIR,
+            TypeTag::VECTOR,
+            TypeTag::STRING,
+            TypeTag::SYMBOL,
+            TypeTag::CLOSURE,
+        ]
+        .iter()
+        .any(|tag| tag.matches_tag(self.0))
+    }
+
+    /// The untagged address of the heap object the value points to.
+    pub fn heap_address(&self) -> Option<usize> {
+        if self.is_heap_object() {
+            Some((self.0 & K_HEAP_PTR_MASK) as usize)
+        } else {
+            None
+        }
+    }
+
+    /// The pointer tag of the value, as used for the kind of heap objects.
+    pub fn heap_tag(&self) -> Word {
+        self.0 & K_HEAP_TAG_MASK
+    }
+
+    /// Points to the object at `address` with the same pointer tag, for a
+    /// value whose object has moved.
+    pub fn with_heap_address(&self, address: usize) -> Self {
+        LispValue(address as Word | self.heap_tag())
+    }
+
     /// Creates a new LispValue from a native integer.
     pub fn from_integer(value: Word) -> Self {
         assert!(
-            value >= K_INTEGER_MIN && value <= K_INTEGER_MAX,
+            (K_INTEGER_MIN..=K_INTEGER_MAX).contains(&value),
             "Integer out of range"
         );
         // The tag (0b00) is implicit in the shift.
@@ -135,6 +912,14 @@ impl LispValue {
         LispValue(K_NIL_VALUE)
     }
 
+    pub fn unbound() -> Self {
+        LispValue(K_UNBOUND_VALUE)
+    }
+
+    pub fn is_unbound(&self) -> bool {
+        TypeTag::UNBOUND.matches(self.0)
+    }
+
     pub fn true_val() -> Self {
         Self::from_bool(true)
     }
@@ -144,19 +929,19 @@ impl LispValue {
     }
 
     pub fn is_integer(&self) -> bool {
-        (self.0 & K_INTEGER_MASK) == K_INTEGER_TAG
+        TypeTag::INTEGER.matches(self.0)
     }
 
     pub fn is_char(&self) -> bool {
-        (self.0 & K_CHAR_TAG) == K_CHAR_TAG
+        TypeTag::CHAR.matches(self.0)
     }
 
     pub fn is_bool(&self) -> bool {
-        (self.0 & K_BOOL_TAG) == K_BOOL_TAG
+        TypeTag::BOOLEAN.matches(self.0)
     }
 
     pub fn is_nil(&self) -> bool {
-        self.0 == K_NIL_VALUE
+        TypeTag::NIL.matches(self.0)
     }
 
     pub fn as_integer(&self) -> Option<Word> {
@@ -196,22 +981,197 @@ impl LispValue {
         }
     }
 
-    // Debug and print the rust value by checking all tags
+    /// The printed representation under `write`: strings and characters
+    /// print as literals, and cycles get datum labels.
+    pub fn write(&self) -> String {
+        printer::print(*self, Style::Write)
+    }
+
+    /// The printed representation under `display`: like `write`, but strings
+    /// and characters print as their text.
+    pub fn display(&self) -> String {
+        printer::print(*self, Style::Display)
+    }
+
+    /// Prints the value to stdout, as `write` does.
     pub fn print(&self) {
-        if self.is_bool() {
-            println!("Bool: {}", self.as_bool().unwrap());
-        } else if self.is_integer() {
-            println!("Integer: {}", self.as_integer().unwrap());
-        } else if self.is_char() {
-            println!("Char: {}", self.as_char().unwrap());
-        } else if self.is_nil() {
-            println!("Nil");
-        } else if self.is_symbol() {
-            println!("Symbol: TODO!");
-        } else if self.is_pair() {
-            println!("Pair: {:?}", self.as_pair_pointer().unwrap());
-        } else {
-            println!("Unknown");
+        println!("{}", self.write());
+    }
+}
+
+impl fmt::Display for LispValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.write())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags_do_not_overlap() {
+        assert_eq!(TagsDict::new().check_for_overlapping(), Ok(()));
+
+        let mut tags = TagsDict::new();
+        // The old `is_char` test, which booleans and nil also pass.
+        tags.add_tag(TypeTag::new("loose char", None, K_CHAR_TAG, K_CHAR_TAG));
+        assert_eq!(
+            tags.check_for_overlapping(),
+            Err("char and loose char overlap".to_string())
+        );
+    }
+
+    #[test]
+    fn test_chars_round_trip() {
+        for c in ['\0', 'a', '\u{ff}', 'λ', '😀', char::MAX] {
+            assert_eq!(LispValue::from_char(c).as_char(), Some(c));
+        }
+    }
+
+    #[test]
+    fn test_every_value_has_one_type() {
+        let mut pair = Pair {
+            car: LispValue::nil(),
+            cdr: LispValue::nil(),
+        };
+        let mut vector = Vector {
+            header: Header::new(K_VECTOR_TAG, 0),
+            elements: [],
+        };
+        let mut string = LispString {
+            header: Header::new(K_STRING_TAG, 0),
+            length: LispValue::from_integer(0),
+            bytes: LispValue::nil(),
+        };
+        let mut symbol = Symbol {
+            header: Header::new(K_SYMBOL_TAG, 0),
+            name: LispValue::nil(),
+        };
+        let mut closure = Closure {
+            header: Header::new(K_CLOSURE_TAG, 0),
+            code: 0,
+            arity: LispValue::from_integer(0),
+            name: LispValue::nil(),
+        };
+        let mut bignum = Bignum {
+            header: Header::new(K_BIGNUM_KIND, 0),
+            negative: 0,
+            limbs: [],
+        };
+        let mut table = HashTable {
+            header: Header::new(K_HASH_TABLE_KIND, 0),
+            equivalence: LispValue::from_integer(0),
+            count: LispValue::from_integer(0),
+            epoch: LispValue::false_val(),
+            buckets: LispValue::nil(),
+        };
+        let mut record = Record {
+            header: Header::new(K_RECORD_KIND, 0),
+            descriptor: LispValue::nil(),
+            fields: [],
+        };
+        let mut persistent_vector = PersistentVector {
+            header: Header::new(K_PERSISTENT_VECTOR_KIND, 0),
+            count: LispValue::from_integer(0),
+            shift: LispValue::from_integer(0),
+            root: LispValue::nil(),
+        };
+        let mut persistent_map = PersistentMap {
+            header: Header::new(K_PERSISTENT_MAP_KIND, 0),
+            count: LispValue::from_integer(0),
+            root: LispValue::nil(),
+        };
+        #[cfg(not(feature = "nan-boxing"))]
+        let mut flonum = Flonum {
+            header: Header::new(K_FLONUM_KIND, 0),
+            value: 0.5,
+        };
+        let mut samples = vec![
+            (LispValue::from_integer(0), "integer"),
+            (LispValue::from_integer(-1), "integer"),
+            (LispValue::from_integer(K_INTEGER_MAX), "integer"),
+            (LispValue::from_integer(K_INTEGER_MIN), "integer"),
+            (LispValue::from_char('\0'), "char"),
+            (LispValue::from_char('a'), "char"),
+            (LispValue::from_char('\u{ff}'), "char"),
+            (LispValue::from_char('λ'), "char"),
+            (LispValue::from_char(char::MAX), "char"),
+            (LispValue::true_val(), "boolean"),
+            (LispValue::false_val(), "boolean"),
+            (LispValue::nil(), "null"),
+            (LispValue::unbound(), "unbound"),
+            (LispValue::from_pair_pointer(&mut pair), "pair"),
+            (LispValue::from_vector_pointer(&mut vector), "vector"),
+            (LispValue::from_string_pointer(&mut string), "string"),
+            (LispValue::from_symbol_pointer(&mut symbol), "symbol"),
+            (LispValue::from_closure_pointer(&mut closure), "procedure"),
+            (LispValue::from_bignum_pointer(&mut bignum), "bignum"),
+            (LispValue::from_hash_table_pointer(&mut table), "hash-table"),
+            (LispValue::from_record_pointer(&mut record), "record"),
+            (
+                LispValue::from_persistent_vector_pointer(&mut persistent_vector),
+                "persistent-vector",
+            ),
+            (
+                LispValue::from_persistent_map_pointer(&mut persistent_map),
+                "persistent-map",
+            ),
+        ];
+        #[cfg(not(feature = "nan-boxing"))]
+        samples.push((LispValue::from_flonum_pointer(&mut flonum), "flonum"));
+        #[cfg(feature = "nan-boxing")]
+        for value in [
+            0.5,
+            -0.0,
+            f64::MAX,
+            -f64::MAX,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NAN,
+            -f64::NAN,
+        ] {
+            let flonum = LispValue::from_f64(value);
+            let bits = if value.is_nan() {
+                K_CANONICAL_NAN
+            } else {
+                value.to_bits()
+            };
+            assert_eq!(flonum.as_flonum().unwrap().to_bits(), bits);
+            samples.push((flonum, "flonum"));
+        }
+        let tags = TagsDict::new();
+        for (value, expected) in samples {
+            let matching: Vec<_> = tags
+                .iter()
+                .filter(|tag| tag.matches(value.as_raw_word()))
+                .map(|tag| tag.name)
+                .collect();
+            assert_eq!(matching, vec![expected], "{:#x}", value.as_raw_word());
+            assert!(!Header::is_header(value.as_raw_word()));
+
+            let predicates = [
+                (value.is_integer(), "integer"),
+                (value.is_char(), "char"),
+                (value.is_bool(), "boolean"),
+                (value.is_nil(), "null"),
+                (value.is_unbound(), "unbound"),
+                (value.is_pair(), "pair"),
+                (value.is_vector(), "vector"),
+                (value.is_string(), "string"),
+                (value.is_symbol(), "symbol"),
+                (value.is_closure(), "procedure"),
+                (value.is_bignum(), "bignum"),
+                (value.is_flonum(), "flonum"),
+                (value.is_hash_table(), "hash-table"),
+                (value.is_record(), "record"),
+                (value.is_persistent_vector(), "persistent-vector"),
+                (value.is_persistent_map(), "persistent-map"),
+            ];
+            for (result, name) in predicates {
+                assert_eq!(result, name == expected, "is_{} on {}", name, expected);
+            }
         }
     }
 }
@@ -0,0 +1,194 @@
+// The equivalence predicates of Scheme, as the runtime compares values.
+//
+// `eq?` is identity: the same word, which compiled code tests inline. `eqv?`
+// also holds for numbers of the same exactness and value, which may be boxed
+// separately, and `equal?` compares pairs, vectors and strings by their
+// contents. Compiled code calls into the runtime for those two once the
+// words differ.
+//
+// `equal?` terminates on cyclic structure: a pair of containers met again
+// while comparing is taken to be equal, so each pair is compared once. If
+// nothing else differs, the two structures unfold into the same tree.
+
+use crate::encodings::{LispValue, Word};
+use crate::runtime::Runtime;
+use std::collections::HashSet;
+
+/// Whether `a` and `b` are `eqv?`.
+pub fn eqv(a: LispValue, b: LispValue) -> bool {
+    if a == b {
+        return true;
+    }
+    if let (Some(a), Some(b)) = (a.as_flonum(), b.as_flonum()) {
+        return a.to_bits() == b.to_bits();
+    }
+    if let (Some(a), Some(b)) = (a.as_bignum_pointer(), b.as_bignum_pointer()) {
+        return unsafe { (*a).is_negative() == (*b).is_negative() && (*a).limbs() == (*b).limbs() };
+    }
+    false
+}
+
+/// Whether `a` and `b` are `equal?`. Walks the structure with a stack of its
+/// own, so deep lists do not exhaust the Rust stack.
+pub fn equal(a: LispValue, b: LispValue) -> bool {
+    // The pairs of containers compared so far.
+    let mut compared = HashSet::new();
+    let mut pending = vec![(a, b)];
+    while let Some((a, b)) = pending.pop() {
+        if eqv(a, b) {
+            continue;
+        }
+        let is_container = |value: LispValue| {
+            value.is_pair()
+                || value.is_vector()
+                || value.is_persistent_vector()
+                || value.is_persistent_map()
+        };
+        if is_container(a) && !compared.insert((a.as_raw_word(), b.as_raw_word())) {
+            continue;
+        }
+        if let (Some(a), Some(b)) = (a.as_pair_pointer(), b.as_pair_pointer()) {
+            let (a, b) = unsafe { (*a, *b) };
+            pending.push((a.cdr, b.cdr));
+            pending.push((a.car, b.car));
+        } else if let (Some(a), Some(b)) = (a.as_vector_pointer(), b.as_vector_pointer()) {
+            let (a, b) = unsafe { ((*a).elements(), (*b).elements()) };
+            if a.len() != b.len() {
+                return false;
+            }
+            pending.extend(a.iter().copied().zip(b.iter().copied()).rev());
+        } else if let (Some(a), Some(b)) = (a.elements(), b.elements()) {
+            if a.len() != b.len() {
+                return false;
+            }
+            pending.extend(a.into_iter().zip(b).rev());
+        } else if let (Some(entries), true) = (a.entries(), b.is_persistent_map()) {
+            if a.count() != b.count() {
+                return false;
+            }
+            for (key, value) in entries {
+                match b.get(key) {
+                    Some(other) => pending.push((value, other)),
+                    None => return false,
+                }
+            }
+        } else if let (Some(a), Some(b)) = (a.as_string_pointer(), b.as_string_pointer()) {
+            if unsafe { (*a).as_str() != (*b).as_str() } {
+                return false;
+            }
+        } else {
+            return false;
+        }
+    }
+    true
+}
+
+/// `(eqv? a b)`, for words that differ.
+pub(crate) extern "C" fn rt_eqv(_rt: *mut Runtime, a: Word, b: Word) -> Word {
+    let (a, b) = (LispValue::from_raw_word(a), LispValue::from_raw_word(b));
+    LispValue::from_bool(eqv(a, b)).as_raw_word()
+}
+
+/// `(equal? a b)`, for words that differ.
+pub(crate) extern "C" fn rt_equal(_rt: *mut Runtime, a: Word, b: Word) -> Word {
+    let (a, b) = (LispValue::from_raw_word(a), LispValue::from_raw_word(b));
+    LispValue::from_bool(equal(a, b)).as_raw_word()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::Runtime;
+    use crate::testing::{assert_evals, eval_str};
+
+    #[test]
+    fn test_equivalence_predicates() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("(eq? 'a 'a)", "#t"),
+            ("(eq? (cons 1 2) (cons 1 2))", "#f"),
+            ("(eq? (expt 2 100) (expt 2 100))", "#f"),
+            ("(eqv? (expt 2 100) (expt 2 100))", "#t"),
+            ("(eqv? (expt 2 100) (- (expt 2 100)))", "#f"),
+            ("(eqv? 1.5 (/ 3 2))", "#t"),
+            ("(eqv? 0.0 (- 0.0))", "#f"),
+            ("(eqv? (/ 0.0 0.0) (/ 0.0 0.0))", "#t"),
+            ("(eqv? 2 2.0)", "#f"),
+            ("(eqv? #\\λ #\\λ)", "#t"),
+            ("(eqv? \"a\" \"a\")", "#f"),
+            ("(eqv? nil nil)", "#t"),
+            ("(equal? \"aλ\" (string-append \"a\" \"λ\"))", "#t"),
+            ("(equal? \"a\" \"b\")", "#f"),
+            (
+                "(equal? '(1 (2 x) 3) (cons 1 (cons '(2 x) (cons 3 nil))))",
+                "#t",
+            ),
+            ("(equal? (cons 1 2) (cons 1 3))", "#f"),
+            ("(equal? '(1 2) '(1 2 3))", "#f"),
+            (
+                "(equal? (vector 1 \"s\" (expt 2 70)) (vector 1 \"s\" (expt 2 70)))",
+                "#t",
+            ),
+            ("(equal? (vector 1 2) (vector 1 2 3))", "#f"),
+            ("(equal? (vector) nil)", "#f"),
+            ("(equal? 1 1.0)", "#f"),
+            ("(equal? (make-hash-table) (make-hash-table))", "#f"),
+        ];
+        assert_evals(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_equal_terminates_on_cycles() {
+        let mut runtime = Runtime::new();
+        for input in [
+            // 1 2 1 2 ..., with periods of two and four.
+            "(define a (cons 1 (cons 2 nil)))",
+            "(set-cdr! (cdr a) a)",
+            "(define b (cons 1 (cons 2 (cons 1 (cons 2 nil)))))",
+            "(set-cdr! (cdr (cdr (cdr b))) b)",
+            // 1 3 1 3 ...
+            "(define c (cons 1 (cons 3 nil)))",
+            "(set-cdr! (cdr c) c)",
+            "(define v (vector 1 0))",
+            "(vector-set! v 1 v)",
+            "(define w (vector 1 (vector 1 0)))",
+            "(vector-set! (vector-ref w 1) 1 w)",
+        ] {
+            eval_str(&mut runtime, input).unwrap();
+        }
+        let cases = [
+            ("(equal? a a)", "#t"),
+            ("(equal? a b)", "#t"),
+            ("(equal? b a)", "#t"),
+            ("(equal? a c)", "#f"),
+            ("(equal? (cons a c) (cons b a))", "#f"),
+            ("(equal? v w)", "#t"),
+            ("(equal? v (vector 1 v))", "#t"),
+            ("(equal? v (vector 1 w 2))", "#f"),
+            ("(eqv? a b)", "#f"),
+            // Hashing a cyclic key stops too.
+            ("(define h (make-hash-table))", "h"),
+            ("(hash-table-set! h a 'cycle)", "()"),
+            ("(hash-table-ref h b)", "cycle"),
+            ("(hash-table-ref h c 'none)", "none"),
+        ];
+        assert_evals(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_equal_long_lists() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define a nil)").unwrap();
+        eval_str(&mut runtime, "(define b nil)").unwrap();
+        for _ in 0..100_000 / 100 {
+            let push = "(cons 1 (cons 2 (cons 3 (cons 4 (cons 5 (cons 6 (cons 7 (cons 8 (cons 9 (cons 10 x))))))))))";
+            for list in ["a", "b"] {
+                let mut input = list.to_string();
+                for _ in 0..10 {
+                    input = push.replace('x', &input);
+                }
+                eval_str(&mut runtime, &format!("(define {} {})", list, input)).unwrap();
+            }
+        }
+        assert_eq!(eval_str(&mut runtime, "(equal? a b)").unwrap(), "#t");
+    }
+}
@@ -35,6 +35,13 @@ impl ExecBuffer {
             Ok(ExecBuffer { memory, size })
         }
     }
+    /// Address of the first instruction.
+    pub fn as_ptr(&self) -> *const u8 {
+        self.memory as *const u8
+    }
+
+    /// # Safety
+    /// `F` must be a function pointer type matching the code in the buffer.
     pub unsafe fn as_function<F: Copy>(&self) -> F {
         unsafe { mem::transmute_copy(&self.memory) }
     }
@@ -48,6 +55,7 @@ impl Drop for ExecBuffer {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
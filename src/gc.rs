@@ -0,0 +1,434 @@
+// A generational, Cheney-style copying collector.
+//
+// Live objects are copied into the old generation in breadth-first order:
+// the roots first, then whatever the copied objects refer to, found by
+// scanning the copies in order. A copied object's first word is overwritten
+// with its new address, tagged as before, which is how later references to it
+// are redirected.
+//
+// A minor collection copies out of the nursery only. Its roots include the
+// fields of old objects on dirty cards, the only places outside the roots
+// that can point into the nursery. A major collection copies out of both
+// generations into a fresh old generation.
+
+use crate::encodings::{
+    Bignum, Closure, Flonum, HashTable, Header, K_BIGNUM_KIND, K_CLOSURE_TAG, K_FLONUM_KIND,
+    K_HASH_TABLE_KIND, K_PAIR_TAG, K_PERSISTENT_MAP_KIND, K_PERSISTENT_VECTOR_KIND, K_RECORD_KIND,
+    K_STRING_BYTES_KIND, K_STRING_TAG, K_SYMBOL_TAG, K_VECTOR_TAG, LispString, LispValue, Pair,
+    PersistentMap, PersistentVector, Record, StringBytes, Symbol, Vector, Word,
+};
+use crate::heap::OldSpace;
+use std::ops::Range;
+
+/// Every call made by compiled code is followed by a `nop dword [rax + disp32]`
+/// whose displacement is the stack map of the call site: the number of values
+/// the calling frame has pushed below its frame pointer. The collector finds it
+/// from the return address alone.
+pub const STACK_MAP_PREFIX: [u8; 3] = [0x0f, 0x1f, 0x80];
+
+/// Reads the stack map of the call site returning to `return_address`.
+///
+/// # Safety
+/// `return_address` must point into compiled code.
+pub unsafe fn read_stack_map(return_address: usize) -> usize {
+    let code = return_address as *const u8;
+    unsafe {
+        let prefix = std::slice::from_raw_parts(code, STACK_MAP_PREFIX.len());
+        assert_eq!(
+            prefix, STACK_MAP_PREFIX,
+            "no stack map at return address {:#x}",
+            return_address
+        );
+        let slots = (code.add(STACK_MAP_PREFIX.len()) as *const u32).read_unaligned();
+        slots as usize
+    }
+}
+
+/// Calls `f` on every value pushed by the frames of compiled code.
+///
+/// `fp` and `sp` are the frame and stack pointers recorded by compiled code
+/// just before it called into the runtime; the frames are followed through
+/// their saved frame pointers up to `entry_frame`.
+///
+/// # Safety
+/// The frames must be live and every call in them followed by a stack map.
+pub unsafe fn for_each_stack_root(
+    mut fp: usize,
+    sp: usize,
+    entry_frame: usize,
+    mut f: impl FnMut(&mut LispValue),
+) {
+    let word = size_of::<Word>();
+    unsafe {
+        let mut return_address = *((sp - word) as *const usize);
+        loop {
+            let slots = read_stack_map(return_address);
+            for slot in 1..=slots {
+                f(&mut *((fp - slot * word) as *mut LispValue));
+            }
+            if fp == entry_frame {
+                break;
+            }
+            return_address = *((fp + word) as *const usize);
+            fp = *(fp as *const usize);
+        }
+    }
+}
+
+/// Size in bytes of the heap object at `address`.
+///
+/// # Safety
+/// `address` must be the start of a heap object.
+pub unsafe fn object_size(address: usize) -> usize {
+    let first = unsafe { *(address as *const Word) };
+    if !Header::is_header(first) {
+        return size_of::<Pair>();
+    }
+    let header = Header::from_raw_word(first);
+    match header.kind() {
+        K_VECTOR_TAG => size_of::<Vector>() + header.length() * size_of::<LispValue>(),
+        K_STRING_TAG => size_of::<LispString>(),
+        K_STRING_BYTES_KIND => StringBytes::allocation_size(header.length()),
+        K_SYMBOL_TAG => size_of::<Symbol>(),
+        K_CLOSURE_TAG => size_of::<Closure>(),
+        K_BIGNUM_KIND => Bignum::allocation_size(header.length()),
+        K_FLONUM_KIND => size_of::<Flonum>(),
+        K_HASH_TABLE_KIND => size_of::<HashTable>(),
+        K_RECORD_KIND => Record::allocation_size(header.length()),
+        K_PERSISTENT_VECTOR_KIND => size_of::<PersistentVector>(),
+        K_PERSISTENT_MAP_KIND => size_of::<PersistentMap>(),
+        _ => panic!("corrupt header {:#x} at {:#x}", first, address),
+    }
+}
+
+/// The kind of the heap object at `address`: its pointer tag, for most.
+///
+/// # Safety
+/// `address` must be the start of a heap object.
+pub unsafe fn object_kind(address: usize) -> Word {
+    let first = unsafe { *(address as *const Word) };
+    if Header::is_header(first) {
+        Header::from_raw_word(first).kind()
+    } else {
+        K_PAIR_TAG
+    }
+}
+
+/// Calls `f` on the address of every object in `start..end`, which must be
+/// filled with objects laid end to end, as the old generation is.
+///
+/// # Safety
+/// `start` must be the start of a heap object.
+pub unsafe fn for_each_object(start: usize, end: usize, mut f: impl FnMut(usize)) {
+    let mut object = start;
+    while object < end {
+        let size = unsafe { object_size(object) };
+        f(object);
+        object += size;
+    }
+}
+
+/// Calls `f` on every value field of the heap object at `address`.
+///
+/// # Safety
+/// `address` must be the start of a heap object.
+pub unsafe fn for_each_field(address: usize, mut f: impl FnMut(&mut LispValue)) {
+    unsafe {
+        let first = *(address as *const Word);
+        if !Header::is_header(first) {
+            let pair = &mut *(address as *mut Pair);
+            f(&mut pair.car);
+            f(&mut pair.cdr);
+            return;
+        }
+        match Header::from_raw_word(first).kind() {
+            K_VECTOR_TAG => {
+                let vector = &mut *(address as *mut Vector);
+                vector.elements_mut().iter_mut().for_each(f);
+            }
+            K_STRING_TAG => {
+                let string = &mut *(address as *mut LispString);
+                f(&mut string.length);
+                f(&mut string.bytes);
+            }
+            K_SYMBOL_TAG => f(&mut (*(address as *mut Symbol)).name),
+            K_CLOSURE_TAG => {
+                let closure = &mut *(address as *mut Closure);
+                f(&mut closure.arity);
+                f(&mut closure.name);
+            }
+            K_HASH_TABLE_KIND => {
+                let table = &mut *(address as *mut HashTable);
+                f(&mut table.equivalence);
+                f(&mut table.count);
+                f(&mut table.epoch);
+                f(&mut table.buckets);
+            }
+            K_RECORD_KIND => {
+                let record = &mut *(address as *mut Record);
+                f(&mut record.descriptor);
+                record.fields_mut().iter_mut().for_each(f);
+            }
+            K_PERSISTENT_VECTOR_KIND => {
+                let vector = &mut *(address as *mut PersistentVector);
+                f(&mut vector.count);
+                f(&mut vector.shift);
+                f(&mut vector.root);
+            }
+            K_PERSISTENT_MAP_KIND => {
+                let map = &mut *(address as *mut PersistentMap);
+                f(&mut map.count);
+                f(&mut map.root);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Copies the objects reachable from the roots it is given out of the
+/// `from` spaces into the old generation `to`, after the objects already there.
+pub struct Collector<'a> {
+    from: Vec<Range<usize>>,
+    to: &'a mut OldSpace,
+    // Where the copies made by this collection start.
+    copied: usize,
+}
+
+impl<'a> Collector<'a> {
+    pub fn new(from: Vec<Range<usize>>, to: &'a mut OldSpace) -> Self {
+        let copied = to.top();
+        Collector { from, to, copied }
+    }
+
+    fn in_from_space(&self, value: LispValue) -> Option<usize> {
+        value
+            .heap_address()
+            .filter(|address| self.from.iter().any(|space| space.contains(address)))
+    }
+
+    /// Where the object `value` points to has been copied, if it has.
+    pub fn forwarded(&self, value: LispValue) -> Option<LispValue> {
+        let address = self.in_from_space(value)?;
+        let first = LispValue::from_raw_word(unsafe { *(address as *const Word) });
+        // Only the copies made by this collection can be forwarding
+        // addresses: nothing pointed to where they are before it started.
+        first
+            .heap_address()
+            .filter(|address| (self.copied..self.to.top()).contains(address))
+            .map(|_| first)
+    }
+
+    /// `value` after the collection, or `None` if its object was not kept.
+    pub fn survivor(&self, value: LispValue) -> Option<LispValue> {
+        match self.in_from_space(value) {
+            Some(_) => self.forwarded(value),
+            None => Some(value),
+        }
+    }
+
+    /// Returns `value` updated to point into the new space, copying its
+    /// object there first if needed. Other values are returned unchanged.
+    pub fn forward(&mut self, value: LispValue) -> LispValue {
+        let Some(address) = self.in_from_space(value) else {
+            return value;
+        };
+        if let Some(forwarded) = self.forwarded(value) {
+            return forwarded;
+        }
+        let size = unsafe { object_size(address) };
+        let new_address = self.to.bump(size).expect("old generation overflow");
+        let copy = value.with_heap_address(new_address);
+        unsafe {
+            std::ptr::copy_nonoverlapping(address as *const u8, new_address as *mut u8, size);
+            *(address as *mut Word) = copy.as_raw_word();
+        }
+        copy
+    }
+
+    /// Forwards the fields of the objects in `start..end` of the old
+    /// generation, walking them from `object`, the one covering `start`.
+    ///
+    /// # Safety
+    /// `object` must be the start of an object in the old generation.
+    pub unsafe fn forward_fields_in(&mut self, start: usize, end: usize, mut object: usize) {
+        while object < end {
+            let size = unsafe { object_size(object) };
+            unsafe {
+                for_each_field(object, |field| {
+                    let address = field as *mut LispValue as usize;
+                    if (start..end).contains(&address) {
+                        *field = self.forward(*field);
+                    }
+                })
+            };
+            object += size;
+        }
+    }
+
+    /// Copies everything reachable from the objects copied so far.
+    pub fn scan(&mut self) {
+        let mut scan = self.copied;
+        while scan < self.to.top() {
+            let size = unsafe { object_size(scan) };
+            unsafe { for_each_field(scan, |field| *field = self.forward(*field)) };
+            scan += size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::Runtime;
+    use crate::testing::{assert_evals, eval_str};
+
+    #[test]
+    fn test_stress_mode() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        runtime.set_gc_stress(true);
+        runtime.set_heap_verification(true);
+        let cases = [
+            ("'(a (b c) (1 . 2) d)", "(a (b c) (1 . 2) d)"),
+            ("(vector 1 (cons 2 3) (vector) 'x)", "#(1 (2 . 3) #() x)"),
+            ("(make-vector 3 (cons 1 nil))", "#((1) (1) (1))"),
+            ("(define l '(1 2 3))", "l"),
+            ("(define (f a b) (cons a (cons b '(x y))))", "f"),
+            ("(f (vector 1 2) (cons 3 l))", "(#(1 2) (3 1 2 3) x y)"),
+            ("(define (g v) (cons (f v v) (f 'p 'q)))", "g"),
+            ("(g (symbol->string 's))", "((\"s\" \"s\" x y) p q x y)"),
+            ("(eq? (string->symbol (symbol->string 'abc)) 'abc)", "#t"),
+            ("(cons (gensym) (gensym))", "(g1 . g2)"),
+            ("l", "(1 2 3)"),
+            ("(cons (gc) l)", "(() 1 2 3)"),
+            ("(cons l (heap-object-count 'procedure))", "((1 2 3) . 2)"),
+            ("f", "#<procedure f>"),
+            ("(define v (make-vector 2 0))", "v"),
+            ("(set-car! (cdr l) (cons 'y (g 'z)))", "()"),
+            ("(vector-set! v 1 (cons l (f 1 2)))", "()"),
+            ("v", "#(0 ((1 (y (z z x y) p q x y) 3) 1 2 x y))"),
+            ("(define s (string-append \"ab\" \"c\"))", "s"),
+            ("(string-set! s 1 #\\λ)", "()"),
+            (
+                "(cons (string->list s) (substring (string-append s s) 2 5))",
+                "((#\\a #\\λ #\\c) . \"caλ\")",
+            ),
+        ];
+        assert_evals(&mut runtime, &cases);
+        assert!(runtime.heap().collections() > 20);
+        assert!(runtime.heap().major_collections() > 0);
+    }
+
+    /// Allocates enough to overwrite whatever the nursery held before its
+    /// last collection.
+    fn clobber_nursery(runtime: &mut Runtime) {
+        eval_str(runtime, "(make-vector 200 'junk)").unwrap();
+    }
+
+    #[test]
+    fn test_old_to_young_pointers_survive_minor_collections() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define p (cons 1 2))").unwrap();
+        eval_str(&mut runtime, "(define v (make-vector 3 0))").unwrap();
+        runtime.collect();
+        let p = runtime.global("p").unwrap();
+        assert!(runtime.heap().old().contains(p.heap_address().unwrap()));
+        assert!(runtime.heap().old().dirty_cards().is_empty());
+
+        // The new pairs are only reachable through old objects.
+        eval_str(&mut runtime, "(set-car! p (cons 3 4))").unwrap();
+        eval_str(&mut runtime, "(vector-set! v 1 (cons 5 (cons 6 nil)))").unwrap();
+        eval_str(&mut runtime, "(define g (cons 7 8))").unwrap();
+        assert!(!runtime.heap().old().dirty_cards().is_empty());
+        runtime.collect_minor();
+        assert!(runtime.heap().old().dirty_cards().is_empty());
+        clobber_nursery(&mut runtime);
+        runtime.collect_minor();
+        clobber_nursery(&mut runtime);
+
+        assert_eq!(eval_str(&mut runtime, "p").unwrap(), "((3 . 4) . 2)");
+        assert_eq!(eval_str(&mut runtime, "v").unwrap(), "#(0 (5 6) 0)");
+        assert_eq!(eval_str(&mut runtime, "g").unwrap(), "(7 . 8)");
+        assert_eq!(runtime.heap().major_collections(), 1);
+        assert_eq!(runtime.heap().minor_collections(), 2);
+    }
+
+    #[test]
+    fn test_minor_collections_leave_old_objects_in_place() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define l '(1 2 3))").unwrap();
+        // Interns the symbol it uses.
+        clobber_nursery(&mut runtime);
+        runtime.collect();
+        let old_used = runtime.heap().old().used();
+        for _ in 0..10 {
+            clobber_nursery(&mut runtime);
+            runtime.collect_minor();
+        }
+        assert_eq!(runtime.heap().old().used(), old_used);
+        assert_eq!(eval_str(&mut runtime, "l").unwrap(), "(1 2 3)");
+    }
+
+    #[test]
+    fn test_large_objects_start_old() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        eval_str(&mut runtime, "(define big (make-vector 1000 0))").unwrap();
+        let big = runtime.global("big").unwrap();
+        assert!(runtime.heap().old().contains(big.heap_address().unwrap()));
+        eval_str(&mut runtime, "(vector-set! big 999 (cons 1 2))").unwrap();
+        runtime.collect_minor();
+        clobber_nursery(&mut runtime);
+        assert_eq!(
+            eval_str(&mut runtime, "(vector-ref big 999)").unwrap(),
+            "(1 . 2)"
+        );
+    }
+
+    #[test]
+    fn test_collect_keeps_roots() {
+        let mut runtime = Runtime::new();
+        eval_str(
+            &mut runtime,
+            "(define v (vector 'a (symbol->string 'b) '(c)))",
+        )
+        .unwrap();
+        eval_str(&mut runtime, "(define (f x) (cons x v))").unwrap();
+        eval_str(&mut runtime, "(vector-set! v 0 v)").unwrap();
+        let a = runtime.intern("a");
+        let used = runtime.heap_used();
+        runtime.collect();
+        assert_eq!(runtime.heap().collections(), 1);
+        assert!(runtime.heap_used() <= used);
+        assert_ne!(runtime.intern("a"), a);
+        assert_eq!(
+            eval_str(&mut runtime, "(f 1)").unwrap(),
+            "(1 . #0=#(#0# \"b\" (c)))"
+        );
+        assert_eq!(
+            eval_str(&mut runtime, "(eq? 'c (car (vector-ref v 2)))").unwrap(),
+            "#t"
+        );
+    }
+
+    #[test]
+    fn test_garbage_is_reclaimed() {
+        let limit = 16 * 4096;
+        let mut runtime = Runtime::with_heap(4096, limit);
+        eval_str(&mut runtime, "(define kept (make-vector 10 'k))").unwrap();
+        for _ in 0..1000 {
+            eval_str(&mut runtime, "(make-vector 100)").unwrap();
+        }
+        assert!(runtime.heap().collections() > 0);
+        assert!(runtime.heap().mapped() <= limit);
+        assert_eq!(eval_str(&mut runtime, "(vector-ref kept 9)").unwrap(), "k");
+    }
+
+    #[test]
+    fn test_unreferenced_gensyms_are_dropped() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define kept (gensym))").unwrap();
+        eval_str(&mut runtime, "(gensym)").unwrap();
+        let before = runtime.symbols().len();
+        runtime.collect();
+        assert_eq!(runtime.symbols().len(), before - 1);
+        assert_eq!(eval_str(&mut runtime, "kept").unwrap(), "g1");
+    }
+}
@@ -0,0 +1,531 @@
+// The hash table primitives that compiled code calls into the runtime for.
+//
+// A table chains its entries in buckets: a vector of lists of `(key . value)`
+// pairs. Some keys hash by address: every heap object under `eq?`, and for
+// instance the symbols inside an `equal?` key. A moving collection changes
+// those hashes, so a table holding such keys records how many collections
+// there had been when it hashed them, and rehashes them when it is next used
+// after another. Rehashing relinks the chains in place without allocating,
+// so a primitive that hashes after its last allocation can rely on the hash.
+
+use crate::encodings::{HashTable, LispValue, Pair, Word};
+use crate::equality::{equal, eqv};
+use crate::runtime::{Runtime, StackArgs, call_with_stack_args as call};
+use crate::strings::heap_exhausted;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Buckets of a new table. A table doubles them when it has more entries.
+pub const INITIAL_BUCKETS: usize = 8;
+
+/// How many of the values making up an `equal?` key are hashed, which bounds
+/// the work for large keys and ends it for cyclic ones.
+const EQUAL_HASH_LIMIT: usize = 32;
+
+/// The predicate a table compares keys with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Equivalence {
+    Eq,
+    Eqv,
+    Equal,
+}
+
+impl Equivalence {
+    /// The equivalence `make-hash-table` names with `name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "eq" => Some(Equivalence::Eq),
+            "eqv" => Some(Equivalence::Eqv),
+            "equal" => Some(Equivalence::Equal),
+            _ => None,
+        }
+    }
+
+    /// The equivalence as a table stores it.
+    pub fn encode(self) -> LispValue {
+        LispValue::from_integer(self as Word)
+    }
+
+    fn decode(value: LispValue) -> Self {
+        match value.as_integer() {
+            Some(0) => Equivalence::Eq,
+            Some(1) => Equivalence::Eqv,
+            _ => Equivalence::Equal,
+        }
+    }
+
+    pub fn matches(self, a: LispValue, b: LispValue) -> bool {
+        match self {
+            Equivalence::Eq => a == b,
+            Equivalence::Eqv => eqv(a, b),
+            Equivalence::Equal => equal(a, b),
+        }
+    }
+
+    /// The hash of `key`, and whether it depends on where some object is.
+    pub fn hash(self, key: LispValue) -> (u64, bool) {
+        let mut hasher = DefaultHasher::new();
+        let mut by_address = false;
+        let mut budget = match self {
+            Equivalence::Equal => EQUAL_HASH_LIMIT,
+            _ => 1,
+        };
+        let mut pending = vec![key];
+        while let Some(value) = pending.pop()
+            && budget > 0
+        {
+            budget -= 1;
+            if self != Equivalence::Eq {
+                if let Some(value) = value.as_flonum() {
+                    value.to_bits().hash(&mut hasher);
+                    continue;
+                }
+                if let Some(ptr) = value.as_bignum_pointer() {
+                    unsafe { ((*ptr).is_negative(), (*ptr).limbs()).hash(&mut hasher) };
+                    continue;
+                }
+            }
+            if self == Equivalence::Equal {
+                if let Some(ptr) = value.as_pair_pointer() {
+                    let pair = unsafe { *ptr };
+                    "pair".hash(&mut hasher);
+                    pending.push(pair.cdr);
+                    pending.push(pair.car);
+                    continue;
+                }
+                if let Some(ptr) = value.as_vector_pointer() {
+                    let elements = unsafe { (*ptr).elements() };
+                    ("vector", elements.len()).hash(&mut hasher);
+                    pending.extend(elements.iter().rev());
+                    continue;
+                }
+                if let Some(ptr) = value.as_string_pointer() {
+                    unsafe { (*ptr).as_str() }.hash(&mut hasher);
+                    continue;
+                }
+                if let Some(elements) = value.elements() {
+                    ("persistent-vector", elements.len()).hash(&mut hasher);
+                    pending.extend(elements.iter().rev());
+                    continue;
+                }
+                if let Some(count) = value.count() {
+                    ("persistent-map", count).hash(&mut hasher);
+                    continue;
+                }
+            }
+            value.as_raw_word().hash(&mut hasher);
+            by_address |= value.heap_address().is_some();
+        }
+        (hasher.finish(), by_address)
+    }
+}
+
+/// The table `value` points to, which `table_arg` made sure of.
+fn table<'a>(value: LispValue) -> &'a mut HashTable {
+    unsafe { &mut *value.as_hash_table_pointer().unwrap() }
+}
+
+/// The table argument 0. Unchecked code may pass anything, so this checks
+/// the type again.
+fn table_arg<'a>(args: &StackArgs, name: &str) -> Result<&'a mut HashTable, String> {
+    let value = args.get(0);
+    match value.as_hash_table_pointer() {
+        Some(table) => Ok(unsafe { &mut *table }),
+        None => Err(format!("{}: expected hash-table, got {}", name, value)),
+    }
+}
+
+fn buckets<'a>(table: &HashTable) -> &'a mut [LispValue] {
+    unsafe { (*table.buckets.as_vector_pointer().unwrap()).elements_mut() }
+}
+
+fn pair<'a>(value: LispValue) -> &'a mut Pair {
+    unsafe { &mut *value.as_pair_pointer().unwrap() }
+}
+
+/// The key of the entry in the chain cell `cell`.
+fn key(cell: LispValue) -> LispValue {
+    pair(pair(cell).car).car
+}
+
+/// Stores `value` into `field` of an object that may be old.
+fn store(rt: &mut Runtime, field: &mut LispValue, value: LispValue) {
+    *field = value;
+    rt.write_barrier(field as *mut LispValue as usize);
+}
+
+/// What a table records for keys hashed by address now.
+fn current_epoch(rt: &Runtime) -> LispValue {
+    LispValue::from_integer(rt.heap().collections() as Word)
+}
+
+/// Rehashes `table` in place if a collection may have moved keys it hashed
+/// by address.
+fn refresh(rt: &mut Runtime, table: &mut HashTable) {
+    if table.epoch.as_integer().is_some() && table.epoch != current_epoch(rt) {
+        rehash(rt, table, table.buckets);
+    }
+}
+
+/// Relinks every entry of `table` into `into`, which is either an empty
+/// vector or the table's own buckets, hashing the keys again.
+fn rehash(rt: &mut Runtime, table: &mut HashTable, into: LispValue) {
+    let mut cells = Vec::new();
+    for bucket in buckets(table) {
+        let mut cell = std::mem::replace(bucket, LispValue::nil());
+        while !cell.is_nil() {
+            cells.push(cell);
+            cell = pair(cell).cdr;
+        }
+    }
+    store(rt, &mut table.buckets, into);
+    let equivalence = Equivalence::decode(table.equivalence);
+    let mut by_address = false;
+    for cell in cells {
+        let (hash, address) = equivalence.hash(key(cell));
+        by_address |= address;
+        link(rt, table, cell, hash);
+    }
+    table.epoch = if by_address {
+        current_epoch(rt)
+    } else {
+        LispValue::false_val()
+    };
+}
+
+/// Pushes the chain cell `cell` onto the bucket for `hash`.
+fn link(rt: &mut Runtime, table: &HashTable, cell: LispValue, hash: u64) {
+    let buckets = buckets(table);
+    let bucket = &mut buckets[hash as usize % buckets.len()];
+    store(rt, &mut pair(cell).cdr, *bucket);
+    store(rt, bucket, cell);
+}
+
+/// Where the entry for a key is in its bucket.
+struct Found {
+    bucket: usize,
+    /// The chain cell before the entry's, or nil if it comes first.
+    previous: LispValue,
+    cell: LispValue,
+}
+
+/// Finds the entry for `key`, which is only right once `table` is refreshed.
+fn find(table: &HashTable, key: LispValue) -> Option<Found> {
+    let equivalence = Equivalence::decode(table.equivalence);
+    let buckets = buckets(table);
+    let bucket = equivalence.hash(key).0 as usize % buckets.len();
+    let mut previous = LispValue::nil();
+    let mut cell = buckets[bucket];
+    while !cell.is_nil() {
+        if equivalence.matches(self::key(cell), key) {
+            return Some(Found {
+                bucket,
+                previous,
+                cell,
+            });
+        }
+        previous = cell;
+        cell = pair(cell).cdr;
+    }
+    None
+}
+
+/// `(make-hash-table [equivalence])`, where the equivalence is one of the
+/// symbols `eq`, `eqv` and `equal`, which is the default.
+pub(crate) extern "C" fn rt_make_hash_table(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let equivalence = match args.len() {
+            0 => Equivalence::Equal,
+            _ => {
+                let name = args.get(0);
+                let symbol = name
+                    .as_symbol_pointer()
+                    .ok_or_else(|| format!("make-hash-table: expected symbol, got {}", name))?;
+                Equivalence::from_name(unsafe { (*symbol).as_str() })
+                    .ok_or_else(|| format!("make-hash-table: unknown equivalence {}", name))?
+            }
+        };
+        rt.alloc_hash_table(equivalence)
+            .ok_or_else(|| heap_exhausted("make-hash-table"))
+    })
+}
+
+/// `(hash-table-ref table key [default])`
+pub(crate) extern "C" fn rt_hash_table_ref(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let table = table_arg(args, "hash-table-ref")?;
+        refresh(rt, table);
+        match find(table, args.get(1)) {
+            Some(found) => Ok(pair(pair(found.cell).car).cdr),
+            None if args.len() == 3 => Ok(args.get(2)),
+            None => Err(format!("hash-table-ref: no value for key {}", args.get(1))),
+        }
+    })
+}
+
+/// `(hash-table-set! table key value)`
+pub(crate) extern "C" fn rt_hash_table_set(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let table = table_arg(args, "hash-table-set!")?;
+        refresh(rt, table);
+        if let Some(found) = find(table, args.get(1)) {
+            let entry = pair(found.cell).car;
+            store(rt, &mut pair(entry).cdr, args.get(2));
+            return Ok(LispValue::nil());
+        }
+        let exhausted = || heap_exhausted("hash-table-set!");
+        let entry = rt
+            .alloc_pair(args.get(1), args.get(2))
+            .ok_or_else(exhausted)?;
+        let mut cell = rt
+            .alloc_pair(entry, LispValue::nil())
+            .ok_or_else(exhausted)?;
+        // Allocating may have moved the table and its keys.
+        let table = self::table(args.get(0));
+        let count = table.count.as_integer().unwrap() + 1;
+        let size = buckets(table).len();
+        if count as usize > size {
+            rt.push_root(cell);
+            let grown = rt.alloc_vector(2 * size, LispValue::nil());
+            cell = rt.pop_root();
+            let grown = grown.ok_or_else(exhausted)?;
+            rehash(rt, self::table(args.get(0)), grown);
+        } else {
+            refresh(rt, table);
+        }
+        let table = self::table(args.get(0));
+        let (hash, by_address) = Equivalence::decode(table.equivalence).hash(args.get(1));
+        link(rt, table, cell, hash);
+        table.count = LispValue::from_integer(count);
+        if by_address && table.epoch.as_integer().is_none() {
+            table.epoch = current_epoch(rt);
+        }
+        Ok(LispValue::nil())
+    })
+}
+
+/// `(hash-table-delete! table key)`
+pub(crate) extern "C" fn rt_hash_table_delete(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let table = table_arg(args, "hash-table-delete!")?;
+        refresh(rt, table);
+        if let Some(found) = find(table, args.get(1)) {
+            let next = pair(found.cell).cdr;
+            if found.previous.is_nil() {
+                store(rt, &mut buckets(table)[found.bucket], next);
+            } else {
+                store(rt, &mut pair(found.previous).cdr, next);
+            }
+            let count = table.count.as_integer().unwrap() - 1;
+            table.count = LispValue::from_integer(count);
+        }
+        Ok(LispValue::nil())
+    })
+}
+
+/// `(hash-table-keys table)`, in no particular order.
+pub(crate) extern "C" fn rt_hash_table_keys(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let table = table_arg(args, "hash-table-keys")?;
+        let mut count = 0;
+        for bucket in buckets(table) {
+            let mut cell = *bucket;
+            while !cell.is_nil() {
+                rt.push_root(key(cell));
+                count += 1;
+                cell = pair(cell).cdr;
+            }
+        }
+        rt.pop_list(count)
+            .ok_or_else(|| heap_exhausted("hash-table-keys"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Safety;
+    use crate::runtime::Runtime;
+    use crate::testing::{assert_errors, assert_errors_with, assert_evals, eval_str};
+
+    #[test]
+    fn test_hash_table_primitives() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("(define h (make-hash-table))", "h"),
+            ("h", "#<hash-table>"),
+            ("(cons (hash-table? h) (hash-table? (vector)))", "(#t . #f)"),
+            ("(hash-table-count h)", "0"),
+            ("(define k (cons 1 (cons (vector 2 \"x\") nil)))", "k"),
+            ("(hash-table-set! h k 'a)", "()"),
+            (
+                "(hash-table-ref h (cons 1 (cons (vector 2 \"x\") nil)))",
+                "a",
+            ),
+            ("(hash-table-set! h (string-append \"k\") 'b)", "()"),
+            ("(hash-table-ref h \"k\")", "b"),
+            ("(hash-table-set! h (cons 1 (cdr k)) 'c)", "()"),
+            (
+                "(cons (hash-table-ref h k) (hash-table-count h))",
+                "(c . 2)",
+            ),
+            (
+                "(hash-table-ref h (cons 1 (cons (vector 2 \"y\") nil)) 'none)",
+                "none",
+            ),
+            ("(hash-table-delete! h \"k\")", "()"),
+            ("(hash-table-delete! h \"k\")", "()"),
+            (
+                "(cons (hash-table-keys h) (hash-table-count h))",
+                "(((1 #(2 \"x\"))) . 1)",
+            ),
+            // `eqv?` compares numbers by value, but strings by identity.
+            ("(define e (make-hash-table 'eqv))", "e"),
+            ("(hash-table-set! e (expt 2 100) 'big)", "()"),
+            ("(hash-table-set! e 1.5 'float)", "()"),
+            ("(hash-table-set! e \"s\" 'string)", "()"),
+            ("(hash-table-ref e (* (expt 2 50) (expt 2 50)))", "big"),
+            ("(hash-table-ref e (/ 3 2))", "float"),
+            ("(hash-table-ref e \"s\" false)", "#f"),
+            ("(hash-table-ref e 1 false)", "#f"),
+            ("(define q (make-hash-table 'eq))", "q"),
+            ("(hash-table-set! q 'x 1)", "()"),
+            ("(hash-table-set! q #\\x 2)", "()"),
+            ("(hash-table-set! q (expt 2 100) 3)", "()"),
+            (
+                "(cons (hash-table-ref q 'x) (hash-table-ref q #\\x))",
+                "(1 . 2)",
+            ),
+            ("(hash-table-ref q (expt 2 100) 'none)", "none"),
+        ];
+        assert_evals(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_hash_table_errors() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define h (make-hash-table 'eq))").unwrap();
+        let cases = [
+            (
+                "(hash-table-ref h 'x)",
+                "hash-table-ref: no value for key x",
+            ),
+            (
+                "(make-hash-table 'same)",
+                "make-hash-table: unknown equivalence same",
+            ),
+            (
+                "(make-hash-table \"eq\")",
+                "make-hash-table: expected symbol, got \"eq\"",
+            ),
+            (
+                "(hash-table-count (vector))",
+                "hash-table-count: expected hash-table, got #()",
+            ),
+            (
+                "(hash-table-set! 'h 1 2)",
+                "hash-table-set!: expected hash-table, got h",
+            ),
+        ];
+        assert_errors(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_unchecked_hash_table_arguments() {
+        let mut runtime = Runtime::new();
+        // Unchecked code leaves the checks to the runtime calls.
+        let cases = [
+            (
+                "(make-hash-table 1)",
+                "make-hash-table: expected symbol, got 1",
+            ),
+            (
+                "(hash-table-ref 1 2)",
+                "hash-table-ref: expected hash-table, got 1",
+            ),
+            (
+                "(hash-table-set! 'h 1 2)",
+                "hash-table-set!: expected hash-table, got h",
+            ),
+            (
+                "(hash-table-delete! nil 1)",
+                "hash-table-delete!: expected hash-table, got ()",
+            ),
+            (
+                "(hash-table-keys (vector))",
+                "hash-table-keys: expected hash-table, got #()",
+            ),
+        ];
+        assert_errors_with(&mut runtime, Safety::Unchecked, &cases);
+    }
+
+    #[test]
+    fn test_keys_hashed_by_address_survive_collections() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        runtime.set_heap_verification(true);
+        eval_str(&mut runtime, "(define q (make-hash-table 'eq))").unwrap();
+        eval_str(&mut runtime, "(define h (make-hash-table 'equal))").unwrap();
+        eval_str(&mut runtime, "(define keys (make-vector 100 0))").unwrap();
+        for i in 0..100 {
+            let key = format!("(vector-set! keys {} (cons {} (gensym)))", i, i);
+            eval_str(&mut runtime, &key).unwrap();
+            let set = format!("(hash-table-set! q (vector-ref keys {}) {})", i, i);
+            eval_str(&mut runtime, &set).unwrap();
+            let set = format!(
+                "(hash-table-set! h (cons 'k (vector-ref keys {})) {})",
+                i, i
+            );
+            eval_str(&mut runtime, &set).unwrap();
+            match i % 3 {
+                0 => runtime.collect_minor(),
+                1 => runtime.collect(),
+                _ => {}
+            }
+        }
+        assert_eq!(
+            eval_str(&mut runtime, "(hash-table-count q)").unwrap(),
+            "100"
+        );
+        assert_eq!(
+            eval_str(&mut runtime, "(hash-table-count h)").unwrap(),
+            "100"
+        );
+        runtime.set_gc_stress(true);
+        for i in 0..100 {
+            let key = format!("(vector-ref keys {})", i);
+            let get = format!("(hash-table-ref q {})", key);
+            assert_eq!(eval_str(&mut runtime, &get).unwrap(), i.to_string());
+            let get = format!(
+                "(hash-table-ref h (cons 'k (cons (car {}) (cdr {}))))",
+                key, key
+            );
+            assert_eq!(eval_str(&mut runtime, &get).unwrap(), i.to_string());
+        }
+        // A copy of a key is another object.
+        assert_eq!(
+            eval_str(
+                &mut runtime,
+                "(hash-table-ref q (cons 0 (cdr (vector-ref keys 0))) false)"
+            ),
+            Ok("#f".to_string())
+        );
+    }
+}
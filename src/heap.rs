@@ -0,0 +1,304 @@
+use libc::{MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE};
+use std::time::Duration;
+
+/// A region of memory mapped for the Lisp heap. Unmapped on drop.
+pub struct Region {
+    start: *mut u8,
+    size: usize,
+}
+
+impl Region {
+    pub fn new(size: usize) -> Result<Self, &'static str> {
+        let memory = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_ANONYMOUS | MAP_PRIVATE,
+                -1,
+                0,
+            )
+        };
+        if memory == libc::MAP_FAILED {
+            return Err("mmap failed");
+        }
+        Ok(Region {
+            start: memory as *mut u8,
+            size,
+        })
+    }
+
+    /// Address of the first byte, aligned to the page size.
+    pub fn start(&self) -> usize {
+        self.start as usize
+    }
+
+    /// One past the last byte.
+    pub fn end(&self) -> usize {
+        self.start() + self.size
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        (self.start()..self.end()).contains(&address)
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.start as *mut libc::c_void, self.size);
+        }
+    }
+}
+
+/// Default size of the nursery, in bytes.
+pub const DEFAULT_HEAP_SIZE: usize = 1 << 20;
+/// Default cap on the live data in the heap, in bytes.
+pub const DEFAULT_HEAP_LIMIT: usize = 1 << 30;
+
+/// The old generation is divided into cards of `1 << CARD_SHIFT` bytes. A
+/// store of a value into an old object marks the card holding the field, so
+/// a minor collection only looks at marked cards for pointers into the nursery.
+pub const CARD_SHIFT: u32 = 9;
+pub const CARD_SIZE: usize = 1 << CARD_SHIFT;
+pub const CARD_CLEAN: u8 = 0;
+pub const CARD_DIRTY: u8 = 1;
+
+const PAGE_SIZE: usize = 4096;
+
+/// The old generation: objects that survived a collection, bump-allocated
+/// by the collector, and objects too large for the nursery.
+pub struct OldSpace {
+    space: Region,
+    top: usize,
+    /// One byte per card, `CARD_DIRTY` once a field in the card was stored to.
+    cards: Vec<u8>,
+    /// For each card, the start of the object covering its first byte, so the
+    /// objects in a card can be walked without scanning the whole space.
+    object_starts: Vec<usize>,
+}
+
+impl OldSpace {
+    pub fn new(size: usize) -> Result<Self, &'static str> {
+        let size = size.max(1).next_multiple_of(PAGE_SIZE);
+        let space = Region::new(size)?;
+        let cards = size >> CARD_SHIFT;
+        Ok(OldSpace {
+            top: space.start(),
+            space,
+            cards: vec![CARD_CLEAN; cards],
+            object_starts: vec![0; cards],
+        })
+    }
+
+    pub fn start(&self) -> usize {
+        self.space.start()
+    }
+
+    pub fn size(&self) -> usize {
+        self.space.size
+    }
+
+    /// One past the last allocated byte.
+    pub fn top(&self) -> usize {
+        self.top
+    }
+
+    pub fn used(&self) -> usize {
+        self.top - self.start()
+    }
+
+    pub fn free(&self) -> usize {
+        self.space.end() - self.top
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        (self.start()..self.top).contains(&address)
+    }
+
+    /// Allocates `size` bytes, recording the object's start for the cards it
+    /// covers. Returns `None` if they do not fit.
+    pub fn bump(&mut self, size: usize) -> Option<usize> {
+        if size > self.free() {
+            return None;
+        }
+        let start = self.top;
+        self.top += size;
+        let first_card = (start - self.start()).div_ceil(CARD_SIZE);
+        let end_card = (self.top - self.start()).div_ceil(CARD_SIZE);
+        for card in first_card..end_card {
+            self.object_starts[card] = start;
+        }
+        Some(start)
+    }
+
+    /// The card table, as indexed by compiled code: the card of the byte at
+    /// `address` is `(address - start) >> CARD_SHIFT`.
+    pub fn card_table(&mut self) -> *mut u8 {
+        self.cards.as_mut_ptr()
+    }
+
+    /// Marks the cards holding `start..end` dirty.
+    pub fn mark(&mut self, start: usize, end: usize) {
+        let first_card = (start - self.start()) >> CARD_SHIFT;
+        let end_card = (end - self.start()).div_ceil(CARD_SIZE);
+        self.cards[first_card..end_card].fill(CARD_DIRTY);
+    }
+
+    /// The address range of each dirty card, with the start of the object
+    /// covering its first byte.
+    pub fn dirty_cards(&self) -> Vec<(usize, usize, usize)> {
+        self.cards
+            .iter()
+            .enumerate()
+            .filter(|(_, card)| **card == CARD_DIRTY)
+            .map(|(card, _)| {
+                let start = self.start() + (card << CARD_SHIFT);
+                (
+                    start,
+                    (start + CARD_SIZE).min(self.top),
+                    self.object_starts[card],
+                )
+            })
+            .filter(|(start, _, _)| *start < self.top)
+            .collect()
+    }
+
+    pub fn clear_cards(&mut self) {
+        self.cards.fill(CARD_CLEAN);
+    }
+}
+
+/// The Lisp heap, in two generations. New objects are bump-allocated in the
+/// nursery; the bump pointer itself lives in the `Runtime`, where compiled
+/// code can reach it. A minor collection copies the live objects of the
+/// nursery into the old generation, and a major one copies every live object
+/// into a fresh old generation, which replaces the previous one.
+pub struct Heap {
+    nursery: Region,
+    old: OldSpace,
+    limit: usize,
+    stats: GcStats,
+}
+
+/// Counters kept by the heap over the life of a runtime.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Bytes handed out by allocation, whether still live or not.
+    pub bytes_allocated: usize,
+    pub minor_collections: usize,
+    pub major_collections: usize,
+    /// Time spent collecting, in total and in the longest single collection.
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+}
+
+impl Heap {
+    /// Maps a nursery of `size` bytes and an old generation of the same size,
+    /// which major collections may grow. Allocation fails once the live data
+    /// would exceed `limit`.
+    pub fn new(size: usize, limit: usize) -> Result<Self, &'static str> {
+        Ok(Heap {
+            nursery: Region::new(size)?,
+            old: OldSpace::new(size)?,
+            limit,
+            stats: GcStats::default(),
+        })
+    }
+
+    pub fn nursery(&self) -> &Region {
+        &self.nursery
+    }
+
+    pub fn old(&self) -> &OldSpace {
+        &self.old
+    }
+
+    pub fn old_mut(&mut self) -> &mut OldSpace {
+        &mut self.old
+    }
+
+    /// Total size of the mapped spaces.
+    pub fn mapped(&self) -> usize {
+        self.nursery.size + self.old.size()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Makes `old` the old generation once a major collection has copied
+    /// into it, unmapping the previous one.
+    pub fn replace_old(&mut self, old: OldSpace) {
+        self.old = old;
+    }
+
+    /// Counts a collection that took `pause`.
+    pub(crate) fn record_collection(&mut self, major: bool, pause: Duration) {
+        if major {
+            self.stats.major_collections += 1;
+        } else {
+            self.stats.minor_collections += 1;
+        }
+        self.stats.total_pause += pause;
+        self.stats.max_pause = self.stats.max_pause.max(pause);
+    }
+
+    pub(crate) fn record_allocation(&mut self, bytes: usize) {
+        self.stats.bytes_allocated += bytes;
+    }
+
+    /// The counters so far. Bytes bump-allocated in the nursery are only
+    /// counted when it is emptied; see `Runtime::gc_stats`.
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+
+    pub fn minor_collections(&self) -> usize {
+        self.stats.minor_collections
+    }
+
+    pub fn major_collections(&self) -> usize {
+        self.stats.major_collections
+    }
+
+    /// Number of collections of either kind so far.
+    pub fn collections(&self) -> usize {
+        self.minor_collections() + self.major_collections()
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        self.nursery.contains(address) || self.old.contains(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_old_space_cards() {
+        let mut old = OldSpace::new(4 * CARD_SIZE).unwrap();
+        let start = old.start();
+        assert_eq!(old.bump(16), Some(start));
+        // Covers the rest of the first card and the start of the next two.
+        let large = old.bump(2 * CARD_SIZE).unwrap();
+        assert_eq!(large, start + 16);
+        assert!(old.bump(old.free() + 1).is_none());
+        assert!(old.dirty_cards().is_empty());
+
+        old.mark(start + CARD_SIZE + 8, start + CARD_SIZE + 16);
+        assert_eq!(
+            old.dirty_cards(),
+            vec![(start + CARD_SIZE, start + 2 * CARD_SIZE, large)]
+        );
+        old.mark(start, start + 8);
+        assert_eq!(old.dirty_cards()[0], (start, start + CARD_SIZE, start));
+        old.clear_cards();
+        assert!(old.dirty_cards().is_empty());
+    }
+}
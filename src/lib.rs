@@ -0,0 +1,25 @@
+pub mod assembler;
+pub mod ast;
+pub mod bignum;
+pub mod census;
+pub mod compiler;
+pub mod encodings;
+pub mod equality;
+pub mod executable_buffer;
+pub mod gc;
+pub mod hashtables;
+pub mod heap;
+pub mod numbers;
+pub mod persistent;
+pub mod primitives;
+pub mod printer;
+pub mod reader;
+pub mod runtime;
+pub mod strings;
+pub mod symbols;
+#[cfg(test)]
+mod testing;
+pub mod tokenizer;
+pub mod verify;
+
+pub use executable_buffer::ExecBuffer;
@@ -1,18 +1,12 @@
-mod assembler;
-mod ast;
-mod compiler;
-mod encodings;
-mod executable_buffer;
-mod reader;
-mod tokenizer;
-
-use crate::encodings::LispValue;
-use compiler::Compiler;
-use executable_buffer::ExecBuffer;
 use iced_x86::{Decoder, DecoderOptions, Formatter, NasmFormatter};
-use std::io::{self, Write};
+use lisp_comp::census::HeapCensus;
+use lisp_comp::compiler::{Compiler, Overflow, Safety};
+use lisp_comp::executable_buffer::ExecBuffer;
+use lisp_comp::runtime::Runtime;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
 
-use crate::reader::Parser;
+use lisp_comp::reader::Parser;
 
 fn main() {
     // let mut compiler = Compiler::new();
@@ -29,8 +23,44 @@ fn main() {
     // assert_eq!(lisp_val.as_integer(), Some(expr));
     //
 
+    // `--analyze-heap <file>` summarises a heap dump instead of starting the REPL.
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--analyze-heap") {
+        let Some(path) = args.get(index + 1) else {
+            eprintln!("--analyze-heap expects a file");
+            std::process::exit(2);
+        };
+        match File::open(path).and_then(|file| HeapCensus::from_dump(BufReader::new(file))) {
+            Ok(census) => println!("{}", census),
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // `--unchecked` compiles without type checks.
+    let safety = if std::env::args().any(|arg| arg == "--unchecked") {
+        Safety::Unchecked
+    } else {
+        Safety::Checked
+    };
+    // `--raise-overflow` and `--wrap-overflow` make `add1` and `sub1` raise
+    // or wrap instead of moving on to bignums.
+    let overflow = if std::env::args().any(|arg| arg == "--raise-overflow") {
+        Overflow::Raise
+    } else if std::env::args().any(|arg| arg == "--wrap-overflow") {
+        Overflow::Wrap
+    } else {
+        Overflow::Promote
+    };
+    // `--verify-heap` checks the heap around every collection and after every
+    // evaluation.
+    let verify_heap = args.iter().any(|arg| arg == "--verify-heap");
+    let mut runtime = Runtime::new();
+    runtime.set_heap_verification(verify_heap);
     loop {
-        let compiler = Compiler::new();
         print!("lisp> ");
         io::stdout().flush().unwrap();
 
@@ -38,19 +68,33 @@ fn main() {
         if input == "quit" {
             break;
         }
+        // `:dump-heap <file>` writes the live objects for `--analyze-heap`.
+        if let Some(path) = input.strip_prefix(":dump-heap") {
+            match runtime.dump_heap(path.trim()) {
+                Ok(()) => println!("Heap dumped to {}", path.trim()),
+                Err(err) => println!("Error: {}", err),
+            }
+            continue;
+        }
         let mut parser = Parser::new(&input);
         if let Ok(ast) = parser.read_form() {
             println!("Parsed AST: {:?}", ast);
-            let code = compiler.compile_function(&ast);
+            let code = Compiler::new(&mut runtime)
+                .with_safety(safety)
+                .with_overflow(overflow)
+                .compile_function(&ast);
             match code {
                 Ok(code) => {
                     print_disassembly(&code, 64);
                     let exec = ExecBuffer::new(&code).unwrap();
 
-                    let func = unsafe { exec.as_function::<unsafe extern "C" fn() -> i64>() };
-                    let encoded_result = unsafe { func() };
-                    let lisp_val = LispValue::from_raw_word(encoded_result);
-                    lisp_val.print();
+                    match runtime.run(&exec) {
+                        Ok(lisp_val) => lisp_val.print(),
+                        Err(message) => println!("Error: {}", message),
+                    }
+                    if verify_heap && let Err(error) = runtime.verify_heap() {
+                        println!("Heap verification failed: {}", error);
+                    }
                 }
                 Err(err) => println!("Compilation error: {:?}", err),
             }
@@ -0,0 +1,757 @@
+// Generic arithmetic over fixnums, bignums and flonums, as called by
+// compiled code once an operand is neither a fixnum nor a flonum, or a fixnum
+// result overflows: the inline fast paths are in `primitives`. Integer
+// results are demoted to fixnums whenever they fit, so a bignum always holds
+// an integer outside the fixnum range. Arithmetic on integers is exact; once
+// a flonum takes part, the result is a flonum.
+
+use crate::bignum::{BigInt, integer_value};
+use crate::encodings::{LispValue, Word};
+use crate::runtime::{Runtime, StackArgs, call_with_stack_args as call};
+use crate::strings::heap_exhausted;
+use std::cmp::Ordering;
+
+/// A number, copied out of the heap.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    /// A fixnum or a bignum.
+    Integer(BigInt),
+    Flonum(f64),
+}
+
+impl Number {
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Integer(value) => value.to_f64(),
+            Number::Flonum(value) => *value,
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        matches!(self, Number::Integer(_))
+    }
+}
+
+/// `value` as a number, if it is one.
+pub fn number_value(value: LispValue) -> Option<Number> {
+    match value.as_flonum() {
+        Some(value) => Some(Number::Flonum(value)),
+        None => integer_value(value).map(Number::Integer),
+    }
+}
+
+/// Writes a flonum the way the reader reads it back: always with a point or
+/// an exponent, so it does not read as an integer.
+pub fn format_flonum(value: f64) -> String {
+    if value.is_nan() {
+        "+nan.0".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+inf.0" } else { "-inf.0" }.to_string()
+    } else {
+        format!("{:?}", value)
+    }
+}
+
+/// Parses a decimal flonum, such as `1.5`, `-2e10` or `+inf.0`.
+pub fn parse_flonum(text: &str) -> Option<f64> {
+    match text {
+        "+inf.0" => return Some(f64::INFINITY),
+        "-inf.0" => return Some(f64::NEG_INFINITY),
+        "+nan.0" | "-nan.0" => return Some(f64::NAN),
+        _ => {}
+    }
+    // Rust also accepts "inf" and "NaN", which are symbols here.
+    let numeric = text
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '+' | '-' | '.' | 'e' | 'E'));
+    if !numeric || !text.contains(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+/// Argument `index` as a number.
+fn number_arg(args: &StackArgs, index: usize, name: &str) -> Result<Number, String> {
+    let value = args.get(index);
+    number_value(value).ok_or_else(|| format!("{}: expected number, got {}", name, value))
+}
+
+fn number_args(args: &StackArgs, name: &str) -> Result<Vec<Number>, String> {
+    (0..args.len())
+        .map(|index| number_arg(args, index, name))
+        .collect()
+}
+
+/// Argument `index` as an integer.
+fn integer_arg(args: &StackArgs, index: usize, name: &str) -> Result<BigInt, String> {
+    let value = args.get(index);
+    integer_value(value).ok_or_else(|| format!("{}: expected integer, got {}", name, value))
+}
+
+fn integer_args(args: &StackArgs, name: &str) -> Result<Vec<BigInt>, String> {
+    (0..args.len())
+        .map(|index| integer_arg(args, index, name))
+        .collect()
+}
+
+/// Stores `value` as a fixnum or a new bignum.
+fn integer_result(rt: &mut Runtime, value: &BigInt, name: &str) -> Result<LispValue, String> {
+    rt.alloc_integer(value).ok_or_else(|| heap_exhausted(name))
+}
+
+/// Stores `value` as a fixnum, a new bignum or a new flonum.
+fn number_result(rt: &mut Runtime, value: Number, name: &str) -> Result<LispValue, String> {
+    match value {
+        Number::Integer(value) => integer_result(rt, &value, name),
+        Number::Flonum(value) => rt.alloc_flonum(value).ok_or_else(|| heap_exhausted(name)),
+    }
+}
+
+/// Combines two numbers exactly if both are integers, and as doubles
+/// otherwise.
+fn combine(
+    a: &Number,
+    b: &Number,
+    exact: fn(&BigInt, &BigInt) -> BigInt,
+    inexact: fn(f64, f64) -> f64,
+) -> Number {
+    match (a, b) {
+        (Number::Integer(a), Number::Integer(b)) => Number::Integer(exact(a, b)),
+        _ => Number::Flonum(inexact(a.to_f64(), b.to_f64())),
+    }
+}
+
+/// Folds the arguments left to right, starting from `empty` if there are
+/// none.
+fn fold(
+    args: &StackArgs,
+    name: &str,
+    empty: i64,
+    exact: fn(&BigInt, &BigInt) -> BigInt,
+    inexact: fn(f64, f64) -> f64,
+) -> Result<Number, String> {
+    let values = number_args(args, name)?;
+    Ok(match values.split_first() {
+        Some((first, rest)) => rest.iter().fold(first.clone(), |result, value| {
+            combine(&result, value, exact, inexact)
+        }),
+        None => Number::Integer(BigInt::from(empty)),
+    })
+}
+
+/// `(+ z ...)`
+pub(crate) extern "C" fn rt_add(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let sum = fold(args, "+", 0, |a, b| a + b, |a, b| a + b)?;
+        number_result(rt, sum, "+")
+    })
+}
+
+/// `(- z)` negates; `(- z1 z2 ...)` subtracts the others from `z1`.
+pub(crate) extern "C" fn rt_sub(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let difference = if args.len() == 1 {
+            match number_arg(args, 0, "-")? {
+                Number::Integer(value) => Number::Integer(-&value),
+                Number::Flonum(value) => Number::Flonum(-value),
+            }
+        } else {
+            fold(args, "-", 0, |a, b| a - b, |a, b| a - b)?
+        };
+        number_result(rt, difference, "-")
+    })
+}
+
+/// `(add1 n)`, for the integers that are not fixnums, and fixnums whose
+/// successor is not.
+pub(crate) extern "C" fn rt_add1(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = &integer_arg(args, 0, "add1")? + &BigInt::from(1);
+        integer_result(rt, &value, "add1")
+    })
+}
+
+/// `(sub1 n)`, for the integers that are not fixnums, and fixnums whose
+/// predecessor is not.
+pub(crate) extern "C" fn rt_sub1(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = &integer_arg(args, 0, "sub1")? - &BigInt::from(1);
+        integer_result(rt, &value, "sub1")
+    })
+}
+
+/// `(* z ...)`
+pub(crate) extern "C" fn rt_mul(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let product = fold(args, "*", 1, |a, b| a * b, |a, b| a * b)?;
+        number_result(rt, product, "*")
+    })
+}
+
+/// `a / b`: exact when both are integers and `b` divides `a`, a flonum
+/// otherwise, as there are no rationals.
+fn divide(a: &Number, b: &Number) -> Result<Number, String> {
+    if let (Number::Integer(a), Number::Integer(b)) = (a, b) {
+        let (quotient, remainder) = a.div_rem(b).ok_or("/: division by zero")?;
+        if remainder.is_zero() {
+            return Ok(Number::Integer(quotient));
+        }
+    }
+    Ok(Number::Flonum(a.to_f64() / b.to_f64()))
+}
+
+/// `(/ z)` is the reciprocal of `z`; `(/ z1 z2 ...)` divides `z1` by the
+/// others.
+pub(crate) extern "C" fn rt_div(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let values = number_args(args, "/")?;
+        let quotient = match values.as_slice() {
+            [value] => divide(&Number::Integer(BigInt::from(1)), value)?,
+            [first, rest @ ..] => rest
+                .iter()
+                .try_fold(first.clone(), |quotient, value| divide(&quotient, value))?,
+            [] => unreachable!("/ takes at least one argument"),
+        };
+        number_result(rt, quotient, "/")
+    })
+}
+
+/// The quotient and remainder of the two arguments, truncating.
+fn divide_integers(args: &StackArgs, name: &str) -> Result<(BigInt, BigInt), String> {
+    let dividend = integer_arg(args, 0, name)?;
+    let divisor = integer_arg(args, 1, name)?;
+    dividend
+        .div_rem(&divisor)
+        .ok_or_else(|| format!("{}: division by zero", name))
+}
+
+/// `(quotient n1 n2)`
+pub(crate) extern "C" fn rt_quotient(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let (quotient, _) = divide_integers(args, "quotient")?;
+        integer_result(rt, &quotient, "quotient")
+    })
+}
+
+/// `(remainder n1 n2)`, which has the sign of `n1`.
+pub(crate) extern "C" fn rt_remainder(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let (_, remainder) = divide_integers(args, "remainder")?;
+        integer_result(rt, &remainder, "remainder")
+    })
+}
+
+/// `(expt z1 z2)`: exact for an integer base and a non-negative integer
+/// exponent, and a flonum otherwise.
+pub(crate) extern "C" fn rt_expt(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let base = number_arg(args, 0, "expt")?;
+        let exponent = number_arg(args, 1, "expt")?;
+        let (Number::Integer(base), Number::Integer(exponent)) = (&base, &exponent) else {
+            let power = base.to_f64().powf(exponent.to_f64());
+            return number_result(rt, Number::Flonum(power), "expt");
+        };
+        if exponent.is_negative() {
+            let power = base.to_f64().powf(exponent.to_f64());
+            return number_result(rt, Number::Flonum(power), "expt");
+        }
+        // 0, 1 and -1 stay small whatever the exponent; anything else must
+        // fit the heap.
+        let odd = exponent.limbs().first().is_some_and(|limb| limb & 1 == 1);
+        let power = if exponent.is_zero() || (base.is_negative() && base.bits() == 1 && !odd) {
+            BigInt::from(1)
+        } else if base.bits() <= 1 {
+            base.clone()
+        } else {
+            let heap_bits = rt.heap().limit() as u64 * 8;
+            match exponent
+                .to_i64()
+                .and_then(|exponent| u32::try_from(exponent).ok())
+            {
+                Some(exponent) if base.bits() * exponent as u64 <= heap_bits => base.pow(exponent),
+                _ => return Err(heap_exhausted("expt")),
+            }
+        };
+        integer_result(rt, &power, "expt")
+    })
+}
+
+/// `(gcd n ...)`, which is 0 without arguments.
+pub(crate) extern "C" fn rt_gcd(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let gcd = integer_args(args, "gcd")?
+            .iter()
+            .fold(BigInt::zero(), |gcd, value| gcd.gcd(value));
+        integer_result(rt, &gcd, "gcd")
+    })
+}
+
+/// `a` against `b`, or `None` if either is a NaN.
+fn compare(a: &Number, b: &Number) -> Option<Ordering> {
+    match (a, b) {
+        (Number::Integer(a), Number::Integer(b)) => Some(a.cmp(b)),
+        _ => a.to_f64().partial_cmp(&b.to_f64()),
+    }
+}
+
+/// Whether `holds` of each argument against the next.
+fn compare_all(
+    args: &StackArgs,
+    name: &str,
+    holds: fn(Ordering) -> bool,
+) -> Result<LispValue, String> {
+    let values = number_args(args, name)?;
+    let ordered = values
+        .windows(2)
+        .all(|pair| compare(&pair[0], &pair[1]).is_some_and(holds));
+    Ok(LispValue::from_bool(ordered))
+}
+
+/// `(= z1 z2 ...)`
+pub(crate) extern "C" fn rt_num_eq(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |_, args| {
+        compare_all(args, "=", Ordering::is_eq)
+    })
+}
+
+/// `(< x1 x2 ...)`
+pub(crate) extern "C" fn rt_num_lt(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |_, args| {
+        compare_all(args, "<", Ordering::is_lt)
+    })
+}
+
+/// `(> x1 x2 ...)`
+pub(crate) extern "C" fn rt_num_gt(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |_, args| {
+        compare_all(args, ">", Ordering::is_gt)
+    })
+}
+
+/// `(<= x1 x2 ...)`
+pub(crate) extern "C" fn rt_num_le(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |_, args| {
+        compare_all(args, "<=", Ordering::is_le)
+    })
+}
+
+/// `(>= x1 x2 ...)`
+pub(crate) extern "C" fn rt_num_ge(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |_, args| {
+        compare_all(args, ">=", Ordering::is_ge)
+    })
+}
+
+/// `(exact? z)`
+pub(crate) extern "C" fn rt_is_exact(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |_, args| {
+        Ok(LispValue::from_bool(
+            number_arg(args, 0, "exact?")?.is_exact(),
+        ))
+    })
+}
+
+/// `(inexact? z)`
+pub(crate) extern "C" fn rt_is_inexact(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |_, args| {
+        Ok(LispValue::from_bool(
+            !number_arg(args, 0, "inexact?")?.is_exact(),
+        ))
+    })
+}
+
+/// `(zero? z)`, for the numbers that are not fixnums.
+pub(crate) extern "C" fn rt_is_zero(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |_, args| {
+        let zero = match number_arg(args, 0, "zero?")? {
+            Number::Integer(value) => value.is_zero(),
+            Number::Flonum(value) => value == 0.0,
+        };
+        Ok(LispValue::from_bool(zero))
+    })
+}
+
+/// `(integer? obj)` for a flonum, which is an integer when it is whole.
+pub(crate) extern "C" fn rt_is_integer(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |_, args| {
+        let integer = match args.get(0).as_flonum() {
+            Some(value) => value.is_finite() && value.fract() == 0.0,
+            None => integer_value(args.get(0)).is_some(),
+        };
+        Ok(LispValue::from_bool(integer))
+    })
+}
+
+/// `(exact->inexact z)`
+pub(crate) extern "C" fn rt_exact_to_inexact(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = number_arg(args, 0, "exact->inexact")?.to_f64();
+        number_result(rt, Number::Flonum(value), "exact->inexact")
+    })
+}
+
+/// `(inexact->exact z)`, for flonums holding whole numbers.
+pub(crate) extern "C" fn rt_inexact_to_exact(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = match number_arg(args, 0, "inexact->exact")? {
+            Number::Flonum(value) => BigInt::from_f64(value).ok_or_else(|| {
+                format!(
+                    "inexact->exact: no exact representation of {}",
+                    format_flonum(value)
+                )
+            })?,
+            Number::Integer(value) => value,
+        };
+        integer_result(rt, &value, "inexact->exact")
+    })
+}
+
+/// Rounds a flonum argument to a whole flonum with `round`; integers are
+/// whole already.
+fn round_with(args: &StackArgs, name: &str, round: fn(f64) -> f64) -> Result<Number, String> {
+    Ok(match number_arg(args, 0, name)? {
+        Number::Flonum(value) => Number::Flonum(round(value)),
+        integer => integer,
+    })
+}
+
+/// `(floor x)`
+pub(crate) extern "C" fn rt_floor(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = round_with(args, "floor", f64::floor)?;
+        number_result(rt, value, "floor")
+    })
+}
+
+/// `(ceiling x)`
+pub(crate) extern "C" fn rt_ceiling(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = round_with(args, "ceiling", f64::ceil)?;
+        number_result(rt, value, "ceiling")
+    })
+}
+
+/// `(round x)`, which rounds halves to even.
+pub(crate) extern "C" fn rt_round(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = round_with(args, "round", f64::round_ties_even)?;
+        number_result(rt, value, "round")
+    })
+}
+
+/// `(truncate x)`
+pub(crate) extern "C" fn rt_truncate(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = round_with(args, "truncate", f64::trunc)?;
+        number_result(rt, value, "truncate")
+    })
+}
+
+/// `(sqrt z)`: exact for the squares of integers, a flonum otherwise. The
+/// root of a negative number is NaN, there being no complex numbers.
+pub(crate) extern "C" fn rt_sqrt(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let value = number_arg(args, 0, "sqrt")?;
+        if let Number::Integer(value) = &value
+            && !value.is_negative()
+        {
+            let root = value.sqrt();
+            if &(&root * &root) == value {
+                return integer_result(rt, &root, "sqrt");
+            }
+        }
+        number_result(rt, Number::Flonum(value.to_f64().sqrt()), "sqrt")
+    })
+}
+
+/// Applies `function` to the argument as a double.
+fn transcendental(
+    rt: &mut Runtime,
+    args: &StackArgs,
+    name: &str,
+    function: fn(f64) -> f64,
+) -> Result<LispValue, String> {
+    let value = number_arg(args, 0, name)?.to_f64();
+    number_result(rt, Number::Flonum(function(value)), name)
+}
+
+/// `(exp z)`
+pub(crate) extern "C" fn rt_exp(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        transcendental(rt, args, "exp", f64::exp)
+    })
+}
+
+/// `(log z)`, the natural logarithm.
+pub(crate) extern "C" fn rt_log(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        transcendental(rt, args, "log", f64::ln)
+    })
+}
+
+/// `(sin z)`
+pub(crate) extern "C" fn rt_sin(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        transcendental(rt, args, "sin", f64::sin)
+    })
+}
+
+/// `(cos z)`
+pub(crate) extern "C" fn rt_cos(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        transcendental(rt, args, "cos", f64::cos)
+    })
+}
+
+/// `(atan z)`
+pub(crate) extern "C" fn rt_atan(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        transcendental(rt, args, "atan", f64::atan)
+    })
+}
+
+/// Copies an integer literal too large for a fixnum, which the runtime
+/// keeps, into a new bignum.
+pub(crate) extern "C" fn rt_bignum_literal(rt: *mut Runtime, literal: *const BigInt) -> Word {
+    let rt = unsafe { &mut *rt };
+    match rt.alloc_integer(unsafe { &*literal }) {
+        Some(value) => value.as_raw_word(),
+        None => {
+            rt.raise(heap_exhausted("integer literal"));
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encodings::{K_INTEGER_MAX, K_INTEGER_MIN};
+    use crate::runtime::Runtime;
+    use crate::testing::{assert_errors, assert_evals, eval_str};
+
+    #[test]
+    fn test_generic_arithmetic() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("(+)", "0"),
+            ("(+ 1 2 3)", "6"),
+            ("(- 5)", "-5"),
+            ("(- 10 1 2)", "7"),
+            ("(*)", "1"),
+            ("(* 2 -3 4)", "-24"),
+            ("(quotient 17 5)", "3"),
+            ("(quotient -17 5)", "-3"),
+            ("(remainder 17 -5)", "2"),
+            ("(remainder -17 5)", "-2"),
+            ("(gcd)", "0"),
+            ("(gcd 12 -18 27)", "3"),
+            ("(expt 3 4)", "81"),
+            ("(expt -1 1000001)", "-1"),
+            ("(expt 0 0)", "1"),
+            // Overflowing fixnums moves on to bignums...
+            ("(+ 2305843009213693951 1)", "2305843009213693952"),
+            ("(- -2305843009213693952 1)", "-2305843009213693953"),
+            ("(- -2305843009213693952)", "2305843009213693952"),
+            ("(* 4294967296 4294967296)", "18446744073709551616"),
+            ("(quotient -2305843009213693952 -1)", "2305843009213693952"),
+            ("(expt 2 100)", "1267650600228229401496703205376"),
+            (
+                "(* 123456789012345678901234567890 -987654321098765432109876543210)",
+                "-121932631137021795226185032733622923332237463801111263526900",
+            ),
+            // ... and results that fit come back as fixnums.
+            ("(fixnum? (- (+ 1 (expt 2 100)) (expt 2 100)))", "#t"),
+            ("(fixnum? (quotient (expt 10 30) (expt 10 29)))", "#t"),
+            ("(remainder (expt 10 30) 7)", "1"),
+            ("(gcd (expt 2 100) (expt 6 50))", "1125899906842624"),
+            ("(integer? (expt 2 100))", "#t"),
+            ("(zero? 0)", "#t"),
+            ("(zero? (expt 2 100))", "#f"),
+            ("(number? 1)", "#t"),
+            ("(integer? #\\a)", "#f"),
+            ("(string? (expt 2 100))", "#f"),
+            ("(bignum? \"abc\")", "#f"),
+            ("(= 1 1 1)", "#t"),
+            ("(< 1 2 2)", "#f"),
+            ("(<= 1 2 2)", "#t"),
+            ("(> (expt 2 70) (expt 2 69) 1)", "#t"),
+            ("(>= -1 (- (expt 2 70)))", "#t"),
+            ("(= (expt 2 70) (* (expt 2 35) (expt 2 35)))", "#t"),
+        ];
+        assert_evals(&mut runtime, &cases);
+        // Across the edge of the fixnums, which depends on the representation.
+        let (max, min) = (K_INTEGER_MAX as i128, K_INTEGER_MIN as i128);
+        for (input, expected) in [
+            (format!("(+ {} 1)", max), (max + 1).to_string()),
+            (format!("(- {} 1)", min), (min - 1).to_string()),
+            (format!("(- {})", min), (-min).to_string()),
+            (format!("(* {} 2)", max), (max * 2).to_string()),
+            (format!("(fixnum? (- (+ {} 1) 1))", max), "#t".to_string()),
+            (format!("(fixnum? (+ {} 1))", max), "#f".to_string()),
+        ] {
+            assert_eq!(eval_str(&mut runtime, &input), Ok(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_flonum_arithmetic() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("1.5", "1.5"),
+            ("-0.25", "-0.25"),
+            ("1e21", "1e21"),
+            ("(+ 1.5 2.25)", "3.75"),
+            ("(+ 1 0.5 2)", "3.5"),
+            ("(- 1.5)", "-1.5"),
+            ("(- 0.0)", "-0.0"),
+            ("(- 10 0.5 2)", "7.5"),
+            ("(* 1.5 4)", "6.0"),
+            ("(* 2 0.5 (expt 2 70))", "1.1805916207174113e21"),
+            ("(/ 12 4)", "3"),
+            ("(/ 7 2)", "3.5"),
+            ("(/ 4)", "0.25"),
+            ("(/ 1 2.0 2)", "0.25"),
+            ("(/ 1.0 0)", "+inf.0"),
+            ("(/ -1 0.0)", "-inf.0"),
+            ("(/ (expt 2 70) (expt 2 68))", "4"),
+            ("(= 1 1.0)", "#t"),
+            ("(< 1 1.5 2)", "#t"),
+            ("(< 1.5 1)", "#f"),
+            ("(>= 2.0 2)", "#t"),
+            ("(> 0.5 (expt 2 70))", "#f"),
+            ("(= (/ 0.0 0.0) (/ 0.0 0.0))", "#f"),
+            ("(< (/ 0.0 0.0) 1)", "#f"),
+            ("(exact->inexact 3)", "3.0"),
+            ("(inexact->exact 3.0)", "3"),
+            ("(inexact->exact 1e20)", "100000000000000000000"),
+            ("(exact? 1)", "#t"),
+            ("(inexact? 1.0)", "#t"),
+            ("(floor 2.5)", "2.0"),
+            ("(floor -2.5)", "-3.0"),
+            ("(ceiling 2.1)", "3.0"),
+            ("(round 2.5)", "2.0"),
+            ("(round 3.5)", "4.0"),
+            ("(truncate -2.7)", "-2.0"),
+            ("(floor 7)", "7"),
+            ("(sqrt 16)", "4"),
+            ("(sqrt (expt 10 40))", "100000000000000000000"),
+            ("(sqrt 2)", "1.4142135623730951"),
+            ("(sqrt 2.25)", "1.5"),
+            ("(exp 0)", "1.0"),
+            ("(log 1)", "0.0"),
+            ("(sin 0)", "0.0"),
+            ("(expt 2 -1)", "0.5"),
+            ("(expt 2.0 3)", "8.0"),
+            ("(expt 4 0.5)", "2.0"),
+            ("(number? 1.5)", "#t"),
+            ("(integer? 1.5)", "#f"),
+            ("(integer? 2.0)", "#t"),
+            ("(integer? -0.0)", "#t"),
+            ("(integer? (/ 1.0 0))", "#f"),
+            ("(integer? (/ 0.0 0.0))", "#f"),
+            ("(zero? 0.0)", "#t"),
+            ("(zero? -0.0)", "#t"),
+            ("(zero? 0.5)", "#f"),
+            ("(zero? (/ 0.0 0.0))", "#f"),
+            ("(flonum? 1)", "#f"),
+        ];
+        assert_evals(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_flonums_survive_collections() {
+        // Every inline flonum result allocates, so under stress each one
+        // collects with the operands in flight.
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        runtime.set_gc_stress(true);
+        runtime.set_heap_verification(true);
+        eval_str(&mut runtime, "(define x (/ 1 4))").unwrap();
+        assert_eq!(
+            eval_str(&mut runtime, "(cons (+ x 1 x) (* x 2 x))").as_deref(),
+            Ok("(1.5 . 0.125)")
+        );
+        assert_eq!(
+            eval_str(&mut runtime, "(- (* x 8) x 0.5)").as_deref(),
+            Ok("1.25")
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("(+ 1 'a)", "+: expected number, got a"),
+            ("(* (expt 2 70) nil)", "*: expected number, got ()"),
+            ("(- \"x\")", "-: expected number, got \"x\""),
+            ("(quotient 1 0)", "quotient: division by zero"),
+            ("(remainder (expt 2 70) 0)", "remainder: division by zero"),
+            ("(expt 2 (expt 2 40))", "expt: heap exhausted"),
+            ("(/ 1 0)", "/: division by zero"),
+            ("(/ 'a 2.0)", "/: expected number, got a"),
+            ("(< 1 2.0 #\\a)", "<: expected number, got #\\a"),
+            ("(quotient 1.5 1)", "quotient: expected integer, got 1.5"),
+            (
+                "(inexact->exact 0.5)",
+                "inexact->exact: no exact representation of 0.5",
+            ),
+            ("(sqrt 'x)", "sqrt: expected number, got x"),
+            ("(zero? 'x)", "zero?: expected number, got x"),
+            (
+                "(string-length (expt 2 70))",
+                "string-length: expected string, got 1180591620717411303424",
+            ),
+        ];
+        assert_errors(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_bignums_survive_collections() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        runtime.set_gc_stress(true);
+        runtime.set_heap_verification(true);
+        eval_str(&mut runtime, "(define big (expt 7 77))").unwrap();
+        let value = eval_str(&mut runtime, "(cons big (* big big))").unwrap();
+        let big = "118181386580595879976868414312001964434038548836769923458287039207";
+        assert!(value.starts_with(&format!("({} . ", big)), "{}", value);
+        assert_eq!(
+            eval_str(&mut runtime, "(quotient (* big big) big)").as_deref(),
+            Ok(big)
+        );
+        assert_eq!(
+            eval_str(&mut runtime, "(heap-object-count 'bignum)").as_deref(),
+            Ok("1")
+        );
+    }
+}
@@ -0,0 +1,992 @@
+// Persistent vectors and maps, after Clojure's. Updating one returns a new
+// collection and leaves the old one as it was: the two share every node but
+// those on the path to the change, which the update copies.
+//
+// Both are tries of nodes with up to 32 slots, each node a vector that no
+// Lisp code gets hold of. A vector keeps its elements in the bottom nodes;
+// five bits of an index at a time, from the top, pick the child to follow.
+// The nodes only grow as far as they are filled, so the rightmost ones may
+// be short. A map is a hash array mapped trie: five bits of the hash of a
+// key at a time pick a slot, and a node stores only the slots in use, as a
+// bitmap of them followed by a key and a value for each. Where the key is
+// unbound, the value is the child node the slot leads to. Keys whose 32 bits
+// of hash all agree end up together in a node past the last level, which is
+// searched in order.
+//
+// Keys are compared with `equal?` and hashed by their contents. A map cannot
+// rehash itself after a collection the way a hash table does, as other maps
+// may share its nodes, so what only compares by identity, such as a
+// procedure, hashes by its kind of object rather than its address: such
+// keys work, but collide with each other.
+//
+// Allocating may move any object, so an update first plans the nodes it
+// makes, then allocates them all while the values it needs are roots, and
+// only then reads the old nodes again to fill the new ones.
+
+use crate::encodings::{LispValue, PersistentMap, PersistentVector, Word};
+use crate::equality::equal;
+use crate::gc;
+use crate::runtime::{Runtime, call_with_stack_args as call};
+use crate::strings::heap_exhausted;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// Bits of an index or hash that pick a slot at each level.
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+/// Bits of the hash of a map key; levels from there on are collision nodes.
+const HASH_BITS: usize = 32;
+/// How many of the values making up a key are hashed.
+const HASH_LIMIT: usize = 32;
+
+/// The slots of the node `node`.
+fn slots<'a>(node: LispValue) -> &'a mut [LispValue] {
+    unsafe { (*node.as_vector_pointer().unwrap()).elements_mut() }
+}
+
+fn vector<'a>(value: LispValue) -> &'a PersistentVector {
+    unsafe { &*value.as_persistent_vector_pointer().unwrap() }
+}
+
+fn map<'a>(value: LispValue) -> &'a PersistentMap {
+    unsafe { &*value.as_persistent_map_pointer().unwrap() }
+}
+
+fn integer(value: LispValue) -> usize {
+    value.as_integer().unwrap() as usize
+}
+
+/// Where a slot of a new node takes its value from.
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    /// Slot `index` of the old node at `level` of the path.
+    Old(usize, usize),
+    /// The old node at `level` of the path itself.
+    OldNode(usize),
+    /// Value `index` of those the update adds.
+    Arg(usize),
+    /// The node made just before this one.
+    Below,
+    /// An immediate, which no collection moves.
+    Immediate(LispValue),
+}
+
+/// The nodes an update makes, children before their parents.
+#[derive(Default)]
+struct Plan {
+    /// The slots followed from the old root down: level 0 is the root, and
+    /// each further level the node in slot `path[level - 1]` of the last.
+    path: Vec<usize>,
+    nodes: Vec<Vec<Source>>,
+}
+
+/// The slots of a copy of the old node at `level`, `length` slots long.
+fn old_slots(level: usize, length: usize) -> Vec<Source> {
+    (0..length).map(|slot| Source::Old(level, slot)).collect()
+}
+
+impl Plan {
+    /// Adds copies of the nodes on the path, listed from the top, to the
+    /// nodes planned so far, which go below the bottom one.
+    fn add_copies(&mut self, copies: Vec<Vec<Source>>) {
+        self.nodes.extend(copies.into_iter().rev());
+    }
+
+    /// Makes the nodes for an update of the trie `root` adding `args`, and
+    /// returns the last one made, or nil if there are none. `None` if the
+    /// heap is exhausted.
+    fn build(&self, rt: &mut Runtime, root: LispValue, args: &[LispValue]) -> Option<LispValue> {
+        rt.push_root(root);
+        args.iter().for_each(|arg| rt.push_root(*arg));
+        let mut made = 0;
+        for node in &self.nodes {
+            let Some(node) = rt.alloc_vector(node.len(), LispValue::nil()) else {
+                break;
+            };
+            rt.push_root(node);
+            made += 1;
+        }
+        let mut nodes: Vec<_> = (0..made).map(|_| rt.pop_root()).collect();
+        nodes.reverse();
+        let mut args: Vec<_> = args.iter().map(|_| rt.pop_root()).collect();
+        args.reverse();
+        let root = rt.pop_root();
+        if made < self.nodes.len() {
+            return None;
+        }
+
+        // Nothing moves from here on.
+        let mut old = vec![root];
+        for &slot in &self.path {
+            old.push(slots(*old.last().unwrap())[slot]);
+        }
+        for (index, sources) in self.nodes.iter().enumerate() {
+            for (slot, source) in sources.iter().enumerate() {
+                let value = match *source {
+                    Source::Old(level, slot) => slots(old[level])[slot],
+                    Source::OldNode(level) => old[level],
+                    Source::Arg(arg) => args[arg],
+                    Source::Below => nodes[index - 1],
+                    Source::Immediate(value) => value,
+                };
+                // The collection run by a later allocation may have promoted
+                // the node.
+                let field = &mut slots(nodes[index])[slot];
+                *field = value;
+                rt.write_barrier(field as *mut LispValue as usize);
+            }
+        }
+        Some(nodes.last().copied().unwrap_or(LispValue::nil()))
+    }
+}
+
+/// Allocates an empty persistent vector.
+pub fn empty_vector(rt: &mut Runtime) -> Option<LispValue> {
+    let root = rt.alloc_vector(0, LispValue::nil())?;
+    rt.alloc_persistent_vector(0, 0, root)
+}
+
+/// Allocates an empty persistent map.
+pub fn empty_map(rt: &mut Runtime) -> Option<LispValue> {
+    rt.alloc_persistent_map(0, LispValue::nil())
+}
+
+/// The element at `index` of the persistent vector `collection`.
+fn vector_get(collection: LispValue, index: usize) -> Option<LispValue> {
+    let vector = vector(collection);
+    if index >= integer(vector.count) {
+        return None;
+    }
+    let mut node = vector.root;
+    let mut shift = integer(vector.shift);
+    loop {
+        let slot = slots(node)[(index >> shift) & MASK];
+        if shift == 0 {
+            return Some(slot);
+        }
+        node = slot;
+        shift -= BITS;
+    }
+}
+
+/// Appends the elements of the trie `node` with top level `shift` to `out`.
+fn collect_elements(node: LispValue, shift: usize, out: &mut Vec<LispValue>) {
+    if shift == 0 {
+        out.extend_from_slice(slots(node));
+    } else {
+        for child in slots(node) {
+            collect_elements(*child, shift - BITS, out);
+        }
+    }
+}
+
+/// Plans the nodes below a new slot of a node at level `shift` of a vector,
+/// down to a bottom node holding the added element.
+fn plan_new_path(plan: &mut Plan, shift: usize) {
+    plan.nodes.push(vec![Source::Arg(0)]);
+    for _ in 0..shift / BITS {
+        plan.nodes.push(vec![Source::Below]);
+    }
+}
+
+/// The persistent vector `collection` with `element` at `index`, which is
+/// appended if `index` is the count.
+fn vector_assoc(
+    rt: &mut Runtime,
+    name: &str,
+    collection: LispValue,
+    index: usize,
+    element: LispValue,
+) -> Result<LispValue, String> {
+    let vector = vector(collection);
+    let (count, shift, root) = (integer(vector.count), integer(vector.shift), vector.root);
+    if index > count {
+        return Err(format!("{}: index {} out of range", name, index));
+    }
+    let mut plan = Plan::default();
+    let mut new_shift = shift;
+    if count == WIDTH << shift {
+        // The trie is full: a new root holds it and the path to the element.
+        plan_new_path(&mut plan, shift);
+        plan.nodes.push(vec![Source::OldNode(0), Source::Below]);
+        new_shift += BITS;
+    } else {
+        let mut copies = Vec::new();
+        let mut node = root;
+        let mut shift = shift;
+        loop {
+            let slot = (index >> shift) & MASK;
+            let mut copy = old_slots(copies.len(), slots(node).len());
+            if slot == copy.len() {
+                if shift == 0 {
+                    copy.push(Source::Arg(0));
+                } else {
+                    plan_new_path(&mut plan, shift - BITS);
+                    copy.push(Source::Below);
+                }
+                copies.push(copy);
+                break;
+            }
+            if shift == 0 {
+                copy[slot] = Source::Arg(0);
+                copies.push(copy);
+                break;
+            }
+            copy[slot] = Source::Below;
+            copies.push(copy);
+            plan.path.push(slot);
+            node = slots(node)[slot];
+            shift -= BITS;
+        }
+        plan.add_copies(copies);
+    }
+    let exhausted = || heap_exhausted(name);
+    let root = plan.build(rt, root, &[element]).ok_or_else(exhausted)?;
+    rt.alloc_persistent_vector(count.max(index + 1), new_shift, root)
+        .ok_or_else(exhausted)
+}
+
+/// The hash of a map key, which agrees with `equal?` and does not depend on
+/// where any object is.
+fn hash(key: LispValue) -> u32 {
+    let mut hasher = DefaultHasher::new();
+    let mut budget = HASH_LIMIT;
+    let mut pending = vec![key];
+    while let Some(value) = pending.pop()
+        && budget > 0
+    {
+        budget -= 1;
+        if let Some(value) = value.as_flonum() {
+            value.to_bits().hash(&mut hasher);
+        } else if let Some(ptr) = value.as_bignum_pointer() {
+            unsafe { ((*ptr).is_negative(), (*ptr).limbs()).hash(&mut hasher) };
+        } else if let Some(ptr) = value.as_string_pointer() {
+            unsafe { (*ptr).as_str() }.hash(&mut hasher);
+        } else if let Some(ptr) = value.as_symbol_pointer() {
+            ("symbol", unsafe { (*ptr).as_str() }).hash(&mut hasher);
+        } else if let Some(ptr) = value.as_pair_pointer() {
+            let pair = unsafe { *ptr };
+            "pair".hash(&mut hasher);
+            pending.push(pair.cdr);
+            pending.push(pair.car);
+        } else if let Some(ptr) = value.as_vector_pointer() {
+            let elements = unsafe { (*ptr).elements() };
+            ("vector", elements.len()).hash(&mut hasher);
+            pending.extend(elements.iter().rev());
+        } else if let Some(elements) = value.elements() {
+            ("persistent-vector", elements.len()).hash(&mut hasher);
+            pending.extend(elements.iter().rev());
+        } else if value.is_persistent_map() {
+            // Entries come in the order of their hashes, which another map
+            // with the same entries may not share.
+            ("persistent-map", value.count()).hash(&mut hasher);
+        } else if let Some(address) = value.heap_address() {
+            unsafe { gc::object_kind(address) }.hash(&mut hasher);
+        } else {
+            value.as_raw_word().hash(&mut hasher);
+        }
+    }
+    hasher.finish() as u32
+}
+
+/// The slot for `hash` at level `shift` of a map node with `bitmap`: its bit
+/// in the bitmap, and where its key is or goes in the node.
+fn map_slot(bitmap: usize, hash: u32, shift: usize) -> (usize, usize) {
+    let bit = 1 << ((hash as usize >> shift) & MASK);
+    (bit, 1 + 2 * (bitmap & (bit - 1)).count_ones() as usize)
+}
+
+fn bitmap(bits: usize) -> Source {
+    Source::Immediate(LispValue::from_integer(bits as Word))
+}
+
+/// The value for `key` in the persistent map `collection`.
+fn map_get(collection: LispValue, key: LispValue) -> Option<LispValue> {
+    let key_hash = hash(key);
+    let mut node = map(collection).root;
+    let mut shift = 0;
+    while !node.is_nil() {
+        let slots = slots(node);
+        if shift >= HASH_BITS {
+            return slots[1..]
+                .chunks(2)
+                .find(|entry| equal(entry[0], key))
+                .map(|entry| entry[1]);
+        }
+        let (bit, at) = map_slot(integer(slots[0]), key_hash, shift);
+        if integer(slots[0]) & bit == 0 {
+            return None;
+        }
+        if !slots[at].is_unbound() {
+            return equal(slots[at], key).then_some(slots[at + 1]);
+        }
+        node = slots[at + 1];
+        shift += BITS;
+    }
+    None
+}
+
+/// Appends the entries of the map trie `node` at level `shift` to `out`.
+fn collect_entries(node: LispValue, shift: usize, out: &mut Vec<(LispValue, LispValue)>) {
+    if node.is_nil() {
+        return;
+    }
+    for entry in slots(node)[1..].chunks(2) {
+        if shift < HASH_BITS && entry[0].is_unbound() {
+            collect_entries(entry[1], shift + BITS, out);
+        } else {
+            out.push((entry[0], entry[1]));
+        }
+    }
+}
+
+/// Plans the nodes at level `shift` and below holding two entries: the old
+/// one in slot `at` of the node at `level`, whose key hashes to `old_hash`,
+/// and the added one, whose key hashes to `key_hash`.
+fn plan_split(
+    plan: &mut Plan,
+    level: usize,
+    at: usize,
+    old_hash: u32,
+    key_hash: u32,
+    shift: usize,
+) {
+    let old = [Source::Old(level, at), Source::Old(level, at + 1)];
+    let added = [Source::Arg(0), Source::Arg(1)];
+    // The levels where both keys take the same slot, from the top.
+    let mut shared = Vec::new();
+    let mut shift = shift;
+    let bottom = loop {
+        if shift >= HASH_BITS {
+            break [&[bitmap(0)][..], &old, &added].concat();
+        }
+        let (old_bit, _) = map_slot(0, old_hash, shift);
+        let (bit, _) = map_slot(0, key_hash, shift);
+        if old_bit != bit {
+            let (first, second) = if old_bit < bit {
+                (old, added)
+            } else {
+                (added, old)
+            };
+            break [&[bitmap(old_bit | bit)][..], &first, &second].concat();
+        }
+        shared.push(bit);
+        shift += BITS;
+    };
+    plan.nodes.push(bottom);
+    for bit in shared.into_iter().rev() {
+        let unbound = Source::Immediate(LispValue::unbound());
+        plan.nodes.push(vec![bitmap(bit), unbound, Source::Below]);
+    }
+}
+
+/// The persistent map `collection` with `key` bound to `element`.
+fn map_assoc(
+    rt: &mut Runtime,
+    name: &str,
+    collection: LispValue,
+    key: LispValue,
+    element: LispValue,
+) -> Result<LispValue, String> {
+    let map = map(collection);
+    let (count, root) = (integer(map.count), map.root);
+    let key_hash = hash(key);
+    let mut plan = Plan::default();
+    let mut added = true;
+    if root.is_nil() {
+        let (bit, _) = map_slot(0, key_hash, 0);
+        plan.nodes
+            .push(vec![bitmap(bit), Source::Arg(0), Source::Arg(1)]);
+    } else {
+        let mut copies = Vec::new();
+        let mut node = root;
+        let mut shift = 0;
+        loop {
+            let level = copies.len();
+            let slots = slots(node);
+            let mut copy = old_slots(level, slots.len());
+            if shift >= HASH_BITS {
+                match slots[1..].chunks(2).position(|entry| equal(entry[0], key)) {
+                    Some(entry) if slots[2 + 2 * entry] == element => return Ok(collection),
+                    Some(entry) => {
+                        copy[2 + 2 * entry] = Source::Arg(1);
+                        added = false;
+                    }
+                    None => copy.extend([Source::Arg(0), Source::Arg(1)]),
+                }
+                copies.push(copy);
+                break;
+            }
+            let bits = integer(slots[0]);
+            let (bit, at) = map_slot(bits, key_hash, shift);
+            if bits & bit == 0 {
+                copy[0] = bitmap(bits | bit);
+                copy.splice(at..at, [Source::Arg(0), Source::Arg(1)]);
+            } else if slots[at].is_unbound() {
+                copy[at + 1] = Source::Below;
+                copies.push(copy);
+                plan.path.push(at + 1);
+                node = slots[at + 1];
+                shift += BITS;
+                continue;
+            } else if equal(slots[at], key) {
+                if slots[at + 1] == element {
+                    return Ok(collection);
+                }
+                copy[at + 1] = Source::Arg(1);
+                added = false;
+            } else {
+                plan_split(
+                    &mut plan,
+                    level,
+                    at,
+                    hash(slots[at]),
+                    key_hash,
+                    shift + BITS,
+                );
+                copy[at] = Source::Immediate(LispValue::unbound());
+                copy[at + 1] = Source::Below;
+            }
+            copies.push(copy);
+            break;
+        }
+        plan.add_copies(copies);
+    }
+    let exhausted = || heap_exhausted(name);
+    let root = plan
+        .build(rt, root, &[key, element])
+        .ok_or_else(exhausted)?;
+    rt.alloc_persistent_map(count + added as usize, root)
+        .ok_or_else(exhausted)
+}
+
+/// The persistent map `collection` without an entry for `key`.
+fn map_dissoc(
+    rt: &mut Runtime,
+    name: &str,
+    collection: LispValue,
+    key: LispValue,
+) -> Result<LispValue, String> {
+    let map = map(collection);
+    let (count, root) = (integer(map.count), map.root);
+    let key_hash = hash(key);
+    let mut plan = Plan::default();
+    // The length of each node on the path to the entry, where the entry or
+    // the slot leading to it is, and the bits of the bitmap.
+    let mut path = Vec::new();
+    let mut node = root;
+    let mut shift = 0;
+    let found = loop {
+        if node.is_nil() {
+            break false;
+        }
+        let slots = slots(node);
+        if shift >= HASH_BITS {
+            let entry = slots[1..].chunks(2).position(|entry| equal(entry[0], key));
+            if let Some(entry) = entry {
+                path.push((slots.len(), 1 + 2 * entry, None));
+            }
+            break entry.is_some();
+        }
+        let bits = integer(slots[0]);
+        let (bit, at) = map_slot(bits, key_hash, shift);
+        if bits & bit == 0 {
+            break false;
+        }
+        path.push((slots.len(), at, Some((bits, bit))));
+        if !slots[at].is_unbound() {
+            break equal(slots[at], key);
+        }
+        plan.path.push(at + 1);
+        node = slots[at + 1];
+        shift += BITS;
+    };
+    if !found {
+        return Ok(collection);
+    }
+    // Nodes the removal leaves empty are removed from their parent in turn.
+    let mut removing = true;
+    for (level, (length, at, bits)) in path.into_iter().enumerate().rev() {
+        let mut copy = old_slots(level, length);
+        if removing {
+            if length == 3 {
+                continue;
+            }
+            copy.drain(at..at + 2);
+            if let Some((bits, bit)) = bits {
+                copy[0] = bitmap(bits & !bit);
+            }
+            removing = false;
+        } else {
+            copy[at + 1] = Source::Below;
+        }
+        plan.nodes.push(copy);
+    }
+    let exhausted = || heap_exhausted(name);
+    let root = plan.build(rt, root, &[]).ok_or_else(exhausted)?;
+    rt.alloc_persistent_map(count - 1, root)
+        .ok_or_else(exhausted)
+}
+
+fn expected_collection(name: &str, value: LispValue) -> String {
+    format!("{}: expected persistent collection, got {}", name, value)
+}
+
+/// `assoc` under the name of the primitive doing it, for its errors.
+fn assoc(
+    rt: &mut Runtime,
+    name: &str,
+    collection: LispValue,
+    key: LispValue,
+    value: LispValue,
+) -> Result<LispValue, String> {
+    if collection.is_persistent_map() {
+        return map_assoc(rt, name, collection, key, value);
+    }
+    if !collection.is_persistent_vector() {
+        return Err(expected_collection(name, collection));
+    }
+    match key.as_integer() {
+        Some(index) if index >= 0 => vector_assoc(rt, name, collection, index as usize, value),
+        _ => Err(format!("{}: expected index, got {}", name, key)),
+    }
+}
+
+/// `conj` under the name of the primitive doing it, for its errors.
+fn conj(
+    rt: &mut Runtime,
+    name: &str,
+    collection: LispValue,
+    value: LispValue,
+) -> Result<LispValue, String> {
+    if let Some(count) = collection.count()
+        && collection.is_persistent_vector()
+    {
+        return vector_assoc(rt, name, collection, count, value);
+    }
+    if !collection.is_persistent_map() {
+        return Err(expected_collection(name, collection));
+    }
+    match value.as_pair_pointer() {
+        Some(pair) => {
+            let pair = unsafe { *pair };
+            map_assoc(rt, name, collection, pair.car, pair.cdr)
+        }
+        None => Err(format!("{}: expected pair, got {}", name, value)),
+    }
+}
+
+/// The Rust side of persistent collections. The updates allocate, so they
+/// take the runtime, and fail with the message the Lisp primitive raises.
+impl LispValue {
+    /// Number of elements of a persistent vector or entries of a persistent map.
+    pub fn count(&self) -> Option<usize> {
+        if let Some(ptr) = self.as_persistent_vector_pointer() {
+            Some(integer(unsafe { (*ptr).count }))
+        } else {
+            self.as_persistent_map_pointer()
+                .map(|ptr| integer(unsafe { (*ptr).count }))
+        }
+    }
+
+    /// The element at index `key` of a persistent vector, or the value for
+    /// `key` in a persistent map.
+    pub fn get(&self, key: LispValue) -> Option<LispValue> {
+        if self.is_persistent_vector() {
+            let index = key.as_integer().filter(|index| *index >= 0)?;
+            vector_get(*self, index as usize)
+        } else if self.is_persistent_map() {
+            map_get(*self, key)
+        } else {
+            None
+        }
+    }
+
+    /// The elements of a persistent vector, in order.
+    pub fn elements(&self) -> Option<Vec<LispValue>> {
+        let vector = self.as_persistent_vector_pointer()?;
+        let vector = unsafe { *vector };
+        let mut elements = Vec::with_capacity(integer(vector.count));
+        collect_elements(vector.root, integer(vector.shift), &mut elements);
+        Some(elements)
+    }
+
+    /// The entries of a persistent map, in no particular order.
+    pub fn entries(&self) -> Option<Vec<(LispValue, LispValue)>> {
+        let map = self.as_persistent_map_pointer()?;
+        let mut entries = Vec::new();
+        collect_entries(unsafe { (*map).root }, 0, &mut entries);
+        Some(entries)
+    }
+
+    /// A persistent map like this one with `key` bound to `value`, or a
+    /// persistent vector like this one with `value` at index `key`, which may
+    /// be one past the end.
+    pub fn assoc(
+        &self,
+        rt: &mut Runtime,
+        key: LispValue,
+        value: LispValue,
+    ) -> Result<LispValue, String> {
+        assoc(rt, "assoc", *self, key, value)
+    }
+
+    /// A persistent map like this one without an entry for `key`.
+    pub fn dissoc(&self, rt: &mut Runtime, key: LispValue) -> Result<LispValue, String> {
+        if !self.is_persistent_map() {
+            return Err(format!("dissoc: expected persistent-map, got {}", self));
+        }
+        map_dissoc(rt, "dissoc", *self, key)
+    }
+
+    /// A persistent vector like this one with `value` appended, or a
+    /// persistent map like this one with the entry in the pair `value`.
+    pub fn conj(&self, rt: &mut Runtime, value: LispValue) -> Result<LispValue, String> {
+        conj(rt, "conj", *self, value)
+    }
+}
+
+/// `(persistent-vector element...)`
+pub(crate) extern "C" fn rt_persistent_vector(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        const NAME: &str = "persistent-vector";
+        let mut vector = empty_vector(rt).ok_or_else(|| heap_exhausted(NAME))?;
+        for index in 0..args.len() {
+            vector = vector_assoc(rt, NAME, vector, index, args.get(index))?;
+        }
+        Ok(vector)
+    })
+}
+
+/// `(persistent-map key value ...)`
+pub(crate) extern "C" fn rt_persistent_map(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        const NAME: &str = "persistent-map";
+        if args.len() % 2 != 0 {
+            return Err(format!("{}: expected keys and values in pairs", NAME));
+        }
+        let mut map = empty_map(rt).ok_or_else(|| heap_exhausted(NAME))?;
+        for index in (0..args.len()).step_by(2) {
+            map = map_assoc(rt, NAME, map, args.get(index), args.get(index + 1))?;
+        }
+        Ok(map)
+    })
+}
+
+/// `(assoc collection key value)`
+pub(crate) extern "C" fn rt_assoc(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        args.get(0).assoc(rt, args.get(1), args.get(2))
+    })
+}
+
+/// `(dissoc map key)`
+pub(crate) extern "C" fn rt_dissoc(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| {
+        args.get(0).dissoc(rt, args.get(1))
+    })
+}
+
+/// `(conj collection value)`
+pub(crate) extern "C" fn rt_conj(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |rt, args| args.get(0).conj(rt, args.get(1)))
+}
+
+/// `(get collection key [default])`, where the default is nil.
+pub(crate) extern "C" fn rt_get(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |_, args| {
+        let collection = args.get(0);
+        if collection.count().is_none() {
+            return Err(expected_collection("get", collection));
+        }
+        let default = match args.len() {
+            3 => args.get(2),
+            _ => LispValue::nil(),
+        };
+        Ok(collection.get(args.get(1)).unwrap_or(default))
+    })
+}
+
+/// `(count collection)`
+pub(crate) extern "C" fn rt_count(rt: *mut Runtime, args: *const LispValue, argc: usize) -> Word {
+    call(rt, args, argc, |_, args| {
+        let collection = args.get(0);
+        collection
+            .count()
+            .map(|count| LispValue::from_integer(count as Word))
+            .ok_or_else(|| expected_collection("count", collection))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encodings::LispValue;
+    use crate::runtime::Runtime;
+    use crate::testing::{assert_errors, assert_evals, eval_str};
+
+    #[test]
+    fn test_persistent_primitives() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("(define v (persistent-vector 1 'b \"c\"))", "v"),
+            ("v", "[1 b \"c\"]"),
+            ("(persistent-vector)", "[]"),
+            ("(count v)", "3"),
+            ("(get v 1)", "b"),
+            ("(get v 3)", "()"),
+            ("(get v 3 'none)", "none"),
+            ("(get v 'b 'none)", "none"),
+            ("(assoc v 0 'a)", "[a b \"c\"]"),
+            ("(assoc v 3 'd)", "[1 b \"c\" d]"),
+            ("(conj v (vector))", "[1 b \"c\" #()]"),
+            // The updates leave the original as it was.
+            ("v", "[1 b \"c\"]"),
+            ("(define m (persistent-map 'a 1 \"b\" 2))", "m"),
+            ("(count m)", "2"),
+            ("(get m 'a)", "1"),
+            ("(get m (string-append \"b\"))", "2"),
+            ("(get m 'c 'none)", "none"),
+            ("(get (assoc m 'a 10) 'a)", "10"),
+            ("(count (assoc m 'a 10))", "2"),
+            ("(get (assoc m '(c) 3) (cons 'c nil))", "3"),
+            ("(count (assoc m 'c 3))", "3"),
+            ("(get (conj m (cons 'c 3)) 'c)", "3"),
+            ("(dissoc m 'a)", "{\"b\" 2}"),
+            ("(dissoc (dissoc m 'a) \"b\")", "{}"),
+            ("(count (dissoc m 'z))", "2"),
+            ("(cons (get m 'a) (count m))", "(1 . 2)"),
+            ("(persistent-map)", "{}"),
+            ("(eq? m (assoc m 'a 1))", "#t"),
+            ("(eq? m (dissoc m 'z))", "#t"),
+            ("(persistent-vector? v)", "#t"),
+            ("(persistent-map? v)", "#f"),
+            ("(persistent-map? m)", "#t"),
+            // Collections make keys of their own, compared by their contents.
+            ("(define n (persistent-map v 'vector m 'map))", "n"),
+            ("(get n (persistent-vector 1 'b \"c\"))", "vector"),
+            ("(get n (persistent-map \"b\" 2 'a 1))", "map"),
+            ("(get n (conj v 'd) 'none)", "none"),
+            ("(equal? v (persistent-vector 1 'b \"c\"))", "#t"),
+            ("(equal? v (persistent-vector 1 'b))", "#f"),
+            ("(equal? m (dissoc (assoc m 'c 3) 'c))", "#t"),
+            ("(equal? m (assoc m 'a 2))", "#f"),
+            ("(equal? v (vector 1 'b \"c\"))", "#f"),
+        ];
+        assert_evals(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_persistent_errors() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            (
+                "(assoc (persistent-vector 1) 2 'x)",
+                "assoc: index 2 out of range",
+            ),
+            (
+                "(assoc (persistent-vector) -1 'x)",
+                "assoc: expected index, got -1",
+            ),
+            (
+                "(assoc (vector) 0 'x)",
+                "assoc: expected persistent collection, got #()",
+            ),
+            (
+                "(dissoc (persistent-vector) 0)",
+                "dissoc: expected persistent-map, got []",
+            ),
+            ("(conj (persistent-map) 'a)", "conj: expected pair, got a"),
+            (
+                "(count nil)",
+                "count: expected persistent collection, got ()",
+            ),
+            (
+                "(get \"s\" 0)",
+                "get: expected persistent collection, got \"s\"",
+            ),
+            (
+                "(persistent-map 'a)",
+                "persistent-map: expected keys and values in pairs",
+            ),
+        ];
+        assert_errors(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_vectors_grow_levels() {
+        let mut runtime = Runtime::with_heap(1 << 16, 1 << 22);
+        runtime.set_heap_verification(true);
+        eval_str(&mut runtime, "(define v (persistent-vector))").unwrap();
+        eval_str(&mut runtime, "(define versions (make-vector 40 0))").unwrap();
+        // Past 32 * 32 elements the trie has three levels.
+        for i in 0..1200 {
+            eval_str(&mut runtime, &format!("(define v (conj v {}))", i)).unwrap();
+            if i % 32 == 0 {
+                let save = format!("(vector-set! versions {} v)", i / 32);
+                eval_str(&mut runtime, &save).unwrap();
+            }
+        }
+        assert_eq!(eval_str(&mut runtime, "(count v)").unwrap(), "1200");
+        for i in (0..1200).step_by(7) {
+            let get = format!("(get v {})", i);
+            assert_eq!(eval_str(&mut runtime, &get).unwrap(), i.to_string());
+            let set = format!("(define v (assoc v {} (- {})))", i, i);
+            eval_str(&mut runtime, &set).unwrap();
+        }
+        runtime.collect();
+        for i in 0..1200 {
+            let expected = if i % 7 == 0 { -i } else { i };
+            let get = format!("(get v {})", i);
+            assert_eq!(eval_str(&mut runtime, &get).unwrap(), expected.to_string());
+        }
+        // Every saved version still holds what it did.
+        for version in 0..38 {
+            let count = format!("(count (vector-ref versions {}))", version);
+            let expected = (version * 32 + 1).to_string();
+            assert_eq!(eval_str(&mut runtime, &count).unwrap(), expected);
+            let last = format!("(get (vector-ref versions {}) {})", version, version * 32);
+            assert_eq!(
+                eval_str(&mut runtime, &last).unwrap(),
+                (version * 32).to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn test_maps_with_many_entries() {
+        let mut runtime = Runtime::with_heap(1 << 16, 1 << 22);
+        runtime.set_heap_verification(true);
+        eval_str(&mut runtime, "(define m (persistent-map))").unwrap();
+        for i in 0..2000 {
+            let set = format!("(define m (assoc m (cons 'k {}) {}))", i, i);
+            eval_str(&mut runtime, &set).unwrap();
+        }
+        eval_str(&mut runtime, "(define full m)").unwrap();
+        assert_eq!(eval_str(&mut runtime, "(count m)").unwrap(), "2000");
+        for i in (0..2000).filter(|i| i % 3 != 0) {
+            let remove = format!("(define m (dissoc m (cons 'k {})))", i);
+            eval_str(&mut runtime, &remove).unwrap();
+        }
+        runtime.collect();
+        assert_eq!(eval_str(&mut runtime, "(count m)").unwrap(), "667");
+        for i in 0..2000 {
+            let get = format!(
+                "(cons (get m (cons 'k {}) 'none) (get full (cons 'k {})))",
+                i, i
+            );
+            let expected = match i % 3 {
+                0 => format!("({} . {})", i, i),
+                _ => format!("(none . {})", i),
+            };
+            assert_eq!(eval_str(&mut runtime, &get).unwrap(), expected);
+        }
+        for i in (0..2000).step_by(3) {
+            let remove = format!("(define m (dissoc m (cons 'k {})))", i);
+            eval_str(&mut runtime, &remove).unwrap();
+        }
+        assert_eq!(eval_str(&mut runtime, "m").unwrap(), "{}");
+        assert_eq!(eval_str(&mut runtime, "(count full)").unwrap(), "2000");
+    }
+
+    #[test]
+    fn test_colliding_keys() {
+        let mut runtime = Runtime::new();
+        // Procedures only compare by identity, so they all hash alike.
+        for input in [
+            "(define (f) 1)",
+            "(define (g) 2)",
+            "(define (h) 3)",
+            "(define (k) 4)",
+            "(define m (persistent-map f 'f g 'g 'x 'x))",
+            "(define n (assoc m h 'h))",
+        ] {
+            eval_str(&mut runtime, input).unwrap();
+        }
+        let cases = [
+            ("(count n)", "4"),
+            ("(cons (get n f) (cons (get n g) (get n h)))", "(f g . h)"),
+            ("(get m h 'none)", "none"),
+            ("(get (assoc n g 'g2) g)", "g2"),
+            ("(count (assoc n g 'g2))", "4"),
+            ("(get (dissoc n g) g 'none)", "none"),
+            ("(get (dissoc n g) h)", "h"),
+            ("(count (dissoc (dissoc (dissoc n g) h) f))", "1"),
+            ("(dissoc (dissoc (dissoc n g) h) f)", "{x x}"),
+            ("(get n k 'none)", "none"),
+        ];
+        assert_evals(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_updates_under_gc_stress() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 22);
+        runtime.set_heap_verification(true);
+        runtime.set_gc_stress(true);
+        eval_str(&mut runtime, "(define v (persistent-vector))").unwrap();
+        eval_str(&mut runtime, "(define m (persistent-map))").unwrap();
+        for i in 0..100 {
+            let conj = format!("(define v (conj v (cons {} (gensym))))", i);
+            eval_str(&mut runtime, &conj).unwrap();
+            let assoc = format!(
+                "(define m (assoc m (string-append \"k\" (number->string {})) (get v {})))",
+                i, i
+            );
+            eval_str(&mut runtime, &assoc).unwrap();
+        }
+        for i in 0..100 {
+            let get = format!("(car (get m (string-append \"k\" (number->string {}))))", i);
+            assert_eq!(eval_str(&mut runtime, &get).unwrap(), i.to_string());
+            let same = format!(
+                "(eq? (get v {}) (get m (string-append \"k\" (number->string {}))))",
+                i, i
+            );
+            assert_eq!(eval_str(&mut runtime, &same).unwrap(), "#t");
+        }
+        for i in 0..50 {
+            let remove = format!(
+                "(define m (dissoc m (string-append \"k\" (number->string {}))))",
+                i
+            );
+            eval_str(&mut runtime, &remove).unwrap();
+        }
+        assert_eq!(eval_str(&mut runtime, "(count m)").unwrap(), "50");
+    }
+
+    #[test]
+    fn test_rust_api() {
+        let mut runtime = Runtime::new();
+        let rt = &mut runtime;
+        let one = LispValue::from_integer(1);
+        let two = LispValue::from_integer(2);
+        let empty = super::empty_vector(rt).unwrap();
+        let vector = empty.conj(rt, one).unwrap().conj(rt, two).unwrap();
+        assert_eq!(vector.count(), Some(2));
+        assert_eq!(vector.elements(), Some(vec![one, two]));
+        assert_eq!(vector.get(one), Some(two));
+        assert_eq!(vector.assoc(rt, one, one).unwrap().write(), "[1 1]");
+        assert_eq!(empty.count(), Some(0));
+
+        let key = rt.alloc_string("key").unwrap();
+        let map = super::empty_map(rt).unwrap();
+        let map = map.assoc(rt, key, vector).unwrap();
+        assert_eq!(map.count(), Some(1));
+        assert_eq!(map.get(key), Some(vector));
+        assert_eq!(map.entries(), Some(vec![(key, vector)]));
+        assert_eq!(map.write(), "{\"key\" [1 2]}");
+        assert_eq!(map.dissoc(rt, key).unwrap().count(), Some(0));
+        assert_eq!(map.get(one), None);
+        assert_eq!(one.count(), None);
+        assert_eq!(
+            one.conj(rt, two),
+            Err("conj: expected persistent collection, got 1".to_string())
+        );
+    }
+}
@@ -0,0 +1,1392 @@
+use crate::assembler::{Assembler, Label, Register, SetccConditions, XmmRegister};
+use crate::compiler::Compiler;
+use crate::encodings::{
+    Header, K_CHAR_SHIFT, K_CHAR_TAG, K_HEADER_LENGTH_SHIFT, K_HEADER_MAX_LENGTH, K_INTEGER_MASK,
+    K_INTEGER_SHIFT, K_INTEGER_TAG, K_PAIR_TAG, K_STRING_TAG, K_VECTOR_TAG, LispValue, TagsDict,
+    TypeTag, Word,
+};
+use crate::equality::{rt_equal, rt_eqv};
+use crate::hashtables::{
+    rt_hash_table_delete, rt_hash_table_keys, rt_hash_table_ref, rt_hash_table_set,
+    rt_make_hash_table,
+};
+use crate::numbers::{
+    rt_add, rt_add1, rt_atan, rt_ceiling, rt_cos, rt_div, rt_exact_to_inexact, rt_exp, rt_expt,
+    rt_floor, rt_gcd, rt_inexact_to_exact, rt_is_exact, rt_is_inexact, rt_is_integer, rt_is_zero,
+    rt_log, rt_mul, rt_num_eq, rt_num_ge, rt_num_gt, rt_num_le, rt_num_lt, rt_quotient,
+    rt_remainder, rt_round, rt_sin, rt_sqrt, rt_sub, rt_sub1, rt_truncate,
+};
+use crate::persistent::{
+    rt_assoc, rt_conj, rt_count, rt_dissoc, rt_get, rt_persistent_map, rt_persistent_vector,
+};
+use crate::runtime::{
+    rt_char_alphabetic, rt_char_downcase, rt_char_numeric, rt_char_upcase, rt_char_whitespace,
+    rt_gc, rt_gc_stats, rt_gensym, rt_heap_object_count, rt_string_to_symbol, rt_symbol_to_string,
+};
+use crate::strings::{
+    rt_list_to_string, rt_number_to_string, rt_string_append, rt_string_eq, rt_string_fill,
+    rt_string_lt, rt_string_ref, rt_string_set, rt_string_to_list, rt_string_to_number,
+    rt_substring,
+};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::rc::Rc;
+
+/// How many arguments a primitive accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Fixed(usize),
+    /// Between `min` and `max` arguments, inclusive.
+    Range(usize, usize),
+    /// At least `min` arguments.
+    Variadic(usize),
+}
+
+impl Arity {
+    fn bounds(self) -> (usize, usize) {
+        match self {
+            Arity::Fixed(count) => (count, count),
+            Arity::Range(min, max) => (min, max),
+            Arity::Variadic(min) => (min, usize::MAX),
+        }
+    }
+
+    /// Whether the arguments are passed in `ARG_REGISTERS` rather than on the stack.
+    fn in_registers(self) -> bool {
+        matches!(self, Arity::Fixed(count) if count <= ARG_REGISTERS.len())
+    }
+}
+
+/// Registers holding the arguments of a primitive with a small fixed arity,
+/// first argument first.
+pub const ARG_REGISTERS: [Register; 4] =
+    [Register::Rax, Register::Rcx, Register::Rdx, Register::Rsi];
+
+/// The type an argument must have, as a tag under a mask, and for boxed
+/// types the kind in the header; see `TypeTag`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgType {
+    /// Used in error messages: "car: expected pair, got 1".
+    pub name: &'static str,
+    pub mask: Word,
+    pub tag: Word,
+    pub kind: Option<Word>,
+}
+
+impl ArgType {
+    /// Accepts any value; no check is emitted.
+    pub const ANY: ArgType = ArgType::new("value", 0, 0);
+    pub const INTEGER: ArgType = ArgType::of(TypeTag::INTEGER);
+    pub const CHAR: ArgType = ArgType::of(TypeTag::CHAR);
+    pub const BOOLEAN: ArgType = ArgType::of(TypeTag::BOOLEAN);
+    pub const PAIR: ArgType = ArgType::of(TypeTag::PAIR);
+    pub const VECTOR: ArgType = ArgType::of(TypeTag::VECTOR);
+    pub const STRING: ArgType = ArgType::of(TypeTag::STRING);
+    pub const SYMBOL: ArgType = ArgType::of(TypeTag::SYMBOL);
+    pub const PROCEDURE: ArgType = ArgType::of(TypeTag::CLOSURE);
+    pub const HASH_TABLE: ArgType = ArgType::of(TypeTag::HASH_TABLE);
+    pub const PERSISTENT_MAP: ArgType = ArgType::of(TypeTag::PERSISTENT_MAP);
+
+    pub const fn new(name: &'static str, mask: Word, tag: Word) -> Self {
+        ArgType {
+            name,
+            mask,
+            tag,
+            kind: None,
+        }
+    }
+
+    /// Requires a type of the tagging scheme.
+    pub const fn of(type_tag: TypeTag) -> Self {
+        ArgType {
+            kind: type_tag.kind,
+            ..ArgType::new(type_tag.name, type_tag.mask, type_tag.tag)
+        }
+    }
+}
+
+/// Emits the body of a primitive once its arguments are evaluated and
+/// type-checked, leaving the result in RAX. Receives the argument count.
+pub type Emitter = dyn Fn(&mut Compiler<'_>, usize);
+
+/// A procedure compiled inline at each call site.
+///
+/// Arguments are evaluated left to right and checked against `arg_types`; the
+/// last type applies to any further arguments. With a fixed arity of at most
+/// four, the emitter finds them in `ARG_REGISTERS`. Otherwise they are on the
+/// stack, first argument deepest, and the emitter must pop them.
+pub struct Primitive {
+    name: String,
+    arity: Arity,
+    arg_types: Vec<ArgType>,
+    emit: Box<Emitter>,
+}
+
+impl Primitive {
+    pub fn new(
+        name: &str,
+        arity: Arity,
+        arg_types: &[ArgType],
+        emit: impl Fn(&mut Compiler<'_>, usize) + 'static,
+    ) -> Self {
+        Primitive {
+            name: name.to_string(),
+            arity,
+            arg_types: arg_types.to_vec(),
+            emit: Box::new(emit),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    /// The smallest and largest argument counts; `usize::MAX` when variadic.
+    pub fn arity_bounds(&self) -> (usize, usize) {
+        self.arity.bounds()
+    }
+
+    pub fn passes_args_in_registers(&self) -> bool {
+        self.arity.in_registers()
+    }
+
+    /// The type expected for argument `index`.
+    pub fn arg_type(&self, index: usize) -> ArgType {
+        let last = self.arg_types.len().checked_sub(1);
+        match last {
+            Some(last) => self.arg_types[index.min(last)],
+            None => ArgType::ANY,
+        }
+    }
+
+    pub fn emit(&self, compiler: &mut Compiler<'_>, argc: usize) {
+        (self.emit)(compiler, argc)
+    }
+}
+
+/// The primitives known to a runtime, by name.
+pub struct Primitives {
+    table: HashMap<String, Rc<Primitive>>,
+}
+
+impl Default for Primitives {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Primitives {
+    /// An empty registry.
+    pub fn new() -> Self {
+        Primitives {
+            table: HashMap::new(),
+        }
+    }
+
+    /// A registry holding the built-in primitives.
+    pub fn builtin() -> Self {
+        let mut primitives = Self::new();
+        register_type_predicates(&mut primitives);
+        register_integer_primitives(&mut primitives);
+        register_arithmetic_primitives(&mut primitives);
+        register_pair_primitives(&mut primitives);
+        register_vector_primitives(&mut primitives);
+        register_char_primitives(&mut primitives);
+        register_string_primitives(&mut primitives);
+        register_hash_table_primitives(&mut primitives);
+        register_persistent_primitives(&mut primitives);
+        register_equivalence_primitives(&mut primitives);
+        register_symbol_primitives(&mut primitives);
+        register_gc_primitives(&mut primitives);
+        primitives
+    }
+
+    /// Adds `primitive`, replacing any primitive of the same name.
+    /// Only code compiled afterwards sees the change.
+    pub fn register(&mut self, primitive: Primitive) {
+        self.table
+            .insert(primitive.name.clone(), Rc::new(primitive));
+    }
+
+    pub fn get(&self, name: &str) -> Option<Rc<Primitive>> {
+        self.table.get(name).cloned()
+    }
+}
+
+/// Registers a one-argument predicate for every type of the tagging scheme
+/// that has one, plus the older `nil?` and `bool?` names.
+fn register_type_predicates(primitives: &mut Primitives) {
+    let tags = TagsDict::new();
+    let aliases = [("nil?", TypeTag::NIL), ("bool?", TypeTag::BOOLEAN)];
+    let predicates = tags
+        .iter()
+        .filter_map(|tag| Some((tag.predicate?, *tag)))
+        .chain(aliases);
+    for (name, tag) in predicates {
+        primitives.register(Primitive::new(name, Arity::Fixed(1), &[], move |c, _| {
+            c.emit_value_type_test(Register::Rax, tag);
+            c.emit_condition_to_bool(SetccConditions::Equal);
+        }));
+    }
+}
+
+fn register_integer_primitives(primitives: &mut Primitives) {
+    let encoded_one = LispValue::from_integer(1).as_raw_word() as i32;
+    primitives.register(Primitive::new("add1", Arity::Fixed(1), &[], move |c, _| {
+        // Adding 1 << 2 due to pointer tagging
+        emit_fixnum_step(c, "add1", rt_add1 as *const (), |asm| {
+            asm.add_reg_imm(Register::Rax, encoded_one)
+        });
+    }));
+    primitives.register(Primitive::new("sub1", Arity::Fixed(1), &[], move |c, _| {
+        emit_fixnum_step(c, "sub1", rt_sub1 as *const (), |asm| {
+            asm.sub_reg_imm(Register::Rax, encoded_one)
+        });
+    }));
+}
+
+/// Emits `add1` or `sub1`: `step` adjusts a fixnum in RAX inline, and
+/// `function` in the runtime takes bignums, non-integers to raise on, and,
+/// in `Overflow::Promote` mode, fixnums whose result overflows.
+fn emit_fixnum_step(
+    c: &mut Compiler<'_>,
+    name: &str,
+    function: *const (),
+    step: impl Fn(&mut Assembler) -> &mut Assembler,
+) {
+    let slow = c.asm().new_label();
+    let done = c.asm().new_label();
+    c.asm().mov_reg_reg(Register::Rcx, Register::Rax);
+    c.emit_tag_dispatch(Register::Rax, K_INTEGER_MASK, K_INTEGER_TAG, slow);
+    step(c.asm());
+    c.emit_promoting_overflow_check(name, slow);
+    c.asm().jmp(done).bind(slow);
+    c.push(Register::Rcx);
+    c.emit_runtime_call_with_stack_args(function, 1);
+    c.asm().bind(done);
+}
+
+/// Jumps to `other` unless the values in RAX and RCX are both fixnums.
+/// Clobbers RDI.
+fn emit_fixnum_pair_test(c: &mut Compiler<'_>, other: Label) {
+    if cfg!(feature = "nan-boxing") {
+        // Doubles can end in the fixnum tag, so each is tested on its own.
+        for reg in [Register::Rax, Register::Rcx] {
+            c.emit_type_test(reg, K_INTEGER_MASK, K_INTEGER_TAG, Register::Rdi);
+            c.asm().jcc(SetccConditions::NotEqual, other);
+        }
+        return;
+    }
+    // The tag of a fixnum is zero, so both are fixnums if their OR is.
+    c.asm()
+        .mov_reg_reg(Register::Rdi, Register::Rax)
+        .or_reg_reg(Register::Rdi, Register::Rcx);
+    c.emit_type_test(Register::Rdi, K_INTEGER_MASK, K_INTEGER_TAG, Register::Rdi);
+    c.asm().jcc(SetccConditions::NotEqual, other);
+}
+
+/// Loads the number in `reg` into `xmm` as a double, converting a fixnum or
+/// unboxing a flonum. Anything else, bignums included, goes to `slow`.
+/// Clobbers RDI.
+fn emit_load_double(c: &mut Compiler<'_>, reg: Register, xmm: XmmRegister, slow: Label) {
+    let flonum = c.asm().new_label();
+    let done = c.asm().new_label();
+    c.emit_type_test(reg, K_INTEGER_MASK, K_INTEGER_TAG, Register::Rdi);
+    c.asm()
+        .jcc(SetccConditions::NotEqual, flonum)
+        .mov_reg_reg(Register::Rdi, reg)
+        .sar_reg_imm8(Register::Rdi, K_INTEGER_SHIFT as u8)
+        .cvtsi2sd(xmm, Register::Rdi)
+        .jmp(done)
+        .bind(flonum);
+    c.emit_value_type_test(reg, TypeTag::FLONUM);
+    c.asm().jcc(SetccConditions::NotEqual, slow);
+    c.emit_unbox_double(reg, xmm);
+    c.asm().bind(done);
+}
+
+/// Combines RAX with the argument at `[rsp + offset]` as doubles, with `op`
+/// leaving its result in XMM0, and boxes it into RAX. Operands that are not
+/// fixnums or flonums go to `slow`.
+fn emit_flonum_step(c: &mut Compiler<'_>, offset: i32, name: &str, op: FlonumOp, slow: Label) {
+    // Any box comes first: it is garbage if `slow` is taken.
+    c.emit_flonum_alloc(name);
+    c.asm().mov_reg_mem(Register::Rdx, Register::Rsp, offset);
+    emit_load_double(c, Register::Rax, XmmRegister::Xmm0, slow);
+    emit_load_double(c, Register::Rdx, XmmRegister::Xmm1, slow);
+    op(c.asm(), XmmRegister::Xmm0, XmmRegister::Xmm1);
+    c.emit_box_double();
+}
+
+/// An SSE2 operation on doubles, `op dst, src`.
+type FlonumOp = for<'a> fn(&'a mut Assembler, XmmRegister, XmmRegister) -> &'a mut Assembler;
+
+/// Emits a generic operation `name` on the `argc` arguments on the stack,
+/// folded left to right. `step` combines the fixnums in RAX and RCX into RAX
+/// or jumps to one of the labels it is given: the first for the runtime, the
+/// second, with RAX as it was, for `op` on doubles, which also combines any
+/// mix of fixnums and flonums. Anything else, and any overflow, goes to
+/// `function` in the runtime, with all the arguments.
+fn emit_generic_fold(
+    c: &mut Compiler<'_>,
+    argc: usize,
+    name: &str,
+    function: *const (),
+    step: impl Fn(&mut Compiler<'_>, Label, Label),
+    op: FlonumOp,
+) {
+    let word = size_of::<Word>() as i32;
+    let slow = c.asm().new_label();
+    let done = c.asm().new_label();
+    c.asm()
+        .mov_reg_mem(Register::Rax, Register::Rsp, (argc as i32 - 1) * word);
+    if argc == 1 {
+        c.asm().mov_reg_reg(Register::Rcx, Register::Rax);
+        emit_fixnum_pair_test(c, slow);
+    }
+    for index in 1..argc as i32 {
+        let offset = (argc as i32 - 1 - index) * word;
+        let flonum = c.asm().new_label();
+        let next = c.asm().new_label();
+        c.asm().mov_reg_mem(Register::Rcx, Register::Rsp, offset);
+        emit_fixnum_pair_test(c, flonum);
+        step(c, slow, flonum);
+        c.asm().jmp(next).bind(flonum);
+        emit_flonum_step(c, offset, name, op, slow);
+        c.asm().bind(next);
+    }
+    // The fast path drops the arguments itself; the runtime call below
+    // accounts for them.
+    c.asm()
+        .add_reg_imm32(Register::Rsp, argc as i32 * word)
+        .jmp(done)
+        .bind(slow);
+    c.emit_runtime_call_with_stack_args(function, argc);
+    c.asm().bind(done);
+}
+
+/// Emits a generic operation on the fixnums in RAX and RCX, computed inline by
+/// `fast`, which jumps to the label it is given for `function` to handle
+/// the operands instead.
+fn emit_generic_binary(
+    c: &mut Compiler<'_>,
+    function: *const (),
+    fast: impl Fn(&mut Compiler<'_>, Label),
+) {
+    let slow = c.asm().new_label();
+    let done = c.asm().new_label();
+    emit_fixnum_pair_test(c, slow);
+    fast(c, slow);
+    c.asm().jmp(done).bind(slow);
+    c.push(Register::Rax);
+    c.push(Register::Rcx);
+    c.emit_runtime_call_with_stack_args(function, 2);
+    c.asm().bind(done);
+}
+
+/// Emits a numeric comparison of the `argc` arguments on the stack. Two
+/// fixnums or flonums are compared inline, by `fixnum` as signed integers or
+/// `flonum` as doubles; NaN compares false. Anything else goes to `function`
+/// in the runtime.
+fn emit_generic_compare(
+    c: &mut Compiler<'_>,
+    argc: usize,
+    function: *const (),
+    fixnum: SetccConditions,
+    flonum: SetccConditions,
+) {
+    if argc != 2 {
+        c.emit_runtime_call_with_stack_args(function, argc);
+        return;
+    }
+    let word = size_of::<Word>() as i32;
+    let doubles = c.asm().new_label();
+    let unordered = c.asm().new_label();
+    let compared = c.asm().new_label();
+    let slow = c.asm().new_label();
+    let done = c.asm().new_label();
+    c.asm()
+        .mov_reg_mem(Register::Rax, Register::Rsp, word)
+        .mov_reg_mem(Register::Rcx, Register::Rsp, 0);
+    emit_fixnum_pair_test(c, doubles);
+    c.asm().cmp_reg_reg(Register::Rax, Register::Rcx);
+    c.emit_condition_to_bool(fixnum);
+    c.asm().jmp(compared).bind(doubles);
+    emit_load_double(c, Register::Rax, XmmRegister::Xmm0, slow);
+    emit_load_double(c, Register::Rcx, XmmRegister::Xmm1, slow);
+    c.asm()
+        .ucomisd(XmmRegister::Xmm0, XmmRegister::Xmm1)
+        .jcc(SetccConditions::Parity, unordered);
+    c.emit_condition_to_bool(flonum);
+    c.asm()
+        .jmp(compared)
+        .bind(unordered)
+        .mov_reg_imm(Register::Rax, LispValue::false_val().as_raw_word())
+        .bind(compared)
+        .add_reg_imm32(Register::Rsp, 2 * word)
+        .jmp(done)
+        .bind(slow);
+    c.emit_runtime_call_with_stack_args(function, argc);
+    c.asm().bind(done);
+}
+
+/// Divides the fixnum in RAX by the one in RCX, leaving the quotient,
+/// untagged, in RAX and the remainder, tagged, in RDX. Zero divisors go to
+/// `slow`.
+fn emit_fixnum_divide(c: &mut Compiler<'_>, slow: Label) {
+    c.asm()
+        .cmp_reg_imm(Register::Rcx, 0)
+        .jcc(SetccConditions::Equal, slow)
+        .cqo()
+        .idiv_reg(Register::Rcx);
+}
+
+fn register_arithmetic_primitives(primitives: &mut Primitives) {
+    let encoded = |value: Word| LispValue::from_integer(value).as_raw_word() as i32;
+    primitives.register(Primitive::new(
+        "+",
+        Arity::Variadic(0),
+        &[],
+        move |c, argc| {
+            if argc == 0 {
+                c.asm().mov_reg_imm(Register::Rax, encoded(0) as i64);
+                return;
+            }
+            emit_generic_fold(
+                c,
+                argc,
+                "+",
+                rt_add as *const (),
+                |c, slow, _| {
+                    c.asm().add_reg_reg(Register::Rax, Register::Rcx);
+                    c.emit_fixnum_overflow_jump(Register::Rax, slow);
+                },
+                Assembler::addsd,
+            );
+        },
+    ));
+    primitives.register(Primitive::new(
+        "-",
+        Arity::Variadic(1),
+        &[],
+        move |c, argc| {
+            if argc == 1 {
+                // Negation, as 0 - n for a fixnum. The runtime negates the
+                // rest, which keeps the sign of -0.0 that 0 - 0.0 would lose.
+                let slow = c.asm().new_label();
+                let done = c.asm().new_label();
+                c.asm().mov_reg_mem(Register::Rcx, Register::Rsp, 0);
+                c.emit_type_test(Register::Rcx, K_INTEGER_MASK, K_INTEGER_TAG, Register::Rdi);
+                c.asm()
+                    .jcc(SetccConditions::NotEqual, slow)
+                    .mov_reg_imm(Register::Rax, encoded(0) as i64)
+                    .sub_reg_reg(Register::Rax, Register::Rcx);
+                c.emit_fixnum_overflow_jump(Register::Rax, slow);
+                c.asm()
+                    .add_reg_imm32(Register::Rsp, size_of::<Word>() as i32)
+                    .jmp(done)
+                    .bind(slow);
+                c.emit_runtime_call_with_stack_args(rt_sub as *const (), 1);
+                c.asm().bind(done);
+                return;
+            }
+            emit_generic_fold(
+                c,
+                argc,
+                "-",
+                rt_sub as *const (),
+                |c, slow, _| {
+                    c.asm().sub_reg_reg(Register::Rax, Register::Rcx);
+                    c.emit_fixnum_overflow_jump(Register::Rax, slow);
+                },
+                Assembler::subsd,
+            );
+        },
+    ));
+    primitives.register(Primitive::new(
+        "*",
+        Arity::Variadic(0),
+        &[],
+        move |c, argc| {
+            if argc == 0 {
+                c.asm().mov_reg_imm(Register::Rax, encoded(1) as i64);
+                return;
+            }
+            emit_generic_fold(
+                c,
+                argc,
+                "*",
+                rt_mul as *const (),
+                |c, slow, _| {
+                    // Untagging one operand leaves the product tagged.
+                    c.asm()
+                        .sar_reg_imm8(Register::Rcx, K_INTEGER_SHIFT as u8)
+                        .imul_reg_reg(Register::Rax, Register::Rcx);
+                    c.emit_fixnum_overflow_jump(Register::Rax, slow);
+                },
+                Assembler::mulsd,
+            );
+        },
+    ));
+    primitives.register(Primitive::new(
+        "/",
+        Arity::Variadic(1),
+        &[],
+        move |c, argc| {
+            if argc == 1 {
+                c.emit_runtime_call_with_stack_args(rt_div as *const (), 1);
+                return;
+            }
+            emit_generic_fold(
+                c,
+                argc,
+                "/",
+                rt_div as *const (),
+                |c, slow, inexact| {
+                    // Fixnums that do not divide exactly make a flonum. The
+                    // runtime handles division by zero, and by -1, whose
+                    // quotient may not fit a fixnum.
+                    let exact = c.asm().new_label();
+                    c.asm()
+                        .mov_reg_reg(Register::Rsi, Register::Rax)
+                        .cmp_reg_imm(Register::Rcx, encoded(-1))
+                        .jcc(SetccConditions::Equal, slow);
+                    emit_fixnum_divide(c, slow);
+                    c.asm()
+                        .cmp_reg_imm(Register::Rdx, 0)
+                        .jcc(SetccConditions::Equal, exact)
+                        .mov_reg_reg(Register::Rax, Register::Rsi)
+                        .jmp(inexact)
+                        .bind(exact)
+                        .shl_reg_imm8(Register::Rax, K_INTEGER_SHIFT as u8);
+                },
+                Assembler::divsd,
+            );
+        },
+    ));
+    let comparisons = [
+        (
+            "=",
+            rt_num_eq as *const (),
+            SetccConditions::Equal,
+            SetccConditions::Equal,
+        ),
+        (
+            "<",
+            rt_num_lt as *const (),
+            SetccConditions::Less,
+            SetccConditions::Below,
+        ),
+        (
+            ">",
+            rt_num_gt as *const (),
+            SetccConditions::Greater,
+            SetccConditions::Above,
+        ),
+        (
+            "<=",
+            rt_num_le as *const (),
+            SetccConditions::LessOrEqual,
+            SetccConditions::BelowOrEqual,
+        ),
+        (
+            ">=",
+            rt_num_ge as *const (),
+            SetccConditions::GreaterOrEqual,
+            SetccConditions::AboveOrEqual,
+        ),
+    ];
+    for (name, function, fixnum, flonum) in comparisons {
+        primitives.register(Primitive::new(
+            name,
+            Arity::Variadic(1),
+            &[],
+            move |c, argc| {
+                emit_generic_compare(c, argc, function, fixnum, flonum);
+            },
+        ));
+    }
+    primitives.register(Primitive::new(
+        "quotient",
+        Arity::Fixed(2),
+        &[],
+        move |c, _| {
+            emit_generic_binary(c, rt_quotient as *const (), |c, slow| {
+                // The quotient of the smallest fixnum by -1 is too large for
+                // a fixnum; the runtime handles every division by -1.
+                c.asm()
+                    .cmp_reg_imm(Register::Rcx, encoded(-1))
+                    .jcc(SetccConditions::Equal, slow);
+                emit_fixnum_divide(c, slow);
+                c.asm().shl_reg_imm8(Register::Rax, K_INTEGER_SHIFT as u8);
+            });
+        },
+    ));
+    primitives.register(Primitive::new("remainder", Arity::Fixed(2), &[], |c, _| {
+        emit_generic_binary(c, rt_remainder as *const (), |c, slow| {
+            emit_fixnum_divide(c, slow);
+            c.asm().mov_reg_reg(Register::Rax, Register::Rdx);
+        });
+    }));
+    primitives.register(Primitive::new("expt", Arity::Fixed(2), &[], |c, _| {
+        c.push(Register::Rax);
+        c.push(Register::Rcx);
+        c.emit_runtime_call_with_stack_args(rt_expt as *const (), 2);
+    }));
+    primitives.register(Primitive::new("gcd", Arity::Variadic(0), &[], |c, argc| {
+        c.emit_runtime_call_with_stack_args(rt_gcd as *const (), argc);
+    }));
+    let unary: [(&str, *const ()); 14] = [
+        ("exact?", rt_is_exact as *const ()),
+        ("inexact?", rt_is_inexact as *const ()),
+        ("exact->inexact", rt_exact_to_inexact as *const ()),
+        ("inexact->exact", rt_inexact_to_exact as *const ()),
+        ("floor", rt_floor as *const ()),
+        ("ceiling", rt_ceiling as *const ()),
+        ("round", rt_round as *const ()),
+        ("truncate", rt_truncate as *const ()),
+        ("sqrt", rt_sqrt as *const ()),
+        ("exp", rt_exp as *const ()),
+        ("log", rt_log as *const ()),
+        ("sin", rt_sin as *const ()),
+        ("cos", rt_cos as *const ()),
+        ("atan", rt_atan as *const ()),
+    ];
+    for (name, function) in unary {
+        primitives.register(Primitive::new(name, Arity::Fixed(1), &[], move |c, _| {
+            c.push(Register::Rax);
+            c.emit_runtime_call_with_stack_args(function, 1);
+        }));
+    }
+    primitives.register(Primitive::new("zero?", Arity::Fixed(1), &[], |c, _| {
+        // Fixnums are compared inline; the runtime takes the other numbers.
+        let slow = c.asm().new_label();
+        let done = c.asm().new_label();
+        c.emit_type_test(Register::Rax, K_INTEGER_MASK, K_INTEGER_TAG, Register::Rdi);
+        c.asm().jcc(SetccConditions::NotEqual, slow);
+        c.compile_compare_imm32(LispValue::from_integer(0));
+        c.asm().jmp(done).bind(slow);
+        c.push(Register::Rax);
+        c.emit_runtime_call_with_stack_args(rt_is_zero as *const (), 1);
+        c.asm().bind(done);
+    }));
+    primitives.register(Primitive::new("integer?", Arity::Fixed(1), &[], |c, _| {
+        // Fixnums and bignums are integers, and so are whole flonums,
+        // which the runtime looks for.
+        let tested = c.asm().new_label();
+        let done = c.asm().new_label();
+        for type_tag in [TypeTag::INTEGER, TypeTag::BIGNUM] {
+            c.emit_value_type_test(Register::Rax, type_tag);
+            c.asm().jcc(SetccConditions::Equal, tested);
+        }
+        c.emit_value_type_test(Register::Rax, TypeTag::FLONUM);
+        c.asm().jcc(SetccConditions::NotEqual, tested);
+        c.push(Register::Rax);
+        c.emit_runtime_call_with_stack_args(rt_is_integer as *const (), 1);
+        c.asm().jmp(done).bind(tested);
+        c.emit_condition_to_bool(SetccConditions::Equal);
+        c.asm().bind(done);
+    }));
+    primitives.register(Primitive::new("number?", Arity::Fixed(1), &[], |c, _| {
+        let done = c.asm().new_label();
+        for type_tag in [TypeTag::INTEGER, TypeTag::BIGNUM, TypeTag::FLONUM] {
+            c.emit_value_type_test(Register::Rax, type_tag);
+            c.asm().jcc(SetccConditions::Equal, done);
+        }
+        c.asm().bind(done);
+        c.emit_condition_to_bool(SetccConditions::Equal);
+    }));
+}
+
+/// Code points reserved for UTF-16 surrogates, which are not characters.
+const SURROGATES: Range<u32> = 0xd800..0xe000;
+
+fn register_char_primitives(primitives: &mut Primitives) {
+    let encoded =
+        |code_point: u32| LispValue::from_integer(code_point as Word).as_raw_word() as i32;
+    primitives.register(Primitive::new(
+        "integer->char",
+        Arity::Fixed(1),
+        &[ArgType::INTEGER],
+        move |c, _| {
+            let invalid = c.error_stub(
+                Register::Rax,
+                "integer->char: {} is not a Unicode scalar value".to_string(),
+            );
+            // Compared unsigned, negative integers are out of range too.
+            c.asm()
+                .cmp_reg_imm(Register::Rax, encoded(char::MAX as u32))
+                .jcc(SetccConditions::Above, invalid)
+                .mov_reg_reg(Register::Rcx, Register::Rax)
+                .sub_reg_imm(Register::Rcx, encoded(SURROGATES.start))
+                .cmp_reg_imm(Register::Rcx, encoded(SURROGATES.len() as u32))
+                .jcc(SetccConditions::Below, invalid)
+                .shl_reg_imm8(Register::Rax, (K_CHAR_SHIFT - K_INTEGER_SHIFT) as u8)
+                .or_reg_imm8(Register::Rax, K_CHAR_TAG as u8);
+        },
+    ));
+    primitives.register(Primitive::new(
+        "char->integer",
+        Arity::Fixed(1),
+        &[ArgType::CHAR],
+        |c, _| {
+            // The tag sits below the shifted code point, so shifting it out
+            // leaves the code point as an encoded integer.
+            c.asm()
+                .shr_reg_imm8(Register::Rax, (K_CHAR_SHIFT - K_INTEGER_SHIFT) as u8);
+        },
+    ));
+    let runtime_calls = [
+        ("char-upcase", rt_char_upcase as *const ()),
+        ("char-downcase", rt_char_downcase as *const ()),
+        ("char-alphabetic?", rt_char_alphabetic as *const ()),
+        ("char-numeric?", rt_char_numeric as *const ()),
+        ("char-whitespace?", rt_char_whitespace as *const ()),
+    ];
+    for (name, function) in runtime_calls {
+        primitives.register(Primitive::new(
+            name,
+            Arity::Fixed(1),
+            &[ArgType::CHAR],
+            move |c, _| {
+                c.asm().mov_reg_reg(Register::Rsi, Register::Rax);
+                c.emit_runtime_call(function);
+            },
+        ));
+    }
+}
+
+fn register_pair_primitives(primitives: &mut Primitives) {
+    primitives.register(Primitive::new("cons", Arity::Fixed(2), &[], |c, _| {
+        c.push(Register::Rax);
+        c.asm().mov_reg_reg(Register::Rax, Register::Rcx);
+        c.emit_cons();
+    }));
+    for (name, index) in [("car", 0), ("cdr", 1)] {
+        let offset = Compiler::field_offset(K_PAIR_TAG, index);
+        primitives.register(Primitive::new(
+            name,
+            Arity::Fixed(1),
+            &[ArgType::PAIR],
+            move |c, _| {
+                c.asm().mov_reg_mem(Register::Rax, Register::Rax, offset);
+            },
+        ));
+    }
+    for (name, index) in [("set-car!", 0), ("set-cdr!", 1)] {
+        let offset = Compiler::field_offset(K_PAIR_TAG, index);
+        primitives.register(Primitive::new(
+            name,
+            Arity::Fixed(2),
+            &[ArgType::PAIR, ArgType::ANY],
+            move |c, _| {
+                c.asm()
+                    .mov_mem_reg(Register::Rax, offset, Register::Rcx)
+                    .lea_reg_mem(Register::Rdi, Register::Rax, offset);
+                c.emit_write_barrier(Register::Rdi);
+                c.asm()
+                    .mov_reg_imm(Register::Rax, LispValue::nil().as_raw_word());
+            },
+        ));
+    }
+}
+
+/// Loads the length of the vector in `vector` into `dst`, as an encoded
+/// integer. The kind and tag bits of the header sit below the length and are
+/// shifted out.
+fn emit_vector_length(c: &mut Compiler<'_>, dst: Register, vector: Register) {
+    c.asm()
+        .mov_reg_mem(dst, vector, Compiler::field_offset(K_VECTOR_TAG, 0))
+        .shr_reg_imm8(dst, (K_HEADER_LENGTH_SHIFT - K_INTEGER_SHIFT) as u8);
+}
+
+/// Checks that the integer in RCX indexes the vector in RAX, leaving the
+/// address of the element in RDI.
+fn emit_vector_index(c: &mut Compiler<'_>, name: &str) {
+    let out_of_range = c.error_stub(Register::Rcx, format!("{}: index {{}} out of range", name));
+    emit_vector_length(c, Register::Rdi, Register::Rax);
+    // An unsigned compare also rejects negative indices.
+    c.asm()
+        .cmp_reg_reg(Register::Rcx, Register::Rdi)
+        .jcc(SetccConditions::AboveOrEqual, out_of_range)
+        .mov_reg_reg(Register::Rdi, Register::Rcx)
+        .shl_reg_imm8(Register::Rdi, (3 - K_INTEGER_SHIFT) as u8)
+        .add_reg_reg(Register::Rdi, Register::Rax);
+}
+
+fn vector_header(length: usize) -> Header {
+    Header::new(K_VECTOR_TAG, length)
+}
+
+fn register_vector_primitives(primitives: &mut Primitives) {
+    let word = size_of::<Word>() as i32;
+    let first_element = Compiler::field_offset(K_VECTOR_TAG, 1);
+    primitives.register(Primitive::new(
+        "make-vector",
+        Arity::Range(1, 2),
+        &[ArgType::INTEGER, ArgType::ANY],
+        move |c, argc| {
+            if argc == 2 {
+                c.pop(Register::Rax);
+            } else {
+                c.asm()
+                    .mov_reg_imm(Register::Rax, LispValue::from_integer(0).as_raw_word());
+            }
+            let negative =
+                c.error_stub(Register::Rdx, "make-vector: negative length {}".to_string());
+            let too_large = c.error_stub(
+                Register::Rdx,
+                "make-vector: length {} too large".to_string(),
+            );
+            // A longer length does not fit the header, and its size in bytes
+            // may not fit a word.
+            let max_length = (K_HEADER_MAX_LENGTH as Word) << K_INTEGER_SHIFT;
+            // The length is an encoded integer (n << 2), so n words take length * 2 bytes.
+            c.asm()
+                .mov_reg_mem(Register::Rdx, Register::Rsp, 0)
+                .cmp_reg_imm(Register::Rdx, 0)
+                .jcc(SetccConditions::Less, negative)
+                .mov_reg_imm(Register::Rsi, max_length)
+                .cmp_reg_reg(Register::Rdx, Register::Rsi)
+                .jcc(SetccConditions::Greater, too_large)
+                .shl_reg_imm8(Register::Rdx, (3 - K_INTEGER_SHIFT) as u8)
+                .add_reg_imm(Register::Rdx, word);
+            c.emit_alloc_dynamic("make-vector");
+            let fill_loop = c.asm().new_label();
+            let done = c.asm().new_label();
+            c.pop(Register::Rsi);
+            c.asm()
+                .shl_reg_imm8(
+                    Register::Rsi,
+                    (K_HEADER_LENGTH_SHIFT - K_INTEGER_SHIFT) as u8,
+                )
+                .or_reg_imm(Register::Rsi, vector_header(0).as_raw_word() as i32)
+                .mov_mem_reg(Register::Rcx, 0, Register::Rsi)
+                .lea_reg_mem(Register::Rdi, Register::Rcx, word)
+                .bind(fill_loop)
+                .cmp_reg_reg(Register::Rdi, Register::Rdx)
+                .jcc(SetccConditions::AboveOrEqual, done)
+                .mov_mem_reg(Register::Rdi, 0, Register::Rax)
+                .add_reg_imm(Register::Rdi, word)
+                .jmp(fill_loop)
+                .bind(done)
+                .lea_reg_mem(Register::Rax, Register::Rcx, K_VECTOR_TAG as i32);
+        },
+    ));
+    primitives.register(Primitive::new(
+        "vector",
+        Arity::Variadic(0),
+        &[],
+        move |c, argc| {
+            if argc == 0 {
+                // RAX is live across the allocation, so it must hold a value.
+                c.asm()
+                    .mov_reg_imm(Register::Rax, LispValue::nil().as_raw_word());
+            }
+            c.emit_alloc(word * (argc as i32 + 1), "vector");
+            c.asm()
+                .mov_reg_imm(Register::Rsi, vector_header(argc).as_raw_word())
+                .mov_mem_reg(Register::Rcx, 0, Register::Rsi);
+            for index in (1..=argc as i32).rev() {
+                c.pop(Register::Rdx);
+                c.asm()
+                    .mov_mem_reg(Register::Rcx, word * index, Register::Rdx);
+            }
+            c.asm()
+                .lea_reg_mem(Register::Rax, Register::Rcx, K_VECTOR_TAG as i32);
+        },
+    ));
+    primitives.register(Primitive::new(
+        "vector-length",
+        Arity::Fixed(1),
+        &[ArgType::VECTOR],
+        |c, _| emit_vector_length(c, Register::Rax, Register::Rax),
+    ));
+    primitives.register(Primitive::new(
+        "vector-ref",
+        Arity::Fixed(2),
+        &[ArgType::VECTOR, ArgType::INTEGER],
+        move |c, _| {
+            emit_vector_index(c, "vector-ref");
+            c.asm()
+                .mov_reg_mem(Register::Rax, Register::Rdi, first_element);
+        },
+    ));
+    primitives.register(Primitive::new(
+        "vector-set!",
+        Arity::Fixed(3),
+        &[ArgType::VECTOR, ArgType::INTEGER, ArgType::ANY],
+        move |c, _| {
+            emit_vector_index(c, "vector-set!");
+            c.asm()
+                .mov_mem_reg(Register::Rdi, first_element, Register::Rdx)
+                .lea_reg_mem(Register::Rdi, Register::Rdi, first_element);
+            c.emit_write_barrier(Register::Rdi);
+            c.asm()
+                .mov_reg_imm(Register::Rax, LispValue::nil().as_raw_word());
+        },
+    ));
+}
+
+fn register_string_primitives(primitives: &mut Primitives) {
+    let length = Compiler::field_offset(K_STRING_TAG, 1);
+    primitives.register(Primitive::new(
+        "string-length",
+        Arity::Fixed(1),
+        &[ArgType::STRING],
+        move |c, _| {
+            c.asm().mov_reg_mem(Register::Rax, Register::Rax, length);
+        },
+    ));
+    let string = ArgType::STRING;
+    let integer = ArgType::INTEGER;
+    let char = ArgType::CHAR;
+    let runtime_calls: [RuntimeCall; 11] = [
+        (
+            "string-ref",
+            Arity::Fixed(2),
+            &[string, integer],
+            rt_string_ref as *const (),
+        ),
+        (
+            "substring",
+            Arity::Range(2, 3),
+            &[string, integer],
+            rt_substring as *const (),
+        ),
+        (
+            "string-append",
+            Arity::Variadic(0),
+            &[string],
+            rt_string_append as *const (),
+        ),
+        (
+            "string=?",
+            Arity::Variadic(1),
+            &[string],
+            rt_string_eq as *const (),
+        ),
+        (
+            "string<?",
+            Arity::Variadic(1),
+            &[string],
+            rt_string_lt as *const (),
+        ),
+        (
+            "string->list",
+            Arity::Fixed(1),
+            &[string],
+            rt_string_to_list as *const (),
+        ),
+        (
+            "list->string",
+            Arity::Fixed(1),
+            &[],
+            rt_list_to_string as *const (),
+        ),
+        (
+            "number->string",
+            Arity::Range(1, 2),
+            &[ArgType::ANY, integer],
+            rt_number_to_string as *const (),
+        ),
+        (
+            "string->number",
+            Arity::Range(1, 2),
+            &[string, integer],
+            rt_string_to_number as *const (),
+        ),
+        (
+            "string-set!",
+            Arity::Fixed(3),
+            &[string, integer, char],
+            rt_string_set as *const (),
+        ),
+        (
+            "string-fill!",
+            Arity::Fixed(2),
+            &[string, char],
+            rt_string_fill as *const (),
+        ),
+    ];
+    register_runtime_calls(primitives, &runtime_calls);
+}
+
+/// A primitive that only calls a runtime function taking its arguments as
+/// `StackArgs`: its name, arity, argument types and the function.
+type RuntimeCall<'a> = (&'a str, Arity, &'a [ArgType], *const ());
+
+fn register_runtime_calls(primitives: &mut Primitives, runtime_calls: &[RuntimeCall<'_>]) {
+    for &(name, arity, arg_types, function) in runtime_calls {
+        primitives.register(Primitive::new(name, arity, arg_types, move |c, argc| {
+            if arity.in_registers() {
+                for register in &ARG_REGISTERS[..argc] {
+                    c.push(*register);
+                }
+            }
+            c.emit_runtime_call_with_stack_args(function, argc);
+        }));
+    }
+}
+
+fn register_hash_table_primitives(primitives: &mut Primitives) {
+    let count = Compiler::field_offset(K_STRING_TAG, 2);
+    primitives.register(Primitive::new(
+        "hash-table-count",
+        Arity::Fixed(1),
+        &[ArgType::HASH_TABLE],
+        move |c, _| {
+            c.asm().mov_reg_mem(Register::Rax, Register::Rax, count);
+        },
+    ));
+    let table = ArgType::HASH_TABLE;
+    let any = ArgType::ANY;
+    let runtime_calls: [RuntimeCall; 5] = [
+        (
+            "make-hash-table",
+            Arity::Range(0, 1),
+            &[ArgType::SYMBOL],
+            rt_make_hash_table as *const (),
+        ),
+        (
+            "hash-table-ref",
+            Arity::Range(2, 3),
+            &[table, any],
+            rt_hash_table_ref as *const (),
+        ),
+        (
+            "hash-table-set!",
+            Arity::Fixed(3),
+            &[table, any],
+            rt_hash_table_set as *const (),
+        ),
+        (
+            "hash-table-delete!",
+            Arity::Fixed(2),
+            &[table, any],
+            rt_hash_table_delete as *const (),
+        ),
+        (
+            "hash-table-keys",
+            Arity::Fixed(1),
+            &[table],
+            rt_hash_table_keys as *const (),
+        ),
+    ];
+    register_runtime_calls(primitives, &runtime_calls);
+}
+
+/// The collections check their own arguments, which may be either kind.
+fn register_persistent_primitives(primitives: &mut Primitives) {
+    let any = ArgType::ANY;
+    let runtime_calls: [RuntimeCall; 7] = [
+        (
+            "persistent-vector",
+            Arity::Variadic(0),
+            &[],
+            rt_persistent_vector as *const (),
+        ),
+        (
+            "persistent-map",
+            Arity::Variadic(0),
+            &[],
+            rt_persistent_map as *const (),
+        ),
+        ("assoc", Arity::Fixed(3), &[any], rt_assoc as *const ()),
+        (
+            "dissoc",
+            Arity::Fixed(2),
+            &[ArgType::PERSISTENT_MAP, any],
+            rt_dissoc as *const (),
+        ),
+        ("conj", Arity::Fixed(2), &[any], rt_conj as *const ()),
+        ("get", Arity::Range(2, 3), &[any], rt_get as *const ()),
+        ("count", Arity::Fixed(1), &[any], rt_count as *const ()),
+    ];
+    register_runtime_calls(primitives, &runtime_calls);
+}
+
+fn register_equivalence_primitives(primitives: &mut Primitives) {
+    primitives.register(Primitive::new("eq?", Arity::Fixed(2), &[], |c, _| {
+        c.asm().cmp_reg_reg(Register::Rax, Register::Rcx);
+        c.emit_condition_to_bool(SetccConditions::Equal);
+    }));
+    // The same word is equivalent under both; the runtime compares the rest.
+    let predicates = [
+        ("eqv?", rt_eqv as *const ()),
+        ("equal?", rt_equal as *const ()),
+    ];
+    for (name, function) in predicates {
+        primitives.register(Primitive::new(name, Arity::Fixed(2), &[], move |c, _| {
+            let same = c.asm().new_label();
+            let done = c.asm().new_label();
+            c.asm()
+                .cmp_reg_reg(Register::Rax, Register::Rcx)
+                .jcc(SetccConditions::Equal, same)
+                .mov_reg_reg(Register::Rsi, Register::Rax)
+                .mov_reg_reg(Register::Rdx, Register::Rcx);
+            c.emit_runtime_call(function);
+            c.asm()
+                .jmp(done)
+                .bind(same)
+                .mov_reg_imm(Register::Rax, LispValue::true_val().as_raw_word())
+                .bind(done);
+        }));
+    }
+}
+
+fn register_symbol_primitives(primitives: &mut Primitives) {
+    primitives.register(Primitive::new(
+        "symbol->string",
+        Arity::Fixed(1),
+        &[ArgType::SYMBOL],
+        |c, _| {
+            c.asm().mov_reg_reg(Register::Rsi, Register::Rax);
+            c.emit_runtime_call(rt_symbol_to_string as *const ());
+        },
+    ));
+    primitives.register(Primitive::new(
+        "string->symbol",
+        Arity::Fixed(1),
+        &[ArgType::STRING],
+        |c, _| {
+            c.asm().mov_reg_reg(Register::Rsi, Register::Rax);
+            c.emit_runtime_call(rt_string_to_symbol as *const ());
+        },
+    ));
+    primitives.register(Primitive::new("gensym", Arity::Fixed(0), &[], |c, _| {
+        c.emit_runtime_call(rt_gensym as *const ());
+    }));
+}
+
+fn register_gc_primitives(primitives: &mut Primitives) {
+    primitives.register(Primitive::new("gc", Arity::Fixed(0), &[], |c, _| {
+        c.emit_runtime_call(rt_gc as *const ());
+    }));
+    primitives.register(Primitive::new("gc-stats", Arity::Fixed(0), &[], |c, _| {
+        c.emit_runtime_call(rt_gc_stats as *const ());
+    }));
+    primitives.register(Primitive::new(
+        "heap-object-count",
+        Arity::Range(0, 1),
+        &[ArgType::SYMBOL],
+        |c, argc| {
+            if argc == 1 {
+                c.pop(Register::Rsi);
+            } else {
+                c.asm()
+                    .mov_reg_imm(Register::Rsi, LispValue::nil().as_raw_word());
+            }
+            c.emit_runtime_call(rt_heap_object_count as *const ());
+        },
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encodings::K_INTEGER_MAX;
+    use crate::reader::Parser;
+    use crate::runtime::Runtime;
+    use crate::testing::{eval, eval_str};
+
+    #[test]
+    fn test_register_primitive() {
+        let mut runtime = Runtime::new();
+        runtime.primitives_mut().register(Primitive::new(
+            "double",
+            Arity::Fixed(1),
+            &[ArgType::INTEGER],
+            |c, _| {
+                c.asm().add_reg_reg(Register::Rax, Register::Rax);
+                c.emit_overflow_check("double");
+            },
+        ));
+        let value = eval(&mut runtime, "(double (add1 20))").unwrap();
+        assert_eq!(value.as_integer(), Some(42));
+        assert_eq!(
+            eval(&mut runtime, "(double 'x)"),
+            Err("double: expected integer, got x".to_string())
+        );
+        assert_eq!(
+            eval(&mut runtime, "(double 1 2)"),
+            Err("InvalidArguments(\"double expects 1 argument(s)\")".to_string())
+        );
+    }
+
+    #[test]
+    fn test_register_variadic_primitive() {
+        let mut runtime = Runtime::new();
+        // Sums its arguments, which arrive on the stack.
+        runtime.primitives_mut().register(Primitive::new(
+            "sum",
+            Arity::Variadic(0),
+            &[ArgType::INTEGER],
+            |c, argc| {
+                c.asm().mov_reg_imm(Register::Rax, 0);
+                for _ in 0..argc {
+                    c.pop(Register::Rcx);
+                    c.asm().add_reg_reg(Register::Rax, Register::Rcx);
+                }
+            },
+        ));
+        let value = eval(&mut runtime, "(sum 1 2 3 4 5 6)").unwrap();
+        assert_eq!(value.as_integer(), Some(21));
+        let value = eval(&mut runtime, "(sum)").unwrap();
+        assert_eq!(value.as_integer(), Some(0));
+        assert_eq!(
+            eval(&mut runtime, "(sum 1 2 #\\a)"),
+            Err("sum: expected integer, got #\\a".to_string())
+        );
+    }
+
+    #[test]
+    fn test_uniform_arity_errors() {
+        let mut runtime = Runtime::new();
+        for input in ["(sub1 1 2)", "(nil? 1 2)", "(zero?)", "(integer->char 1 2)"] {
+            let ast = Parser::new(input).read_form().unwrap();
+            let error = Compiler::new(&mut runtime)
+                .compile_function(&ast)
+                .unwrap_err();
+            let name = input[1..].split([' ', ')']).next().unwrap();
+            match error {
+                crate::compiler::CompilerError::InvalidArguments(message) => {
+                    assert_eq!(message, format!("{} expects 1 argument(s)", name))
+                }
+                other => panic!("{}: unexpected {:?}", input, other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_type_predicates_are_exclusive() {
+        let mut runtime = Runtime::new();
+        eval(&mut runtime, "(define (f) 1)").unwrap();
+        eval(&mut runtime, "(define-record-type r (make-r) r?)").unwrap();
+        let max = K_INTEGER_MAX.to_string();
+        let past = (K_INTEGER_MAX as i128 + 1).to_string();
+        let samples = [
+            ("0", "fixnum?"),
+            ("(sub1 0)", "fixnum?"),
+            (max.as_str(), "fixnum?"),
+            (past.as_str(), "bignum?"),
+            ("1.5", "flonum?"),
+            ("#\\a", "char?"),
+            ("(integer->char 255)", "char?"),
+            ("(integer->char 0)", "char?"),
+            ("true", "boolean?"),
+            ("false", "boolean?"),
+            ("nil", "null?"),
+            ("(cons 1 2)", "pair?"),
+            ("(vector)", "vector?"),
+            ("(symbol->string 'a)", "string?"),
+            ("'a", "symbol?"),
+            ("f", "procedure?"),
+            ("(make-hash-table)", "hash-table?"),
+            ("(make-r)", "record?"),
+            ("(persistent-vector)", "persistent-vector?"),
+            ("(persistent-map)", "persistent-map?"),
+        ];
+        let predicates: Vec<_> = TagsDict::new()
+            .iter()
+            .filter_map(|tag| tag.predicate)
+            .collect();
+        assert_eq!(predicates.len(), 15);
+        for (input, expected) in samples {
+            for predicate in &predicates {
+                let value = eval(&mut runtime, &format!("({} {})", predicate, input)).unwrap();
+                assert_eq!(
+                    value.as_bool(),
+                    Some(predicate == &expected),
+                    "({} {})",
+                    predicate,
+                    input
+                );
+            }
+        }
+        for (alias, predicate) in [("nil?", "null?"), ("bool?", "boolean?")] {
+            for (input, expected) in samples {
+                let value = eval(&mut runtime, &format!("({} {})", alias, input)).unwrap();
+                assert_eq!(
+                    value.as_bool(),
+                    Some(predicate == expected),
+                    "({} {})",
+                    alias,
+                    input
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_unicode_chars() {
+        let mut runtime = Runtime::new();
+        let mut eval = |input: &str| eval_str(&mut runtime, input);
+        for code_point in [0, 0x41, 0x3bb, 0xd7ff, 0xe000, 0x1f600, 0x10ffff] {
+            assert_eq!(
+                eval(&format!("(char->integer (integer->char {}))", code_point)),
+                Ok(code_point.to_string())
+            );
+        }
+        assert_eq!(eval("(integer->char 955)"), Ok("#\\λ".to_string()));
+        assert_eq!(eval("(char->integer #\\λ)"), Ok("955".to_string()));
+        assert_eq!(eval("(integer->char 7)"), Ok("#\\x7".to_string()));
+        let max = K_INTEGER_MAX.to_string();
+        for code_point in ["(sub1 0)", "55296", "57343", "1114112", max.as_str()] {
+            assert_eq!(
+                eval(&format!("(integer->char {})", code_point)),
+                Err(format!(
+                    "integer->char: {} is not a Unicode scalar value",
+                    eval(code_point).unwrap()
+                ))
+            );
+        }
+        let cases = [
+            ("(char-upcase #\\a)", "#\\A"),
+            ("(char-upcase #\\λ)", "#\\Λ"),
+            ("(char-downcase #\\Λ)", "#\\λ"),
+            ("(char-upcase #\\ß)", "#\\ß"),
+            ("(char-upcase #\\1)", "#\\1"),
+            ("(char-alphabetic? #\\λ)", "#t"),
+            ("(char-alphabetic? #\\1)", "#f"),
+            ("(char-numeric? #\\7)", "#t"),
+            ("(char-numeric? #\\a)", "#f"),
+            ("(char-whitespace? #\\space)", "#t"),
+            ("(char-whitespace? (integer->char 12288))", "#t"),
+            ("(char-whitespace? #\\a)", "#f"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input), Ok(expected.to_string()), "{}", input);
+        }
+        assert_eq!(
+            eval("(char-upcase 1)"),
+            Err("char-upcase: expected char, got 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_typed_arguments() {
+        let mut runtime = Runtime::new();
+        runtime.primitives_mut().register(Primitive::new(
+            "char-id",
+            Arity::Fixed(1),
+            &[ArgType::CHAR],
+            |_, _| {},
+        ));
+        for input in ["true", "false", "nil", "0", "'a"] {
+            assert_eq!(
+                eval(&mut runtime, &format!("(char-id {})", input)),
+                Err(format!(
+                    "char-id: expected char, got {}",
+                    eval(&mut runtime, input).unwrap()
+                ))
+            );
+        }
+        let value = eval(&mut runtime, "(char-id #\\z)").unwrap();
+        assert_eq!(value.as_char(), Some('z'));
+    }
+}
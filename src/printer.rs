@@ -0,0 +1,420 @@
+// Prints values the way `write` and `display` do, walking the heap.
+//
+// Pairs, vectors, records and persistent collections that are part of a cycle
+// get a datum label: the first occurrence prints as `#0=(...)` and later ones
+// as `#0#`, so printing always terminates.
+//
+// Persistent vectors print as `[1 2 3]` and persistent maps as `{a 1, b 2}`.
+
+use crate::bignum::BigInt;
+use crate::encodings::{LispValue, Record, Word};
+use crate::numbers::format_flonum;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+/// Whether strings and characters print as data (`write`) or as text (`display`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Write,
+    Display,
+}
+
+/// Prints `value` in `style`.
+///
+/// Every heap object reachable from `value` must be valid, as it is for the
+/// values returned by compiled code.
+pub fn print(value: LispValue, style: Style) -> String {
+    let mut printer = Printer {
+        style,
+        labels: find_cycles(value)
+            .into_iter()
+            .map(|address| (address, None))
+            .collect(),
+        next_label: 0,
+        out: String::new(),
+    };
+    printer.print(value);
+    printer.out
+}
+
+/// The address of the container `value` points to.
+fn container_address(value: LispValue) -> Option<Word> {
+    if let Some(ptr) = value.as_pair_pointer() {
+        Some(ptr as Word)
+    } else if let Some(ptr) = value.as_vector_pointer() {
+        Some(ptr as Word)
+    } else if let Some(ptr) = value.as_persistent_vector_pointer() {
+        Some(ptr as Word)
+    } else if let Some(ptr) = value.as_persistent_map_pointer() {
+        Some(ptr as Word)
+    } else {
+        value.as_record_pointer().map(|ptr| ptr as Word)
+    }
+}
+
+/// The children of a container, in printing order.
+fn children(value: LispValue) -> Vec<LispValue> {
+    if let Some(pair) = value.as_pair_pointer() {
+        let pair = unsafe { *pair };
+        vec![pair.car, pair.cdr]
+    } else if let Some(vector) = value.as_vector_pointer() {
+        unsafe { (*vector).elements().to_vec() }
+    } else if let Some(record) = value.as_record_pointer() {
+        unsafe { (*record).fields().to_vec() }
+    } else if let Some(elements) = value.elements() {
+        elements
+    } else if let Some(entries) = value.entries() {
+        entries.into_iter().flat_map(|(k, v)| [k, v]).collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Finds the containers reachable from `root` that are reachable from
+/// themselves, with an iterative depth-first search so that long lists do
+/// not exhaust the Rust stack.
+fn find_cycles(root: LispValue) -> HashSet<Word> {
+    enum Step {
+        Enter(LispValue),
+        Leave(Word),
+    }
+    let mut on_path = HashSet::new();
+    let mut done = HashSet::new();
+    let mut cycles = HashSet::new();
+    let mut stack = vec![Step::Enter(root)];
+    while let Some(step) = stack.pop() {
+        match step {
+            Step::Enter(value) => {
+                let Some(address) = container_address(value) else {
+                    continue;
+                };
+                if on_path.contains(&address) {
+                    cycles.insert(address);
+                    continue;
+                }
+                if !done.insert(address) {
+                    continue;
+                }
+                on_path.insert(address);
+                stack.push(Step::Leave(address));
+                for child in children(value).into_iter().rev() {
+                    stack.push(Step::Enter(child));
+                }
+            }
+            Step::Leave(address) => {
+                on_path.remove(&address);
+            }
+        }
+    }
+    cycles
+}
+
+struct Printer {
+    style: Style,
+    // Containers needing a label, with the label once assigned.
+    labels: HashMap<Word, Option<usize>>,
+    next_label: usize,
+    out: String,
+}
+
+impl Printer {
+    /// Prints the label of a labelled container. Returns true if it was
+    /// already printed, in which case only the reference is written.
+    fn print_label(&mut self, value: LispValue) -> bool {
+        let Some(address) = container_address(value) else {
+            return false;
+        };
+        match self.labels.get_mut(&address) {
+            Some(Some(label)) => {
+                write!(self.out, "#{}#", label).unwrap();
+                true
+            }
+            Some(label) => {
+                *label = Some(self.next_label);
+                write!(self.out, "#{}=", self.next_label).unwrap();
+                self.next_label += 1;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn is_labelled(&self, value: LispValue) -> bool {
+        container_address(value).is_some_and(|address| self.labels.contains_key(&address))
+    }
+
+    fn print(&mut self, value: LispValue) {
+        if self.print_label(value) {
+            return;
+        }
+        if value.is_pair() {
+            self.print_list(value);
+        } else if let Some(vector) = value.as_vector_pointer() {
+            self.out.push_str("#(");
+            let elements = unsafe { (*vector).elements() };
+            for (index, element) in elements.iter().enumerate() {
+                if index > 0 {
+                    self.out.push(' ');
+                }
+                self.print(*element);
+            }
+            self.out.push(')');
+        } else if let Some(record) = value.as_record_pointer() {
+            self.print_record(record);
+        } else if let Some(elements) = value.elements() {
+            self.out.push('[');
+            for (index, element) in elements.into_iter().enumerate() {
+                if index > 0 {
+                    self.out.push(' ');
+                }
+                self.print(element);
+            }
+            self.out.push(']');
+        } else if let Some(entries) = value.entries() {
+            self.out.push('{');
+            for (index, (key, value)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    self.out.push_str(", ");
+                }
+                self.print(key);
+                self.out.push(' ');
+                self.print(value);
+            }
+            self.out.push('}');
+        } else if let Some(string) = value.as_string_pointer() {
+            let string = unsafe { (*string).as_str() };
+            match self.style {
+                Style::Write => write_string_literal(&mut self.out, string),
+                Style::Display => self.out.push_str(string),
+            }
+        } else if let Some(c) = value.as_char() {
+            match self.style {
+                Style::Write => write_char_literal(&mut self.out, c),
+                Style::Display => self.out.push(c),
+            }
+        } else {
+            write_atom(&mut self.out, value);
+        }
+    }
+
+    /// Prints a proper or improper list, following the cdrs iteratively.
+    fn print_list(&mut self, mut list: LispValue) {
+        self.out.push('(');
+        loop {
+            let pair = unsafe { *list.as_pair_pointer().unwrap() };
+            self.print(pair.car);
+            list = pair.cdr;
+            if list.is_nil() {
+                break;
+            }
+            // A labelled tail must be printed as a datum of its own.
+            if !list.is_pair() || self.is_labelled(list) {
+                self.out.push_str(" . ");
+                self.print(list);
+                break;
+            }
+            self.out.push(' ');
+        }
+        self.out.push(')');
+    }
+
+    /// Prints a record as `#<record point x: 1 y: 2>`, taking the names from
+    /// its type descriptor.
+    fn print_record(&mut self, record: *mut Record) {
+        let (descriptor, fields) = unsafe { ((*record).descriptor, (*record).fields()) };
+        let names = unsafe { (*descriptor.as_vector_pointer().unwrap()).elements() };
+        write!(self.out, "#<record {}", print(names[0], Style::Display)).unwrap();
+        for (name, field) in names[1..].iter().zip(fields) {
+            write!(self.out, " {}: ", print(*name, Style::Display)).unwrap();
+            self.print(*field);
+        }
+        self.out.push('>');
+    }
+}
+
+fn write_string_literal(out: &mut String, string: &str) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_char_literal(out: &mut String, c: char) {
+    out.push_str("#\\");
+    match c {
+        ' ' => out.push_str("space"),
+        '\n' => out.push_str("newline"),
+        '\t' => out.push_str("tab"),
+        '\r' => out.push_str("return"),
+        '\0' => out.push_str("null"),
+        _ if c.is_control() => out.push_str(&format!("x{:x}", c as u32)),
+        _ => out.push(c),
+    }
+}
+
+/// Prints values that look the same under `write` and `display`.
+fn write_atom(out: &mut String, value: LispValue) {
+    if let Some(value) = value.as_bool() {
+        out.push_str(if value { "#t" } else { "#f" });
+    } else if let Some(value) = value.as_integer() {
+        write!(out, "{}", value).unwrap();
+    } else if let Some(ptr) = value.as_bignum_pointer() {
+        write!(out, "{}", unsafe { BigInt::from_bignum(&*ptr) }).unwrap();
+    } else if let Some(value) = value.as_flonum() {
+        out.push_str(&format_flonum(value));
+    } else if value.is_nil() {
+        out.push_str("()");
+    } else if value.is_unbound() {
+        out.push_str("#<unbound>");
+    } else if let Some(ptr) = value.as_symbol_pointer() {
+        out.push_str(unsafe { (*ptr).as_str() });
+    } else if let Some(ptr) = value.as_closure_pointer() {
+        let name = unsafe { (*ptr).name };
+        write!(out, "#<procedure {}>", print(name, Style::Display)).unwrap();
+    } else if value.is_hash_table() {
+        out.push_str("#<hash-table>");
+    } else {
+        write!(out, "#<unknown {:#x}>", value.as_raw_word()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::Runtime;
+    use crate::testing::eval;
+
+    #[test]
+    fn test_write_atoms() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("42", "42"),
+            ("(sub1 0)", "-1"),
+            ("true", "#t"),
+            ("false", "#f"),
+            ("nil", "()"),
+            ("#\\a", "#\\a"),
+            ("(integer->char 32)", "#\\space"),
+            ("'hello", "hello"),
+            ("(symbol->string 'hello)", "\"hello\""),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                eval(&mut runtime, input).unwrap().write(),
+                expected,
+                "{}",
+                input
+            );
+        }
+        eval(&mut runtime, "(define (f x) x)").unwrap();
+        assert_eq!(eval(&mut runtime, "f").unwrap().write(), "#<procedure f>");
+    }
+
+    #[test]
+    fn test_write_and_display_differ() {
+        let mut runtime = Runtime::new();
+        let value = eval(&mut runtime, "(cons #\\a (symbol->string 'b))").unwrap();
+        assert_eq!(value.write(), "(#\\a . \"b\")");
+        assert_eq!(value.display(), "(a . b)");
+        let string = runtime.alloc_string("say \"hi\"\n").unwrap();
+        assert_eq!(string.write(), "\"say \\\"hi\\\"\\n\"");
+        assert_eq!(string.display(), "say \"hi\"\n");
+    }
+
+    #[test]
+    fn test_write_lists() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("'(1 2 3)", "(1 2 3)"),
+            ("(cons 1 2)", "(1 . 2)"),
+            ("(cons 1 (cons 2 3))", "(1 2 . 3)"),
+            ("'((a b) (c) ())", "((a b) (c) ())"),
+            ("(vector 1 '(2 3) (vector))", "#(1 (2 3) #())"),
+            ("(cons (vector) nil)", "(#())"),
+            (
+                "(persistent-vector 1 (persistent-map 'a \"b\") nil)",
+                "[1 {a \"b\"} ()]",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                eval(&mut runtime, input).unwrap().write(),
+                expected,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_shared_structure_is_not_labelled() {
+        let mut runtime = Runtime::new();
+        eval(&mut runtime, "(define shared '(1 2))").unwrap();
+        let value = eval(&mut runtime, "(cons shared shared)").unwrap();
+        assert_eq!(value.write(), "((1 2) 1 2)");
+    }
+
+    #[test]
+    fn test_write_cycles() {
+        let mut runtime = Runtime::new();
+        eval(&mut runtime, "(define l '(1 2 3))").unwrap();
+        eval(&mut runtime, "(set-cdr! (cdr (cdr l)) l)").unwrap();
+        assert_eq!(eval(&mut runtime, "l").unwrap().write(), "#0=(1 2 3 . #0#)");
+        assert_eq!(
+            eval(&mut runtime, "(cons 0 l)").unwrap().write(),
+            "(0 . #0=(1 2 3 . #0#))"
+        );
+
+        eval(&mut runtime, "(define v (vector 1 2))").unwrap();
+        eval(&mut runtime, "(vector-set! v 1 v)").unwrap();
+        assert_eq!(eval(&mut runtime, "v").unwrap().write(), "#0=#(1 #0#)");
+
+        eval(&mut runtime, "(define p (cons 1 2))").unwrap();
+        eval(&mut runtime, "(set-car! p p)").unwrap();
+        eval(&mut runtime, "(set-cdr! p p)").unwrap();
+        assert_eq!(eval(&mut runtime, "p").unwrap().write(), "#0=(#0# . #0#)");
+        assert_eq!(
+            eval(&mut runtime, "(cons p v)").unwrap().write(),
+            "(#0=(#0# . #0#) . #1=#(1 #1#))"
+        );
+
+        eval(
+            &mut runtime,
+            "(define-record-type box (make-box x) box? (x unbox set-box!))",
+        )
+        .unwrap();
+        eval(&mut runtime, "(define b (make-box 1))").unwrap();
+        eval(&mut runtime, "(set-box! b (cons b #\\a))").unwrap();
+        assert_eq!(
+            eval(&mut runtime, "b").unwrap().write(),
+            "#0=#<record box x: (#0# . #\\a)>"
+        );
+        assert_eq!(
+            eval(&mut runtime, "b").unwrap().display(),
+            "#0=#<record box x: (#0# . a)>"
+        );
+    }
+
+    #[test]
+    fn test_write_long_list() {
+        let mut runtime = Runtime::new();
+        let mut list = LispValue::nil();
+        for _ in 0..100_000 {
+            let pair = runtime
+                .alloc_pair(LispValue::from_integer(7), list)
+                .unwrap();
+            list = pair;
+        }
+        let printed = list.write();
+        assert!(printed.starts_with("(7 7 7"));
+        assert_eq!(printed.len(), 2 * 100_000 + 1);
+    }
+}
@@ -27,11 +27,27 @@ impl<'a> Parser<'a> {
         match token {
             Token::LParen => self.read_list_tail(),
             Token::RParen => Err("Unexpected ')'".to_string()),
+            Token::Error(message) => Err(message),
 
             Token::Char(c) => Ok(AstNode::Char(c)),
+            Token::Str(s) => Ok(AstNode::Str(s)),
 
             Token::Integer(i) => Ok(AstNode::Integer(i)),
+            Token::BigInteger(i) => Ok(AstNode::BigInteger(i)),
+            Token::Float(f) => Ok(AstNode::Float(f)),
             Token::Symbol(s) => self.parse_symbol(s),
+
+            // 'x is shorthand for (quote x)
+            Token::Quote => {
+                let quoted = self.read_form()?;
+                Ok(AstNode::Pair {
+                    car: Box::new(AstNode::Symbol("quote".to_string())),
+                    cdr: Box::new(AstNode::Pair {
+                        car: Box::new(quoted),
+                        cdr: Box::new(AstNode::Nil),
+                    }),
+                })
+            }
         }
     }
 
@@ -69,6 +85,7 @@ impl<'a> Parser<'a> {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -89,4 +106,29 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_reader_quote() {
+        let mut reader = Parser::new("'foo");
+        assert_eq!(reader.read_form(), Parser::new("(quote foo)").read_form());
+    }
+
+    #[test]
+    fn test_reader_errors() {
+        let cases = [
+            ("\"abc", "Unterminated string: \"abc"),
+            ("(f \"a\\", "Unterminated string: \"a"),
+            ("\"a\\q\"", "Unknown escape in string: \\q"),
+            ("(list #\\bogus)", "Unknown character name: #\\bogus"),
+            ("#\\xd800", "Unknown character name: #\\xd800"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                Parser::new(input).read_form(),
+                Err(expected.to_string()),
+                "{}",
+                input
+            );
+        }
+    }
 }
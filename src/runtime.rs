@@ -0,0 +1,1047 @@
+use crate::bignum::BigInt;
+use crate::census::{self, HeapCensus};
+use crate::encodings::{
+    Bignum, HashTable, Header, K_BIGNUM_KIND, K_HASH_TABLE_KIND, K_INTEGER_MAX, K_INTEGER_MIN,
+    K_PERSISTENT_MAP_KIND, K_PERSISTENT_VECTOR_KIND, K_STRING_BYTES_KIND, K_STRING_TAG,
+    K_SYMBOL_TAG, K_VECTOR_TAG, LispString, LispValue, Pair, PersistentMap, PersistentVector,
+    StringBytes, Symbol, TagsDict, Vector, Word,
+};
+#[cfg(not(feature = "nan-boxing"))]
+use crate::encodings::{Flonum, K_FLONUM_KIND};
+use crate::executable_buffer::ExecBuffer;
+use crate::gc::{self, Collector};
+use crate::hashtables::{Equivalence, INITIAL_BUCKETS};
+use crate::heap::{DEFAULT_HEAP_LIMIT, DEFAULT_HEAP_SIZE, GcStats, Heap, OldSpace};
+use crate::primitives::Primitives;
+use crate::symbols::SymbolTable;
+use crate::verify::{self, HeapError};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem::offset_of;
+use std::time::Instant;
+
+/// Signature of the code produced by `Compiler::compile_function`.
+/// The runtime pointer arrives in RDI and is kept in RBX while the code runs.
+pub type JitFunction = unsafe extern "C" fn(*mut Runtime) -> Word;
+
+/// State shared between Rust and the generated code.
+/// The generated code reads and writes the first fields directly, so the
+/// layout is fixed with `repr(C)` and the offsets are exported below.
+#[repr(C)]
+pub struct Runtime {
+    /// Next free byte in the nursery. Compiled code bumps it inline and calls
+    /// `rt_alloc_slow` once it would pass `heap_limit`.
+    heap_ptr: usize,
+    /// One past the last usable byte in the nursery.
+    heap_limit: usize,
+    /// Frame pointer of the outermost compiled frame, used to unwind on errors.
+    entry_frame: usize,
+    /// Non-zero once `error` is set; checked by compiled code after runtime calls.
+    error_pending: usize,
+    /// Frame and stack pointers of the compiled code that last called into
+    /// the runtime, recorded before the call. The collector walks the stack
+    /// from there; zero while no compiled code is running.
+    last_fp: usize,
+    last_sp: usize,
+    /// Bounds of the old generation and its card table, for the write barrier
+    /// in compiled code. Updated whenever a major collection replaces it.
+    old_start: usize,
+    old_size: usize,
+    card_table: usize,
+    /// Non-zero once a global was stored to since the last collection; only
+    /// then can a global point into the nursery.
+    globals_dirty: usize,
+    heap: Heap,
+    /// Collect on every allocation, to shake out missing roots.
+    gc_stress: bool,
+    /// Verify the heap before and after every collection.
+    verify_heap: bool,
+    /// Values held by Rust code across an allocation, which the collector
+    /// treats as roots and updates.
+    roots: Vec<LispValue>,
+    error: Option<String>,
+    /// Shared by every compilation against this runtime, so a name always
+    /// yields the same symbol.
+    symbols: SymbolTable,
+    /// Top-level bindings. Each value is boxed so compiled code can refer to the
+    /// cell directly; redefining a name updates the cell in place.
+    globals: HashMap<String, Box<LispValue>>,
+    /// Code of the procedures defined so far, kept alive for their callers.
+    procedures: Vec<ExecBuffer>,
+    /// Text of the string literals compiled so far, which their code copies.
+    string_literals: Vec<Box<str>>,
+    /// Integer literals too large for a fixnum, which their code copies
+    /// into the heap. Boxed, as the code refers to them by address.
+    #[allow(clippy::vec_box)]
+    bignum_literals: Vec<Box<BigInt>>,
+    /// The type descriptor of each `define-record-type` compiled so far,
+    /// which the code of its procedures loads by address. Roots of every
+    /// collection, so no write barrier guards them.
+    #[allow(clippy::vec_box)]
+    record_types: Vec<Box<LispValue>>,
+    /// Primitives the compiler inlines, including any registered by the embedder.
+    primitives: Primitives,
+}
+
+pub const RT_HEAP_PTR: i32 = offset_of!(Runtime, heap_ptr) as i32;
+pub const RT_HEAP_LIMIT: i32 = offset_of!(Runtime, heap_limit) as i32;
+pub const RT_ENTRY_FRAME: i32 = offset_of!(Runtime, entry_frame) as i32;
+pub const RT_ERROR_PENDING: i32 = offset_of!(Runtime, error_pending) as i32;
+pub const RT_LAST_FP: i32 = offset_of!(Runtime, last_fp) as i32;
+pub const RT_LAST_SP: i32 = offset_of!(Runtime, last_sp) as i32;
+pub const RT_OLD_START: i32 = offset_of!(Runtime, old_start) as i32;
+pub const RT_OLD_SIZE: i32 = offset_of!(Runtime, old_size) as i32;
+pub const RT_CARD_TABLE: i32 = offset_of!(Runtime, card_table) as i32;
+pub const RT_GLOBALS_DIRTY: i32 = offset_of!(Runtime, globals_dirty) as i32;
+
+/// In stress mode, every this many collections is a major one.
+const STRESS_MAJOR_INTERVAL: usize = 8;
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        Self::with_heap(DEFAULT_HEAP_SIZE, DEFAULT_HEAP_LIMIT)
+    }
+
+    /// A runtime with a nursery of `size` bytes, whose old generation grows
+    /// as collections find more live data, up to `limit` bytes.
+    pub fn with_heap(size: usize, limit: usize) -> Self {
+        if let Err(message) = TagsDict::new().check_for_overlapping() {
+            panic!("Invalid tagging scheme: {}", message);
+        }
+        let heap = Heap::new(size, limit).expect("failed to map the heap");
+        let mut runtime = Runtime {
+            heap_ptr: heap.nursery().start(),
+            heap_limit: heap.nursery().end(),
+            entry_frame: 0,
+            error_pending: 0,
+            last_fp: 0,
+            last_sp: 0,
+            old_start: 0,
+            old_size: 0,
+            card_table: 0,
+            globals_dirty: 0,
+            heap,
+            gc_stress: false,
+            verify_heap: false,
+            roots: Vec::new(),
+            error: None,
+            symbols: SymbolTable::new(),
+            globals: HashMap::new(),
+            procedures: Vec::new(),
+            string_literals: Vec::new(),
+            bignum_literals: Vec::new(),
+            record_types: Vec::new(),
+            primitives: Primitives::builtin(),
+        };
+        runtime.update_old_bounds();
+        runtime
+    }
+
+    /// Runs compiled code, returning either its value or the error it raised.
+    pub fn run(&mut self, code: &ExecBuffer) -> Result<LispValue, String> {
+        let func = unsafe { code.as_function::<JitFunction>() };
+        let encoded_result = unsafe { func(self) };
+        self.error_pending = 0;
+        self.last_fp = 0;
+        match self.error.take() {
+            Some(message) => Err(message),
+            None => Ok(LispValue::from_raw_word(encoded_result)),
+        }
+    }
+
+    /// Interns `name` in the runtime's symbol table, allocating the symbol on
+    /// first use. Panics if the heap is exhausted.
+    pub fn intern(&mut self, name: &str) -> LispValue {
+        self.try_intern(name)
+            .expect("heap exhausted while interning")
+    }
+
+    /// Like `intern`, but returns `None` if the heap is exhausted.
+    pub fn try_intern(&mut self, name: &str) -> Option<LispValue> {
+        if let Some(symbol) = self.symbols.get(name) {
+            return Some(symbol);
+        }
+        let symbol = self.alloc_symbol(name)?;
+        self.symbols.insert(name, symbol);
+        Some(symbol)
+    }
+
+    /// Creates a fresh, uninterned symbol that is never `eq?` to any other.
+    pub fn gensym(&mut self) -> Option<LispValue> {
+        let name = self.symbols.next_gensym_name();
+        let symbol = self.alloc_symbol(&name)?;
+        self.symbols.insert_weak(symbol);
+        Some(symbol)
+    }
+
+    fn alloc_symbol(&mut self, name: &str) -> Option<LispValue> {
+        let name = self.alloc_string(name)?;
+        self.push_root(name);
+        let ptr = self.alloc(size_of::<Symbol>());
+        let name = self.pop_root();
+        let ptr = ptr? as *mut Symbol;
+        unsafe {
+            ptr.write(Symbol {
+                header: Header::new(K_SYMBOL_TAG, 0),
+                name,
+            })
+        };
+        Some(LispValue::from_symbol_pointer(ptr))
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut SymbolTable {
+        &mut self.symbols
+    }
+
+    pub fn primitives(&self) -> &Primitives {
+        &self.primitives
+    }
+
+    pub fn primitives_mut(&mut self) -> &mut Primitives {
+        &mut self.primitives
+    }
+
+    /// Returns the cell holding the global `name`, creating an unbound one if needed.
+    /// The caller may store through it, so the globals count as dirty.
+    pub fn global_cell(&mut self, name: &str) -> *mut LispValue {
+        self.globals_dirty = 1;
+        let cell = self
+            .globals
+            .entry(name.to_string())
+            .or_insert_with(|| Box::new(LispValue::unbound()));
+        &mut **cell
+    }
+
+    /// The value of the global `name`, if it has been defined.
+    pub fn global(&self, name: &str) -> Option<LispValue> {
+        self.globals
+            .get(name)
+            .map(|cell| **cell)
+            .filter(|value| !value.is_unbound())
+    }
+
+    /// Keeps the code of a procedure alive for as long as the runtime, returning its address.
+    pub(crate) fn keep_procedure(&mut self, code: ExecBuffer) -> *const u8 {
+        let address = code.as_ptr();
+        self.procedures.push(code);
+        address
+    }
+
+    /// Keeps the text of a string literal alive for as long as the runtime,
+    /// returning its address and length in bytes.
+    pub(crate) fn keep_string_literal(&mut self, value: &str) -> (*const u8, usize) {
+        let value: Box<str> = value.into();
+        let literal = (value.as_ptr(), value.len());
+        self.string_literals.push(value);
+        literal
+    }
+
+    /// Keeps an integer literal too large for a fixnum alive for as long as
+    /// the runtime, returning its address.
+    pub(crate) fn keep_bignum_literal(&mut self, value: &BigInt) -> *const BigInt {
+        let value = Box::new(value.clone());
+        let literal = &*value as *const BigInt;
+        self.bignum_literals.push(value);
+        literal
+    }
+
+    /// Returns a new cell for the type descriptor of a record type, holding
+    /// false until the code defining the type stores the descriptor.
+    pub(crate) fn record_type_cell(&mut self) -> *mut LispValue {
+        let mut cell = Box::new(LispValue::false_val());
+        let address = &mut *cell as *mut LispValue;
+        self.record_types.push(cell);
+        address
+    }
+
+    /// Records an error; compiled code unwinds once control returns to it.
+    pub(crate) fn raise(&mut self, message: String) {
+        self.error = Some(message);
+        self.error_pending = 1;
+    }
+
+    /// Bump-allocates `size` bytes from the heap, as the inline fast path in
+    /// compiled code does, collecting when the heap is full.
+    fn alloc(&mut self, size: usize) -> Option<*mut u8> {
+        if size > self.heap_limit - self.heap_ptr {
+            return self.alloc_slow(size);
+        }
+        let ptr = self.heap_ptr as *mut u8;
+        self.heap_ptr += size;
+        Some(ptr)
+    }
+
+    /// Collects, then allocates `size` bytes: from the nursery, or from the
+    /// old generation when the object is too large for the nursery to hold
+    /// comfortably. Returns `None` once the live data would exceed the limit.
+    fn alloc_slow(&mut self, size: usize) -> Option<*mut u8> {
+        if size > self.heap.limit() {
+            return None;
+        }
+        if size > self.heap.nursery().size() / 2 {
+            return self.alloc_old(size);
+        }
+        self.try_collect_minor().ok()?;
+        if self.heap.old().used() + size > self.heap.limit() {
+            self.try_collect_major(size).ok()?;
+            if self.heap.old().used() + size > self.heap.limit() {
+                return None;
+            }
+        }
+        let ptr = self.heap_ptr;
+        self.heap_ptr += size;
+        self.reset_heap_limit();
+        Some(ptr as *mut u8)
+    }
+
+    /// Allocates `size` bytes directly in the old generation. Its cards are
+    /// marked, as the caller fills the object without a write barrier.
+    fn alloc_old(&mut self, size: usize) -> Option<*mut u8> {
+        let old = self.heap.old();
+        if old.free() < size || old.used() + size > self.heap.limit() {
+            self.try_collect_major(size).ok()?;
+            if self.heap.old().used() + size > self.heap.limit() {
+                return None;
+            }
+        }
+        let old = self.heap.old_mut();
+        let ptr = old.bump(size)?;
+        old.mark(ptr, ptr + size);
+        self.heap.record_allocation(size);
+        Some(ptr as *mut u8)
+    }
+
+    /// Runs a major collection, of both generations.
+    pub fn collect(&mut self) {
+        self.try_collect_major(0)
+            .expect("failed to map the old generation");
+    }
+
+    /// Runs a minor collection, of the nursery only.
+    pub fn collect_minor(&mut self) {
+        self.try_collect_minor()
+            .expect("failed to map the old generation");
+    }
+
+    /// Copies the live objects of the nursery into the old generation. Falls
+    /// back to a major collection when they might not fit.
+    fn try_collect_minor(&mut self) -> Result<(), &'static str> {
+        let nursery = self.heap.nursery().start()..self.heap_ptr;
+        let stress_major = self.gc_stress
+            && self.heap.collections() % STRESS_MAJOR_INTERVAL == STRESS_MAJOR_INTERVAL - 1;
+        if self.heap.old().free() < nursery.len() || stress_major {
+            return self.try_collect_major(0);
+        }
+        self.verify_if_enabled("before a minor collection");
+        let started = Instant::now();
+        let dirty_cards = self.heap.old().dirty_cards();
+        let stack = self.stack_bounds();
+        let Runtime {
+            heap,
+            globals,
+            symbols,
+            roots,
+            globals_dirty,
+            record_types,
+            ..
+        } = self;
+        let globals = (*globals_dirty != 0).then_some(globals);
+        let mut collector = Collector::new(vec![nursery], heap.old_mut());
+        forward_roots(&mut collector, globals, record_types, symbols, roots, stack);
+        for (start, end, object) in dirty_cards {
+            unsafe { collector.forward_fields_in(start, end, object) };
+        }
+        collector.scan();
+        symbols.update_weak(|symbol| collector.survivor(symbol));
+        heap.old_mut().clear_cards();
+        self.finish_collection();
+        self.heap.record_collection(false, started.elapsed());
+        self.verify_if_enabled("after a minor collection");
+        Ok(())
+    }
+
+    /// Copies every live object into a fresh old generation, leaving room for
+    /// an allocation of `extra` bytes and a full nursery. Grows the old
+    /// generation again when the live data fills more than half of it.
+    fn try_collect_major(&mut self, extra: usize) -> Result<(), &'static str> {
+        self.verify_if_enabled("before a major collection");
+        let started = Instant::now();
+        let nursery_size = self.heap.nursery().size();
+        // Even if everything survives, the nursery can still be emptied into
+        // what is left.
+        let worst_case = self.heap_used() + extra + nursery_size;
+        self.copy_into_old(worst_case.max(self.heap.old().size()))?;
+        let live = self.heap.old().used() + extra;
+        if 2 * live > self.heap.old().size() && live <= self.heap.limit() {
+            self.copy_into_old(2 * live + nursery_size)?;
+        }
+        self.heap.record_collection(true, started.elapsed());
+        self.verify_if_enabled("after a major collection");
+        Ok(())
+    }
+
+    /// Copies every live object into a new old generation of `size` bytes.
+    fn copy_into_old(&mut self, size: usize) -> Result<(), &'static str> {
+        let mut to = OldSpace::new(size)?;
+        let from = vec![
+            self.heap.nursery().start()..self.heap_ptr,
+            self.heap.old().start()..self.heap.old().top(),
+        ];
+        let stack = self.stack_bounds();
+        let mut collector = Collector::new(from, &mut to);
+        forward_roots(
+            &mut collector,
+            Some(&mut self.globals),
+            &mut self.record_types,
+            &mut self.symbols,
+            &mut self.roots,
+            stack,
+        );
+        collector.scan();
+        self.symbols
+            .update_weak(|symbol| collector.survivor(symbol));
+        self.heap.replace_old(to);
+        self.update_old_bounds();
+        self.finish_collection();
+        Ok(())
+    }
+
+    /// The frame and stack pointers of the compiled code that called into
+    /// the runtime, with its entry frame, if any is running.
+    fn stack_bounds(&self) -> Option<(usize, usize, usize)> {
+        (self.last_fp != 0).then_some((self.last_fp, self.last_sp, self.entry_frame))
+    }
+
+    /// Empties the nursery once its survivors have been copied out.
+    fn finish_collection(&mut self) {
+        let allocated = self.heap_ptr - self.heap.nursery().start();
+        self.heap.record_allocation(allocated);
+        self.globals_dirty = 0;
+        self.heap_ptr = self.heap.nursery().start();
+        self.reset_heap_limit();
+    }
+
+    fn reset_heap_limit(&mut self) {
+        self.heap_limit = if self.gc_stress {
+            self.heap_ptr
+        } else {
+            self.heap.nursery().end()
+        };
+    }
+
+    fn update_old_bounds(&mut self) {
+        let old = self.heap.old_mut();
+        self.old_start = old.start();
+        self.old_size = old.size();
+        self.card_table = old.card_table() as usize;
+    }
+
+    /// In stress mode every allocation, inline or from Rust, runs a collection.
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.gc_stress = stress;
+        self.reset_heap_limit();
+    }
+
+    /// When enabled, the heap is verified before and after every collection,
+    /// panicking at the first corrupt value.
+    pub fn set_heap_verification(&mut self, verify: bool) {
+        self.verify_heap = verify;
+    }
+
+    /// Checks every value reachable from the roots; see `verify::verify`.
+    pub fn verify_heap(&mut self) -> Result<(), HeapError> {
+        let spaces = [
+            self.heap.nursery().start()..self.heap_ptr,
+            self.heap.old().start()..self.heap.old().top(),
+        ];
+        verify::verify(&self.named_roots(), &spaces)
+    }
+
+    fn verify_if_enabled(&mut self, when: &str) {
+        if self.verify_heap
+            && let Err(error) = self.verify_heap()
+        {
+            panic!("heap verification failed {}: {}", when, error);
+        }
+    }
+
+    /// Keeps `value` alive, and up to date, across collections until the
+    /// matching `pop_root`.
+    pub fn push_root(&mut self, value: LispValue) {
+        self.roots.push(value);
+    }
+
+    /// Returns the value of the most recent `push_root`, wherever the
+    /// collector has moved it.
+    pub fn pop_root(&mut self) -> LispValue {
+        self.roots.pop().expect("no root to pop")
+    }
+
+    /// Allocates a new pair.
+    pub fn alloc_pair(&mut self, car: LispValue, cdr: LispValue) -> Option<LispValue> {
+        self.push_root(car);
+        self.push_root(cdr);
+        let ptr = self.alloc(size_of::<Pair>());
+        let cdr = self.pop_root();
+        let car = self.pop_root();
+        let ptr = ptr? as *mut Pair;
+        unsafe { ptr.write(Pair { car, cdr }) };
+        Some(LispValue::from_pair_pointer(ptr))
+    }
+
+    /// Allocates a vector of `length` elements, each `fill`.
+    pub fn alloc_vector(&mut self, length: usize, fill: LispValue) -> Option<LispValue> {
+        self.push_root(fill);
+        let ptr = self.alloc(size_of::<Vector>() + length * size_of::<LispValue>());
+        let fill = self.pop_root();
+        let ptr = ptr? as *mut Vector;
+        unsafe {
+            (*ptr).header = Header::new(K_VECTOR_TAG, length);
+            (*ptr).elements_mut().fill(fill);
+        }
+        Some(LispValue::from_vector_pointer(ptr))
+    }
+
+    /// Allocates an empty hash table comparing keys with `equivalence`.
+    pub fn alloc_hash_table(&mut self, equivalence: Equivalence) -> Option<LispValue> {
+        let buckets = self.alloc_vector(INITIAL_BUCKETS, LispValue::nil())?;
+        self.push_root(buckets);
+        let ptr = self.alloc(size_of::<HashTable>());
+        let buckets = self.pop_root();
+        let ptr = ptr? as *mut HashTable;
+        unsafe {
+            ptr.write(HashTable {
+                header: Header::new(K_HASH_TABLE_KIND, 0),
+                equivalence: equivalence.encode(),
+                count: LispValue::from_integer(0),
+                epoch: LispValue::false_val(),
+                buckets,
+            });
+        }
+        Some(LispValue::from_hash_table_pointer(ptr))
+    }
+
+    /// Allocates a persistent vector of `count` elements in the trie `root`,
+    /// whose top level `shift` selects from; see `persistent.rs`.
+    pub(crate) fn alloc_persistent_vector(
+        &mut self,
+        count: usize,
+        shift: usize,
+        root: LispValue,
+    ) -> Option<LispValue> {
+        self.push_root(root);
+        let ptr = self.alloc(size_of::<PersistentVector>());
+        let root = self.pop_root();
+        let ptr = ptr? as *mut PersistentVector;
+        unsafe {
+            ptr.write(PersistentVector {
+                header: Header::new(K_PERSISTENT_VECTOR_KIND, 0),
+                count: LispValue::from_integer(count as Word),
+                shift: LispValue::from_integer(shift as Word),
+                root,
+            });
+        }
+        Some(LispValue::from_persistent_vector_pointer(ptr))
+    }
+
+    /// Allocates a persistent map of `count` entries in the trie `root`; see
+    /// `persistent.rs`.
+    pub(crate) fn alloc_persistent_map(
+        &mut self,
+        count: usize,
+        root: LispValue,
+    ) -> Option<LispValue> {
+        self.push_root(root);
+        let ptr = self.alloc(size_of::<PersistentMap>());
+        let root = self.pop_root();
+        let ptr = ptr? as *mut PersistentMap;
+        unsafe {
+            ptr.write(PersistentMap {
+                header: Header::new(K_PERSISTENT_MAP_KIND, 0),
+                count: LispValue::from_integer(count as Word),
+                root,
+            });
+        }
+        Some(LispValue::from_persistent_map_pointer(ptr))
+    }
+
+    /// Copies `value` into a new heap string. The string and its storage are
+    /// allocated together, the storage right after the string.
+    pub fn alloc_string(&mut self, value: &str) -> Option<LispValue> {
+        let size = size_of::<LispString>() + StringBytes::allocation_size(value.len());
+        let ptr = self.alloc(size)? as *mut LispString;
+        unsafe {
+            let bytes = ptr.add(1) as *mut StringBytes;
+            Self::init_string_bytes(bytes, value.as_bytes());
+            ptr.write(LispString {
+                header: Header::new(K_STRING_TAG, 0),
+                length: LispValue::from_integer(value.chars().count() as Word),
+                bytes: LispValue::from_string_bytes_pointer(bytes),
+            });
+        }
+        Some(LispValue::from_string_pointer(ptr))
+    }
+
+    /// Returns `value` as a fixnum if it fits one, and otherwise copies it
+    /// into a new bignum.
+    pub fn alloc_integer(&mut self, value: &BigInt) -> Option<LispValue> {
+        if let Some(value) = value.to_i64()
+            && (K_INTEGER_MIN..=K_INTEGER_MAX).contains(&value)
+        {
+            return Some(LispValue::from_integer(value));
+        }
+        let limbs = value.limbs();
+        let ptr = self.alloc(Bignum::allocation_size(limbs.len()))? as *mut Bignum;
+        unsafe {
+            ptr.write(Bignum {
+                header: Header::new(K_BIGNUM_KIND, limbs.len()),
+                negative: value.is_negative() as Word,
+                limbs: [],
+            });
+            std::ptr::copy_nonoverlapping(limbs.as_ptr(), (*ptr).limbs.as_mut_ptr(), limbs.len());
+        }
+        Some(LispValue::from_bignum_pointer(ptr))
+    }
+
+    /// Boxes `value` into a new flonum.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn alloc_flonum(&mut self, value: f64) -> Option<LispValue> {
+        let ptr = self.alloc(size_of::<Flonum>())? as *mut Flonum;
+        unsafe {
+            ptr.write(Flonum {
+                header: Header::new(K_FLONUM_KIND, 0),
+                value,
+            });
+        }
+        Some(LispValue::from_flonum_pointer(ptr))
+    }
+
+    /// The flonum `value`, which NaN-boxing leaves unboxed.
+    #[cfg(feature = "nan-boxing")]
+    pub fn alloc_flonum(&mut self, value: f64) -> Option<LispValue> {
+        Some(LispValue::from_f64(value))
+    }
+
+    /// Allocates storage for a string holding `value`, which must be UTF-8.
+    pub(crate) fn alloc_string_bytes(&mut self, value: &[u8]) -> Option<LispValue> {
+        let ptr = self.alloc(StringBytes::allocation_size(value.len()))? as *mut StringBytes;
+        unsafe { Self::init_string_bytes(ptr, value) };
+        Some(LispValue::from_string_bytes_pointer(ptr))
+    }
+
+    /// # Safety
+    /// `ptr` must have room for the storage of `value`.
+    unsafe fn init_string_bytes(ptr: *mut StringBytes, value: &[u8]) {
+        unsafe {
+            (*ptr).header = Header::new(K_STRING_BYTES_KIND, value.len());
+            let bytes = (*ptr).bytes.as_mut_ptr();
+            std::ptr::copy_nonoverlapping(value.as_ptr(), bytes, value.len());
+        }
+    }
+
+    /// Records a store of a young object into the field at `address`, for
+    /// Rust code writing to objects that may be old.
+    pub(crate) fn write_barrier(&mut self, address: usize) {
+        let old = self.heap.old_mut();
+        if old.contains(address) {
+            old.mark(address, address + size_of::<Word>());
+        }
+    }
+
+    /// Number of heap bytes in use in both generations, including garbage not
+    /// yet collected.
+    pub fn heap_used(&self) -> usize {
+        self.heap_ptr - self.heap.nursery().start() + self.heap.old().used()
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// The collector's counters, with the bytes allocated in the nursery
+    /// since it was last emptied.
+    pub fn gc_stats(&self) -> GcStats {
+        let mut stats = self.heap.stats();
+        stats.bytes_allocated += self.heap_ptr - self.heap.nursery().start();
+        stats
+    }
+
+    /// Counts the live objects by type, after a major collection to leave
+    /// only those in the heap.
+    pub fn heap_census(&mut self) -> Option<HeapCensus> {
+        self.try_collect_major(0).ok()?;
+        let old = self.heap.old();
+        let mut census = unsafe { HeapCensus::of_objects(old.start(), old.top()) };
+        census.set_roots(self.root_values().len());
+        Some(census)
+    }
+
+    /// Writes every live object to the file at `path`, in the format read by
+    /// `HeapCensus::from_dump`.
+    pub fn dump_heap(&mut self, path: &str) -> io::Result<()> {
+        self.try_collect_major(0).map_err(io::Error::other)?;
+        let roots = self.root_values();
+        let mut out = BufWriter::new(File::create(path)?);
+        let old = self.heap.old();
+        unsafe { census::write_dump(&mut out, &roots, old.start(), old.top())? };
+        out.flush()
+    }
+
+    /// The values of every root, in the order a collection forwards them.
+    fn root_values(&mut self) -> Vec<LispValue> {
+        self.named_roots()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    /// Every root, named by where it is held.
+    fn named_roots(&self) -> Vec<(String, LispValue)> {
+        let mut roots: Vec<_> = self
+            .globals
+            .iter()
+            .map(|(name, cell)| (format!("global {}", name), **cell))
+            .collect();
+        roots.extend(
+            self.record_types
+                .iter()
+                .enumerate()
+                .map(|(index, cell)| (format!("record type {}", index), **cell)),
+        );
+        roots.extend(
+            self.symbols
+                .iter_interned()
+                .map(|(name, symbol)| (format!("symbol {}", name), symbol)),
+        );
+        roots.extend(
+            self.roots
+                .iter()
+                .enumerate()
+                .map(|(index, value)| (format!("root {}", index), *value)),
+        );
+        if let Some((fp, sp, entry_frame)) = self.stack_bounds() {
+            let mut slot = 0;
+            unsafe {
+                gc::for_each_stack_root(fp, sp, entry_frame, |value| {
+                    roots.push((format!("stack slot {}", slot), *value));
+                    slot += 1;
+                })
+            };
+        }
+        roots
+    }
+
+    /// Pops the values of the last `count` calls to `push_root` into a list,
+    /// the first pushed first. Returns `None`, popping them all the same, if
+    /// the heap is exhausted.
+    pub(crate) fn pop_list(&mut self, count: usize) -> Option<LispValue> {
+        let base = self.roots.len() - count;
+        let mut list = LispValue::nil();
+        for _ in 0..count {
+            let value = self.pop_root();
+            match self.alloc_pair(value, list) {
+                Some(pair) => list = pair,
+                None => {
+                    self.roots.truncate(base);
+                    return None;
+                }
+            }
+        }
+        Some(list)
+    }
+
+    /// The statistics returned by `gc-stats`, as a list of lists such as
+    /// `((bytes-allocated 4096) ... (live (pair 10 160) ...))`.
+    fn gc_stats_list(&mut self) -> Option<LispValue> {
+        // The parts built so far are roots until the heap runs out.
+        let base = self.roots.len();
+        let list = self.build_gc_stats_list();
+        self.roots.truncate(base);
+        list
+    }
+
+    fn build_gc_stats_list(&mut self) -> Option<LispValue> {
+        let census = self.heap_census()?;
+        let stats = self.gc_stats();
+        let fields = [
+            ("bytes-allocated", stats.bytes_allocated),
+            ("minor-collections", stats.minor_collections),
+            ("major-collections", stats.major_collections),
+            ("pause-us", stats.total_pause.as_micros() as usize),
+            ("max-pause-us", stats.max_pause.as_micros() as usize),
+        ];
+        for (name, value) in fields {
+            let name = self.try_intern(name)?;
+            self.push_root(name);
+            self.push_root(LispValue::from_integer(value as i64));
+            let field = self.pop_list(2)?;
+            self.push_root(field);
+        }
+        let live = self.try_intern("live")?;
+        self.push_root(live);
+        for (name, counts) in census.iter() {
+            let name = self.try_intern(name)?;
+            self.push_root(name);
+            self.push_root(LispValue::from_integer(counts.objects as i64));
+            self.push_root(LispValue::from_integer(counts.bytes as i64));
+            let entry = self.pop_list(3)?;
+            self.push_root(entry);
+        }
+        let live = self.pop_list(census.iter().count() + 1)?;
+        self.push_root(live);
+        self.pop_list(fields.len() + 1)
+    }
+}
+
+/// Forwards the roots of a collection: everything outside the heap that
+/// refers into it. The globals are left out of minor collections when none
+/// was stored to since the last one.
+fn forward_roots(
+    collector: &mut Collector<'_>,
+    globals: Option<&mut HashMap<String, Box<LispValue>>>,
+    record_types: &mut [Box<LispValue>],
+    symbols: &mut SymbolTable,
+    roots: &mut [LispValue],
+    stack: Option<(usize, usize, usize)>,
+) {
+    let mut forward = |value: &mut LispValue| *value = collector.forward(*value);
+    if let Some(globals) = globals {
+        globals.values_mut().for_each(|cell| forward(cell));
+    }
+    record_types.iter_mut().for_each(|cell| forward(cell));
+    symbols.for_each_interned(&mut forward);
+    roots.iter_mut().for_each(&mut forward);
+    if let Some((fp, sp, entry_frame)) = stack {
+        unsafe { gc::for_each_stack_root(fp, sp, entry_frame, forward) };
+    }
+}
+
+/// The arguments of a primitive that compiled code left on the stack for a
+/// runtime call, as `Compiler::emit_runtime_call_with_stack_args` does. They
+/// are roots of any collection during the call, so reading them again after
+/// an allocation gives their current values.
+pub(crate) struct StackArgs {
+    top: *const LispValue,
+    count: usize,
+}
+
+impl StackArgs {
+    /// # Safety
+    /// `top` must point at `count` values on the stack of compiled code,
+    /// live for the duration of the call.
+    pub(crate) unsafe fn new(top: *const LispValue, count: usize) -> Self {
+        StackArgs { top, count }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Argument `index`, counting from the first, which is deepest.
+    pub(crate) fn get(&self, index: usize) -> LispValue {
+        assert!(index < self.count);
+        unsafe { *self.top.add(self.count - 1 - index) }
+    }
+}
+
+/// A runtime function working on `StackArgs`, which fails with an error message.
+pub(crate) type StackArgsFunction = fn(&mut Runtime, &StackArgs) -> Result<LispValue, String>;
+
+/// Runs `function` on the arguments compiled code passed, raising its error
+/// if it fails.
+pub(crate) fn call_with_stack_args(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+    function: StackArgsFunction,
+) -> Word {
+    let rt = unsafe { &mut *rt };
+    let args = unsafe { StackArgs::new(args, argc) };
+    match function(rt, &args) {
+        Ok(value) => value.as_raw_word(),
+        Err(message) => {
+            rt.raise(message);
+            0
+        }
+    }
+}
+
+/// Called from the error exit of compiled code.
+/// `message` points at a length-prefixed string emitted next to the code, where
+/// a `{}` is replaced by the offending value.
+pub(crate) extern "C" fn rt_raise(rt: *mut Runtime, message: *const u8, value: Word) {
+    let (rt, message) = unsafe {
+        let len = u32::from_le_bytes(*(message as *const [u8; 4])) as usize;
+        let bytes = std::slice::from_raw_parts(message.add(4), len);
+        (
+            &mut *rt,
+            std::str::from_utf8(bytes).unwrap_or("invalid error message"),
+        )
+    };
+    let value = LispValue::from_raw_word(value);
+    rt.raise(message.replace("{}", &value.to_string()));
+}
+
+/// Called by compiled code when an allocation does not fit in the heap, which
+/// may move any object. Returns the untagged address of `size` fresh bytes, with the
+/// heap pointer moved past them, or 0 if the heap is exhausted.
+pub(crate) extern "C" fn rt_alloc_slow(rt: *mut Runtime, size: usize) -> usize {
+    let rt = unsafe { &mut *rt };
+    rt.alloc_slow(size).map_or(0, |ptr| ptr as usize)
+}
+
+/// `symbol->string`: copies the name of `symbol` into a new heap string.
+pub(crate) extern "C" fn rt_symbol_to_string(rt: *mut Runtime, symbol: Word) -> Word {
+    let rt = unsafe { &mut *rt };
+    let value = LispValue::from_raw_word(symbol);
+    // Unchecked code passes anything.
+    let Some(symbol) = value.as_symbol_pointer() else {
+        rt.raise(format!("symbol->string: expected symbol, got {}", value));
+        return 0;
+    };
+    let name = unsafe { (*symbol).as_str().to_string() };
+    match rt.alloc_string(&name) {
+        Some(string) => string.as_raw_word(),
+        None => {
+            rt.raise("symbol->string: heap exhausted".to_string());
+            0
+        }
+    }
+}
+
+/// `string->symbol`: interns the contents of `string`.
+pub(crate) extern "C" fn rt_string_to_symbol(rt: *mut Runtime, string: Word) -> Word {
+    let rt = unsafe { &mut *rt };
+    let value = LispValue::from_raw_word(string);
+    let Some(string) = value.as_string_pointer() else {
+        rt.raise(format!("string->symbol: expected string, got {}", value));
+        return 0;
+    };
+    let name = unsafe { (*string).as_str().to_string() };
+    match rt.try_intern(&name) {
+        Some(symbol) => symbol.as_raw_word(),
+        None => {
+            rt.raise("string->symbol: heap exhausted".to_string());
+            0
+        }
+    }
+}
+
+/// `gensym`: creates a fresh uninterned symbol.
+pub(crate) extern "C" fn rt_gensym(rt: *mut Runtime) -> Word {
+    let rt = unsafe { &mut *rt };
+    match rt.gensym() {
+        Some(symbol) => symbol.as_raw_word(),
+        None => {
+            rt.raise("gensym: heap exhausted".to_string());
+            0
+        }
+    }
+}
+
+/// `gc`: runs a major collection.
+pub(crate) extern "C" fn rt_gc(rt: *mut Runtime) -> Word {
+    let rt = unsafe { &mut *rt };
+    if rt.try_collect_major(0).is_err() {
+        rt.raise("gc: heap exhausted".to_string());
+    }
+    LispValue::nil().as_raw_word()
+}
+
+/// `gc-stats`: the collector's counters and the live objects by type.
+pub(crate) extern "C" fn rt_gc_stats(rt: *mut Runtime) -> Word {
+    let rt = unsafe { &mut *rt };
+    match rt.gc_stats_list() {
+        Some(stats) => stats.as_raw_word(),
+        None => {
+            rt.raise("gc-stats: heap exhausted".to_string());
+            0
+        }
+    }
+}
+
+/// `heap-object-count`: the number of live objects, of the type named by
+/// `type_name` unless it is nil.
+pub(crate) extern "C" fn rt_heap_object_count(rt: *mut Runtime, type_name: Word) -> Word {
+    let rt = unsafe { &mut *rt };
+    let type_name = LispValue::from_raw_word(type_name)
+        .as_symbol_pointer()
+        .map(|symbol| unsafe { (*symbol).as_str().to_string() });
+    if let Some(name) = &type_name
+        && !TagsDict::new().iter().any(|tag| tag.name == name)
+    {
+        rt.raise(format!("heap-object-count: unknown type {}", name));
+        return 0;
+    }
+    let Some(census) = rt.heap_census() else {
+        rt.raise("heap-object-count: heap exhausted".to_string());
+        return 0;
+    };
+    let count = match type_name {
+        Some(name) => census.get(&name).objects,
+        None => census.objects(),
+    };
+    LispValue::from_integer(count as i64).as_raw_word()
+}
+
+/// The character argument of the character primitive `primitive`, raising
+/// an error if unchecked code passed something else.
+fn char_arg(rt: *mut Runtime, primitive: &str, c: Word) -> Option<char> {
+    let value = LispValue::from_raw_word(c);
+    let c = value.as_char();
+    if c.is_none() {
+        let rt = unsafe { &mut *rt };
+        rt.raise(format!("{}: expected char, got {}", primitive, value));
+    }
+    c
+}
+
+/// Maps `c` to the single character `case` turns it into, leaving it alone
+/// when that is several characters, as for the upper case of `ß`.
+fn map_case<I: ExactSizeIterator<Item = char>>(c: char, case: fn(char) -> I) -> Word {
+    let mut mapped = case(c);
+    let c = match mapped.len() {
+        1 => mapped.next().unwrap(),
+        _ => c,
+    };
+    LispValue::from_char(c).as_raw_word()
+}
+
+/// `char-upcase`.
+pub(crate) extern "C" fn rt_char_upcase(rt: *mut Runtime, c: Word) -> Word {
+    char_arg(rt, "char-upcase", c).map_or(0, |c| map_case(c, char::to_uppercase))
+}
+
+/// `char-downcase`.
+pub(crate) extern "C" fn rt_char_downcase(rt: *mut Runtime, c: Word) -> Word {
+    char_arg(rt, "char-downcase", c).map_or(0, |c| map_case(c, char::to_lowercase))
+}
+
+/// `char-alphabetic?`.
+pub(crate) extern "C" fn rt_char_alphabetic(rt: *mut Runtime, c: Word) -> Word {
+    char_arg(rt, "char-alphabetic?", c)
+        .map_or(0, |c| LispValue::from_bool(c.is_alphabetic()).as_raw_word())
+}
+
+/// `char-numeric?`.
+pub(crate) extern "C" fn rt_char_numeric(rt: *mut Runtime, c: Word) -> Word {
+    char_arg(rt, "char-numeric?", c)
+        .map_or(0, |c| LispValue::from_bool(c.is_numeric()).as_raw_word())
+}
+
+/// `char-whitespace?`.
+pub(crate) extern "C" fn rt_char_whitespace(rt: *mut Runtime, c: Word) -> Word {
+    char_arg(rt, "char-whitespace?", c)
+        .map_or(0, |c| LispValue::from_bool(c.is_whitespace()).as_raw_word())
+}
@@ -0,0 +1,481 @@
+// The string primitives that compiled code calls into the runtime for.
+//
+// Strings hold UTF-8, so indexing by character walks the bytes, except in
+// strings whose cached length shows they are all ASCII. Each function takes
+// its arguments as `StackArgs` and copies what it needs out of them before
+// allocating, since a collection moves the strings they point to.
+
+use crate::bignum::BigInt;
+use crate::encodings::{LispString, LispValue, Word};
+use crate::numbers::{Number, format_flonum, number_value, parse_flonum};
+use crate::runtime::{Runtime, StackArgs, call_with_stack_args as call};
+
+pub(crate) fn heap_exhausted(name: &str) -> String {
+    format!("{}: heap exhausted", name)
+}
+
+/// The string `value` points to, which the caller made sure of.
+fn string<'a>(value: LispValue) -> &'a mut LispString {
+    unsafe { &mut *value.as_string_pointer().unwrap() }
+}
+
+/// The string argument `index`. Unchecked code may pass anything, so this
+/// checks the type again.
+fn string_arg<'a>(
+    args: &StackArgs,
+    index: usize,
+    name: &str,
+) -> Result<&'a mut LispString, String> {
+    let value = args.get(index);
+    match value.as_string_pointer() {
+        Some(string) => Ok(unsafe { &mut *string }),
+        None => Err(format!("{}: expected string, got {}", name, value)),
+    }
+}
+
+fn text_arg<'a>(args: &StackArgs, index: usize, name: &str) -> Result<&'a str, String> {
+    string_arg(args, index, name).map(|string| unsafe { string.as_str() })
+}
+
+/// The character argument `index`, checked like `string_arg`.
+fn char_arg(args: &StackArgs, index: usize, name: &str) -> Result<char, String> {
+    let value = args.get(index);
+    value
+        .as_char()
+        .ok_or_else(|| format!("{}: expected char, got {}", name, value))
+}
+
+/// Argument `index` as an index no greater than `limit`.
+fn index_arg(args: &StackArgs, index: usize, limit: usize, name: &str) -> Result<usize, String> {
+    let value = args.get(index);
+    match value.as_integer() {
+        Some(index) if (0..=limit as Word).contains(&index) => Ok(index as usize),
+        _ => Err(format!("{}: index {} out of range", name, value)),
+    }
+}
+
+/// The byte offset of character `index` of `string`, or its length in bytes
+/// when `index` is its length.
+fn byte_offset(string: &LispString, text: &str, index: usize) -> usize {
+    if string.char_count() == text.len() {
+        return index;
+    }
+    text.char_indices()
+        .nth(index)
+        .map_or(text.len(), |(offset, _)| offset)
+}
+
+/// Gives the string argument 0 the contents `value`, in place when its
+/// storage has the same size.
+fn replace_contents(
+    rt: &mut Runtime,
+    args: &StackArgs,
+    value: &str,
+    name: &str,
+) -> Result<(), String> {
+    let storage = string(args.get(0)).storage();
+    unsafe {
+        if (*storage).header.length() == value.len() {
+            (*storage).as_bytes_mut().copy_from_slice(value.as_bytes());
+            return Ok(());
+        }
+    }
+    let bytes = rt
+        .alloc_string_bytes(value.as_bytes())
+        .ok_or_else(|| heap_exhausted(name))?;
+    let string = string(args.get(0));
+    string.bytes = bytes;
+    rt.write_barrier(&raw mut string.bytes as usize);
+    Ok(())
+}
+
+/// Copies a string literal, whose text the runtime keeps, into a new string.
+pub(crate) extern "C" fn rt_string_literal(rt: *mut Runtime, bytes: *const u8, len: usize) -> Word {
+    let rt = unsafe { &mut *rt };
+    let value = unsafe { std::str::from_utf8_unchecked(std::slice::from_raw_parts(bytes, len)) };
+    match rt.alloc_string(value) {
+        Some(string) => string.as_raw_word(),
+        None => {
+            rt.raise(heap_exhausted("string"));
+            0
+        }
+    }
+}
+
+/// `(string-ref string k)`
+pub(crate) extern "C" fn rt_string_ref(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |_, args| {
+        let string = string_arg(args, 0, "string-ref")?;
+        let length = string.char_count();
+        let index = index_arg(args, 1, length, "string-ref")?;
+        if index == length {
+            return Err(format!("string-ref: index {} out of range", index));
+        }
+        let text = unsafe { string.as_str() };
+        let c = text[byte_offset(string, text, index)..]
+            .chars()
+            .next()
+            .unwrap();
+        Ok(LispValue::from_char(c))
+    })
+}
+
+/// `(substring string start [end])`
+pub(crate) extern "C" fn rt_substring(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let string = string_arg(args, 0, "substring")?;
+        let length = string.char_count();
+        let end = match args.len() {
+            3 => index_arg(args, 2, length, "substring")?,
+            _ => length,
+        };
+        let start = index_arg(args, 1, end, "substring")?;
+        let text = unsafe { string.as_str() };
+        let slice =
+            text[byte_offset(string, text, start)..byte_offset(string, text, end)].to_string();
+        rt.alloc_string(&slice)
+            .ok_or_else(|| heap_exhausted("substring"))
+    })
+}
+
+/// `(string-append string ...)`
+pub(crate) extern "C" fn rt_string_append(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let joined = (0..args.len())
+            .map(|index| text_arg(args, index, "string-append"))
+            .collect::<Result<String, _>>()?;
+        rt.alloc_string(&joined)
+            .ok_or_else(|| heap_exhausted("string-append"))
+    })
+}
+
+/// Whether `holds` holds between each string argument and the next.
+fn compare(
+    args: &StackArgs,
+    name: &str,
+    holds: fn(&str, &str) -> bool,
+) -> Result<LispValue, String> {
+    let texts = (0..args.len())
+        .map(|index| text_arg(args, index, name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let all = texts.windows(2).all(|pair| holds(pair[0], pair[1]));
+    Ok(LispValue::from_bool(all))
+}
+
+/// `(string=? string string ...)`
+pub(crate) extern "C" fn rt_string_eq(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |_, args| {
+        compare(args, "string=?", |a, b| a == b)
+    })
+}
+
+/// `(string<? string string ...)`: UTF-8 orders strings by code point.
+pub(crate) extern "C" fn rt_string_lt(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |_, args| {
+        compare(args, "string<?", |a, b| a < b)
+    })
+}
+
+/// `(string->list string)`
+pub(crate) extern "C" fn rt_string_to_list(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let chars: Vec<char> = text_arg(args, 0, "string->list")?.chars().collect();
+        let mut list = LispValue::nil();
+        for c in chars.into_iter().rev() {
+            list = rt
+                .alloc_pair(LispValue::from_char(c), list)
+                .ok_or_else(|| heap_exhausted("string->list"))?;
+        }
+        Ok(list)
+    })
+}
+
+/// `(list->string list)`
+pub(crate) extern "C" fn rt_list_to_string(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let list = args.get(0);
+        let invalid = || format!("list->string: expected list of chars, got {}", list);
+        let mut value = String::new();
+        let mut rest = list;
+        while let Some(pair) = rest.as_pair_pointer() {
+            let pair = unsafe { *pair };
+            value.push(pair.car.as_char().ok_or_else(invalid)?);
+            rest = pair.cdr;
+        }
+        if !rest.is_nil() {
+            return Err(invalid());
+        }
+        rt.alloc_string(&value)
+            .ok_or_else(|| heap_exhausted("list->string"))
+    })
+}
+
+/// The radix argument `index`, when given, of `number->string` and `string->number`.
+fn radix_arg(args: &StackArgs, index: usize, name: &str) -> Result<u32, String> {
+    if args.len() <= index {
+        return Ok(10);
+    }
+    let radix = args.get(index);
+    match radix.as_integer() {
+        Some(radix @ (2 | 8 | 10 | 16)) => Ok(radix as u32),
+        _ => Err(format!("{}: invalid radix {}", name, radix)),
+    }
+}
+
+/// `(number->string z [radix])`
+pub(crate) extern "C" fn rt_number_to_string(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let radix = radix_arg(args, 1, "number->string")?;
+        let value = args.get(0);
+        let text = match number_value(value) {
+            Some(Number::Integer(number)) => number.to_string_radix(radix),
+            Some(Number::Flonum(number)) if radix == 10 => format_flonum(number),
+            Some(Number::Flonum(_)) => {
+                return Err(format!(
+                    "number->string: inexact {} in radix {}",
+                    value, radix
+                ));
+            }
+            None => return Err(format!("number->string: expected number, got {}", value)),
+        };
+        rt.alloc_string(&text)
+            .ok_or_else(|| heap_exhausted("number->string"))
+    })
+}
+
+/// `(string->number string [radix])`: the number, or #f if the string does
+/// not spell one.
+pub(crate) extern "C" fn rt_string_to_number(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let radix = radix_arg(args, 1, "string->number")?;
+        let text = text_arg(args, 0, "string->number")?;
+        if let Some(number) = BigInt::parse(text, radix) {
+            return rt
+                .alloc_integer(&number)
+                .ok_or_else(|| heap_exhausted("string->number"));
+        }
+        match parse_flonum(text).filter(|_| radix == 10) {
+            Some(number) => rt
+                .alloc_flonum(number)
+                .ok_or_else(|| heap_exhausted("string->number")),
+            None => Ok(LispValue::false_val()),
+        }
+    })
+}
+
+/// `(string-set! string k char)`
+pub(crate) extern "C" fn rt_string_set(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let string = string_arg(args, 0, "string-set!")?;
+        let length = string.char_count();
+        let index = index_arg(args, 1, length, "string-set!")?;
+        if index == length {
+            return Err(format!("string-set!: index {} out of range", index));
+        }
+        let c = char_arg(args, 2, "string-set!")?;
+        let text = unsafe { string.as_str() };
+        let offset = byte_offset(string, text, index);
+        let old = text[offset..].chars().next().unwrap();
+        let mut value = String::with_capacity(text.len() + c.len_utf8());
+        value.push_str(&text[..offset]);
+        value.push(c);
+        value.push_str(&text[offset + old.len_utf8()..]);
+        replace_contents(rt, args, &value, "string-set!")?;
+        Ok(LispValue::nil())
+    })
+}
+
+/// `(string-fill! string char)`
+pub(crate) extern "C" fn rt_string_fill(
+    rt: *mut Runtime,
+    args: *const LispValue,
+    argc: usize,
+) -> Word {
+    call(rt, args, argc, |rt, args| {
+        let length = string_arg(args, 0, "string-fill!")?.char_count();
+        let c = char_arg(args, 1, "string-fill!")?;
+        let value: String = std::iter::repeat_n(c, length).collect();
+        replace_contents(rt, args, &value, "string-fill!")?;
+        Ok(LispValue::nil())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::Safety;
+    use crate::runtime::Runtime;
+    use crate::testing::{assert_errors, assert_errors_with, assert_evals, eval_str};
+
+    #[test]
+    fn test_string_primitives() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("\"héllo\"", "\"héllo\""),
+            ("(string-length \"héllo\")", "5"),
+            ("(string-length \"\")", "0"),
+            ("(string-ref \"héllo\" 1)", "#\\é"),
+            ("(string-ref \"abc\" 2)", "#\\c"),
+            ("(substring \"λx.x y\" 1 4)", "\"x.x\""),
+            ("(substring \"λx.x y\" 2)", "\".x y\""),
+            ("(string-append)", "\"\""),
+            ("(string-append \"a\" \"λ\" \"\" \"c\")", "\"aλc\""),
+            ("(string=? \"ab\" \"ab\" \"ab\")", "#t"),
+            ("(string=? \"ab\" \"ab\" \"abc\")", "#f"),
+            ("(string<? \"ab\" \"abc\" \"b\")", "#t"),
+            ("(string<? \"b\" \"a\")", "#f"),
+            ("(string<? \"z\" \"λ\")", "#t"),
+            ("(string->list \"aλ\")", "(#\\a #\\λ)"),
+            ("(list->string (string->list \"aλb\"))", "\"aλb\""),
+            ("(list->string nil)", "\"\""),
+            ("(number->string 42)", "\"42\""),
+            ("(number->string (sub1 0) 2)", "\"-1\""),
+            ("(number->string 255 16)", "\"ff\""),
+            ("(string->number \"-17\")", "-17"),
+            ("(string->number \"ff\" 16)", "255"),
+            ("(string->number \"12x\")", "#f"),
+            (
+                "(string->number \"99999999999999999999\")",
+                "99999999999999999999",
+            ),
+            ("(number->string (expt 2 64) 16)", "\"10000000000000000\""),
+            ("(number->string -1.5)", "\"-1.5\""),
+            ("(string->number \"2.5e3\")", "2500.0"),
+            ("(string->number \"+inf.0\")", "+inf.0"),
+            ("(string->number \"inf\")", "#f"),
+            ("(string->number \"1.5\" 16)", "#f"),
+            ("(define s (string-append \"abc\"))", "s"),
+            ("(string-set! s 1 #\\B)", "()"),
+            ("s", "\"aBc\""),
+            ("(string-set! s 1 #\\λ)", "()"),
+            ("(cons s (string-length s))", "(\"aλc\" . 3)"),
+            ("(string-set! s 1 #\\b)", "()"),
+            ("s", "\"abc\""),
+            ("(string-fill! s #\\z)", "()"),
+            ("s", "\"zzz\""),
+            ("(string-fill! s #\\λ)", "()"),
+            ("(cons s (string-ref s 2))", "(\"λλλ\" . #\\λ)"),
+        ];
+        assert_evals(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_string_errors() {
+        let mut runtime = Runtime::new();
+        let cases = [
+            ("(string-ref \"abc\" 3)", "string-ref: index 3 out of range"),
+            (
+                "(string-ref \"abc\" (sub1 0))",
+                "string-ref: index -1 out of range",
+            ),
+            ("(substring \"abc\" 2 1)", "substring: index 2 out of range"),
+            ("(substring \"abc\" 0 4)", "substring: index 4 out of range"),
+            (
+                "(string-append \"a\" 'b)",
+                "string-append: expected string, got b",
+            ),
+            (
+                "(string-length 'a)",
+                "string-length: expected string, got a",
+            ),
+            (
+                "(list->string (cons #\\a 1))",
+                "list->string: expected list of chars, got (#\\a . 1)",
+            ),
+            ("(number->string 1 3)", "number->string: invalid radix 3"),
+            (
+                "(string-set! \"abc\" 1 2)",
+                "string-set!: expected char, got 2",
+            ),
+        ];
+        assert_errors(&mut runtime, &cases);
+    }
+
+    #[test]
+    fn test_unchecked_string_arguments() {
+        let mut runtime = Runtime::new();
+        // Unchecked code leaves the checks to the runtime calls.
+        let cases = [
+            (
+                "(string-append \"a\" 1)",
+                "string-append: expected string, got 1",
+            ),
+            ("(string-ref 1 0)", "string-ref: expected string, got 1"),
+            ("(substring 'a 0)", "substring: expected string, got a"),
+            ("(string=? \"a\" nil)", "string=?: expected string, got ()"),
+            (
+                "(string->list #\\a)",
+                "string->list: expected string, got #\\a",
+            ),
+            (
+                "(string->number 1)",
+                "string->number: expected string, got 1",
+            ),
+            (
+                "(string-set! \"a\" 0 1)",
+                "string-set!: expected char, got 1",
+            ),
+            (
+                "(string-fill! 1 #\\a)",
+                "string-fill!: expected string, got 1",
+            ),
+            (
+                "(string-fill! \"a\" 'b)",
+                "string-fill!: expected char, got b",
+            ),
+        ];
+        assert_errors_with(&mut runtime, Safety::Unchecked, &cases);
+    }
+
+    #[test]
+    fn test_replaced_storage_survives_minor_collections() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define s (string-append \"abc\"))").unwrap();
+        runtime.collect();
+        // The new storage is young and only reachable from the old string.
+        eval_str(&mut runtime, "(string-set! s 0 #\\λ)").unwrap();
+        runtime.collect_minor();
+        eval_str(&mut runtime, "(make-vector 200 (string-append \"junk\"))").unwrap();
+        assert_eq!(eval_str(&mut runtime, "s").unwrap(), "\"λbc\"");
+        assert_eq!(runtime.verify_heap(), Ok(()));
+    }
+}
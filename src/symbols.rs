@@ -0,0 +1,131 @@
+use crate::encodings::LispValue;
+use std::collections::HashMap;
+
+/// Keeps track of the symbols of a runtime, which live on its heap.
+/// Interned symbols are unique per name and live as long as the table.
+/// Each interned symbol is held in a boxed cell, which compiled code loads it
+/// from, so the collector can move the symbol and update a single place.
+/// Symbols made by `gensym` are uninterned and held weakly: the collector may
+/// free them once nothing refers to them.
+pub struct SymbolTable {
+    interned: HashMap<String, Box<LispValue>>,
+    weak: Vec<LispValue>,
+    gensym_counter: usize,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            interned: HashMap::new(),
+            weak: Vec::new(),
+            gensym_counter: 0,
+        }
+    }
+
+    /// The interned symbol named `name`, if there is one.
+    pub fn get(&self, name: &str) -> Option<LispValue> {
+        self.interned.get(name).map(|cell| **cell)
+    }
+
+    /// The cell holding the interned symbol named `name`, if there is one.
+    pub fn cell(&mut self, name: &str) -> Option<*mut LispValue> {
+        self.interned
+            .get_mut(name)
+            .map(|cell| &mut **cell as *mut LispValue)
+    }
+
+    /// Records `symbol` as the interned symbol named `name`.
+    pub(crate) fn insert(&mut self, name: &str, symbol: LispValue) {
+        self.interned.insert(name.to_string(), Box::new(symbol));
+    }
+
+    /// Calls `f` on the cell of every interned symbol.
+    pub(crate) fn for_each_interned(&mut self, f: impl FnMut(&mut LispValue)) {
+        self.interned
+            .values_mut()
+            .map(|cell| &mut **cell)
+            .for_each(f);
+    }
+
+    /// The interned symbols, with their names.
+    pub fn iter_interned(&self) -> impl Iterator<Item = (&str, LispValue)> {
+        self.interned
+            .iter()
+            .map(|(name, cell)| (name.as_str(), **cell))
+    }
+
+    /// Replaces each weak symbol with what `update` returns for it, forgetting
+    /// it if that is `None`.
+    pub(crate) fn update_weak(&mut self, mut update: impl FnMut(LispValue) -> Option<LispValue>) {
+        self.weak.retain_mut(|symbol| match update(*symbol) {
+            Some(moved) => {
+                *symbol = moved;
+                true
+            }
+            None => false,
+        });
+    }
+
+    /// Records an uninterned symbol, to be held weakly.
+    pub(crate) fn insert_weak(&mut self, symbol: LispValue) {
+        self.weak.push(symbol);
+    }
+
+    /// A fresh name for `gensym`.
+    pub(crate) fn next_gensym_name(&mut self) -> String {
+        self.gensym_counter += 1;
+        format!("g{}", self.gensym_counter)
+    }
+
+    /// Forgets the weak symbols for which `is_live` returns false.
+    /// Returns how many were dropped.
+    pub fn sweep_weak(&mut self, mut is_live: impl FnMut(LispValue) -> bool) -> usize {
+        let before = self.weak.len();
+        self.weak.retain(|symbol| is_live(*symbol));
+        before - self.weak.len()
+    }
+
+    /// Number of symbols currently known to the table.
+    pub fn len(&self) -> usize {
+        self.interned.len() + self.weak.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn test_intern_is_unique() {
+        let mut runtime = Runtime::new();
+        let a = runtime.intern("a");
+        assert_eq!(a, runtime.intern("a"));
+        assert_ne!(a, runtime.intern("b"));
+        assert_eq!(runtime.symbols().len(), 2);
+        assert_eq!(runtime.symbols().get("a"), Some(a));
+    }
+
+    #[test]
+    fn test_gensym_is_weak() {
+        let mut runtime = Runtime::new();
+        let kept = runtime.gensym().unwrap();
+        let dropped = runtime.gensym().unwrap();
+        assert_ne!(kept, dropped);
+        assert_ne!(kept, runtime.intern(&kept.to_string()));
+
+        let table = runtime.symbols_mut();
+        assert_eq!(table.sweep_weak(|symbol| symbol == kept), 1);
+        assert_eq!(table.len(), 2);
+        assert_eq!(kept.to_string(), "g1");
+    }
+}
@@ -0,0 +1,73 @@
+// Helpers for the tests of every module that runs Lisp code: compiling a
+// form and running it, and checking a table of inputs against what they
+// print or the error they raise.
+
+use crate::compiler::{Compiler, Overflow, Safety};
+use crate::encodings::LispValue;
+use crate::executable_buffer::ExecBuffer;
+use crate::reader::Parser;
+use crate::runtime::Runtime;
+
+/// Compiles the form `input` with the given settings and runs it.
+pub(crate) fn eval_configured(
+    runtime: &mut Runtime,
+    input: &str,
+    safety: Safety,
+    overflow: Overflow,
+) -> Result<LispValue, String> {
+    let ast = Parser::new(input).read_form()?;
+    let code = Compiler::new(runtime)
+        .with_safety(safety)
+        .with_overflow(overflow)
+        .compile_function(&ast)
+        .map_err(|err| format!("{:?}", err))?;
+    let exec = ExecBuffer::new(&code).unwrap();
+    runtime.run(&exec)
+}
+
+pub(crate) fn eval_with(
+    runtime: &mut Runtime,
+    input: &str,
+    safety: Safety,
+) -> Result<LispValue, String> {
+    eval_configured(runtime, input, safety, Overflow::Promote)
+}
+
+/// Compiles the form `input` as the compiler does by default and runs it.
+pub(crate) fn eval(runtime: &mut Runtime, input: &str) -> Result<LispValue, String> {
+    eval_with(runtime, input, Safety::Checked)
+}
+
+/// Like `eval`, returning the value as `write` prints it.
+pub(crate) fn eval_str(runtime: &mut Runtime, input: &str) -> Result<String, String> {
+    eval(runtime, input).map(|value| value.write())
+}
+
+/// Evaluates each input in turn, checking what its value prints as.
+pub(crate) fn assert_evals(runtime: &mut Runtime, cases: &[(&str, &str)]) {
+    for (input, expected) in cases {
+        assert_eq!(
+            eval_str(runtime, input).as_deref(),
+            Ok(*expected),
+            "{}",
+            input
+        );
+    }
+}
+
+/// Evaluates each input in turn, checking the error it raises.
+pub(crate) fn assert_errors(runtime: &mut Runtime, cases: &[(&str, &str)]) {
+    assert_errors_with(runtime, Safety::Checked, cases)
+}
+
+/// Like `assert_errors`, compiling with `safety`.
+pub(crate) fn assert_errors_with(runtime: &mut Runtime, safety: Safety, cases: &[(&str, &str)]) {
+    for (input, expected) in cases {
+        assert_eq!(
+            eval_with(runtime, input, safety),
+            Err(expected.to_string()),
+            "{}",
+            input
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use crate::bignum::BigInt;
 use std::iter::Peekable;
 use std::str::Chars;
 
@@ -7,8 +8,15 @@ pub enum Token {
     LParen, // (
     RParen, // )
     Integer(i64),
+    /// An integer too large for an `i64`.
+    BigInteger(BigInt),
+    Float(f64),
     Symbol(String),
     Char(char), // <-- ADD THIS
+    Str(String),
+    Quote, // '
+    /// Input that cannot be read, with a message saying why.
+    Error(String),
 }
 
 /// The Tokenizer struct, which is itself an iterator.
@@ -30,8 +38,13 @@ impl<'a> Iterator for Tokenizer<'a> {
         match ch {
             '(' => Some(Token::LParen),
             ')' => Some(Token::RParen),
+            '\'' => Some(Token::Quote),
+            '"' => Some(self.tokenize_string()),
 
             '0'..='9' => Some(self.tokenize_number(ch)),
+            '-' if self.chars.peek().is_some_and(char::is_ascii_digit) => {
+                Some(self.tokenize_number(ch))
+            }
 
             '#' => {
                 if self.chars.peek() == Some(&'\\') {
@@ -55,21 +68,32 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
-    /// Consumes and returns a number token.
+    /// Consumes and returns a number token: an integer, or a float when it
+    /// has a fraction or an exponent. Malformed input such as `1.2.3` or `1e`
+    /// gives an error token.
     fn tokenize_number(&mut self, first_char: char) -> Token {
         let mut s = String::new();
         s.push(first_char);
 
         while let Some(&ch) = self.chars.peek() {
-            if ch.is_digit(10) {
+            let exponent_sign = (ch == '+' || ch == '-') && s.ends_with(['e', 'E']);
+            if ch.is_ascii_digit() || matches!(ch, '.' | 'e' | 'E') || exponent_sign {
                 s.push(self.chars.next().unwrap());
             } else {
                 break;
             }
         }
 
-        let num = s.parse::<i64>().expect("Tokenizer failed to parse number");
-        Token::Integer(num)
+        if s.contains(['.', 'e', 'E']) {
+            return match s.parse::<f64>() {
+                Ok(num) => Token::Float(num),
+                Err(_) => Token::Error(format!("Invalid number: {}", s)),
+            };
+        }
+        match s.parse::<i64>() {
+            Ok(num) => Token::Integer(num),
+            Err(_) => Token::BigInteger(BigInt::parse(&s, 10).unwrap()),
+        }
     }
 
     /// Consumes and returns a symbol token.
@@ -84,7 +108,12 @@ impl<'a> Tokenizer<'a> {
                 s.push(self.chars.next().unwrap());
             }
         }
-        Token::Symbol(s)
+        match s.as_str() {
+            "+inf.0" => Token::Float(f64::INFINITY),
+            "-inf.0" => Token::Float(f64::NEG_INFINITY),
+            "+nan.0" | "-nan.0" => Token::Float(f64::NAN),
+            _ => Token::Symbol(s),
+        }
     }
 
     /// Skips over any whitespace
@@ -98,6 +127,26 @@ impl<'a> Tokenizer<'a> {
         }
     }
 
+    fn tokenize_string(&mut self) -> Token {
+        // We've already consumed the opening quote
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Token::Str(s),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some(c @ ('"' | '\\')) => s.push(c),
+                    Some(c) => return Token::Error(format!("Unknown escape in string: \\{}", c)),
+                    None => return Token::Error(format!("Unterminated string: \"{}", s)),
+                },
+                Some(c) => s.push(c),
+                None => return Token::Error(format!("Unterminated string: \"{}", s)),
+            }
+        }
+    }
+
     fn tokenize_char(&mut self) -> Token {
         // We've already consumed the #\
         // Read the rest of the symbol (e.g., "a", "space", "newline")
@@ -110,21 +159,29 @@ impl<'a> Tokenizer<'a> {
             }
         }
 
-        if s.len() == 1 {
-            // It's a single char, e.g., #\a
-            Token::Char(s.chars().next().unwrap())
-        } else {
-            // It's a named char, e.g., #\space
-            match s.as_str() {
-                "space" => Token::Char(' '),
-                "newline" => Token::Char('\n'),
-                "tab" => Token::Char('\t'),
-                _ => panic!("Unknown character name: #\\{}", s),
-            }
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            // It's a single char, e.g., #\a or #\λ
+            return Token::Char(c);
+        }
+        // It's a named char, e.g., #\space, or a code point in hex, e.g., #\x3bb
+        match s.as_str() {
+            "space" => Token::Char(' '),
+            "newline" => Token::Char('\n'),
+            "tab" => Token::Char('\t'),
+            "return" => Token::Char('\r'),
+            "null" => Token::Char('\0'),
+            _ => s
+                .strip_prefix('x')
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .and_then(char::from_u32)
+                .map(Token::Char)
+                .unwrap_or_else(|| Token::Error(format!("Unknown character name: #\\{}", s))),
         }
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
 
@@ -141,5 +198,84 @@ mod tests {
 
         let mut tokenizer = Tokenizer::new("#\\tab");
         assert_eq!(tokenizer.next(), Some(Token::Char('\t')));
+
+        let mut tokenizer = Tokenizer::new("#\\λ #\\x3bb #\\x #\\x1F600");
+        assert_eq!(tokenizer.next(), Some(Token::Char('λ')));
+        assert_eq!(tokenizer.next(), Some(Token::Char('λ')));
+        assert_eq!(tokenizer.next(), Some(Token::Char('x')));
+        assert_eq!(tokenizer.next(), Some(Token::Char('😀')));
+    }
+
+    #[test]
+    fn test_tokenize_string() {
+        let tokens: Vec<Token> = Tokenizer::new("(\"a b\" \"λ\\\"\\n\" \"\")").collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LParen,
+                Token::Str("a b".to_string()),
+                Token::Str("λ\"\n".to_string()),
+                Token::Str(String::new()),
+                Token::RParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_numbers() {
+        let tokens: Vec<Token> = Tokenizer::new("42 -7 - -x 9223372036854775808").collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Integer(42),
+                Token::Integer(-7),
+                Token::Symbol("-".to_string()),
+                Token::Symbol("-x".to_string()),
+                Token::BigInteger(BigInt::parse("9223372036854775808", 10).unwrap()),
+            ]
+        );
+
+        let tokens: Vec<Token> = Tokenizer::new("1.5 -0.25 2e3 1.5E-3 3. +inf.0 -inf.0").collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Float(1.5),
+                Token::Float(-0.25),
+                Token::Float(2000.0),
+                Token::Float(0.0015),
+                Token::Float(3.0),
+                Token::Float(f64::INFINITY),
+                Token::Float(f64::NEG_INFINITY),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_invalid_numbers() {
+        for input in ["1e", "1.2.3", "2e+", "-3E-"] {
+            let tokens: Vec<Token> = Tokenizer::new(input).collect();
+            assert_eq!(
+                tokens,
+                vec![Token::Error(format!("Invalid number: {}", input))],
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_tokenize_quote() {
+        let tokens: Vec<Token> = Tokenizer::new("'(a 'b)").collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Quote,
+                Token::LParen,
+                Token::Symbol("a".to_string()),
+                Token::Quote,
+                Token::Symbol("b".to_string()),
+                Token::RParen,
+            ]
+        );
     }
 }
@@ -0,0 +1,250 @@
+// A heap verifier, for when compiled code or the collector has corrupted the
+// heap and the symptom is a crash far from the cause.
+//
+// It walks everything reachable from the roots and checks each value: its
+// tag must be one of the tagging scheme, and a heap pointer must be aligned,
+// point at an allocated object in one of the generations, and agree with the
+// header it finds there. The first bad value is reported with the path that
+// led to it from a root.
+
+use crate::encodings::{
+    Header, K_BIGNUM_KIND, K_CLOSURE_TAG, K_FLONUM_KIND, K_HASH_TABLE_KIND, K_PAIR_TAG,
+    K_PERSISTENT_MAP_KIND, K_PERSISTENT_VECTOR_KIND, K_RECORD_KIND, K_STRING_BYTES_KIND,
+    K_STRING_TAG, K_SYMBOL_TAG, K_VECTOR_TAG, LispValue, TagsDict, TypeTag, Word,
+};
+use crate::gc;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+/// A corrupt value found by `verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapError {
+    /// What is wrong with the value.
+    pub problem: String,
+    pub value: Word,
+    /// How the value was reached: the root first, then each field followed.
+    pub path: Vec<String>,
+}
+
+impl fmt::Display for HeapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {:#x} at {}",
+            self.problem,
+            self.value,
+            self.path.join(" -> ")
+        )
+    }
+}
+
+/// Checks every value reachable from `roots`, each named by where it is held.
+/// `spaces` are the allocated parts of the heap: a pointer anywhere else is
+/// corrupt.
+pub fn verify(roots: &[(String, LispValue)], spaces: &[Range<usize>]) -> Result<(), HeapError> {
+    let tags = TagsDict::new();
+    // How each object was first reached: its parent, if not a root, and the
+    // name of the field or root.
+    let mut reached: HashMap<usize, (Option<usize>, String)> = HashMap::new();
+    let mut pending = Vec::new();
+    let path_to = |reached: &HashMap<usize, (Option<usize>, String)>,
+                   mut parent: Option<usize>,
+                   last: String| {
+        let mut path = vec![last];
+        while let Some(address) = parent {
+            let (grandparent, name) = &reached[&address];
+            path.push(name.clone());
+            parent = *grandparent;
+        }
+        path.reverse();
+        path
+    };
+
+    for (name, value) in roots {
+        if let Err(problem) = check_value(&tags, spaces, *value) {
+            return Err(HeapError {
+                problem,
+                value: value.as_raw_word(),
+                path: vec![name.clone()],
+            });
+        }
+        if let Some(address) = value.heap_address()
+            && !reached.contains_key(&address)
+        {
+            reached.insert(address, (None, name.clone()));
+            pending.push(address);
+        }
+    }
+    while let Some(object) = pending.pop() {
+        let kind = unsafe { gc::object_kind(object) };
+        let mut fields = Vec::new();
+        unsafe { gc::for_each_field(object, |field| fields.push(*field)) };
+        for (index, value) in fields.into_iter().enumerate() {
+            let name = field_name(kind, index);
+            if let Err(problem) = check_value(&tags, spaces, value) {
+                return Err(HeapError {
+                    problem,
+                    value: value.as_raw_word(),
+                    path: path_to(&reached, Some(object), name),
+                });
+            }
+            if let Some(address) = value.heap_address()
+                && !reached.contains_key(&address)
+            {
+                reached.insert(address, (Some(object), name));
+                pending.push(address);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How the path of a `HeapError` names field `index` of an object of `kind`,
+/// in the order `gc::for_each_field` visits them.
+fn field_name(kind: Word, index: usize) -> String {
+    match (kind, index) {
+        (K_PAIR_TAG, 0) => "car".to_string(),
+        (K_PAIR_TAG, _) => "cdr".to_string(),
+        (K_VECTOR_TAG, _) => format!("[{}]", index),
+        (K_CLOSURE_TAG, 0) => "arity".to_string(),
+        (K_STRING_TAG, 0) => "length".to_string(),
+        (K_STRING_TAG, _) => "bytes".to_string(),
+        (K_HASH_TABLE_KIND, 0) => "equivalence".to_string(),
+        (K_HASH_TABLE_KIND, 1) => "count".to_string(),
+        (K_HASH_TABLE_KIND, 2) => "epoch".to_string(),
+        (K_HASH_TABLE_KIND, _) => "buckets".to_string(),
+        (K_RECORD_KIND, 0) => "descriptor".to_string(),
+        (K_RECORD_KIND, _) => format!("[{}]", index - 1),
+        (K_PERSISTENT_VECTOR_KIND | K_PERSISTENT_MAP_KIND, 0) => "count".to_string(),
+        (K_PERSISTENT_VECTOR_KIND, 1) => "shift".to_string(),
+        (K_PERSISTENT_VECTOR_KIND | K_PERSISTENT_MAP_KIND, _) => "root".to_string(),
+        _ => "name".to_string(),
+    }
+}
+
+/// Checks a single value, and the header of the object it points to.
+fn check_value(tags: &TagsDict, spaces: &[Range<usize>], value: LispValue) -> Result<(), String> {
+    // Only the tag: the header of a boxed object is checked below, once the
+    // pointer is known to be sound.
+    let word = value.as_raw_word();
+    match tags.iter().find(|tag| tag.matches_tag(word)) {
+        None | Some(&TypeTag::HEADER) => return Err("invalid tag".to_string()),
+        Some(_) => {}
+    }
+    let Some(address) = value.heap_address() else {
+        return Ok(());
+    };
+    // The pointer tags take the low bits, so this only fails if the untagging
+    // itself is wrong.
+    if !address.is_multiple_of(size_of::<Word>()) {
+        return Err("misaligned pointer".to_string());
+    }
+    let Some(space) = spaces.iter().find(|space| space.contains(&address)) else {
+        return Err("pointer outside the heap".to_string());
+    };
+    let first = unsafe { *(address as *const Word) };
+    let kind = value.heap_tag();
+    if kind == K_PAIR_TAG {
+        if Header::is_header(first) {
+            return Err("pair pointer to a header".to_string());
+        }
+    } else {
+        if !Header::is_header(first) {
+            return Err(format!("pointer to a non-header {:#x}", first));
+        }
+        let header = Header::from_raw_word(first);
+        if header.pointer_tag() != kind {
+            return Err(format!("pointer to a header {:#x} of another type", first));
+        }
+        let kinds = [
+            K_VECTOR_TAG,
+            K_STRING_TAG,
+            K_STRING_BYTES_KIND,
+            K_SYMBOL_TAG,
+            K_CLOSURE_TAG,
+            K_BIGNUM_KIND,
+            K_FLONUM_KIND,
+            K_HASH_TABLE_KIND,
+            K_RECORD_KIND,
+            K_PERSISTENT_VECTOR_KIND,
+            K_PERSISTENT_MAP_KIND,
+        ];
+        if !kinds.contains(&header.kind()) {
+            return Err(format!(
+                "pointer to an object of unknown kind {:#x}",
+                header.kind()
+            ));
+        }
+    }
+    let size = unsafe { gc::object_size(address) };
+    if address + size > space.end {
+        return Err(format!(
+            "object of {} bytes past the end of its space",
+            size
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encodings::{LispValue, Word};
+    use crate::runtime::Runtime;
+    use crate::testing::eval_str;
+
+    /// Overwrites field `index` of the object `value` points to.
+    fn poke(value: LispValue, index: usize, word: Word) {
+        let address = value.heap_address().unwrap();
+        unsafe { *((address + index * size_of::<Word>()) as *mut Word) = word };
+    }
+
+    #[test]
+    fn test_verify_healthy_heap() {
+        let mut runtime = Runtime::with_heap(4096, 1 << 20);
+        runtime.set_heap_verification(true);
+        runtime.set_gc_stress(true);
+        for input in [
+            "(define l (cons 1 (cons #\\a nil)))",
+            "(define v (vector l (symbol->string 'b) (gensym)))",
+            "(define (f x) (cons x v))",
+            "(vector-set! v 0 v)",
+            "(f (make-vector 3 l))",
+        ] {
+            eval_str(&mut runtime, input).unwrap();
+            assert_eq!(runtime.verify_heap(), Ok(()), "{}", input);
+        }
+        assert!(runtime.heap().collections() > 0);
+    }
+
+    #[test]
+    fn test_verify_reports_path_to_corruption() {
+        let mut runtime = Runtime::new();
+        eval_str(&mut runtime, "(define v (vector 1 (cons 2 (cons 3 nil))))").unwrap();
+        let v = runtime.global("v").unwrap();
+        let pair = eval_str(&mut runtime, "(cdr (vector-ref v 1))").unwrap();
+        assert_eq!(pair, "(3)");
+
+        // A header where a value belongs.
+        let inner =
+            LispValue::from_raw_word(unsafe { *((v.heap_address().unwrap() + 16) as *const Word) });
+        poke(inner, 1, 0x4f);
+        let error = runtime.verify_heap().unwrap_err();
+        assert_eq!(error.problem, "invalid tag");
+        assert_eq!(error.path, ["global v", "[1]", "cdr"]);
+        assert_eq!(
+            error.to_string(),
+            "invalid tag 0x4f at global v -> [1] -> cdr"
+        );
+
+        // A pointer that is well tagged but leads nowhere.
+        poke(inner, 1, 0x1000 | 1);
+        let error = runtime.verify_heap().unwrap_err();
+        assert_eq!(error.problem, "pointer outside the heap");
+
+        // A vector pointer to a pair.
+        poke(inner, 1, inner.as_raw_word() - 1 + 2);
+        let error = runtime.verify_heap().unwrap_err();
+        assert_eq!(error.problem, "pointer to a non-header 0x8");
+    }
+}